@@ -46,7 +46,7 @@ while True:
     adapter = input("Active adapter >>> ")
     messages.append({"role": "user", "content": prompt})
     completion = client.chat.completions.create(
-        model="mistral",
+        model="default",
         messages=messages,
         max_tokens=256,
         frequency_penalty=1.0,
@@ -45,7 +45,7 @@ headers = {
 }
 
 payload = {
-    "model": "phi3v",
+    "model": "default",
     "messages": [
         {
             "role": "user",
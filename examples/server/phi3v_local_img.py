@@ -42,7 +42,7 @@ headers = {
 }
 
 payload = {
-    "model": "phi3v",
+    "model": "default",
     "messages": [
         {
             "role": "user",
@@ -58,7 +58,7 @@ messages = [
 ]
 
 completion = client.chat.completions.create(
-    model="llama-3.1", messages=messages, tools=tools, tool_choice="auto"
+    model="default", messages=messages, tools=tools, tool_choice="auto"
 )
 
 # print(completion.usage)
@@ -81,7 +81,7 @@ if tool_called.name in functions:
     messages.append({"role": "tool", "content": result})
 
     completion = client.chat.completions.create(
-        model="llama-3.1", messages=messages, tools=tools, tool_choice="auto"
+        model="default", messages=messages, tools=tools, tool_choice="auto"
     )
     # print(completion.usage)
     print(completion.choices[0].message.content)
@@ -6,7 +6,7 @@ with open("examples/server/c.y", "r") as f:
     c_yacc = f.read()
 
 completion = client.chat.completions.create(
-    model="mistral",
+    model="default",
     messages=[
         {
             "role": "user",
@@ -64,6 +64,16 @@ fn run_bench(
         stop_toks: None,
         logits_bias: None,
         n_choices: 1,
+        beam_width: None,
+        length_penalty: None,
+        seed: None,
+        processed_logprobs: false,
+        repetition_penalty: None,
+        no_repeat_ngram_size: None,
+        dry_params: None,
+        typical_p: None,
+        top_a: None,
+        mirostat: None,
     };
     let sender = mistralrs.get_sender().unwrap();
     let (tx, mut rx) = channel(10_000);
@@ -80,6 +90,10 @@ fn run_bench(
         adapters: None,
         tools: None,
         tool_choice: None,
+        priority: 0,
+        tenant: None,
+        logits_processors: None,
+        include_usage: false,
     });
 
     let mut usages = Vec::new();
@@ -111,6 +125,8 @@ fn run_bench(
                         usages.push(res.usage);
                     }
                     Response::CompletionChunk(_) => unreachable!(),
+                    Response::Embeddings(_) => unreachable!(),
+                    Response::Score(_) => unreachable!(),
                 },
                 None => unreachable!("Expected a Done response, got None",),
             }
@@ -226,6 +242,16 @@ fn warmup_run(mistralrs: Arc<MistralRs>) {
         stop_toks: None,
         logits_bias: None,
         n_choices: 1,
+        beam_width: None,
+        length_penalty: None,
+        seed: None,
+        processed_logprobs: false,
+        repetition_penalty: None,
+        no_repeat_ngram_size: None,
+        dry_params: None,
+        typical_p: None,
+        top_a: None,
+        mirostat: None,
     };
     let sender = mistralrs.get_sender().unwrap();
     let (tx, mut rx) = channel(10_000);
@@ -246,6 +272,10 @@ fn warmup_run(mistralrs: Arc<MistralRs>) {
         adapters: None,
         tools: None,
         tool_choice: None,
+        priority: 0,
+        tenant: None,
+        logits_processors: None,
+        include_usage: false,
     });
 
     sender
@@ -1,11 +1,13 @@
 use std::{
     collections::{hash_map::Entry, HashMap},
-    hash::Hash,
+    hash::{DefaultHasher, Hash, Hasher},
     marker::PhantomData,
     ops::Deref,
     sync::{Arc, Mutex, MutexGuard},
 };
 
+use indexmap::IndexSet;
+
 use super::block_engine_sequence::BlockEngineSequence;
 
 pub struct LogicalTokenBlock {
@@ -42,6 +44,10 @@ impl LogicalTokenBlock {
         self.tokens.pop();
         self.num_tokens -= 1;
     }
+
+    pub fn toks(&self) -> &[usize] {
+        &self.tokens[..self.num_tokens]
+    }
 }
 
 #[derive(Hash, PartialEq, Eq)]
@@ -107,10 +113,10 @@ struct Allocator<T> {
 }
 
 impl<T> Allocator<T> {
-    fn allocate(&mut self) -> Arc<PhysicalTokenBlock> {
-        let block = self.free_blocks.pop().unwrap();
+    fn allocate(&mut self) -> Option<Arc<PhysicalTokenBlock>> {
+        let block = self.free_blocks.pop()?;
         block.deref_mut().refcount = 1;
-        block
+        Some(block)
     }
 
     fn free_block(&mut self, block: Arc<PhysicalTokenBlock>) {
@@ -171,6 +177,89 @@ impl Allocator<CPUAllocator> {
     }
 }
 
+/// Full GPU blocks indexed by a hash of their tokens and of all tokens before them, so that sequences
+/// which share a prefix (such as a system prompt) can share the physical blocks holding its KV cache.
+struct PrefixBlockCache {
+    blocks: HashMap<u64, Arc<PhysicalTokenBlock>>,
+    hashes: HashMap<usize, u64>,
+    /// Blocks the model has not written yet, with the sequence which registered them and their logical index.
+    pending: HashMap<u64, (SeqID, usize)>,
+    /// For the sequences in the current step, how many of their leading logical blocks the step writes.
+    written: HashMap<SeqID, usize>,
+    /// Cached blocks which no sequence uses, least recently used first.
+    evictable: IndexSet<u64>,
+}
+
+impl PrefixBlockCache {
+    fn new() -> Self {
+        Self {
+            blocks: HashMap::new(),
+            hashes: HashMap::new(),
+            pending: HashMap::new(),
+            written: HashMap::new(),
+            evictable: IndexSet::new(),
+        }
+    }
+
+    /// Hashes of the full logical blocks of the sequence, each chained to the hash of the block before it.
+    fn block_hashes(seq: &impl BlockEngineSequence) -> Vec<u64> {
+        let mut parent = 0;
+        seq.logical_token_blocks()
+            .iter()
+            .take_while(|block| block.is_full())
+            .map(|block| {
+                let mut hasher = DefaultHasher::new();
+                parent.hash(&mut hasher);
+                block.toks().hash(&mut hasher);
+                parent = hasher.finish();
+                parent
+            })
+            .collect()
+    }
+
+    /// The number of leading blocks of the sequence which are cached. The last two prompt tokens are always
+    /// run, as a single token would be attended to without a mask like a decoding step.
+    fn num_cached_blocks(&self, seq: &impl BlockEngineSequence, hashes: &[u64]) -> usize {
+        let blocks = seq.logical_token_blocks();
+        let num_toks = blocks.iter().map(|block| block.num_tokens).sum::<usize>();
+        let max_blocks = blocks
+            .first()
+            .map(|block| num_toks.saturating_sub(2) / block.block_size)
+            .unwrap_or(0);
+        hashes
+            .iter()
+            .take(max_blocks)
+            .take_while(|hash| self.blocks.contains_key(hash) && !self.pending.contains_key(hash))
+            .count()
+    }
+
+    fn insert(
+        &mut self,
+        hash: u64,
+        block: Arc<PhysicalTokenBlock>,
+        seq_id: SeqID,
+        logical_idx: usize,
+    ) {
+        if let Entry::Vacant(e) = self.blocks.entry(hash) {
+            self.hashes.insert(block.deref_mut().block_id, hash);
+            self.pending.insert(hash, (seq_id, logical_idx));
+            e.insert(block);
+        }
+    }
+
+    fn is_cached(&self, block: &PhysicalTokenBlock) -> bool {
+        self.hashes.contains_key(&block.deref_mut().block_id)
+    }
+
+    /// Remove the least recently used unreferenced block from the cache.
+    fn evict(&mut self) -> Option<Arc<PhysicalTokenBlock>> {
+        let hash = self.evictable.shift_remove_index(0)?;
+        let block = self.blocks.remove(&hash).unwrap();
+        self.hashes.remove(&block.deref_mut().block_id);
+        Some(block)
+    }
+}
+
 pub enum AllocStatus {
     Ok,
     Later,
@@ -185,64 +274,221 @@ type SeqID = usize;
 /// These new tokens will be added to the logical token block for each sequence.
 pub struct BlockEngine {
     num_gpu_blocks: usize,
+    block_size: usize,
     gpu_allocator: Allocator<GPUAllocator>,
     cpu_allocator: Allocator<CPUAllocator>,
+    prefix_cache: Option<PrefixBlockCache>,
     pub block_tables: HashMap<SeqID, BlockTable>,
 }
 
 pub type BlockTables = HashMap<usize, BlockTable>;
 
 impl BlockEngine {
+    /// With `prefix_caching`, full prompt blocks are shared between sequences with the same prefix and kept
+    /// after their sequences finish, until they are evicted to make room for new blocks.
     #[must_use]
-    pub fn new(block_size: usize, num_gpu_blocks: usize, num_cpu_blocks: usize) -> Self {
+    pub fn new(
+        block_size: usize,
+        num_gpu_blocks: usize,
+        num_cpu_blocks: usize,
+        prefix_caching: bool,
+    ) -> Self {
         Self {
             num_gpu_blocks,
+            block_size,
             gpu_allocator: Allocator::<GPUAllocator>::new(block_size, num_gpu_blocks),
             cpu_allocator: Allocator::<CPUAllocator>::new(block_size, num_cpu_blocks),
+            prefix_cache: prefix_caching.then(PrefixBlockCache::new),
             block_tables: HashMap::new(),
         }
     }
 
-    pub fn can_allocate(&self, seq: &impl BlockEngineSequence) -> AllocStatus {
-        let num_required_blocks = seq.get_logical_token_blocks();
-        let num_free_gpu_blocks = self.gpu_allocator.get_num_free_blocks();
+    /// The number of GPU blocks in use and the total number of GPU blocks. Cached blocks which no sequence
+    /// uses are not counted as used.
+    pub fn gpu_block_usage(&self) -> (usize, usize) {
+        (
+            self.num_gpu_blocks - self.get_num_free_gpu_blocks(),
+            self.num_gpu_blocks,
+        )
+    }
 
-        if self.num_gpu_blocks > *num_free_gpu_blocks + num_required_blocks {
-            AllocStatus::Later
-        } else if self.num_gpu_blocks < num_required_blocks {
+    /// Free GPU blocks, including cached blocks which may be evicted.
+    fn get_num_free_gpu_blocks(&self) -> usize {
+        *self.gpu_allocator.get_num_free_blocks()
+            + self
+                .prefix_cache
+                .as_ref()
+                .map(|cache| cache.evictable.len())
+                .unwrap_or(0)
+    }
+
+    /// Allocate a free GPU block, evicting a cached block if there is none. Returns `None` if every block is used.
+    fn allocate_gpu_block(&mut self) -> Option<Arc<PhysicalTokenBlock>> {
+        if self.gpu_allocator.free_blocks.is_empty() {
+            if let Some(block) = self.prefix_cache.as_mut().and_then(PrefixBlockCache::evict) {
+                block.deref_mut().refcount = 1;
+                return Some(block);
+            }
+        }
+        self.gpu_allocator.allocate()
+    }
+
+    fn free_gpu_block(&mut self, block: Arc<PhysicalTokenBlock>) {
+        match &mut self.prefix_cache {
+            Some(cache) if cache.is_cached(&block) && block.deref_mut().refcount == 1 => {
+                let block_id = block.deref_mut().block_id;
+                let hash = cache.hashes[&block_id];
+                if cache.pending.remove(&hash).is_some() {
+                    // The model never wrote the block, so it must not be matched by later prompts.
+                    cache.blocks.remove(&hash);
+                    cache.hashes.remove(&block_id);
+                    self.gpu_allocator.free_block(block);
+                } else {
+                    block.deref_mut().refcount = 0;
+                    cache.evictable.insert(hash);
+                }
+            }
+            _ => self.gpu_allocator.free_block(block),
+        }
+    }
+
+    /// The number of leading prompt tokens of the sequence whose KV cache is already held by cached blocks.
+    pub fn num_cached_prompt_toks(&self, seq: &impl BlockEngineSequence) -> usize {
+        match &self.prefix_cache {
+            Some(cache) => {
+                cache.num_cached_blocks(seq, &PrefixBlockCache::block_hashes(seq)) * self.block_size
+            }
+            None => 0,
+        }
+    }
+
+    /// Record that the next step runs the prompt of the sequence up to token `end`, writing the blocks before it.
+    pub fn set_prefix_cache_writes(&mut self, seq_id: usize, end: usize) {
+        if let Some(cache) = &mut self.prefix_cache {
+            cache.written.insert(seq_id, end / self.block_size);
+        }
+    }
+
+    /// Mark the blocks written by the last step as such, so that they may be reused. Blocks of prompt chunks
+    /// which have not run yet stay pending.
+    pub fn commit_prefix_cache(&mut self) {
+        if let Some(cache) = &mut self.prefix_cache {
+            let written = std::mem::take(&mut cache.written);
+            cache.pending.retain(|_, (seq_id, logical_idx)| {
+                !written.get(seq_id).is_some_and(|n| *logical_idx < *n)
+            });
+        }
+    }
+
+    /// The number of new GPU blocks the sequence needs and the number of GPU blocks free for them. Cached blocks
+    /// the sequence shares are not needed, and those which no other sequence uses are then no longer free.
+    fn required_and_free_blocks(
+        &self,
+        seq: &impl BlockEngineSequence,
+        hashes: &[u64],
+    ) -> (usize, usize) {
+        let num_free_blocks = self.get_num_free_gpu_blocks();
+        match &self.prefix_cache {
+            Some(cache) => {
+                let num_cached_blocks = cache.num_cached_blocks(seq, hashes);
+                let num_reused_evictable = hashes[..num_cached_blocks]
+                    .iter()
+                    .filter(|hash| cache.evictable.contains(*hash))
+                    .count();
+                (
+                    seq.get_logical_token_blocks() - num_cached_blocks,
+                    num_free_blocks - num_reused_evictable,
+                )
+            }
+            None => (seq.get_logical_token_blocks(), num_free_blocks),
+        }
+    }
+
+    pub fn can_allocate(&self, seq: &impl BlockEngineSequence) -> AllocStatus {
+        let hashes = self
+            .prefix_cache
+            .as_ref()
+            .map(|_| PrefixBlockCache::block_hashes(seq))
+            .unwrap_or_default();
+        let (num_required_blocks, num_free_gpu_blocks) =
+            self.required_and_free_blocks(seq, &hashes);
+
+        if self.num_gpu_blocks < num_required_blocks {
             AllocStatus::Impossible
+        } else if num_free_gpu_blocks < num_required_blocks {
+            AllocStatus::Later
         } else {
             AllocStatus::Ok
         }
     }
 
-    pub fn allocate(&mut self, seq: &impl BlockEngineSequence) {
+    /// Allocate the blocks of a sequence, returning how many leading prompt tokens are already in shared
+    /// cached blocks and so do not need to be run. Returns `None`, without allocating anything, if there are
+    /// not enough free blocks.
+    pub fn allocate(&mut self, seq: &impl BlockEngineSequence) -> Option<usize> {
+        let hashes = self
+            .prefix_cache
+            .as_ref()
+            .map(|_| PrefixBlockCache::block_hashes(seq))
+            .unwrap_or_default();
+        let (num_required_blocks, num_free_gpu_blocks) =
+            self.required_and_free_blocks(seq, &hashes);
+        if num_free_gpu_blocks < num_required_blocks {
+            return None;
+        }
+        let num_cached_blocks = self
+            .prefix_cache
+            .as_ref()
+            .map(|cache| cache.num_cached_blocks(seq, &hashes))
+            .unwrap_or(0);
+
         let mut block_table = Vec::new();
-        for _logcical_idx in 0..seq.get_logical_token_blocks() {
-            block_table.push(self.gpu_allocator.allocate());
+        for logical_idx in 0..seq.get_logical_token_blocks() {
+            let block = if logical_idx < num_cached_blocks {
+                // Share the cached block, which is no longer free to evict.
+                let cache = self.prefix_cache.as_mut().unwrap();
+                let hash = hashes[logical_idx];
+                cache.evictable.shift_remove(&hash);
+                let block = cache.blocks[&hash].clone();
+                block.deref_mut().refcount += 1;
+                block
+            } else {
+                let block = self
+                    .allocate_gpu_block()
+                    .expect("The number of free blocks was checked.");
+                if let (Some(cache), Some(hash)) = (&mut self.prefix_cache, hashes.get(logical_idx))
+                {
+                    cache.insert(*hash, block.clone(), seq.get_id(), logical_idx);
+                }
+                block
+            };
+            block_table.push(block);
         }
         self.block_tables.insert(seq.get_id(), block_table.clone());
+
+        Some(num_cached_blocks * self.block_size)
     }
 
     pub fn can_append_token_to_seq(&self, seq: &impl BlockEngineSequence) -> bool {
-        let free_blocks = self.gpu_allocator.get_num_free_blocks();
+        let free_blocks = self.get_num_free_gpu_blocks();
         // Physical blocks = logical blocks
-        seq.blocks_to_add_new_tok() <= *free_blocks
+        seq.blocks_to_add_new_tok() <= free_blocks
     }
 
     pub fn free_sequence(&mut self, id: usize) {
         // Handle double free if run out of tokens
-        if let Some(block_table) = self.block_tables.get(&id) {
+        if let Some(cache) = &mut self.prefix_cache {
+            cache.written.remove(&id);
+        }
+        if let Some(block_table) = self.block_tables.remove(&id) {
             // Free from block table
             for block in block_table {
                 if block.deref_mut().is_gpu {
-                    self.gpu_allocator.free_block(block.clone())
+                    self.free_gpu_block(block)
                 } else {
-                    self.cpu_allocator.free_block(block.clone())
+                    self.cpu_allocator.free_block(block)
                 }
             }
-
-            self.block_tables.remove(&id);
         }
     }
 
@@ -272,7 +518,7 @@ impl BlockEngine {
             let cpu_block =
                 if let Entry::Vacant(e) = new_mapping.entry(gpu_block.deref_mut().block_id) {
                     // Create a new block
-                    let cpu_block = self.cpu_allocator.allocate();
+                    let cpu_block = self.cpu_allocator.allocate().unwrap();
                     e.insert(cpu_block.clone());
                     cpu_block
                 } else {
@@ -301,25 +547,38 @@ impl BlockEngine {
         &mut self,
         sequence: &impl BlockEngineSequence,
     ) -> Option<(usize, usize)> {
-        let table = self.block_tables.get_mut(&sequence.get_id())?;
+        let table = self.block_tables.get(&sequence.get_id())?;
 
         match sequence.blocks_to_add_new_tok() {
             1 => {
-                table.push(self.gpu_allocator.allocate());
+                let new_block = self
+                    .allocate_gpu_block()
+                    .expect("`can_append_token_to_seq` checks that there is a free block.");
+                self.block_tables
+                    .get_mut(&sequence.get_id())
+                    .unwrap()
+                    .push(new_block);
                 None
             }
             0 => {
-                let last_block = table.last_mut().unwrap();
+                let last_block = table.last().unwrap().clone();
                 assert!(last_block.deref_mut().is_gpu);
                 if last_block.deref_mut().refcount == 1 {
                     None
                 } else {
                     // We would be writing into shared, so COW.
-                    let new_block = self.gpu_allocator.allocate();
-                    self.gpu_allocator.free_block(last_block.clone());
+                    let new_block = self
+                        .allocate_gpu_block()
+                        .expect("`can_append_token_to_seq` checks that there is a free block.");
                     let old_number = last_block.deref_mut().block_id;
                     let new_number = new_block.deref_mut().block_id;
-                    *last_block = new_block;
+                    *self
+                        .block_tables
+                        .get_mut(&sequence.get_id())
+                        .unwrap()
+                        .last_mut()
+                        .unwrap() = new_block;
+                    self.free_gpu_block(last_block);
                     Some((old_number, new_number))
                 }
             }
@@ -353,7 +612,7 @@ impl BlockEngine {
             let gpu_block =
                 if let Entry::Vacant(e) = new_mapping.entry(cpu_block.deref_mut().block_id) {
                     // Create a new block
-                    let gpu_block = self.cpu_allocator.allocate();
+                    let gpu_block = self.cpu_allocator.allocate().unwrap();
                     e.insert(gpu_block.clone());
                     gpu_block
                 } else {
@@ -1,4 +1,8 @@
+use candle_core::{DType, Tensor, D};
+use either::Either;
 use std::{
+    collections::{HashMap, HashSet},
+    num::NonZeroUsize,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
@@ -11,12 +15,14 @@ use crate::{
     aici::{cfg::CfgParser, recognizer::StackRecognizer, rx::RecRx},
     pipeline::{
         text_models_inputs_processor::PagedAttentionMeta, AdapterInstruction, CacheBackendMetadata,
-        CacheInstruction,
+        CacheInstruction, ModelCategory, ModelKind,
+    },
+    request::{EmbeddingInput, EmbeddingPooling, EmbeddingRequest, NormalRequest, ScoreRequest},
+    response::{
+        CompletionChoice, Embedding, EmbeddingResponse, EmbeddingUsage, ScoreResponse, ScoredToken,
     },
-    request::NormalRequest,
-    response::CompletionChoice,
     scheduler::{Scheduler, SchedulerOutput},
-    tools::{ToolCallingMatcher, ToolChoice},
+    tools::{tool_call_constraint, ToolCallingMatcher, ToolChoice},
     CompletionResponse, RequestMessage, Response, SchedulerConfig, DEBUG,
 };
 use rand::SeedableRng;
@@ -25,12 +31,14 @@ use tracing::{info, warn};
 
 use crate::{
     get_mut_arcmutex, handle_pipeline_forward_error, handle_seq_error,
+    json_schema::json_schema_to_regex,
+    metrics::EngineMetrics,
     pipeline::Pipeline,
-    prefix_cacher::PrefixCacheManager,
+    prefix_cacher::{PrefixCacheManager, PrefixCacheTiers},
     request::Request,
     response::{ChatCompletionResponse, Choice, ResponseMessage},
-    sampler::Sampler,
-    sequence::{Sequence, SequenceGroup, SequenceRecognizer, SequenceState},
+    sampler::{Sampler, TopLogprob},
+    sequence::{BeamSearch, Sequence, SequenceGroup, SequenceRecognizer, SequenceState},
     Constraint, StopTokens,
 };
 
@@ -49,6 +57,10 @@ pub struct Engine {
     is_debug: bool,
     disable_eos_stop: bool,
     throughput_logging_enabled: bool,
+    prefill_chunk_size: Option<NonZeroUsize>,
+    metrics: Arc<EngineMetrics>,
+    cancelled: Arc<std::sync::Mutex<HashSet<usize>>>,
+    terminating: bool,
 }
 
 impl Engine {
@@ -61,18 +73,41 @@ impl Engine {
         no_kv_cache: bool,
         no_prefix_cache: bool,
         prefix_cache_n: usize,
+        prefix_cache_tiers: PrefixCacheTiers,
         disable_eos_stop: bool,
+        prefill_chunk_size: Option<NonZeroUsize>,
+        metrics: Arc<EngineMetrics>,
+        cancelled: Arc<std::sync::Mutex<HashSet<usize>>>,
     ) -> Self {
         let device = get_mut_arcmutex!(pipeline).device().clone();
         let is_xlora = get_mut_arcmutex!(pipeline).get_metadata().is_xlora;
-        // Prefix caching is always disabled if using PagedAttention for now.
-        // TODO
-        let no_prefix_cache =
-            matches!(config, SchedulerConfig::PagedAttentionMeta { .. }) || no_prefix_cache;
+        let model_key = {
+            let pipeline = get_mut_arcmutex!(pipeline);
+            let metadata = pipeline.get_metadata();
+            PrefixCacheManager::model_key(&pipeline.name(), &metadata, metadata.isq)
+        };
+        // The PagedAttention scheduler chunks the prompts itself. Its prefix caching skips the cached
+        // prompt tokens like a prompt chunk.
+        let supports_chunked_prefill = {
+            let pipeline = get_mut_arcmutex!(pipeline);
+            pipeline.category() == ModelCategory::Text
+                && !is_xlora
+                && !no_kv_cache
+                && !matches!(pipeline.get_metadata().kind, ModelKind::Speculative { .. })
+        };
+        if prefill_chunk_size.is_some() && !supports_chunked_prefill {
+            warn!("Chunked prefill is only supported for text models without X-LoRA, speculative decoding or a disabled KV cache, prompts will not be chunked.");
+        }
+        // With PagedAttention, prefixes are cached by sharing blocks in the block engine instead.
+        let is_paged_attn = matches!(config, SchedulerConfig::PagedAttentionMeta { .. });
+        let paged_attn_prefix_caching =
+            is_paged_attn && !no_prefix_cache && supports_chunked_prefill;
+        let no_prefix_cache = is_paged_attn || no_prefix_cache;
+        let prefill_chunk_size = prefill_chunk_size.filter(|_| supports_chunked_prefill);
         Self {
             rx,
             pipeline,
-            scheduler: config.into_scheduler(),
+            scheduler: config.into_scheduler(prefill_chunk_size, paged_attn_prefix_caching),
             id: 0,
             truncate_sequence,
             no_kv_cache,
@@ -81,10 +116,16 @@ impl Engine {
                 prefix_cache_n,
                 is_xlora,
                 no_prefix_cache,
+                prefix_cache_tiers,
+                model_key,
             ),
             is_debug: DEBUG.load(Ordering::Relaxed),
             disable_eos_stop,
             throughput_logging_enabled: false,
+            prefill_chunk_size,
+            metrics,
+            cancelled,
+            terminating: false,
         }
     }
 
@@ -98,9 +139,20 @@ impl Engine {
         let rng = Arc::new(std::sync::Mutex::new(Isaac64Rng::seed_from_u64(SEED)));
         let mut last_completion_ids: Vec<usize> = vec![];
         'lp: loop {
+            // Take the cancellations before receiving the queued requests: a request is always sent before
+            // it is cancelled, so it has been added by the time its cancellation is applied.
+            let cancelled = std::mem::take(
+                &mut *self
+                    .cancelled
+                    .lock()
+                    .expect("Cancelled requests lock was poisoned"),
+            );
             while let Ok(request) = self.rx.try_recv() {
                 self.handle_request(request).await;
             }
+            for id in cancelled {
+                self.scheduler.cancel_request(id);
+            }
             let run_start = Instant::now();
             let scheduled = self.scheduler.schedule();
 
@@ -108,16 +160,28 @@ impl Engine {
                 SchedulerOutput::DefaultScheduler {
                     output: mut scheduled,
                 } => {
+                    scheduled.completion = defer_partial_beam_groups(scheduled.completion);
+                    scheduled.prompt = defer_partial_beam_groups(scheduled.prompt);
                     let mut prompt_ts = None;
                     let mut completion_ts = None;
                     if scheduled.completion.len() > 0 {
                         let throughput_start = Instant::now();
                         let current_completion_ids: Vec<usize> =
                             scheduled.completion.iter().map(|seq| *seq.id()).collect();
+                        let completion_lens: Vec<usize> = scheduled
+                            .completion
+                            .iter()
+                            .map(|seq| seq.get_toks().len())
+                            .collect();
                         let res = {
                             let mut pipeline = get_mut_arcmutex!(self.pipeline);
+                            // Beam search forks the KV caches of its sequences, so they must be reloaded.
                             let pre_op = if !self.no_kv_cache
-                                && last_completion_ids != current_completion_ids
+                                && (last_completion_ids != current_completion_ids
+                                    || scheduled
+                                        .completion
+                                        .iter()
+                                        .any(|seq| seq.beam_width().is_some()))
                             {
                                 CacheInstruction::In(
                                     scheduled.completion[0]
@@ -164,6 +228,14 @@ impl Engine {
                         );
 
                         let throughput_end = Instant::now();
+                        for (seq, len) in scheduled.completion.iter_mut().zip(completion_lens) {
+                            Self::record_completion_metrics(
+                                &self.metrics,
+                                seq,
+                                len,
+                                throughput_end,
+                            );
+                        }
                         #[allow(clippy::cast_precision_loss)]
                         if self.throughput_logging_enabled {
                             completion_ts = Some(
@@ -179,6 +251,50 @@ impl Engine {
 
                     if scheduled.prompt.len() > 0 {
                         let throughput_start = Instant::now();
+                        // With chunked prefill, only run a chunk of the prompts which share an offset and
+                        // length. The rest of the prompt (and the other prompts) run in later steps.
+                        let mut prompt = match self.prefill_chunk_size {
+                            Some(chunk_size)
+                                if scheduled
+                                    .prompt
+                                    .iter()
+                                    .any(|seq| seq.prompt_chunk_offset() > 0)
+                                    || scheduled
+                                        .prompt
+                                        .iter()
+                                        .map(|seq| seq.get_toks().len())
+                                        .sum::<usize>()
+                                        > chunk_size.get() =>
+                            {
+                                let chunk_key = |seq: &Sequence| {
+                                    (seq.prompt_chunk_offset(), seq.get_toks().len())
+                                };
+                                let key = chunk_key(&*scheduled.prompt[0]);
+                                let mut prompt = scheduled
+                                    .prompt
+                                    .iter_mut()
+                                    .filter(|seq| chunk_key(seq) == key)
+                                    .map(|seq| &mut **seq)
+                                    .collect::<Vec<_>>();
+                                let chunk_len = (chunk_size.get() / prompt.len()).max(1);
+                                for seq in prompt.iter_mut() {
+                                    seq.set_prompt_chunk_len(Some(chunk_len));
+                                }
+                                prompt
+                            }
+                            _ => scheduled
+                                .prompt
+                                .iter_mut()
+                                .map(|seq| &mut **seq)
+                                .collect::<Vec<_>>(),
+                        };
+                        let n_prompt_toks = prompt
+                            .iter()
+                            .map(|seq| {
+                                seq.prompt_chunk()
+                                    .map_or(seq.get_toks().len(), |(_, len)| len)
+                            })
+                            .sum::<usize>();
                         let logits = {
                             let mut pipeline = get_mut_arcmutex!(self.pipeline);
 
@@ -191,27 +307,30 @@ impl Engine {
                                     adapter_inst: AdapterInstruction::None,
                                 }
                             };
-                            let adapter_inst = scheduled.prompt[0]
+                            let adapter_inst = prompt[0]
                                 .get_adapters()
                                 .map(AdapterInstruction::Activate)
                                 .unwrap_or(AdapterInstruction::None);
 
-                            // Reset non granular state because the old sequence must be dead.
+                            // Continue a chunked prefill from the cache of the previous chunks.
+                            // Otherwise, reset non granular state because the old sequence must be dead.
                             // Technically we don't need to do this but it is better to be safe.
+                            let pre_op = if prompt[0].prompt_chunk_offset() > 0 {
+                                CacheInstruction::In(adapter_inst)
+                            } else {
+                                CacheInstruction::Reset {
+                                    reset_non_granular: false,
+                                    adapter_inst,
+                                }
+                            };
                             pipeline
                                 .step(
-                                    &mut scheduled.prompt,
+                                    &mut prompt,
                                     true,
                                     &mut self.prefix_cacher,
                                     self.disable_eos_stop,
                                     rng.clone(),
-                                    CacheBackendMetadata::DefaultInstructions {
-                                        pre_op: CacheInstruction::Reset {
-                                            reset_non_granular: false,
-                                            adapter_inst,
-                                        },
-                                        post_op,
-                                    },
+                                    CacheBackendMetadata::DefaultInstructions { pre_op, post_op },
                                 )
                                 .await
                         };
@@ -219,7 +338,7 @@ impl Engine {
                         handle_pipeline_forward_error!(
                             "prompt step",
                             logits,
-                            &mut scheduled.prompt,
+                            &mut prompt,
                             self.pipeline,
                             'lp,
                             self.prefix_cacher
@@ -229,18 +348,17 @@ impl Engine {
                         #[allow(clippy::cast_precision_loss)]
                         if self.throughput_logging_enabled {
                             prompt_ts = Some(
-                                scheduled
-                                    .prompt
-                                    .iter()
-                                    .map(|seq| seq.get_toks().len())
-                                    .sum::<usize>() as f64
+                                n_prompt_toks as f64
                                     / throughput_end
                                         .duration_since(throughput_start)
                                         .as_secs_f64(),
                             );
                         }
 
-                        for seq in scheduled.prompt.iter_mut() {
+                        for seq in prompt.iter_mut() {
+                            if !seq.finish_prompt_chunk() {
+                                continue;
+                            }
                             seq.set_state(SequenceState::RunningCompletion);
                             let now = SystemTime::now()
                                 .duration_since(UNIX_EPOCH)
@@ -251,6 +369,7 @@ impl Engine {
                                 seq.len() as f32 / (now - seq.timestamp()) as f32;
                             seq.prompt_tok_per_sec = prompt_tok_per_sec * 1000.;
                             seq.prompt_timestamp = Some(now);
+                            Self::record_prompt_metrics(&self.metrics, seq, now);
                         }
                         last_completion_ids = vec![];
                     }
@@ -300,6 +419,7 @@ impl Engine {
                     if scheduled.prompt.len() == 0
                         && scheduled.completion.len() == 0
                         && self.scheduler.waiting_len() == 0
+                        && !self.terminating
                     {
                         // If there is nothing to do, sleep until a request comes in
                         if let Some(request) = self.rx.recv().await {
@@ -321,6 +441,17 @@ impl Engine {
 
                         let mut guards_mut =
                             guards.iter_mut().map(|seq| &mut **seq).collect::<Vec<_>>();
+                        let completion_lens = guards_mut
+                            .iter()
+                            .map(|seq| seq.get_toks().len())
+                            .collect::<Vec<_>>();
+                        let n_prompt_toks = guards_mut
+                            .iter()
+                            .map(|seq| {
+                                seq.prompt_chunk()
+                                    .map_or(seq.get_toks().len(), |(_, len)| len)
+                            })
+                            .sum::<usize>();
 
                         let res = {
                             let mut pipeline = get_mut_arcmutex!(self.pipeline);
@@ -388,7 +519,7 @@ impl Engine {
                         #[allow(clippy::cast_precision_loss)]
                         if self.throughput_logging_enabled {
                             let n_toks = if is_prompt {
-                                guards.iter().map(|seq| seq.get_toks().len()).sum::<usize>()
+                                n_prompt_toks
                             } else {
                                 guards.len()
                             };
@@ -400,7 +531,14 @@ impl Engine {
                         }
 
                         if is_prompt {
+                            for _ in 0..output.prefix_cache_hits {
+                                self.metrics.record_prefix_cache_hit();
+                            }
                             for mut seq in guards {
+                                // The scheduler runs the rest of a chunked prompt in later steps.
+                                if !seq.finish_prompt_chunk() {
+                                    continue;
+                                }
                                 let now = SystemTime::now()
                                     .duration_since(UNIX_EPOCH)
                                     .expect("Time travel has occurred!")
@@ -410,6 +548,16 @@ impl Engine {
                                     seq.len() as f32 / (now - seq.timestamp()) as f32;
                                 seq.prompt_tok_per_sec = prompt_tok_per_sec * 1000.;
                                 seq.prompt_timestamp = Some(now);
+                                Self::record_prompt_metrics(&self.metrics, &mut seq, now);
+                            }
+                        } else {
+                            for (seq, len) in guards.iter_mut().zip(completion_lens) {
+                                Self::record_completion_metrics(
+                                    &self.metrics,
+                                    seq,
+                                    len,
+                                    throughput_end,
+                                );
                             }
                         }
                     }
@@ -417,6 +565,41 @@ impl Engine {
             }
 
             self.scheduler.free_finished_sequence_groups();
+            self.metrics
+                .set_scheduler_lens(self.scheduler.waiting_len(), self.scheduler.running_len());
+            if let Some(block_engine) = self.scheduler.block_engine() {
+                let (used, total) = block_engine.gpu_block_usage();
+                self.metrics.set_kv_cache_blocks(used, total);
+            }
+            if self.terminating
+                && self.scheduler.waiting_len() == 0
+                && self.scheduler.running_len() == 0
+            {
+                info!("All requests finished, stopping the engine.");
+                break 'lp;
+            }
+        }
+    }
+
+    /// Record the tokens and the time to first token of a sequence which finished its prompt at `now`, in
+    /// milliseconds since the epoch.
+    #[allow(clippy::cast_precision_loss)]
+    fn record_prompt_metrics(metrics: &EngineMetrics, seq: &mut Sequence, now: u128) {
+        metrics.record_prompt(seq.prompt_tokens(), (now - seq.timestamp()) as f64 / 1000.);
+        seq.replace_last_token_time(Instant::now());
+    }
+
+    /// Record the tokens a sequence generated in a completion step which ended at `now`. It had `prev_len`
+    /// tokens before the step.
+    fn record_completion_metrics(
+        metrics: &EngineMetrics,
+        seq: &mut Sequence,
+        prev_len: usize,
+        now: Instant,
+    ) {
+        let n_new = seq.get_toks().len().saturating_sub(prev_len);
+        if let Some(last) = seq.replace_last_token_time(now) {
+            metrics.record_completion_tokens(n_new, now.duration_since(last).as_secs_f64());
         }
     }
 
@@ -426,6 +609,9 @@ impl Engine {
                 SequenceRecognizer::Regex(StackRecognizer::from(RecRx::from_rx(rx, None)?).into())
             }
             Constraint::Yacc(cfg) => SequenceRecognizer::Cfg(CfgParser::from_yacc(cfg)?.into()),
+            Constraint::JsonSchema(schema) => SequenceRecognizer::Regex(
+                StackRecognizer::from(RecRx::from_rx(&json_schema_to_regex(schema)?, None)?).into(),
+            ),
             Constraint::None => SequenceRecognizer::None,
         };
         Ok(recognizer)
@@ -440,9 +626,22 @@ impl Engine {
                 }
             }
             Request::Normal(request) => self.add_request(request).await,
+            Request::Embedding(request) => self.handle_embedding_request(request).await,
+            Request::Score(request) => self.handle_score_request(request).await,
+            Request::Cancel(id) => self.scheduler.cancel_request(id),
+            Request::Terminate => self.terminating = true,
             Request::ReIsq(level) => {
-                if let Err(e) = get_mut_arcmutex!(self.pipeline).re_isq_model(level) {
+                let mut pipeline = get_mut_arcmutex!(self.pipeline);
+                if let Err(e) = pipeline.re_isq_model(level) {
                     warn!("ISQ requantization failed: {e:?}");
+                } else {
+                    let model_key = PrefixCacheManager::model_key(
+                        &pipeline.name(),
+                        &pipeline.get_metadata(),
+                        Some(level),
+                    );
+                    drop(pipeline);
+                    self.prefix_cacher.set_model_key(model_key);
                 }
             }
         }
@@ -488,6 +687,36 @@ impl Engine {
             _ => None,
         };
 
+        // When a tool call is required, the model is constrained to only emit a valid call.
+        let tool_constraint = match (&request.tools, &request.tool_choice) {
+            (Some(tools), Some(tool_choice)) => match tool_call_constraint(tools, tool_choice) {
+                Ok(constraint) => constraint,
+                Err(e) => {
+                    request
+                        .response
+                        .send(Response::ValidationError(e.into()))
+                        .await
+                        .expect("Expected receiver.");
+                    return;
+                }
+            },
+            _ => None,
+        };
+        let constraint = match (tool_constraint, &request.constraint) {
+            (Some(constraint), Constraint::None) => constraint,
+            (Some(_), _) => {
+                request
+                    .response
+                    .send(Response::ValidationError(
+                        "A grammar cannot be used together with a required tool call.".into(),
+                    ))
+                    .await
+                    .expect("Expected receiver.");
+                return;
+            }
+            (None, constraint) => constraint.clone(),
+        };
+
         let matcher = if request.tools.is_some() {
             Some(Arc::new(handle_seq_error!(
                 ToolCallingMatcher::new(request.tool_choice.unwrap_or(ToolChoice::Auto),),
@@ -563,6 +792,9 @@ impl Engine {
             self.prefix_cacher.search_for_matching_cache(&prompt),
             request.response
         );
+        if prefill_cache.is_some() {
+            self.metrics.record_prefix_cache_hit();
+        }
 
         let topk = request
             .sampling_params
@@ -629,12 +861,74 @@ impl Engine {
             }
         };
 
-        let group = Arc::new(tokio::sync::Mutex::new(SequenceGroup::new(
+        if let Some(beam_width) = request.sampling_params.beam_width {
+            let (is_paged_attn, is_speculative) = {
+                let pipeline = get_mut_arcmutex!(self.pipeline);
+                let metadata = pipeline.get_metadata();
+                (
+                    metadata.cache_config.is_some(),
+                    matches!(metadata.kind, ModelKind::Speculative { .. }),
+                )
+            };
+            let err = if beam_width == 0 {
+                Some("Beam width must be greater than 0.")
+            } else if request.sampling_params.n_choices > beam_width {
+                Some("Number of choices must not be greater than the beam width.")
+            } else if request.is_streaming {
+                Some("Beam search does not support streaming.")
+            } else if !matches!(constraint, Constraint::None) {
+                Some("Beam search does not support grammar constraints.")
+            } else if is_paged_attn {
+                Some("Beam search is not supported with PagedAttention.")
+            } else if is_speculative {
+                Some("Beam search is not supported with speculative decoding.")
+            } else {
+                None
+            };
+            if let Some(err) = err {
+                request
+                    .response
+                    .send(Response::ValidationError(err.into()))
+                    .await
+                    .expect("Expected receiver.");
+                return;
+            }
+        }
+
+        if request.sampling_params.mirostat.is_some() {
+            let is_speculative = {
+                let pipeline = get_mut_arcmutex!(self.pipeline);
+                let metadata = pipeline.get_metadata();
+                matches!(metadata.kind, ModelKind::Speculative { .. })
+            };
+            if is_speculative {
+                request
+                    .response
+                    .send(Response::ValidationError(
+                        "Mirostat is not supported with speculative decoding.".into(),
+                    ))
+                    .await
+                    .expect("Expected receiver.");
+                return;
+            }
+        }
+
+        let mut group = SequenceGroup::new(
             request.sampling_params.n_choices,
             request.is_streaming,
             is_chat,
-            best_of,
-        )));
+            // All finished beams are already ranked by score.
+            if request.sampling_params.beam_width.is_some() {
+                request.sampling_params.n_choices
+            } else {
+                best_of
+            },
+        );
+        group.beam_search = request.sampling_params.beam_width.map(|width| {
+            BeamSearch::new(width, request.sampling_params.length_penalty.unwrap_or(1.0))
+        });
+        group.include_usage = request.is_streaming && request.include_usage;
+        let group = Arc::new(tokio::sync::Mutex::new(group));
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("Time travel has occurred!");
@@ -647,10 +941,20 @@ impl Engine {
             tokenizer,
             request.sampling_params.frequency_penalty,
             request.sampling_params.presence_penalty,
+            request.sampling_params.repetition_penalty,
+            request.sampling_params.no_repeat_ngram_size,
+            request.sampling_params.dry_params.clone(),
+            request.sampling_params.logits_bias.clone(),
+            request.logits_processors.clone().unwrap_or_default(),
             topk,
             topp,
             minp,
+            request.sampling_params.typical_p.unwrap_or(1.0),
+            request.sampling_params.top_a.unwrap_or(0.0),
+            request.sampling_params.mirostat.clone(),
+            request.sampling_params.processed_logprobs,
         );
+        let sampler = handle_seq_error!(sampler, request.response);
 
         if request.sampling_params.n_choices == 0 {
             request
@@ -663,9 +967,13 @@ impl Engine {
             return;
         }
 
-        // Add sequences
-        for response_index in 0..request.sampling_params.n_choices {
-            let recognizer = match Self::build_sequence_recognizer(&request.constraint) {
+        // Add sequences, one per beam for beam search
+        let n_seqs = request
+            .sampling_params
+            .beam_width
+            .unwrap_or(request.sampling_params.n_choices);
+        for response_index in 0..n_seqs {
+            let recognizer = match Self::build_sequence_recognizer(&constraint) {
                 Ok(recognizer) => recognizer,
                 Err(err) => {
                     request
@@ -688,6 +996,7 @@ impl Engine {
             let seq = Sequence::new_waiting(
                 prompt.clone(),
                 self.id,
+                request.id,
                 now.as_millis(),
                 num_hidden_layers,
                 request.response.clone(),
@@ -706,7 +1015,7 @@ impl Engine {
                     Some(
                         get_mut_arcmutex!(self.pipeline)
                             .tokenizer()
-                            .decode(&prompt, false)
+                            .decode(&prompt, true)
                             .expect("cannot decode completion tokens"),
                     )
                 } else {
@@ -717,6 +1026,13 @@ impl Engine {
                 block_size,
                 trie,
                 matcher.clone(),
+                request.priority,
+                request.tenant.clone(),
+                // Each choice gets its own stream so that they differ
+                request
+                    .sampling_params
+                    .seed
+                    .map(|seed| seed.wrapping_add(response_index as u64)),
             );
             let seq = if let Some(prefill_cache) = prefill_cache.clone() {
                 seq.prefill(
@@ -731,4 +1047,292 @@ impl Engine {
             self.scheduler.add_seq(seq);
         }
     }
+
+    async fn handle_embedding_request(&mut self, request: EmbeddingRequest) {
+        let response = match self.compute_embeddings(&request) {
+            Ok(response) => Response::Embeddings(response),
+            Err(e) => e,
+        };
+        request
+            .response
+            .send(response)
+            .await
+            .expect("Expected receiver.");
+    }
+
+    /// Embeddings are computed immediately, outside of the scheduler, as they only need one forward pass.
+    fn compute_embeddings(
+        &self,
+        request: &EmbeddingRequest,
+    ) -> Result<EmbeddingResponse, Response> {
+        if request.input.is_empty() {
+            return Err(Response::ValidationError(
+                "Received no input to embed.".into(),
+            ));
+        }
+        let pipeline = get_mut_arcmutex!(self.pipeline);
+        let max_seq_len = pipeline.get_metadata().max_seq_len;
+
+        let mut data = Vec::new();
+        let mut prompt_tokens = 0;
+        for (index, input) in request.input.iter().enumerate() {
+            let toks = match input {
+                EmbeddingInput::Text(text) => pipeline
+                    .tokenizer()
+                    .encode(text.clone(), true)
+                    .map_err(|e| Response::ValidationError(e.to_string().into()))?
+                    .get_ids()
+                    .to_vec(),
+                EmbeddingInput::Tokens(toks) => toks.clone(),
+            };
+            if toks.is_empty() {
+                return Err(Response::ValidationError("Received an empty input.".into()));
+            }
+            if toks.len() > max_seq_len {
+                return Err(Response::ValidationError(
+                    format!("Input sequence length is greater than {max_seq_len}.").into(),
+                ));
+            }
+            let embedding = pipeline
+                .forward_hidden_states(&toks)
+                .and_then(|hidden_states| pool_hidden_states(&hidden_states, request.pooling))
+                .map_err(|e| Response::InternalError(e.into()))?;
+            let embedding = if request.normalize {
+                let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+                if norm > 0. {
+                    embedding.into_iter().map(|x| x / norm).collect()
+                } else {
+                    embedding
+                }
+            } else {
+                embedding
+            };
+            prompt_tokens += toks.len();
+            data.push(Embedding {
+                object: "embedding".to_string(),
+                embedding,
+                index,
+            });
+        }
+
+        Ok(EmbeddingResponse {
+            object: "list".to_string(),
+            data,
+            model: pipeline.name(),
+            usage: EmbeddingUsage {
+                prompt_tokens,
+                total_tokens: prompt_tokens,
+            },
+        })
+    }
+
+    async fn handle_score_request(&mut self, request: ScoreRequest) {
+        let response = match self.compute_score(&request) {
+            Ok(response) => Response::Score(response),
+            Err(e) => e,
+        };
+        request
+            .response
+            .send(response)
+            .await
+            .expect("Expected receiver.");
+    }
+
+    /// Like embeddings, scores are computed immediately with a single prefill.
+    fn compute_score(&self, request: &ScoreRequest) -> Result<ScoreResponse, Response> {
+        let pipeline = get_mut_arcmutex!(self.pipeline);
+        let metadata = pipeline.get_metadata();
+        let unsupported = if !pipeline.supports_prompt_scoring() {
+            Some("for this model")
+        } else if metadata.is_xlora {
+            Some("for X-LoRA models")
+        } else if metadata.cache_engine.is_some() {
+            Some("with PagedAttention")
+        } else {
+            None
+        };
+        if let Some(unsupported) = unsupported {
+            return Err(Response::ValidationError(
+                format!("Prompt scoring, which prompt logprobs require, is not supported {unsupported}.")
+                    .into(),
+            ));
+        }
+        let tokenizer = pipeline.tokenizer();
+        let tokenize = |input: &Either<String, Vec<u32>>, add_special_tokens: bool| match input {
+            Either::Left(text) => tokenizer
+                .encode(text.clone(), add_special_tokens)
+                .map(|encoding| encoding.get_ids().to_vec())
+                .map_err(|e| Response::ValidationError(e.to_string().into())),
+            Either::Right(toks) => Ok(toks.clone()),
+        };
+
+        let mut toks = tokenize(&request.prompt, true)?;
+        let prompt_tokens = toks.len();
+        if prompt_tokens == 0 {
+            return Err(Response::ValidationError(
+                "Received an empty prompt.".into(),
+            ));
+        }
+        // The first token has no context, so it is never scored.
+        let first_scored = match &request.continuation {
+            Some(continuation) => {
+                toks.extend(tokenize(continuation, false)?);
+                prompt_tokens
+            }
+            None => 1,
+        };
+        let max_seq_len = metadata.max_seq_len;
+        if toks.len() > max_seq_len {
+            return Err(Response::ValidationError(
+                format!("Scored sequence length is greater than {max_seq_len}.").into(),
+            ));
+        }
+
+        let scores = if first_scored < toks.len() {
+            pipeline
+                .forward_prompt_logits(&toks)
+                .and_then(|logits| {
+                    score_tokens(&logits, &toks, first_scored, request.top_n_logprobs)
+                })
+                .map_err(|e| Response::InternalError(e.into()))?
+        } else {
+            Vec::new()
+        };
+
+        let decode = |toks: &[u32], skip_special_tokens: bool| {
+            tokenizer
+                .decode(toks, skip_special_tokens)
+                .map_err(|e| Response::InternalError(e.to_string().into()))
+        };
+        // Decode the tokens incrementally, so that their texts add up to the decoded text. Decoding a token
+        // alone drops the leading space which SentencePiece tokenizers only add after other tokens.
+        let (mut prefix_offset, mut read_offset) = (0, 0);
+        let mut tokens = Vec::with_capacity(toks.len());
+        for (i, tok) in toks.iter().enumerate() {
+            let special = decode(&[*tok], true)?.is_empty();
+            let text = if special {
+                decode(&[*tok], false)?
+            } else {
+                let prefix_text = decode(&toks[prefix_offset..read_offset], true)?;
+                let new_text = decode(&toks[prefix_offset..=i], true)?;
+                match new_text.get(prefix_text.len()..) {
+                    // An incomplete UTF-8 sequence is part of the text of the token which completes it.
+                    Some(delta) if !delta.is_empty() && !new_text.ends_with('\u{FFFD}') => {
+                        prefix_offset = read_offset;
+                        read_offset = i + 1;
+                        delta.to_string()
+                    }
+                    _ => String::new(),
+                }
+            };
+            let (logprob, is_greedy, top_logprobs) = if i >= first_scored {
+                let (logprob, is_greedy, top) = &scores[i - first_scored];
+                let top_logprobs = top
+                    .iter()
+                    .map(|(token, logprob)| {
+                        Ok(TopLogprob {
+                            token: *token,
+                            logprob: *logprob,
+                            bytes: decode(&[*token], false)?,
+                        })
+                    })
+                    .collect::<Result<Vec<_>, Response>>()?;
+                (Some(*logprob), *is_greedy, top_logprobs)
+            } else {
+                (None, false, Vec::new())
+            };
+            tokens.push(ScoredToken {
+                token: *tok,
+                text,
+                special,
+                logprob,
+                is_greedy,
+                top_logprobs,
+            });
+        }
+
+        Ok(ScoreResponse {
+            model: pipeline.name(),
+            total_logprob: scores.iter().map(|(logprob, _, _)| logprob).sum(),
+            is_greedy: scores.iter().all(|(_, is_greedy, _)| *is_greedy),
+            tokens,
+            prompt_tokens,
+        })
+    }
+}
+
+/// Beam search advances all beams of a request in the same step, so beams whose group was not scheduled in
+/// full are left out of this step. They keep their state and run once the whole group is scheduled.
+fn defer_partial_beam_groups(seqs: Box<[&mut Sequence]>) -> Box<[&mut Sequence]> {
+    let mut n_beams: HashMap<usize, usize> = HashMap::new();
+    for seq in seqs.iter() {
+        if seq.beam_width().is_some() {
+            *n_beams.entry(seq.request_id()).or_default() += 1;
+        }
+    }
+    seqs.into_vec()
+        .into_iter()
+        .filter(|seq| {
+            !seq.beam_width()
+                .is_some_and(|width| n_beams[&seq.request_id()] < width)
+        })
+        .collect()
+}
+
+/// The score of one token: its logprob, whether it is the argmax, and the top alternatives.
+type TokenScore = (f32, bool, Vec<(u32, f32)>);
+
+/// Score `toks[first_scored..]` from the logits at every position, of shape `(seq_len, vocab_size)`.
+#[allow(clippy::cast_possible_truncation)]
+fn score_tokens(
+    logits: &Tensor,
+    toks: &[u32],
+    first_scored: usize,
+    top_n_logprobs: usize,
+) -> candle_core::Result<Vec<TokenScore>> {
+    let n_scored = toks.len() - first_scored;
+    // The logits at position i predict token i + 1.
+    let logprobs = candle_nn::ops::log_softmax(
+        &logits
+            .narrow(0, first_scored - 1, n_scored)?
+            .to_dtype(DType::F32)?,
+        D::Minus1,
+    )?;
+    let targets = Tensor::new(&toks[first_scored..], logprobs.device())?.unsqueeze(1)?;
+    let token_logprobs = logprobs.gather(&targets, 1)?.squeeze(1)?.to_vec1::<f32>()?;
+    let argmax = logprobs.argmax(D::Minus1)?.to_vec1::<u32>()?;
+
+    let mut scores = Vec::with_capacity(n_scored);
+    for (i, (logprob, argmax)) in token_logprobs.into_iter().zip(argmax).enumerate() {
+        let top = if top_n_logprobs > 0 {
+            let row = logprobs.get(i)?.to_vec1::<f32>()?;
+            let mut indices = (0..row.len()).collect::<Vec<_>>();
+            let top_n = top_n_logprobs.min(row.len());
+            indices.select_nth_unstable_by(top_n - 1, |a, b| row[*b].total_cmp(&row[*a]));
+            indices.truncate(top_n);
+            indices.sort_by(|a, b| row[*b].total_cmp(&row[*a]));
+            indices
+                .into_iter()
+                .map(|idx| (idx as u32, row[idx]))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        scores.push((logprob, argmax == toks[first_scored + i], top));
+    }
+    Ok(scores)
+}
+
+/// Reduce hidden states of shape `(seq_len, hidden_size)` to one vector.
+fn pool_hidden_states(
+    hidden_states: &Tensor,
+    pooling: EmbeddingPooling,
+) -> candle_core::Result<Vec<f32>> {
+    let pooled = match pooling {
+        EmbeddingPooling::Mean => hidden_states.to_dtype(DType::F32)?.mean(0)?,
+        EmbeddingPooling::Last => hidden_states
+            .get(hidden_states.dim(0)? - 1)?
+            .to_dtype(DType::F32)?,
+    };
+    pooled.to_vec1::<f32>()
 }
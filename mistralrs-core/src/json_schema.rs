@@ -0,0 +1,599 @@
+//! Compile a JSON schema into a regex which only matches JSON documents valid against the schema.
+//!
+//! The regex is then used to constrain decoding through [`RecRx`](crate::aici::rx::RecRx). Object
+//! properties are emitted in the order of the schema's `properties` map, which `serde_json` keeps
+//! in document order with its `preserve_order` feature, and additional properties are not allowed.
+//! Numbers with a `minimum` or `maximum` are only generated without an exponent.
+
+use anyhow::{bail, Result};
+use serde_json::{Map, Value};
+
+const WHITESPACE: &str = r"[ ]?";
+const STRING_INNER: &str = r#"([^"\\\x00-\x1F]|\\["\\/bfnrt]|\\u[0-9a-fA-F]{4})"#;
+const INTEGER: &str = r"-?(0|[1-9][0-9]*)";
+const NUMBER: &str = r"-?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][+-]?[0-9]+)?";
+const BOOLEAN: &str = "(true|false)";
+const NULL: &str = "null";
+
+/// How deeply `$ref`s may be followed, which bounds recursive schemas.
+const MAX_REF_DEPTH: usize = 8;
+/// How deeply values without a schema (like `{}` or untyped array items) may be nested.
+const MAX_ANY_DEPTH: usize = 2;
+
+fn string() -> String {
+    format!(r#""{STRING_INNER}*""#)
+}
+
+fn escape(literal: &str) -> String {
+    let mut escaped = String::with_capacity(literal.len());
+    for c in literal.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Matches any JSON value, with arrays and objects nested up to `depth` levels.
+fn any_value(depth: usize) -> String {
+    let scalars = format!("{}|{NUMBER}|{BOOLEAN}|{NULL}", string());
+    if depth == 0 {
+        return format!("({scalars})");
+    }
+    let inner = any_value(depth - 1);
+    let array =
+        format!(r"\[{WHITESPACE}({inner}({WHITESPACE},{WHITESPACE}{inner})*)?{WHITESPACE}\]");
+    format!("({scalars}|{array}|{})", any_object(depth))
+}
+
+/// Matches any JSON object, with arrays and objects nested up to `depth` levels.
+fn any_object(depth: usize) -> String {
+    let inner = any_value(depth.saturating_sub(1));
+    let member = format!("{}{WHITESPACE}:{WHITESPACE}{inner}", string());
+    format!(r"\{{{WHITESPACE}({member}({WHITESPACE},{WHITESPACE}{member})*)?{WHITESPACE}\}}")
+}
+
+/// Matches the integers from `lo` to `hi`, which are written with the same number of digits.
+fn same_length_range(lo: &str, hi: &str) -> String {
+    let (Some(&first_lo), Some(&first_hi)) = (lo.as_bytes().first(), hi.as_bytes().first()) else {
+        return String::new();
+    };
+    let (rest_lo, rest_hi) = (&lo[1..], &hi[1..]);
+    if first_lo == first_hi {
+        return format!(
+            "{}{}",
+            char::from(first_lo),
+            same_length_range(rest_lo, rest_hi)
+        );
+    }
+    let any_digits = |n: usize| {
+        if n == 0 {
+            String::new()
+        } else {
+            format!("[0-9]{{{n}}}")
+        }
+    };
+    if rest_lo.bytes().all(|c| c == b'0') && rest_hi.bytes().all(|c| c == b'9') {
+        return format!(
+            "[{}-{}]{}",
+            char::from(first_lo),
+            char::from(first_hi),
+            any_digits(rest_lo.len())
+        );
+    }
+
+    // The numbers starting with the first digit of `lo`, with a digit in between, and with the
+    // first digit of `hi`.
+    let mut alternatives = vec![format!(
+        "{}{}",
+        char::from(first_lo),
+        same_length_range(rest_lo, &"9".repeat(rest_lo.len()))
+    )];
+    if first_lo + 1 < first_hi {
+        alternatives.push(format!(
+            "[{}-{}]{}",
+            char::from(first_lo + 1),
+            char::from(first_hi - 1),
+            any_digits(rest_lo.len())
+        ));
+    }
+    alternatives.push(format!(
+        "{}{}",
+        char::from(first_hi),
+        same_length_range(&"0".repeat(rest_hi.len()), rest_hi)
+    ));
+    format!("({})", alternatives.join("|"))
+}
+
+/// Matches the non-negative integers from `lo` to `hi`, or from `lo` on if `hi` is `None`.
+fn natural_range(lo: u64, hi: Option<u64>) -> String {
+    let lo = lo.to_string();
+    let mut alternatives = Vec::new();
+    match hi {
+        Some(hi) => {
+            let hi = hi.to_string();
+            for len in lo.len()..=hi.len() {
+                let first = if len == lo.len() {
+                    lo.clone()
+                } else {
+                    format!("1{}", "0".repeat(len - 1))
+                };
+                let last = if len == hi.len() {
+                    hi.clone()
+                } else {
+                    "9".repeat(len)
+                };
+                alternatives.push(same_length_range(&first, &last));
+            }
+        }
+        None => {
+            alternatives.push(same_length_range(&lo, &"9".repeat(lo.len())));
+            alternatives.push(format!("[1-9][0-9]{{{},}}", lo.len()));
+        }
+    }
+    format!("({})", alternatives.join("|"))
+}
+
+/// Matches the integers from `min` to `max`, where `None` is unbounded. Returns `None` if there
+/// are no such integers.
+fn integer_range(min: Option<i64>, max: Option<i64>) -> Option<String> {
+    if let (Some(min), Some(max)) = (min, max) {
+        if min > max {
+            return None;
+        }
+    }
+    let mut alternatives = Vec::new();
+    if !min.is_some_and(|min| min >= 0) {
+        // The negative integers, by their absolute value.
+        let lo = max.filter(|max| *max < 0).map_or(1, i64::unsigned_abs);
+        let hi = min.map(i64::unsigned_abs);
+        alternatives.push(format!("-{}", natural_range(lo, hi)));
+    }
+    if !max.is_some_and(|max| max < 0) {
+        let lo = min.map_or(0, |min| min.max(0).unsigned_abs());
+        let hi = max.map(i64::unsigned_abs);
+        alternatives.push(natural_range(lo, hi));
+    }
+    Some(format!("({})", alternatives.join("|")))
+}
+
+/// The lower or upper bound of a numeric schema, and whether it is exclusive. Both the boolean
+/// `exclusiveMinimum` of draft 4 and the numeric one of later drafts are accepted.
+fn bound(
+    schema: &Map<String, Value>,
+    inclusive: &str,
+    exclusive: &str,
+) -> Result<Option<(i64, bool)>> {
+    let (value, is_exclusive) = match (schema.get(inclusive), schema.get(exclusive)) {
+        (_, Some(Value::Number(value))) => (value, true),
+        (Some(Value::Number(value)), None | Some(Value::Bool(_))) => {
+            (value, schema.get(exclusive) == Some(&Value::Bool(true)))
+        }
+        (None, None | Some(Value::Bool(_))) => return Ok(None),
+        _ => bail!("JSON schema `{inclusive}` and `{exclusive}` must be numbers."),
+    };
+    match value.as_i64() {
+        Some(value) => Ok(Some((value, is_exclusive))),
+        None => {
+            bail!("Only integer `{inclusive}` and `{exclusive}` are supported in JSON schemas.")
+        }
+    }
+}
+
+struct SchemaCompiler<'a> {
+    root: &'a Value,
+}
+
+impl SchemaCompiler<'_> {
+    fn resolve_ref(&self, reference: &str) -> Result<&Value> {
+        let Some(pointer) = reference.strip_prefix('#') else {
+            bail!("Only local JSON schema references are supported, got `{reference}`.");
+        };
+        match self.root.pointer(pointer) {
+            Some(schema) => Ok(schema),
+            None => bail!("Could not resolve JSON schema reference `{reference}`."),
+        }
+    }
+
+    fn compile(&self, schema: &Value, ref_depth: usize) -> Result<String> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok(any_value(MAX_ANY_DEPTH)),
+            Value::Bool(false) => bail!("A `false` JSON schema cannot match any value."),
+            Value::Object(schema) => schema,
+            _ => bail!("A JSON schema must be an object or a boolean."),
+        };
+
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            if ref_depth >= MAX_REF_DEPTH {
+                bail!("JSON schema references are nested too deeply.");
+            }
+            return self.compile(self.resolve_ref(reference)?, ref_depth + 1);
+        }
+        if let Some(value) = schema.get("const") {
+            return Ok(escape(&serde_json::to_string(value)?));
+        }
+        if let Some(values) = schema.get("enum") {
+            let Some(values) = values.as_array() else {
+                bail!("JSON schema `enum` must be an array.");
+            };
+            let values = values
+                .iter()
+                .map(|value| Ok(escape(&serde_json::to_string(value)?)))
+                .collect::<Result<Vec<_>>>()?;
+            return Ok(format!("({})", values.join("|")));
+        }
+        for key in ["anyOf", "oneOf"] {
+            if let Some(schemas) = schema.get(key) {
+                let Some(schemas) = schemas.as_array() else {
+                    bail!("JSON schema `{key}` must be an array.");
+                };
+                let alternatives = schemas
+                    .iter()
+                    .map(|schema| self.compile(schema, ref_depth))
+                    .collect::<Result<Vec<_>>>()?;
+                return Ok(format!("({})", alternatives.join("|")));
+            }
+        }
+        if let Some(schemas) = schema.get("allOf") {
+            match schemas.as_array().map(|schemas| &schemas[..]) {
+                Some([schema]) => return self.compile(schema, ref_depth),
+                _ => bail!("JSON schema `allOf` is only supported with a single schema."),
+            }
+        }
+
+        match schema.get("type") {
+            Some(Value::String(tp)) => self.compile_type(tp, schema, ref_depth),
+            Some(Value::Array(tps)) => {
+                let alternatives = tps
+                    .iter()
+                    .map(|tp| match tp.as_str() {
+                        Some(tp) => self.compile_type(tp, schema, ref_depth),
+                        None => bail!("JSON schema `type` entries must be strings."),
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(format!("({})", alternatives.join("|")))
+            }
+            Some(_) => bail!("JSON schema `type` must be a string or an array."),
+            None if schema.contains_key("properties") => {
+                self.compile_type("object", schema, ref_depth)
+            }
+            None => Ok(any_value(MAX_ANY_DEPTH)),
+        }
+    }
+
+    fn compile_type(
+        &self,
+        tp: &str,
+        schema: &Map<String, Value>,
+        ref_depth: usize,
+    ) -> Result<String> {
+        match tp {
+            "string" => Self::compile_string(schema),
+            "integer" => Self::compile_integer(schema),
+            "number" => Self::compile_number(schema),
+            "boolean" => Ok(BOOLEAN.to_string()),
+            "null" => Ok(NULL.to_string()),
+            "array" => self.compile_array(schema, ref_depth),
+            "object" => self.compile_object(schema, ref_depth),
+            other => bail!("Unsupported JSON schema type `{other}`."),
+        }
+    }
+
+    fn compile_string(schema: &Map<String, Value>) -> Result<String> {
+        if let Some(pattern) = schema.get("pattern").and_then(Value::as_str) {
+            let pattern = pattern.strip_prefix('^').unwrap_or(pattern);
+            let pattern = pattern.strip_suffix('$').unwrap_or(pattern);
+            return Ok(format!(r#""({pattern})""#));
+        }
+        if let Some(format) = schema.get("format").and_then(Value::as_str) {
+            let date = r"[0-9]{4}-(0[1-9]|1[0-2])-(0[1-9]|[12][0-9]|3[01])";
+            let time = r"([01][0-9]|2[0-3]):[0-5][0-9]:[0-5][0-9](\.[0-9]+)?(Z|[+-]([01][0-9]|2[0-3]):[0-5][0-9])?";
+            match format {
+                "date" => return Ok(format!(r#""{date}""#)),
+                "time" => return Ok(format!(r#""{time}""#)),
+                "date-time" => return Ok(format!(r#""{date}T{time}""#)),
+                "uuid" => {
+                    return Ok(
+                        r#""[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}""#
+                            .to_string(),
+                    )
+                }
+                // Unknown formats are only annotations.
+                _ => (),
+            }
+        }
+        let min_length = schema.get("minLength").and_then(Value::as_u64);
+        let max_length = schema.get("maxLength").and_then(Value::as_u64);
+        Ok(match (min_length, max_length) {
+            (None, None) => string(),
+            (min, Some(max)) => format!(r#""{STRING_INNER}{{{},{max}}}""#, min.unwrap_or(0)),
+            (Some(min), None) => format!(r#""{STRING_INNER}{{{min},}}""#),
+        })
+    }
+
+    fn compile_integer(schema: &Map<String, Value>) -> Result<String> {
+        let min = bound(schema, "minimum", "exclusiveMinimum")?;
+        let max = bound(schema, "maximum", "exclusiveMaximum")?;
+        if min.is_none() && max.is_none() {
+            return Ok(INTEGER.to_string());
+        }
+        let min = min.map(|(min, exclusive)| {
+            if exclusive {
+                min.saturating_add(1)
+            } else {
+                min
+            }
+        });
+        let max = max.map(|(max, exclusive)| {
+            if exclusive {
+                max.saturating_sub(1)
+            } else {
+                max
+            }
+        });
+        match integer_range(min, max) {
+            Some(regex) => Ok(regex),
+            None => bail!("JSON schema has no integer between its minimum and maximum."),
+        }
+    }
+
+    /// Bounded numbers are written without an exponent.
+    fn compile_number(schema: &Map<String, Value>) -> Result<String> {
+        let min = bound(schema, "minimum", "exclusiveMinimum")?;
+        let max = bound(schema, "maximum", "exclusiveMaximum")?;
+        if min.is_none() && max.is_none() {
+            return Ok(NUMBER.to_string());
+        }
+        // A non-zero fraction never equals a bound, so whether it is exclusive does not matter.
+        let fraction = r"\.[0-9]*[1-9]";
+        let (raw_min, raw_max) = (min.map(|(min, _)| min), max.map(|(max, _)| max));
+        let mut alternatives = Vec::new();
+        let min = min.map(|(min, exclusive)| {
+            if exclusive {
+                min.saturating_add(1)
+            } else {
+                min
+            }
+        });
+        let max = max.map(|(max, exclusive)| {
+            if exclusive {
+                max.saturating_sub(1)
+            } else {
+                max
+            }
+        });
+        alternatives.extend(integer_range(min, max));
+        // Positive `i.f` lies between the bounds if `min <= i` and `i + 1 <= max`.
+        let positive_min = raw_min.map_or(0, |min| min.max(0));
+        let positive_max = raw_max.map(|max| max.saturating_sub(1));
+        if let Some(integer) = integer_range(Some(positive_min), positive_max) {
+            alternatives.push(format!("{integer}{fraction}"));
+        }
+        // Negative `-i.f` lies between the bounds if `-max <= i` and `i + 1 <= -min`.
+        let negative_min = raw_max.map_or(0, |max| max.saturating_neg().max(0));
+        let negative_max = raw_min.map(|min| min.saturating_neg().saturating_sub(1));
+        if let Some(integer) = integer_range(Some(negative_min), negative_max) {
+            alternatives.push(format!("-{integer}{fraction}"));
+        }
+        if alternatives.is_empty() {
+            bail!("JSON schema has no number between its minimum and maximum.");
+        }
+        Ok(format!("({})", alternatives.join("|")))
+    }
+
+    fn compile_array(&self, schema: &Map<String, Value>, ref_depth: usize) -> Result<String> {
+        let item = match schema.get("items") {
+            Some(items) => self.compile(items, ref_depth)?,
+            None => any_value(MAX_ANY_DEPTH - 1),
+        };
+        let min_items = schema.get("minItems").and_then(Value::as_u64).unwrap_or(0);
+        let max_items = schema.get("maxItems").and_then(Value::as_u64);
+        if max_items == Some(0) {
+            return Ok(format!(r"\[{WHITESPACE}\]"));
+        }
+
+        // The number of items after the first one
+        let repeat = match max_items {
+            Some(max_items) => format!("{{{},{}}}", min_items.saturating_sub(1), max_items - 1),
+            None => format!("{{{},}}", min_items.saturating_sub(1)),
+        };
+        let items = format!("{item}({WHITESPACE},{WHITESPACE}{item}){repeat}");
+        if min_items == 0 {
+            Ok(format!(r"\[{WHITESPACE}({items})?{WHITESPACE}\]"))
+        } else {
+            Ok(format!(r"\[{WHITESPACE}{items}{WHITESPACE}\]"))
+        }
+    }
+
+    fn compile_object(&self, schema: &Map<String, Value>, ref_depth: usize) -> Result<String> {
+        let Some(properties) = schema.get("properties").and_then(Value::as_object) else {
+            return Ok(any_object(MAX_ANY_DEPTH));
+        };
+        let required = schema
+            .get("required")
+            .and_then(Value::as_array)
+            .map(|required| {
+                required
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        let mut members = Vec::new();
+        for (name, property) in properties {
+            let key = escape(&serde_json::to_string(name)?);
+            let value = self.compile(property, ref_depth)?;
+            members.push((
+                format!("{WHITESPACE}{key}{WHITESPACE}:{WHITESPACE}{value}"),
+                required.contains(&name.as_str()),
+            ));
+        }
+
+        let mut regex = r"\{".to_string();
+        match members.iter().rposition(|(_, is_required)| *is_required) {
+            Some(last_required) => {
+                // Members before the last required one carry their trailing comma, the ones after
+                // it carry their leading comma, so optional members can be left out.
+                for (i, (member, is_required)) in members.iter().enumerate() {
+                    let member = if i < last_required {
+                        format!("{member}{WHITESPACE},")
+                    } else if i > last_required {
+                        format!("{WHITESPACE},{member}")
+                    } else {
+                        member.clone()
+                    };
+                    if *is_required {
+                        regex.push_str(&member);
+                    } else {
+                        regex.push_str(&format!("({member})?"));
+                    }
+                }
+            }
+            None => {
+                // Nothing is required: choose which member comes first, and the others before it
+                // are left out.
+                let mut alternatives = Vec::new();
+                for (i, (member, _)) in members.iter().enumerate() {
+                    let mut alternative = member.clone();
+                    for (next, _) in &members[i + 1..] {
+                        alternative.push_str(&format!("({WHITESPACE},{next})?"));
+                    }
+                    alternatives.push(alternative);
+                }
+                if !alternatives.is_empty() {
+                    regex.push_str(&format!("({})?", alternatives.join("|")));
+                }
+            }
+        }
+        regex.push_str(&format!(r"{WHITESPACE}\}}"));
+        Ok(regex)
+    }
+}
+
+/// Compile a JSON schema into a regex for constrained decoding.
+pub(crate) fn json_schema_to_regex(schema: &Value) -> Result<String> {
+    SchemaCompiler { root: schema }.compile(schema, 0)
+}
+
+/// Compile a regex for an object with exactly these members, in order. Each member value is
+/// constrained by its own JSON schema, which is also the root for its references.
+pub(crate) fn json_object_to_regex(members: &[(&str, &Value)]) -> Result<String> {
+    let members = members
+        .iter()
+        .map(|(name, schema)| {
+            Ok(format!(
+                "{WHITESPACE}{}{WHITESPACE}:{WHITESPACE}{}",
+                escape(&serde_json::to_string(name)?),
+                json_schema_to_regex(schema)?
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(format!(
+        r"\{{{}{WHITESPACE}\}}",
+        members.join(&format!("{WHITESPACE},"))
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use regex_automata::meta::Regex;
+    use serde_json::json;
+
+    use super::{json_object_to_regex, json_schema_to_regex};
+
+    fn matches(schema: serde_json::Value, text: &str) -> bool {
+        let rx = json_schema_to_regex(&schema).unwrap();
+        Regex::new(&format!("^({rx})$")).unwrap().is_match(text)
+    }
+
+    #[test]
+    fn object_properties() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "age": {"type": "integer"},
+                "name": {"type": "string"},
+                "tags": {"type": "array", "items": {"type": "string"}, "maxItems": 2},
+            },
+            "required": ["name"],
+        });
+        assert!(matches(schema.clone(), r#"{"name": "Bob"}"#));
+        assert!(matches(schema.clone(), r#"{"age":42,"name":"Bob"}"#));
+        assert!(matches(
+            schema.clone(),
+            r#"{"age": 42, "name": "Bob", "tags": ["a", "b"]}"#
+        ));
+        assert!(!matches(schema.clone(), r#"{"age": 42}"#));
+        assert!(!matches(schema.clone(), r#"{"age": 4.2, "name": "Bob"}"#));
+        assert!(!matches(
+            schema,
+            r#"{"name": "Bob", "tags": ["a", "b", "c"]}"#
+        ));
+    }
+
+    #[test]
+    fn enums_and_refs() {
+        let schema = json!({
+            "$defs": {"color": {"enum": ["red", "green"]}},
+            "type": "object",
+            "properties": {"color": {"$ref": "#/$defs/color"}, "opt": {"type": ["boolean", "null"]}},
+        });
+        assert!(matches(schema.clone(), "{}"));
+        assert!(matches(schema.clone(), r#"{"color": "red"}"#));
+        assert!(matches(schema.clone(), r#"{"opt": null}"#));
+        assert!(matches(
+            schema.clone(),
+            r#"{"color": "green", "opt": true}"#
+        ));
+        assert!(!matches(schema, r#"{"color": "blue"}"#));
+    }
+
+    #[test]
+    fn numeric_bounds() {
+        let schema = json!({"type": "integer", "minimum": -12, "exclusiveMaximum": 250});
+        assert!(matches(schema.clone(), "-12"));
+        assert!(matches(schema.clone(), "0"));
+        assert!(matches(schema.clone(), "249"));
+        assert!(!matches(schema.clone(), "-13"));
+        assert!(!matches(schema.clone(), "250"));
+        assert!(!matches(schema, "1000"));
+
+        let schema = json!({"type": "number", "exclusiveMinimum": 0, "maximum": 10});
+        assert!(matches(schema.clone(), "0.5"));
+        assert!(matches(schema.clone(), "9.99"));
+        assert!(matches(schema.clone(), "10"));
+        assert!(!matches(schema.clone(), "0"));
+        assert!(!matches(schema.clone(), "-0.5"));
+        assert!(!matches(schema.clone(), "10.5"));
+        assert!(!matches(schema, "1e3"));
+        assert!(json_schema_to_regex(&json!({"type": "integer", "minimum": 1.5})).is_err());
+    }
+
+    #[test]
+    fn property_order() {
+        // Properties are generated in the order of the schema, not sorted.
+        let schema: serde_json::Value = serde_json::from_str(
+            r#"{"type": "object", "properties": {"b": {"type": "integer"}, "a": {"type": "integer"}}, "required": ["b", "a"]}"#,
+        )
+        .unwrap();
+        assert!(matches(schema.clone(), r#"{"b": 1, "a": 2}"#));
+        assert!(!matches(schema, r#"{"a": 2, "b": 1}"#));
+    }
+
+    #[test]
+    fn tool_call_object() {
+        let name = json!({"const": "get_weather"});
+        let parameters = json!({
+            "type": "object",
+            "properties": {"city": {"type": "string"}},
+            "required": ["city"],
+        });
+        let rx = json_object_to_regex(&[("name", &name), ("parameters", &parameters)]).unwrap();
+        let rx = Regex::new(&format!("^({rx})$")).unwrap();
+        assert!(rx.is_match(r#"{"name": "get_weather", "parameters": {"city": "Paris"}}"#));
+        assert!(!rx.is_match(r#"{"name": "get_time", "parameters": {"city": "Paris"}}"#));
+        assert!(!rx.is_match(r#"{"name": "get_weather", "parameters": {}}"#));
+    }
+}
@@ -1,8 +1,10 @@
 #![deny(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 
 use cublaslt::setup_cublas_lt_wrapper;
+use either::Either;
 use engine::Engine;
 pub use engine::TERMINATE_ALL_NEXT_STEP;
+use indexmap::IndexMap;
 pub use lora::Ordering;
 use pipeline::ModelCategory;
 pub use pipeline::Pipeline;
@@ -10,20 +12,23 @@ pub use pipeline::Pipeline;
 use pyo3::exceptions::PyValueError;
 use std::{
     cell::RefCell,
+    collections::HashSet,
     error::Error,
     fs::OpenOptions,
     io::Write,
+    num::NonZeroUsize,
     sync::{atomic::AtomicBool, Arc, Mutex, RwLock},
     thread::{self, JoinHandle},
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
-use tokio::sync::mpsc::{channel, Sender};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
 
 mod aici;
 mod cuda;
 mod device_map;
 mod engine;
 mod lora;
+mod metrics;
 mod model_loader;
 mod ops;
 pub use model_loader::{get_model_dtype, get_tgt_non_granular_index, LoaderBuilder};
@@ -37,6 +42,7 @@ mod cublaslt;
 #[cfg(not(all(feature = "cuda", target_family = "unix")))]
 mod dummy_paged_attention;
 mod gguf;
+mod json_schema;
 pub mod layers;
 mod layers_masker;
 mod layers_utils;
@@ -60,6 +66,7 @@ mod xlora_models;
 
 pub use amoe::{AnyMoeConfig, AnyMoeExpertType};
 pub use device_map::{DeviceLayerMapMetadata, DeviceMapMetadata, LayerDeviceMapper};
+pub use metrics::{EngineMetrics, HistogramSnapshot};
 pub use paged_attention::{MemoryGpuConfig, PagedAttentionConfig};
 pub use pipeline::{
     chat_template::ChatTemplate, parse_isq_value, AnyMoeLoader, AnyMoePipeline, GGMLLoader,
@@ -71,16 +78,24 @@ pub use pipeline::{
     Starcoder2Loader, TokenSource, VisionLoader, VisionLoaderBuilder, VisionLoaderType,
     VisionModelLoader, VisionSpecificConfig,
 };
-pub use request::{Constraint, MessageContent, NormalRequest, Request, RequestMessage};
+pub use prefix_cacher::PrefixCacheTiers;
+pub use request::{
+    Constraint, EmbeddingInput, EmbeddingPooling, EmbeddingRequest, MessageContent, NormalRequest,
+    Request, RequestMessage, ScoreRequest,
+};
 pub use response::Response;
 pub use response::*;
-pub use sampler::{SamplingParams, StopTokens, TopLogprob};
+pub use sampler::{
+    DrySamplingParams, LogitsProcessor, MirostatParams, SamplingParams, StopTokens, TopLogprob,
+};
 pub use scheduler::{DefaultSchedulerMethod, SchedulerConfig};
 use serde::Serialize;
+use tokenizers::Tokenizer;
 use tokio::runtime::Runtime;
 use toml_selector::{TomlLoaderArgs, TomlSelector};
 pub use tools::{
-    CalledFunction, Function, Tool, ToolCallResponse, ToolCallType, ToolChoice, ToolType,
+    CalledFunction, CalledFunctionDelta, Function, Tool, ToolCallDelta, ToolCallResponse,
+    ToolCallType, ToolChoice, ToolType,
 };
 pub use utils::debug::initialize_logging;
 pub use utils::memory_usage::MemoryUsage;
@@ -97,11 +112,13 @@ pub(crate) static DEBUG: AtomicBool = AtomicBool::new(false);
 pub struct MistralRs {
     sender: RwLock<Sender<Request>>,
     log: Option<String>,
-    id: String,
+    id: RwLock<String>,
     creation_time: u64,
     next_request_id: Mutex<RefCell<usize>>,
-    reboot_state: RebootState,
+    reboot_state: RwLock<RebootState>,
     engine_handler: RwLock<JoinHandle<()>>,
+    metrics: Arc<EngineMetrics>,
+    unloaded: AtomicBool,
 }
 
 #[derive(Clone)]
@@ -112,8 +129,17 @@ struct RebootState {
     no_kv_cache: bool,
     no_prefix_cache: bool,
     prefix_cache_n: usize,
+    prefix_cache_tiers: PrefixCacheTiers,
     disable_eos_stop: bool,
     throughput_logging_enabled: bool,
+    prefill_chunk_size: Option<NonZeroUsize>,
+    metrics: Arc<EngineMetrics>,
+    // Ids of the requests to cancel, which the engine takes on each step.
+    cancelled: Arc<Mutex<HashSet<usize>>>,
+    // Captured from the pipeline when it is set up, so tokenization does not wait for the engine to release it.
+    tokenizer: Arc<Tokenizer>,
+    chat_template: Arc<ChatTemplate>,
+    category: ModelCategory,
 }
 
 #[derive(Debug)]
@@ -148,9 +174,11 @@ pub struct MistralRsBuilder {
     no_kv_cache: Option<bool>,
     no_prefix_cache: Option<bool>,
     prefix_cache_n: Option<usize>,
+    prefix_cache_tiers: PrefixCacheTiers,
     disable_eos_stop: Option<bool>,
     gemm_full_precision_f16: Option<bool>,
     throughput_logging_enabled: Option<()>,
+    prefill_chunk_size: Option<NonZeroUsize>,
 }
 
 impl MistralRsBuilder {
@@ -163,9 +191,11 @@ impl MistralRsBuilder {
             no_kv_cache: None,
             no_prefix_cache: None,
             prefix_cache_n: None,
+            prefix_cache_tiers: PrefixCacheTiers::default(),
             disable_eos_stop: None,
             gemm_full_precision_f16: None,
             throughput_logging_enabled: None,
+            prefill_chunk_size: None,
         }
     }
     pub fn with_log(mut self, log: String) -> Self {
@@ -192,6 +222,11 @@ impl MistralRsBuilder {
         self.prefix_cache_n = Some(prefix_cache_n);
         self
     }
+    /// Byte budgets for the device, host RAM and disk tiers of the prefix cache.
+    pub fn with_prefix_cache_tiers(mut self, prefix_cache_tiers: PrefixCacheTiers) -> Self {
+        self.prefix_cache_tiers = prefix_cache_tiers;
+        self
+    }
     pub fn with_disable_eos_stop(mut self, disable_eos_stop: bool) -> Self {
         self.disable_eos_stop = Some(disable_eos_stop);
         self
@@ -204,6 +239,12 @@ impl MistralRsBuilder {
         self.throughput_logging_enabled = Some(());
         self
     }
+    /// Split prompts into chunks of at most this many tokens, run over multiple engine steps
+    /// so that running sequences keep emitting tokens during long prompts.
+    pub fn with_prefill_chunk_size(mut self, prefill_chunk_size: Option<NonZeroUsize>) -> Self {
+        self.prefill_chunk_size = prefill_chunk_size;
+        self
+    }
 
     pub fn build(self) -> Arc<MistralRs> {
         MistralRs::new(self)
@@ -248,7 +289,11 @@ fn set_gemm_reduced_precision_f16() {
 fn set_gemm_reduced_precision_f16() {}
 
 impl MistralRs {
-    fn new(config: MistralRsBuilder) -> Arc<Self> {
+    /// Set up the global state for the pipeline of `config` and collect what its engine needs.
+    fn into_reboot_state(
+        config: MistralRsBuilder,
+        metrics: Arc<EngineMetrics>,
+    ) -> (RebootState, Option<String>) {
         let MistralRsBuilder {
             pipeline,
             method,
@@ -257,12 +302,22 @@ impl MistralRs {
             no_kv_cache,
             no_prefix_cache,
             prefix_cache_n,
+            prefix_cache_tiers,
             disable_eos_stop,
             gemm_full_precision_f16,
             throughput_logging_enabled,
+            prefill_chunk_size,
         } = config;
 
-        let model_supports_reduced_gemm = match pipeline.try_lock().unwrap().category() {
+        let (tokenizer, chat_template, category) = {
+            let pipeline = pipeline.try_lock().unwrap();
+            (
+                pipeline.tokenizer(),
+                pipeline.get_chat_template(),
+                pipeline.category(),
+            )
+        };
+        let model_supports_reduced_gemm = match category {
             ModelCategory::Text => true,
             ModelCategory::Vision { has_conv2d } => !has_conv2d,
         };
@@ -271,67 +326,104 @@ impl MistralRs {
         }
         setup_cublas_lt_wrapper();
 
-        let truncate_sequence = truncate_sequence.unwrap_or(false);
-        let no_kv_cache = no_kv_cache.unwrap_or(false);
-        let no_prefix_cache = no_prefix_cache.unwrap_or(false);
-        let prefix_cache_n = prefix_cache_n.unwrap_or(16);
-        let disable_eos_stop = disable_eos_stop.unwrap_or(false);
-
         let reboot_state = RebootState {
-            pipeline: pipeline.clone(),
-            method: method.clone(),
-            truncate_sequence,
-            no_kv_cache,
-            no_prefix_cache,
-            prefix_cache_n,
-            disable_eos_stop,
+            pipeline,
+            method,
+            truncate_sequence: truncate_sequence.unwrap_or(false),
+            no_kv_cache: no_kv_cache.unwrap_or(false),
+            no_prefix_cache: no_prefix_cache.unwrap_or(false),
+            prefix_cache_n: prefix_cache_n.unwrap_or(16),
+            prefix_cache_tiers,
+            disable_eos_stop: disable_eos_stop.unwrap_or(false),
             throughput_logging_enabled: throughput_logging_enabled.is_some(),
+            prefill_chunk_size,
+            metrics,
+            cancelled: Arc::new(Mutex::new(HashSet::new())),
+            tokenizer,
+            chat_template,
+            category,
         };
+        (reboot_state, log)
+    }
+
+    fn new(config: MistralRsBuilder) -> Arc<Self> {
+        let (reboot_state, log) =
+            Self::into_reboot_state(config, Arc::new(EngineMetrics::default()));
 
         let (tx, rx) = channel(10_000);
 
         let sender = RwLock::new(tx);
-        let id = pipeline.try_lock().unwrap().name();
+        let id = reboot_state.pipeline.try_lock().unwrap().name();
+        let metrics = reboot_state.metrics.clone();
 
-        let engine_handler = thread::spawn(move || {
-            let rt = Runtime::new().unwrap();
-            rt.block_on(async move {
-                let mut engine = Engine::new(
-                    rx,
-                    pipeline,
-                    method,
-                    truncate_sequence,
-                    no_kv_cache,
-                    no_prefix_cache,
-                    prefix_cache_n,
-                    disable_eos_stop,
-                );
-                if throughput_logging_enabled.is_some() {
-                    engine.enable_throughput_logging();
-                }
-                engine.run().await;
-            });
-        });
+        let engine_handler = Self::spawn_engine(rx, reboot_state.clone(), None);
 
         Arc::new(Self {
             sender,
             log,
-            id,
+            id: RwLock::new(id),
             creation_time: SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .expect("Time travel has occurred!")
                 .as_secs(),
             next_request_id: Mutex::new(RefCell::new(0)),
-            reboot_state,
+            reboot_state: RwLock::new(reboot_state),
             engine_handler: RwLock::new(engine_handler),
+            metrics,
+            unloaded: AtomicBool::new(false),
+        })
+    }
+
+    /// Run an engine for the pipeline of `reboot_state` on a new thread, receiving requests from `rx`. If `start`
+    /// is set, the engine only starts once it is signalled or its sender is dropped.
+    fn spawn_engine(
+        rx: Receiver<Request>,
+        reboot_state: RebootState,
+        start: Option<std::sync::mpsc::Receiver<()>>,
+    ) -> JoinHandle<()> {
+        thread::spawn(move || {
+            if let Some(start) = start {
+                let _ = start.recv();
+            }
+            let rt = Runtime::new().unwrap();
+            rt.block_on(async move {
+                let mut engine = Engine::new(
+                    rx,
+                    reboot_state.pipeline,
+                    reboot_state.method,
+                    reboot_state.truncate_sequence,
+                    reboot_state.no_kv_cache,
+                    reboot_state.no_prefix_cache,
+                    reboot_state.prefix_cache_n,
+                    reboot_state.prefix_cache_tiers,
+                    reboot_state.disable_eos_stop,
+                    reboot_state.prefill_chunk_size,
+                    reboot_state.metrics,
+                    reboot_state.cancelled,
+                );
+                if reboot_state.throughput_logging_enabled {
+                    engine.enable_throughput_logging();
+                }
+                engine.run().await;
+            });
         })
     }
 
+    fn get_reboot_state(&self) -> Result<RebootState, MistralRsError> {
+        match self.reboot_state.read() {
+            Ok(reboot_state) => Ok(reboot_state.clone()),
+            Err(_) => {
+                tracing::warn!("Couldn't get read lock on the reboot state!");
+                Err(MistralRsError::EnginePoisoned)
+            }
+        }
+    }
+
     /// attempts to reboot the engine, if the sender (only way to communicate with
     /// the engine) is closed
     fn reboot_engine(&self) -> Result<(), MistralRsError> {
         let (new_sender, rx) = channel(10_000);
-        let reboot_state = self.reboot_state.clone();
+        let reboot_state = self.get_reboot_state()?;
         let mut sender_lock = self.sender.write().map_err(|_| {
             tracing::warn!("Couldn't get write lock on the sender during reboot attempt");
             MistralRsError::SenderPoisoned
@@ -346,25 +438,7 @@ impl MistralRs {
             Ok(())
         } else {
             // critical section. A panic here could lead to poisoned locks
-            let new_engine_handler = thread::spawn(move || {
-                let rt = Runtime::new().unwrap();
-                rt.block_on(async move {
-                    let mut engine = Engine::new(
-                        rx,
-                        reboot_state.pipeline.clone(),
-                        reboot_state.method,
-                        reboot_state.truncate_sequence,
-                        reboot_state.no_kv_cache,
-                        reboot_state.no_prefix_cache,
-                        reboot_state.prefix_cache_n,
-                        reboot_state.disable_eos_stop,
-                    );
-                    if reboot_state.throughput_logging_enabled {
-                        engine.enable_throughput_logging();
-                    }
-                    engine.run().await;
-                });
-            });
+            let new_engine_handler = Self::spawn_engine(rx, reboot_state, None);
             *sender_lock = new_sender;
             *engine_lock = new_engine_handler;
             tracing::info!("Successfully rebooted engine and updated sender + engine handler");
@@ -372,6 +446,68 @@ impl MistralRs {
         }
     }
 
+    /// Replace the model with the one of `config`, keeping the metrics and the log file. New requests are
+    /// queued for the new model at once, and its engine starts once the requests in flight on the old model
+    /// have finished and the old engine has stopped, which this blocks until. Both models are in memory until
+    /// the old one is freed, so the device must have room for both. This must not be called from an async context.
+    pub fn swap_model(&self, config: MistralRsBuilder) -> Result<(), MistralRsError> {
+        let (new_sender, rx) = channel(10_000);
+        let (start_tx, start_rx) = std::sync::mpsc::channel();
+        let (reboot_state, _) = Self::into_reboot_state(config, self.metrics.clone());
+        let id = reboot_state.pipeline.try_lock().unwrap().name();
+        let (old_sender, old_engine_handler) = {
+            let mut reboot_state_lock = self.reboot_state.write().map_err(|_| {
+                tracing::warn!("Couldn't get write lock on the reboot state during swap attempt");
+                MistralRsError::EnginePoisoned
+            })?;
+            let mut sender_lock = self.sender.write().map_err(|_| {
+                tracing::warn!("Couldn't get write lock on the sender during swap attempt");
+                MistralRsError::SenderPoisoned
+            })?;
+            let mut engine_lock = self.engine_handler.write().map_err(|_| {
+                tracing::warn!("Couldn't get write lock on the engine during swap attempt");
+                MistralRsError::EnginePoisoned
+            })?;
+
+            // critical section. A panic here could lead to poisoned locks
+            *reboot_state_lock = reboot_state.clone();
+            let new_engine_handler = Self::spawn_engine(rx, reboot_state, Some(start_rx));
+            self.unloaded
+                .store(false, std::sync::atomic::Ordering::SeqCst);
+            if let Ok(mut id_lock) = self.id.write() {
+                *id_lock = id;
+            }
+            (
+                std::mem::replace(&mut *sender_lock, new_sender),
+                std::mem::replace(&mut *engine_lock, new_engine_handler),
+            )
+        };
+
+        // The send only fails if the old engine has already stopped.
+        let _ = old_sender.blocking_send(Request::Terminate);
+        let stopped = old_engine_handler.join();
+        // Start the new engine even if the old one panicked, the requests queued for it must still run.
+        let _ = start_tx.send(());
+        stopped.map_err(|_| MistralRsError::EnginePoisoned)?;
+        tracing::info!("Successfully stopped the old engine and started the one of the new model");
+        Ok(())
+    }
+
+    /// Stop the engine once the requests in flight have finished, blocking until it has stopped. The engine
+    /// is not rebooted afterwards, so later requests fail, and the pipeline is freed when this is dropped. This
+    /// must not be called from an async context.
+    pub fn unload(&self) -> Result<(), MistralRsError> {
+        self.unloaded
+            .store(true, std::sync::atomic::Ordering::SeqCst);
+        // The send only fails if the engine has already stopped.
+        let _ = self.get_sender()?.blocking_send(Request::Terminate);
+        while !self.engine_dead()? {
+            thread::sleep(Duration::from_millis(10));
+        }
+        tracing::info!("Successfully unloaded the model");
+        Ok(())
+    }
+
     fn engine_dead(&self) -> Result<bool, MistralRsError> {
         match self.engine_handler.read() {
             Ok(handler) => Ok(handler.is_finished()),
@@ -383,7 +519,7 @@ impl MistralRs {
     }
 
     pub fn get_sender(&self) -> Result<Sender<Request>, MistralRsError> {
-        if self.engine_dead()? {
+        if self.engine_dead()? && !self.unloaded.load(std::sync::atomic::Ordering::SeqCst) {
             tracing::warn!("Engine is dead, rebooting");
             self.reboot_engine()?
         }
@@ -393,8 +529,90 @@ impl MistralRs {
         }
     }
 
+    /// Cancel all sequences belonging to the request with this id. No further responses are
+    /// sent for the request and its response channel is closed once the sequences are dropped. Unlike
+    /// [`Request::Cancel`], this does not queue behind other requests, the engine applies it on its next step.
+    pub fn cancel_request(&self, id: usize) -> Result<(), MistralRsError> {
+        self.get_reboot_state()?
+            .cancelled
+            .lock()
+            .map_err(|_| MistralRsError::EnginePoisoned)?
+            .insert(id);
+        Ok(())
+    }
+
+    /// Tokenize a prompt, or chat messages after applying the chat template and the processor a request with
+    /// them would use. `add_special_tokens` only applies to a prompt, the chat template adds them for messages.
+    /// The `images` of the messages are processed like those of a request, adding the image tokens of vision
+    /// models. Only messages wait for the engine to release the pipeline, whose processor applies the chat template.
+    pub fn tokenize(
+        &self,
+        text: Either<Vec<IndexMap<String, MessageContent>>, String>,
+        tools: Option<Vec<Tool>>,
+        add_generation_prompt: bool,
+        add_special_tokens: bool,
+        images: Vec<image::DynamicImage>,
+    ) -> anyhow::Result<Vec<u32>> {
+        let reboot_state = self.get_reboot_state()?;
+        match text {
+            Either::Left(messages) => {
+                if !reboot_state.chat_template.has_chat_template() {
+                    anyhow::bail!(
+                        "The model does not have a chat template, so messages cannot be tokenized."
+                    );
+                }
+                let pipeline = get_mut_arcmutex!(reboot_state.pipeline);
+                let pipeline = &*pipeline;
+                let processor = pipeline.get_processor();
+                let toks = processor.process(
+                    pipeline,
+                    messages,
+                    add_generation_prompt,
+                    tools.unwrap_or_default(),
+                )?;
+                if images.is_empty()
+                    || !matches!(reboot_state.category, ModelCategory::Vision { .. })
+                {
+                    return Ok(toks);
+                }
+                processor.inputs_processor().prompt_tokens_with_images(
+                    &reboot_state.tokenizer,
+                    toks,
+                    images,
+                    pipeline.get_input_processor_config(),
+                )
+            }
+            Either::Right(prompt) => {
+                let encoding = reboot_state
+                    .tokenizer
+                    .encode(prompt, add_special_tokens)
+                    .map_err(anyhow::Error::msg)?;
+                Ok(encoding.get_ids().to_vec())
+            }
+        }
+    }
+
+    /// Whether the model takes images as input.
+    pub fn is_vision_model(&self) -> anyhow::Result<bool> {
+        let category = self.get_reboot_state()?.category;
+        Ok(matches!(category, ModelCategory::Vision { .. }))
+    }
+
+    /// Decode tokens into text with the tokenizer of the model.
+    pub fn detokenize(&self, tokens: &[u32], skip_special_tokens: bool) -> anyhow::Result<String> {
+        self.get_reboot_state()?
+            .tokenizer
+            .decode(tokens, skip_special_tokens)
+            .map_err(anyhow::Error::msg)
+    }
+
+    /// Metrics describing the load and latency of the engine.
+    pub fn get_metrics(&self) -> &EngineMetrics {
+        &self.metrics
+    }
+
     pub fn get_id(&self) -> String {
-        self.id.clone()
+        self.id.read().expect("Id lock was poisoned").clone()
     }
 
     pub fn get_creation_time(&self) -> u64 {
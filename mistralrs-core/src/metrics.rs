@@ -0,0 +1,203 @@
+#![allow(clippy::cast_precision_loss)]
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use crate::response::Response;
+
+/// Upper bounds, in seconds, of the time to first token histogram buckets.
+const TTFT_BUCKETS: &[f64] = &[
+    0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+/// Upper bounds, in seconds, of the inter-token latency histogram buckets.
+const INTER_TOKEN_LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.02, 0.04, 0.06, 0.08, 0.1, 0.25, 0.5, 1.0,
+];
+
+/// A point-in-time copy of a histogram.
+#[derive(Debug, Clone)]
+pub struct HistogramSnapshot {
+    /// Upper bound of each bucket with the cumulative number of observations less than or equal to it.
+    pub buckets: Vec<(f64, u64)>,
+    pub sum: f64,
+    pub count: u64,
+}
+
+struct Histogram {
+    bounds: &'static [f64],
+    snapshot: Mutex<HistogramSnapshot>,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            snapshot: Mutex::new(HistogramSnapshot {
+                buckets: bounds.iter().map(|bound| (*bound, 0)).collect(),
+                sum: 0.,
+                count: 0,
+            }),
+        }
+    }
+
+    fn observe(&self, value: f64) {
+        let mut snapshot = self.snapshot.lock().expect("Metrics lock was poisoned");
+        let first = self.bounds.partition_point(|bound| *bound < value);
+        for (_, count) in &mut snapshot.buckets[first..] {
+            *count += 1;
+        }
+        snapshot.sum += value;
+        snapshot.count += 1;
+    }
+
+    fn snapshot(&self) -> HistogramSnapshot {
+        self.snapshot
+            .lock()
+            .expect("Metrics lock was poisoned")
+            .clone()
+    }
+}
+
+/// Counters and gauges describing the load and latency of an engine, updated as it runs.
+pub struct EngineMetrics {
+    waiting_seqs: AtomicUsize,
+    running_seqs: AtomicUsize,
+    kv_cache_blocks_used: AtomicUsize,
+    kv_cache_blocks_total: AtomicUsize,
+    prefix_cache_hits: AtomicU64,
+    prompt_tokens: AtomicU64,
+    completion_tokens: AtomicU64,
+    time_to_first_token: Histogram,
+    inter_token_latency: Histogram,
+    errors: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl Default for EngineMetrics {
+    fn default() -> Self {
+        Self {
+            waiting_seqs: AtomicUsize::new(0),
+            running_seqs: AtomicUsize::new(0),
+            kv_cache_blocks_used: AtomicUsize::new(0),
+            kv_cache_blocks_total: AtomicUsize::new(0),
+            prefix_cache_hits: AtomicU64::new(0),
+            prompt_tokens: AtomicU64::new(0),
+            completion_tokens: AtomicU64::new(0),
+            time_to_first_token: Histogram::new(TTFT_BUCKETS),
+            inter_token_latency: Histogram::new(INTER_TOKEN_LATENCY_BUCKETS),
+            errors: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl EngineMetrics {
+    pub(crate) fn set_scheduler_lens(&self, waiting: usize, running: usize) {
+        self.waiting_seqs.store(waiting, Ordering::Relaxed);
+        self.running_seqs.store(running, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_kv_cache_blocks(&self, used: usize, total: usize) {
+        self.kv_cache_blocks_used.store(used, Ordering::Relaxed);
+        self.kv_cache_blocks_total.store(total, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_prefix_cache_hit(&self) {
+        self.prefix_cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a prompt which finished processing, producing the first completion token after `ttft_secs`.
+    pub(crate) fn record_prompt(&self, prompt_tokens: usize, ttft_secs: f64) {
+        self.prompt_tokens
+            .fetch_add(prompt_tokens as u64, Ordering::Relaxed);
+        self.completion_tokens.fetch_add(1, Ordering::Relaxed);
+        self.time_to_first_token.observe(ttft_secs);
+    }
+
+    /// Record `n` completion tokens generated `elapsed_secs` after the last token of their sequence.
+    pub(crate) fn record_completion_tokens(&self, n: usize, elapsed_secs: f64) {
+        if n == 0 {
+            return;
+        }
+        self.completion_tokens
+            .fetch_add(n as u64, Ordering::Relaxed);
+        let latency = elapsed_secs / n as f64;
+        for _ in 0..n {
+            self.inter_token_latency.observe(latency);
+        }
+    }
+
+    /// Count the response if it is an error, by its variant.
+    pub fn record_response(&self, response: &Response) {
+        let variant = match response {
+            Response::InternalError(_) => "InternalError",
+            Response::ValidationError(_) => "ValidationError",
+            Response::ModelError(_, _) => "ModelError",
+            Response::CompletionModelError(_, _) => "CompletionModelError",
+            Response::Done(_)
+            | Response::Chunk(_)
+            | Response::CompletionDone(_)
+            | Response::CompletionChunk(_)
+            | Response::Embeddings(_)
+            | Response::Score(_) => return,
+        };
+        *self
+            .errors
+            .lock()
+            .expect("Metrics lock was poisoned")
+            .entry(variant)
+            .or_default() += 1;
+    }
+
+    /// Sequences waiting to be scheduled.
+    pub fn waiting_seqs(&self) -> usize {
+        self.waiting_seqs.load(Ordering::Relaxed)
+    }
+
+    /// Sequences being run.
+    pub fn running_seqs(&self) -> usize {
+        self.running_seqs.load(Ordering::Relaxed)
+    }
+
+    /// The used and total number of KV cache blocks, if PagedAttention is used.
+    pub fn kv_cache_blocks(&self) -> Option<(usize, usize)> {
+        let total = self.kv_cache_blocks_total.load(Ordering::Relaxed);
+        (total > 0).then(|| (self.kv_cache_blocks_used.load(Ordering::Relaxed), total))
+    }
+
+    /// Prompts which reused a cached prefix.
+    pub fn prefix_cache_hits(&self) -> u64 {
+        self.prefix_cache_hits.load(Ordering::Relaxed)
+    }
+
+    pub fn prompt_tokens(&self) -> u64 {
+        self.prompt_tokens.load(Ordering::Relaxed)
+    }
+
+    pub fn completion_tokens(&self) -> u64 {
+        self.completion_tokens.load(Ordering::Relaxed)
+    }
+
+    /// Seconds from the creation of a sequence to its first completion token.
+    pub fn time_to_first_token(&self) -> HistogramSnapshot {
+        self.time_to_first_token.snapshot()
+    }
+
+    /// Seconds between consecutive completion tokens of a sequence.
+    pub fn inter_token_latency(&self) -> HistogramSnapshot {
+        self.inter_token_latency.snapshot()
+    }
+
+    /// Error responses by `Response` variant.
+    pub fn errors(&self) -> Vec<(&'static str, u64)> {
+        self.errors
+            .lock()
+            .expect("Metrics lock was poisoned")
+            .iter()
+            .map(|(variant, count)| (*variant, *count))
+            .collect()
+    }
+}
@@ -17,7 +17,7 @@ fn parse_model_dtype(x: &str) -> Result<ModelDType, String> {
     x.parse()
 }
 
-#[derive(Debug, Subcommand)]
+#[derive(Debug, Clone, Subcommand)]
 pub enum ModelSelected {
     /// Select the model from a toml file
     Toml {
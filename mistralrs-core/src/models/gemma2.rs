@@ -545,6 +545,29 @@ impl Model {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         context_lens: Vec<(usize, usize)>,
+    ) -> Result<Tensor> {
+        let mut xs = self.forward_hidden_states(input_ids, seqlen_offsets, start_offsets_kernel)?;
+        if matches!(self.lm_head, QMatMul::QTensor(_)) {
+            xs = xs.to_dtype(DType::F32)?;
+        }
+
+        let mut xs = MatMul.qmatmul(&xs, &self.lm_head)?;
+
+        if let Some(final_logit_softcapping) = self.final_logit_softcapping {
+            xs = (xs / final_logit_softcapping)?;
+            xs = xs.tanh()?;
+            xs = (xs * final_logit_softcapping)?;
+        }
+
+        extract_logits(&xs, context_lens)
+    }
+
+    /// The hidden states after the final norm, without the LM head.
+    pub fn forward_hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
     ) -> Result<Tensor> {
         let xs = self.embed_tokens.forward(input_ids)?;
         let mut xs = (xs * (self.hidden_size as f64).sqrt())?;
@@ -580,21 +603,7 @@ impl Model {
                 &mut cache[i],
             )?;
         }
-        let xs = xs.to_device(&self.device)?;
-        let mut xs = xs.apply(&self.norm)?;
-        if matches!(self.lm_head, QMatMul::QTensor(_)) {
-            xs = xs.to_dtype(DType::F32)?;
-        }
-
-        let mut xs = MatMul.qmatmul(&xs, &self.lm_head)?;
-
-        if let Some(final_logit_softcapping) = self.final_logit_softcapping {
-            xs = (xs / final_logit_softcapping)?;
-            xs = xs.tanh()?;
-            xs = (xs * final_logit_softcapping)?;
-        }
-
-        extract_logits(&xs, context_lens)
+        xs.to_device(&self.device)?.apply(&self.norm)
     }
 }
 
@@ -655,6 +664,16 @@ impl NormalModel for Model {
             context_lens,
         )
     }
+    fn forward_hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        _position_ids: Vec<usize>,
+        _metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+    ) -> Result<Tensor> {
+        self.forward_hidden_states(input_ids, seqlen_offsets, start_offsets_kernel)
+    }
     fn xlora_forward(
         &self,
         _input_ids: &Tensor,
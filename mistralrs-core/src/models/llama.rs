@@ -374,6 +374,31 @@ impl Llama {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         context_lens: Vec<(usize, usize)>,
+        metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+    ) -> Result<Tensor> {
+        let mut x =
+            self.forward_hidden_states(input_ids, seqlen_offsets, start_offsets_kernel, metadata)?;
+        if matches!(self.lm_head, QMatMul::QTensor(_)) {
+            x = x.to_dtype(DType::F32)?;
+        }
+        println!("before logits");
+        let logits = MatMul.qmatmul(&x, &self.lm_head)?;
+        // extract_logits(&logits, context_lens)
+        let extracted_logits = extract_logits(&logits, context_lens);
+
+        // Add logging
+        println!("Extracted logits: {:?}", extracted_logits);
+
+        // Return the result
+        extracted_logits
+    }
+
+    /// The hidden states after the final norm, without the LM head.
+    pub fn forward_hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
         mut metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
     ) -> Result<Tensor> {
         // println!("input_ids size {:?}", input_ids.shape());
@@ -526,20 +551,7 @@ impl Llama {
 
         println!("after blocks");
         x = candle_core::Tensor::cat(&processed_chunks, 1)?;
-        let mut x = self.ln_f.forward(&x)?;
-        if matches!(self.lm_head, QMatMul::QTensor(_)) {
-            x = x.to_dtype(DType::F32)?;
-        }
-        println!("before logits");
-        let logits = MatMul.qmatmul(&x, &self.lm_head)?;
-        // extract_logits(&logits, context_lens)
-        let extracted_logits = extract_logits(&logits, context_lens);
-
-        // Add logging
-        println!("Extracted logits: {:?}", extracted_logits);
-
-        // Return the result
-        extracted_logits
+        self.ln_f.forward(&x)
     }
 
     pub fn new(
@@ -685,6 +697,16 @@ impl NormalModel for Llama {
             metadata,
         )
     }
+    fn forward_hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        _position_ids: Vec<usize>,
+        metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+    ) -> Result<Tensor> {
+        self.forward_hidden_states(input_ids, seqlen_offsets, start_offsets_kernel, metadata)
+    }
     fn xlora_forward(
         &self,
         _input_ids: &Tensor,
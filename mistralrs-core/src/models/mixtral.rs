@@ -519,6 +519,22 @@ impl Model {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         context_lens: Vec<(usize, usize)>,
+        metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+    ) -> Result<Tensor> {
+        let mut xs =
+            self.forward_hidden_states(input_ids, seqlen_offsets, start_offsets_kernel, metadata)?;
+        if matches!(self.lm_head, QMatMul::QTensor(_)) {
+            xs = xs.to_dtype(DType::F32)?;
+        }
+        extract_logits(&MatMul.qmatmul(&xs, &self.lm_head)?, context_lens)
+    }
+
+    /// The hidden states after the final norm, without the LM head.
+    pub fn forward_hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
         mut metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
     ) -> Result<Tensor> {
         let mut xs = self.embed_tokens.forward(input_ids)?;
@@ -549,12 +565,7 @@ impl Model {
                     .map(|(kv_cache, metadata)| (kv_cache[i].clone(), &mut **metadata)),
             )?;
         }
-        let xs = xs.to_device(&self.device)?;
-        let mut xs = xs.apply(&self.norm)?;
-        if matches!(self.lm_head, QMatMul::QTensor(_)) {
-            xs = xs.to_dtype(DType::F32)?;
-        }
-        extract_logits(&MatMul.qmatmul(&xs, &self.lm_head)?, context_lens)
+        xs.to_device(&self.device)?.apply(&self.norm)
     }
 }
 
@@ -599,6 +610,16 @@ impl NormalModel for Model {
             metadata,
         )
     }
+    fn forward_hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        _position_ids: Vec<usize>,
+        metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+    ) -> Result<Tensor> {
+        self.forward_hidden_states(input_ids, seqlen_offsets, start_offsets_kernel, metadata)
+    }
     fn xlora_forward(
         &self,
         _input_ids: &Tensor,
@@ -478,6 +478,22 @@ impl Model {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         context_lens: Vec<(usize, usize)>,
+        metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+    ) -> Result<Tensor> {
+        let mut xs =
+            self.forward_hidden_states(input_ids, seqlen_offsets, start_offsets_kernel, metadata)?;
+        if self.lm_head.is_quant() {
+            xs = xs.to_dtype(DType::F32)?;
+        }
+        extract_logits(&xs.apply(&self.lm_head)?, context_lens)
+    }
+
+    /// The hidden states after the final layer norm, without the LM head.
+    pub fn forward_hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
         mut metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
     ) -> Result<Tensor> {
         let mut xs = input_ids.apply(&self.embed_tokens)?;
@@ -506,12 +522,7 @@ impl Model {
                     .map(|(kv_cache, metadata)| (kv_cache[i].clone(), &mut **metadata)),
             )?;
         }
-        let xs = xs.to_device(&self.device)?;
-        let mut xs = xs.apply(&self.final_layernorm)?;
-        if self.lm_head.is_quant() {
-            xs = xs.to_dtype(DType::F32)?;
-        }
-        extract_logits(&xs.apply(&self.lm_head)?, context_lens)
+        xs.to_device(&self.device)?.apply(&self.final_layernorm)
     }
 }
 
@@ -573,6 +584,16 @@ impl NormalModel for Model {
             metadata,
         )
     }
+    fn forward_hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        _position_ids: Vec<usize>,
+        metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+    ) -> Result<Tensor> {
+        self.forward_hidden_states(input_ids, seqlen_offsets, start_offsets_kernel, metadata)
+    }
     fn xlora_forward(
         &self,
         _input_ids: &Tensor,
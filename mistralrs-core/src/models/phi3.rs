@@ -468,6 +468,22 @@ impl Model {
         seqlen_offsets: &[usize],
         position_ids: &[usize],
         context_lens: Vec<(usize, usize)>,
+        metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+    ) -> Result<Tensor> {
+        let mut xs =
+            self.forward_hidden_states(input_ids, seqlen_offsets, position_ids, metadata)?;
+        if matches!(self.lm_head, QMatMul::QTensor(_)) {
+            xs = xs.to_dtype(DType::F32)?;
+        }
+        extract_logits(&MatMul.qmatmul(&xs, &self.lm_head)?, context_lens)
+    }
+
+    /// The hidden states after the final norm, without the LM head.
+    pub fn forward_hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        position_ids: &[usize],
         mut metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
     ) -> Result<Tensor> {
         let mut xs = self.embed_tokens.forward(input_ids)?;
@@ -499,12 +515,7 @@ impl Model {
                     .map(|(kv_cache, metadata)| (kv_cache[i].clone(), &mut **metadata)),
             )?
         }
-        let xs = xs.to_device(&self.device)?;
-        let mut xs = xs.apply(&self.norm)?;
-        if matches!(self.lm_head, QMatMul::QTensor(_)) {
-            xs = xs.to_dtype(DType::F32)?;
-        }
-        extract_logits(&MatMul.qmatmul(&xs, &self.lm_head)?, context_lens)
+        xs.to_device(&self.device)?.apply(&self.norm)
     }
 }
 
@@ -549,6 +560,16 @@ impl NormalModel for Model {
             metadata,
         )
     }
+    fn forward_hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        _start_offsets_kernel: Tensor,
+        position_ids: Vec<usize>,
+        metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+    ) -> Result<Tensor> {
+        self.forward_hidden_states(input_ids, seqlen_offsets, &position_ids, metadata)
+    }
     fn xlora_forward(
         &self,
         _input_ids: &Tensor,
@@ -565,6 +565,21 @@ impl ModelWeights {
         start_offsets: &[usize],
         start_offsets_kernel: Tensor,
         context_lens: Vec<(usize, usize)>,
+        metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+    ) -> Result<Tensor> {
+        let x = self.forward_hidden_states(x, start_offsets, start_offsets_kernel, metadata)?;
+        extract_logits(
+            &MatMul.qmatmul(&x.contiguous()?, &self.output)?,
+            context_lens,
+        )
+    }
+
+    /// The hidden states after the final norm, without the output layer.
+    pub fn forward_hidden_states(
+        &self,
+        x: &Tensor,
+        start_offsets: &[usize],
+        start_offsets_kernel: Tensor,
         mut metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
     ) -> Result<Tensor> {
         let mut layer_in = self.tok_embeddings.forward(x)?;
@@ -607,10 +622,6 @@ impl ModelWeights {
             layer_in = x;
         }
         let layer_in = layer_in.to_device(&self.device)?;
-        let x = self.norm.forward(&layer_in)?;
-        extract_logits(
-            &MatMul.qmatmul(&x.contiguous()?, &self.output)?,
-            context_lens,
-        )
+        self.norm.forward(&layer_in)
     }
 }
@@ -311,6 +311,18 @@ impl ModelWeights {
         input_ids: &Tensor,
         seqlen_offsets: &[usize],
         context_lens: Vec<(usize, usize)>,
+        metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+    ) -> Result<Tensor> {
+        let xs = self.forward_hidden_states(input_ids, seqlen_offsets, metadata)?;
+        let xs = extract_logits(&xs, context_lens)?;
+        self.output.forward(&xs)
+    }
+
+    /// The hidden states after the final norm, without the output layer.
+    pub fn forward_hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
         mut metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
     ) -> Result<Tensor> {
         let mut xs = self.tok_embeddings.forward(input_ids)?;
@@ -339,8 +351,6 @@ impl ModelWeights {
             let feed_forward_hidden_states = layer.mlp.forward(&xs_norm)?;
             xs = (attn_outputs + feed_forward_hidden_states + residual)?
         }
-        let xs = xs.to_device(&self.device)?;
-        let xs = extract_logits(&xs.apply(&self.output_norm)?, context_lens)?;
-        self.output.forward(&xs)
+        xs.to_device(&self.device)?.apply(&self.output_norm)
     }
 }
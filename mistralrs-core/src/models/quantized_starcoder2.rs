@@ -311,9 +311,35 @@ impl ModelWeights {
         input_ids: &Tensor,
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
-        mut metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
     ) -> Result<Tensor> {
         let (_b_sz, seq_len) = input_ids.dims2()?;
+        let xs = self
+            .forward_hidden_states(input_ids, seqlen_offsets, start_offsets_kernel, metadata)?
+            .i((.., seq_len - 1, ..))?;
+        MatMul.qmatmul(&xs, &self.output)
+    }
+
+    /// The logits at every position, for prompt scoring.
+    pub fn forward_all_logits(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+    ) -> Result<Tensor> {
+        let xs =
+            self.forward_hidden_states(input_ids, seqlen_offsets, start_offsets_kernel, None)?;
+        MatMul.qmatmul(&xs.contiguous()?, &self.output)
+    }
+
+    /// The hidden states after the final norm, without the output layer.
+    pub fn forward_hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        mut metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+    ) -> Result<Tensor> {
         let mut xs = self.tok_embeddings.forward(input_ids)?;
         let mut cache = self.cache.lock();
         let mask = CausalMasker.make_causal_mask_as_attn_bias(
@@ -349,8 +375,6 @@ impl ModelWeights {
             let ys = layer.mlp.forward(&ys)?;
             xs = (ys + residual)?
         }
-        let xs = xs.to_device(&self.device)?;
-        let xs = xs.apply(&self.output_norm)?.i((.., seq_len - 1, ..))?;
-        MatMul.qmatmul(&xs, &self.output)
+        xs.to_device(&self.device)?.apply(&self.output_norm)
     }
 }
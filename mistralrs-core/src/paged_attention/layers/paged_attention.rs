@@ -80,14 +80,29 @@ impl PagedAttention {
         let att = match attention_mask {
             None => None,
             Some(mask) => {
+                // With prefix caching, the start of the prompt is already in the cache blocks.
+                let (key, value) =
+                    match (&input_metadata.prefix_block_ids, &key_cache, &value_cache) {
+                        (Some(prefix_block_ids), Some(key_cache), Some(value_cache)) => {
+                            let (prefix_key, prefix_value) =
+                                gather_cached_prefix(prefix_block_ids, key_cache, value_cache)?;
+                            (
+                                Tensor::cat(&[&prefix_key, key], 2)?,
+                                Tensor::cat(&[&prefix_value, value], 2)?,
+                            )
+                        }
+                        _ => (key.clone(), value.clone()),
+                    };
+                let kv_len = key.dim(2)?;
+
                 //Only perform key/value repeat in prefiling stage, this will reduce kvcache
                 //and remove redundant repeat_kv in decoding stage
                 let att = if key_value_heads != attention_heads {
                     let key_repeat = if key_value_heads == 1 {
-                        key.broadcast_as((batch_size, attention_heads, seq_len, head_size))?
+                        key.broadcast_as((batch_size, attention_heads, kv_len, head_size))?
                     } else {
                         Tensor::cat(&vec![&key; attention_heads / key_value_heads], 2)?
-                            .reshape((batch_size, attention_heads, seq_len, head_size))?
+                            .reshape((batch_size, attention_heads, kv_len, head_size))?
                     };
                     (query.matmul(&key_repeat.t()?.contiguous()?)? * self.scale as f64)?
                 } else {
@@ -98,10 +113,10 @@ impl PagedAttention {
                 let att = candle_nn::ops::softmax_last_dim(&att)?;
                 if key_value_heads != attention_heads {
                     let value_repeat = if key_value_heads == 1 {
-                        value.broadcast_as((batch_size, attention_heads, seq_len, head_size))?
+                        value.broadcast_as((batch_size, attention_heads, kv_len, head_size))?
                     } else {
                         Tensor::cat(&vec![&value; attention_heads / key_value_heads], 2)?
-                            .reshape((batch_size, attention_heads, seq_len, head_size))?
+                            .reshape((batch_size, attention_heads, kv_len, head_size))?
                     };
                     Some(att.matmul(&value_repeat.contiguous()?)?)
                 } else {
@@ -174,3 +189,41 @@ impl PagedAttention {
         )
     }
 }
+
+/// Read the keys and values of the cached prompt prefixes back out of the cache blocks.
+///
+/// prefix_block_ids: shape = [batch_size, num_prefix_blocks]
+/// Returns keys and values of shape [batch_size, num_kv_heads, num_prefix_blocks * block_size, head_size]
+fn gather_cached_prefix(
+    prefix_block_ids: &Tensor,
+    key_cache: &Tensor,
+    value_cache: &Tensor,
+) -> Result<(Tensor, Tensor)> {
+    let (batch_size, num_blocks) = prefix_block_ids.dims2()?;
+    let (_, num_kv_heads, head_size_x, block_size, x) = key_cache.dims5()?;
+    let head_size = head_size_x * x;
+    let ids = prefix_block_ids.flatten_all()?;
+
+    // [bs * num_blocks, num_kv_heads, head_size/x, block_size, x] -> [bs, num_kv_heads, tokens, head_size]
+    let key = key_cache
+        .index_select(&ids, 0)?
+        .reshape((
+            batch_size,
+            num_blocks,
+            num_kv_heads,
+            head_size_x,
+            block_size,
+            x,
+        ))?
+        .permute((0, 2, 1, 4, 3, 5))?
+        .contiguous()?
+        .reshape((batch_size, num_kv_heads, num_blocks * block_size, head_size))?;
+    // [bs * num_blocks, num_kv_heads, head_size, block_size] -> [bs, num_kv_heads, tokens, head_size]
+    let value = value_cache
+        .index_select(&ids, 0)?
+        .reshape((batch_size, num_blocks, num_kv_heads, head_size, block_size))?
+        .permute((0, 2, 1, 4, 3))?
+        .contiguous()?
+        .reshape((batch_size, num_kv_heads, num_blocks * block_size, head_size))?;
+    Ok((key, value))
+}
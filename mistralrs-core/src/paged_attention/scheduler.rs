@@ -11,6 +11,7 @@ type DstBlocksTo = Vec<usize>;
 
 use std::{
     collections::{HashMap, VecDeque},
+    num::NonZeroUsize,
     sync::{atomic::Ordering, Arc, Mutex},
 };
 
@@ -32,10 +33,18 @@ pub struct PagedAttentionSchedulerOutput {
     pub blocks_to_swap_in: HashMap<CPUBlockFrom, GPUBlockTo>,
     pub blocks_to_swap_out: HashMap<GPUBlockFrom, CPUBlockTo>,
     pub blocks_to_copy: HashMap<SrcBlockFrom, DstBlocksTo>,
+    /// The number of newly scheduled prompts which start after blocks found in the prefix cache.
+    pub prefix_cache_hits: usize,
 }
 
 pub struct PagedAttentionSchedulerConfig {
     pub max_num_seqs: usize,
+    /// Maximum number of prompt tokens to run in one step. Longer prompts are run in chunks of whole blocks
+    /// over several steps. If set, a prompt step is always followed by a completion step when there are
+    /// running sequences, so that they keep emitting tokens.
+    pub prefill_chunk_size: Option<NonZeroUsize>,
+    /// Share the cache blocks of common prompt prefixes between sequences, see [`BlockEngine::new`].
+    pub prefix_caching: bool,
 }
 
 pub struct PagedAttentionScheduler {
@@ -45,6 +54,7 @@ pub struct PagedAttentionScheduler {
     config: PagedAttentionSchedulerConfig,
     pub block_engine: BlockEngine,
     block_size: usize,
+    last_step_was_prompt: bool,
 }
 
 impl PagedAttentionScheduler {
@@ -58,16 +68,48 @@ impl PagedAttentionScheduler {
                 cache_config.block_size,
                 cache_config.num_gpu_blocks,
                 cache_config.num_cpu_blocks,
+                config.prefix_caching,
             ),
             block_size: cache_config.block_size,
+            last_step_was_prompt: false,
         }
     }
 
     pub fn schedule(&mut self) -> PagedAttentionSchedulerOutput {
+        // The last step has written the blocks it registered in the prefix cache.
+        self.block_engine.commit_prefix_cache();
+
+        // With a prefill budget, let the running sequences decode between prompt steps.
+        let decode_turn = self.config.prefill_chunk_size.is_some()
+            && self.last_step_was_prompt
+            && self
+                .running
+                .iter()
+                .any(|seq| !Self::is_prefilling(&get_mut_arcmutex!(seq)));
+
+        // Finish the chunked prefills of the running sequences before admitting new prompts.
+        if !decode_turn {
+            let scheduled = self.next_prompt_chunks();
+            if !scheduled.is_empty() {
+                self.set_prefix_cache_writes(&scheduled);
+                self.last_step_was_prompt = true;
+                return PagedAttentionSchedulerOutput {
+                    scheduled,
+                    blocks_to_swap_in: HashMap::new(),
+                    blocks_to_copy: HashMap::new(),
+                    blocks_to_swap_out: HashMap::new(),
+                    prefix_cache_hits: 0,
+                };
+            }
+        }
+
         // If there are no swapped seqs (they have higher priority), add seqs that are in the
         // waiting queue to the running queue.
-        if self.swapped_out.is_empty() {
+        if self.swapped_out.is_empty() && !decode_turn {
             let mut scheduled = VecDeque::new();
+            let mut scheduled_toks = 0;
+            let mut scheduled_cached_toks = None;
+            let mut prefix_cache_hits = 0;
             let mut did_ignore = false;
             while !self.waiting.is_empty() {
                 let seq = self.waiting.front().unwrap().clone();
@@ -77,6 +119,23 @@ impl PagedAttentionScheduler {
                     break;
                 }
 
+                // Prompts run together must start after the same number of tokens found in the prefix cache.
+                let cached_toks = self
+                    .block_engine
+                    .num_cached_prompt_toks(&*get_mut_arcmutex!(seq));
+                if scheduled_cached_toks.is_some_and(|n| n != cached_toks) {
+                    break;
+                }
+
+                // Stop once the prefill budget is used, but always admit at least one sequence.
+                // A prompt which exceeds the budget on its own is run in chunks.
+                let len = get_mut_arcmutex!(seq).get_toks().len() - cached_toks;
+                if self.config.prefill_chunk_size.is_some_and(|budget| {
+                    !scheduled.is_empty() && scheduled_toks + len > budget.get()
+                }) {
+                    break;
+                }
+
                 // If we cannot allocate either now or in the future, either do not continue or remove the sequence.
                 let can_allocate = self.block_engine.can_allocate(&*get_mut_arcmutex!(seq));
                 match can_allocate {
@@ -94,25 +153,39 @@ impl PagedAttentionScheduler {
                 }
 
                 if !did_ignore {
-                    get_mut_arcmutex!(seq).set_state(SequenceState::RunningPrompt);
-                    let seq_handle = get_mut_arcmutex!(seq);
-                    self._allocate(&seq_handle);
+                    let mut seq_handle = get_mut_arcmutex!(seq);
+                    // `can_allocate` checked there are enough free blocks, but wait rather than fail if not.
+                    let Some(cached_toks) = self._allocate(&seq_handle) else {
+                        break;
+                    };
+                    seq_handle.set_state(SequenceState::RunningPrompt);
+                    seq_handle.set_cached_prompt_toks(cached_toks);
+                    scheduled_cached_toks = Some(cached_toks);
+                    if cached_toks > 0 {
+                        prefix_cache_hits += 1;
+                    }
                 }
 
                 let seq = self.waiting.pop_front().unwrap();
                 self.running.push_back(seq.clone());
                 if !did_ignore {
+                    scheduled_toks += len;
                     scheduled.push_back(seq);
                 }
             }
 
             // If we did schedule, or we ignored sequences.
             if !scheduled.is_empty() || did_ignore {
+                let scheduled = Vec::from(scheduled);
+                self.set_prompt_chunk_lens(&scheduled);
+                self.set_prefix_cache_writes(&scheduled);
+                self.last_step_was_prompt = !scheduled.is_empty();
                 return PagedAttentionSchedulerOutput {
-                    scheduled: scheduled.into(),
+                    scheduled,
                     blocks_to_swap_in: HashMap::new(),
                     blocks_to_copy: HashMap::new(),
                     blocks_to_swap_out: HashMap::new(),
+                    prefix_cache_hits,
                 };
             }
         }
@@ -130,6 +203,14 @@ impl PagedAttentionScheduler {
         // Sorts by creation time, in descending order so that earliest are latest (first come first serve).
         self.sort_running_by_priority_fcfs();
 
+        // Sequences in the middle of a chunked prefill already hold the blocks for their whole prompt and
+        // do not take part in the completion step.
+        let (prefilling, running): (VecDeque<_>, VecDeque<_>) = self
+            .running
+            .drain(..)
+            .partition(|seq| Self::is_prefilling(&get_mut_arcmutex!(seq)));
+        self.running = running;
+
         let mut running = VecDeque::new();
         let mut did_preempt = false;
         while !self.running.is_empty() {
@@ -196,6 +277,8 @@ impl PagedAttentionScheduler {
         self.running
             .iter()
             .for_each(|seq| get_mut_arcmutex!(seq).set_state(SequenceState::RunningCompletion));
+        let scheduled = self.running.clone().into(); // Clone should be cheap.
+        self.running.extend(prefilling);
 
         if TERMINATE_ALL_NEXT_STEP.load(Ordering::SeqCst) {
             self.running.iter().for_each(|seq| {
@@ -204,11 +287,72 @@ impl PagedAttentionScheduler {
             TERMINATE_ALL_NEXT_STEP.store(false, Ordering::SeqCst);
         }
 
+        self.last_step_was_prompt = false;
         PagedAttentionSchedulerOutput {
-            scheduled: self.running.clone().into(), // Clone should be cheap.
+            scheduled,
             blocks_to_swap_in,
             blocks_to_copy,
             blocks_to_swap_out,
+            prefix_cache_hits: 0,
+        }
+    }
+
+    /// Whether the sequence has run some chunks of its prompt, but not all of them.
+    fn is_prefilling(seq: &Sequence) -> bool {
+        seq.is_prompt() && seq.prompt_chunk_offset() > 0
+    }
+
+    /// The next prompt chunks of the running sequences in the middle of a chunked prefill. Prompts run
+    /// together must continue from the same offset.
+    fn next_prompt_chunks(&self) -> Vec<Arc<Mutex<Sequence>>> {
+        let mut offset = None;
+        let scheduled = self
+            .running
+            .iter()
+            .filter(|seq| {
+                let seq = get_mut_arcmutex!(seq);
+                Self::is_prefilling(&seq)
+                    && *offset.get_or_insert(seq.prompt_chunk_offset()) == seq.prompt_chunk_offset()
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        self.set_prompt_chunk_lens(&scheduled);
+        scheduled
+    }
+
+    /// Run at most `prefill_chunk_size` prompt tokens in this prompt step. The chunks are whole blocks, so
+    /// that the next chunk starts after the cache blocks of the previous ones.
+    fn set_prompt_chunk_lens(&self, scheduled: &[Arc<Mutex<Sequence>>]) {
+        let Some(budget) = self.config.prefill_chunk_size else {
+            return;
+        };
+        let mut seqs = scheduled
+            .iter()
+            .map(|seq| get_mut_arcmutex!(seq))
+            .collect::<Vec<_>>();
+        let n_toks = seqs
+            .iter()
+            .map(|seq| seq.get_toks().len() - seq.prompt_chunk_offset())
+            .sum::<usize>();
+        let chunk_len = if n_toks > budget.get() {
+            (budget.get() / seqs.len() / self.block_size).max(1) * self.block_size
+        } else {
+            usize::MAX
+        };
+        for seq in seqs.iter_mut() {
+            seq.set_prompt_chunk_len(Some(chunk_len));
+        }
+    }
+
+    /// Tell the block engine which prompt blocks of the scheduled sequences this step writes, so that only
+    /// those become reusable from the prefix cache.
+    fn set_prefix_cache_writes(&mut self, scheduled: &[Arc<Mutex<Sequence>>]) {
+        for seq in scheduled {
+            let seq = get_mut_arcmutex!(seq);
+            let end = seq
+                .prompt_chunk()
+                .map_or(seq.get_toks().len(), |(offset, len)| offset + len);
+            self.block_engine.set_prefix_cache_writes(seq.get_id(), end);
         }
     }
 
@@ -226,10 +370,31 @@ impl PagedAttentionScheduler {
             self._free(id);
         }
     }
+
+    /// Remove all sequences of a request from the waiting, running and swapped out queues,
+    /// marking them as canceled and freeing their blocks.
+    pub fn cancel_request(&mut self, request_id: usize) {
+        let mut to_cancel_ids = Vec::new();
+        for seq in self
+            .waiting
+            .iter()
+            .chain(self.running.iter())
+            .chain(self.swapped_out.iter())
+        {
+            let seq = get_mut_arcmutex!(seq);
+            if seq.request_id() == request_id {
+                to_cancel_ids.push(seq.get_id());
+            }
+        }
+        for id in to_cancel_ids {
+            let removed = self.remove_seq(id);
+            get_mut_arcmutex!(removed).set_state(SequenceState::Done(StopReason::Canceled));
+            self._free(id);
+        }
+    }
 }
 
 impl PagedAttentionScheduler {
-    #[allow(dead_code)]
     fn remove_seq(&mut self, seq_id: usize) -> Arc<Mutex<Sequence>> {
         // Remove it if it is in waiting
         if let Some(idx) = self
@@ -312,7 +477,7 @@ impl PagedAttentionScheduler {
         self.swapped_out.push_back(seq);
     }
 
-    fn _allocate(&mut self, seq: &Sequence) {
+    fn _allocate(&mut self, seq: &Sequence) -> Option<usize> {
         self.block_engine.allocate(seq)
     }
 
@@ -347,6 +512,9 @@ impl Scheduler for PagedAttentionScheduler {
     fn waiting_len(&self) -> usize {
         self.waiting.len()
     }
+    fn running_len(&self) -> usize {
+        self.running.len()
+    }
     fn block_tables(&self) -> Option<&BlockTables> {
         Some(&self.block_engine.block_tables)
     }
@@ -356,6 +524,9 @@ impl Scheduler for PagedAttentionScheduler {
     fn free_finished_sequence_groups(&mut self) {
         self.free_finished_sequence_groups()
     }
+    fn cancel_request(&mut self, request_id: usize) {
+        self.cancel_request(request_id)
+    }
     fn block_engine(&mut self) -> Option<&mut BlockEngine> {
         Some(&mut self.block_engine)
     }
@@ -353,7 +353,25 @@ impl AnyMoePipelineMixin for AnyMoePipeline {
 
         // Create several dummy objects for the sequences.
         let (dummy_sender, _) = tokio::sync::mpsc::channel(10000);
-        let dummy_sampler = Sampler::new(None, 0, tokenizer.clone(), None, None, -1, 0.0, 0.0);
+        let dummy_sampler = Sampler::new(
+            None,
+            0,
+            tokenizer.clone(),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Vec::new(),
+            -1,
+            0.0,
+            0.0,
+            1.0,
+            0.0,
+            None,
+            false,
+        )?;
 
         let dummy_group = Arc::new(tokio::sync::Mutex::new(SequenceGroup::new(
             1, false, false, 0,
@@ -549,6 +567,7 @@ fn new_dummy_seq(
         tokens,
         0,
         0,
+        0,
         1,
         dummy_sender,
         dummy_sampler,
@@ -568,5 +587,8 @@ fn new_dummy_seq(
         None, // TODO incorrect for PagedAttention
         trie,
         None,
+        0,
+        None,
+        None,
     )
 }
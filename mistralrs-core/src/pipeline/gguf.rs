@@ -1,8 +1,8 @@
 use super::cache_manager::DefaultCacheManager;
 use super::{
-    get_model_paths, get_xlora_paths, text_models_inputs_processor::ModelInputs, AdapterKind,
-    CacheManager, GeneralMetadata, Loader, ModelKind, ModelPaths, PrettyName, QuantizationKind,
-    TokenSource, XLoraPaths,
+    forward_uncached, get_model_paths, get_xlora_paths, text_models_inputs_processor::ModelInputs,
+    AdapterKind, CacheManager, GeneralMetadata, Loader, ModelKind, ModelPaths, PrettyName,
+    QuantizationKind, TokenSource, XLoraPaths,
 };
 use super::{
     AdapterActivationMixin, AnyMoePipelineMixin, CacheManagerMixin, IsqPipelineMixin,
@@ -583,6 +583,7 @@ impl Loader for GGUFLoader {
                 kind: self.kind.clone(),
                 is_xlora,
                 activation_dtype: DType::F32,
+                isq: None,
                 sliding_window: None,
                 cache_config,
                 cache_engine,
@@ -775,6 +776,55 @@ impl Pipeline for GGUFPipeline {
             ),
         }
     }
+    fn forward_hidden_states(&self, toks: &[u32]) -> Result<Tensor, candle_core::Error> {
+        forward_uncached(
+            self,
+            "Embeddings",
+            toks,
+            |input_ids, positions_kernel| match self.model {
+                Model::Llama(ref model) => {
+                    model.forward_hidden_states(input_ids, &[0], positions_kernel, None)
+                }
+                Model::Phi2(ref model) => model.forward_hidden_states(input_ids, &[0], None),
+                Model::Phi3(ref model) => model.forward_hidden_states(input_ids, &[0], None),
+                Model::Starcoder2(ref model) => {
+                    model.forward_hidden_states(input_ids, &[0], positions_kernel, None)
+                }
+                Model::XLoraLlama(_) | Model::XLoraPhi3(_) => {
+                    unreachable!("`forward_uncached` rejects X-LoRA models.")
+                }
+            },
+        )
+    }
+    fn forward_prompt_logits(&self, toks: &[u32]) -> Result<Tensor, candle_core::Error> {
+        forward_uncached(
+            self,
+            "Prompt scoring",
+            toks,
+            |input_ids, positions_kernel| match self.model {
+                Model::Llama(ref model) => model.forward(
+                    input_ids,
+                    &[0],
+                    positions_kernel,
+                    vec![(0, toks.len())],
+                    None,
+                ),
+                Model::Phi2(ref model) => {
+                    model.forward(input_ids, &[0], vec![(0, toks.len())], None)
+                }
+                Model::Phi3(ref model) => model.forward_all_logits(input_ids, &[0]),
+                Model::Starcoder2(ref model) => {
+                    model.forward_all_logits(input_ids, &[0], positions_kernel)
+                }
+                Model::XLoraLlama(_) | Model::XLoraPhi3(_) => {
+                    unreachable!("`forward_uncached` rejects X-LoRA models.")
+                }
+            },
+        )
+    }
+    fn supports_prompt_scoring(&self) -> bool {
+        true
+    }
     async fn sample(
         &self,
         seqs: &mut [&mut Sequence],
@@ -2,6 +2,7 @@ use std::{any::Any, num::NonZeroUsize, sync::Arc};
 
 use anyhow::Result;
 use candle_core::Device;
+use image::DynamicImage;
 use text_models_inputs_processor::PagedAttentionMeta;
 use tokenizers::Tokenizer;
 
@@ -40,6 +41,18 @@ pub trait InputsProcessor {
     ) -> Box<dyn Iterator<Item = Result<InputProcessorOutput>>>;
 
     fn get_type(&self) -> InputsProcessorType;
+
+    /// The prompt tokens which the model runs for a request with `images`, given the tokens of its formatted
+    /// prompt. Models which insert image tokens when processing the inputs add them here.
+    fn prompt_tokens_with_images(
+        &self,
+        _tokenizer: &Tokenizer,
+        toks: Vec<u32>,
+        _images: Vec<DynamicImage>,
+        _other_config: Option<Arc<dyn Any>>,
+    ) -> Result<Vec<u32>> {
+        Ok(toks)
+    }
 }
 
 // ========================= Test models input processor
@@ -90,6 +103,8 @@ pub mod text_models_inputs_processor {
         pub context_lens: Option<Tensor>,
         pub slot_mappings: Tensor,
         pub max_context_len: Option<usize>,
+        /// Cache blocks holding the prompt prefixes shared through prefix caching, shape [bs, num_prefix_blocks].
+        pub prefix_block_ids: Option<Tensor>,
     }
 
     pub struct InputMetadata {
@@ -130,6 +145,7 @@ pub mod text_models_inputs_processor {
         let mut slot_mappings = Vec::new();
         let mut block_tables = Vec::new();
         let mut paged_attn_context_lens = Vec::new();
+        let mut prefix_block_ids = Vec::new();
         for (seq, mut ctxt) in input_seqs.iter().zip(toks) {
             let prompt_len = ctxt.len();
             let offset = last_n_context_len.unwrap_or_default();
@@ -158,6 +174,16 @@ pub mod text_models_inputs_processor {
                     .map(|block| block.deref_mut().block_id)
                     .collect::<Vec<_>>();
 
+                // The prompt chunk starts after the blocks shared through prefix caching.
+                if chunk_offset_toks > 0 {
+                    #[allow(clippy::cast_possible_truncation)]
+                    prefix_block_ids.extend(
+                        table[..chunk_offset_toks / paged_attn_metadata.block_size]
+                            .iter()
+                            .map(|x| *x as u32),
+                    );
+                }
+
                 let start_idx = if let Some(sliding_window) = paged_attn_metadata.sliding_window {
                     if prompt_len > sliding_window {
                         chunk_offset_toks.min(prompt_len - sliding_window)
@@ -198,7 +224,7 @@ pub mod text_models_inputs_processor {
         }
 
         let mut tmp = Vec::new();
-        if last_n_context_len.is_some() {
+        if last_n_context_len.is_some() || chunk_offset_toks > 0 {
             for pos in (0..seqs_tensors.len())
                 .map(|i| {
                     (*seqlen_offsets.get(i).unwrap() as i64
@@ -261,11 +287,24 @@ pub mod text_models_inputs_processor {
             )?
             .reshape(((),))?;
 
+            let prefix_block_ids = if prefix_block_ids.is_empty() {
+                None
+            } else {
+                let n_seqs = input_seqs.len();
+                let n_blocks = prefix_block_ids.len() / n_seqs;
+                Some(Tensor::from_vec(
+                    prefix_block_ids,
+                    (n_seqs, n_blocks),
+                    device,
+                )?)
+            };
+
             Some(PagedAttentionInputMetadata {
                 slot_mappings,
                 block_tables: Some(block_tables),
                 context_lens: Some(context_lens),
                 max_context_len: Some(max_context_len),
+                prefix_block_ids,
             })
         } else {
             None
@@ -392,6 +431,7 @@ pub mod text_models_inputs_processor {
                 block_tables: Some(block_tables),
                 context_lens: Some(context_lens),
                 max_context_len: Some(*max_context_len),
+                prefix_block_ids: None,
             })
         } else {
             None
@@ -415,6 +455,34 @@ pub mod text_models_inputs_processor {
         mut paged_attn_metadata: Option<&mut PagedAttentionMeta<'_>>,
         prompt_batchsize: Option<NonZeroUsize>,
     ) -> Box<dyn Iterator<Item = Result<InnerInputProcessorOutput>>> {
+        // Chunked prefill and PagedAttention prefix caching: the engine runs sequences whose prompt
+        // chunks start at the same offset together.
+        if let Some((offset, _)) = input_seqs.first().and_then(|seq| seq.prompt_chunk()) {
+            let toks = toks
+                .into_iter()
+                .zip(input_seqs.iter())
+                .map(|(ctxt, seq)| {
+                    let len = seq
+                        .prompt_chunk()
+                        .map_or(ctxt.len() - offset, |(_, len)| len);
+                    ctxt[offset..offset + len].to_vec()
+                })
+                .collect::<Vec<_>>();
+            return Box::new(std::iter::once(
+                make_prompt_chunk(
+                    offset,
+                    toks,
+                    &input_seqs.iter().map(|s| &**s).collect::<Vec<_>>(),
+                    device,
+                    last_n_context_len,
+                    paged_attn_metadata,
+                )
+                .map(|inputs| InnerInputProcessorOutput {
+                    inputs,
+                    seq_indices: (0..input_seqs.len()).collect(),
+                }),
+            ));
+        }
         if let (Some(prompt_batchsize), true) = (prompt_batchsize, paged_attn_metadata.is_none()) {
             let mut seq_chunks = Vec::new();
             let mut n_chunks = Vec::new();
@@ -63,6 +63,7 @@ use crate::{
     xlora_models::{NonGranularState, XLoraConfig},
 };
 
+use self::cache_manager::DefaultCacheManager;
 pub use self::cache_manager::{Cache, CacheManager, LayerCaches};
 pub use self::inputs_processor::{
     text_models_inputs_processor, InputsProcessor, InputsProcessorType,
@@ -443,6 +444,8 @@ pub struct GeneralMetadata {
     // TODO: Replace is_xlora queries to check via kind instead:
     pub is_xlora: bool,
     pub activation_dtype: DType,
+    /// In-situ quantization applied when the model was loaded.
+    pub isq: Option<GgmlDType>,
     pub sliding_window: Option<usize>,
     // PagedAttention stuff
     pub cache_config: Option<CacheConfig>,
@@ -590,6 +593,24 @@ pub trait Pipeline:
 {
     fn forward_inputs(&self, inputs: Box<dyn Any>) -> Result<Tensor, candle_core::Error>;
 
+    /// Compute the final hidden states for a single tokenized input, without the LM head.
+    /// The returned tensor has shape `(seq_len, hidden_size)`. The KV cache is left untouched.
+    fn forward_hidden_states(&self, _toks: &[u32]) -> Result<Tensor, candle_core::Error> {
+        candle_core::bail!("Embeddings are not supported for this pipeline.");
+    }
+
+    /// Compute the logits at every position of a single tokenized input, of shape `(seq_len, vocab_size)`.
+    /// Used for prompt scoring. The KV cache is left untouched.
+    fn forward_prompt_logits(&self, _toks: &[u32]) -> Result<Tensor, candle_core::Error> {
+        candle_core::bail!("Prompt scoring is not supported for this pipeline.");
+    }
+
+    /// Whether this pipeline implements `forward_prompt_logits`. It is still not supported for X-LoRA models
+    /// or with PagedAttention.
+    fn supports_prompt_scoring(&self) -> bool {
+        false
+    }
+
     #[allow(clippy::too_many_arguments)]
     async fn step(
         &mut self,
@@ -700,6 +721,11 @@ pub trait Pipeline:
                     _ => unreachable!("Unreachable POST cache op."),
                 }
 
+                // The rest of the prompt will be run in later steps, so there is nothing to sample yet.
+                if input_seqs.iter().any(|seq| seq.is_partial_prompt_chunk()) {
+                    return Ok(());
+                }
+
                 self.sample(input_seqs, logits, prefix_cacher, disable_eos_stop, rng)
                     .await?;
                 Ok(())
@@ -752,8 +778,18 @@ pub trait Pipeline:
                     })
                     .collect::<candle_core::Result<Vec<_>>>()?;
 
-                self.sample(input_seqs, logits, prefix_cacher, disable_eos_stop, rng)
-                    .await?;
+                // The scheduler runs the rest of a chunked prompt in later steps, so there is nothing to
+                // sample yet for those sequences.
+                let (mut seqs, logits): (Vec<_>, Vec<_>) = input_seqs
+                    .iter_mut()
+                    .zip(logits)
+                    .filter(|(seq, _)| !seq.is_partial_prompt_chunk())
+                    .map(|(seq, logits)| (&mut **seq, logits))
+                    .unzip();
+                if !seqs.is_empty() {
+                    self.sample(&mut seqs, logits, prefix_cacher, disable_eos_stop, rng)
+                        .await?;
+                }
                 Ok(())
             }
         }
@@ -795,6 +831,18 @@ pub trait NormalModel: IsqModel + AnyMoeBaseModelMixin {
         context_lens: Vec<(usize, usize)>,
         position_ids: Vec<usize>,
     ) -> candle_core::Result<Tensor>;
+    /// Run the model without the LM head, returning the normed hidden states of shape
+    /// `(bs, seq_len, hidden_size)`. Used for embeddings.
+    fn forward_hidden_states(
+        &self,
+        _input_ids: &Tensor,
+        _seqlen_offsets: &[usize],
+        _start_offsets_kernel: Tensor,
+        _position_ids: Vec<usize>,
+        _metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+    ) -> candle_core::Result<Tensor> {
+        candle_core::bail!("Embeddings are not supported for this model.");
+    }
     fn is_xlora(&self) -> bool;
     fn device(&self) -> &Device;
     fn cache(&self) -> &Cache;
@@ -840,6 +888,33 @@ pub(crate) fn extract_logits(
     Tensor::cat(&toks, 0)
 }
 
+/// Run a single input through `forward`, outside of the scheduler, for embeddings and prompt scoring. This
+/// starts from an empty cache and restores the running sequences' cache afterwards. The batch dimension is
+/// removed from the output. `what` names the operation in the errors for the unsupported X-LoRA models
+/// and PagedAttention.
+pub(crate) fn forward_uncached<P: CacheManagerMixin + MetadataMixin + ?Sized>(
+    pipeline: &P,
+    what: &str,
+    toks: &[u32],
+    forward: impl FnOnce(&Tensor, Tensor) -> candle_core::Result<Tensor>,
+) -> candle_core::Result<Tensor> {
+    if pipeline.get_metadata().is_xlora {
+        candle_core::bail!("{what} is not supported for X-LoRA models.");
+    }
+    if pipeline.get_metadata().cache_engine.is_some() {
+        candle_core::bail!("{what} is not supported with PagedAttention.");
+    }
+    let device = pipeline.device();
+    let input_ids = Tensor::new(toks, &device)?.unsqueeze(0)?;
+    let positions_kernel = Tensor::arange(0, toks.len() as i64, &device)?.unsqueeze(0)?;
+
+    let saved_cache = pipeline.cache().lock().clone();
+    DefaultCacheManager.set_none_cache(pipeline, false);
+    let output = forward(&input_ids, positions_kernel);
+    *pipeline.cache().lock() = saved_cache;
+    output?.squeeze(0)
+}
+
 #[cfg(test)]
 mod tests {
     use crate::MessageContent;
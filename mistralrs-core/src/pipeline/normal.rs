@@ -4,9 +4,9 @@ use super::normal_loaders::{
     Phi2Loader, Phi3Loader, Qwen2Loader, Starcoder2Loader,
 };
 use super::{
-    get_model_paths, get_xlora_paths, text_models_inputs_processor::ModelInputs, AdapterKind,
-    CacheManager, GeneralMetadata, Loader, ModelKind, ModelPaths, NormalModel, NormalModelLoader,
-    TokenSource, XLoraPaths,
+    forward_uncached, get_model_paths, get_xlora_paths, text_models_inputs_processor::ModelInputs,
+    AdapterKind, CacheManager, GeneralMetadata, Loader, ModelKind, ModelPaths, NormalModel,
+    NormalModelLoader, TokenSource, XLoraPaths,
 };
 use super::{
     AdapterActivationMixin, AnyMoePipelineMixin, CacheManagerMixin, IsqPipelineMixin,
@@ -366,6 +366,7 @@ impl Loader for NormalLoader {
                 kind: self.kind.clone(),
                 is_xlora,
                 activation_dtype: dtype,
+                isq: in_situ_quant,
                 sliding_window,
                 cache_config,
                 cache_engine,
@@ -493,6 +494,37 @@ impl Pipeline for NormalPipeline {
             ),
         }
     }
+    fn forward_hidden_states(&self, toks: &[u32]) -> Result<Tensor, candle_core::Error> {
+        forward_uncached(self, "Embeddings", toks, |input_ids, positions_kernel| {
+            self.model.forward_hidden_states(
+                input_ids,
+                &[0],
+                positions_kernel,
+                vec![toks.len()],
+                None,
+            )
+        })
+    }
+    fn forward_prompt_logits(&self, toks: &[u32]) -> Result<Tensor, candle_core::Error> {
+        forward_uncached(
+            self,
+            "Prompt scoring",
+            toks,
+            |input_ids, positions_kernel| {
+                self.model.forward(
+                    input_ids,
+                    &[0],
+                    positions_kernel,
+                    vec![(0, toks.len())],
+                    vec![toks.len()],
+                    None,
+                )
+            },
+        )
+    }
+    fn supports_prompt_scoring(&self) -> bool {
+        true
+    }
     async fn sample(
         &self,
         seqs: &mut [&mut Sequence],
@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
 use candle_core::{DType, Device, Result, Tensor};
 use rand_isaac::Isaac64Rng;
@@ -7,7 +7,7 @@ use crate::{
     get_bias_if_not_allowed,
     prefix_cacher::PrefixCacheManager,
     sampler::Logprobs,
-    sequence::{Sequence, SequenceRecognizer},
+    sequence::{BeamHypothesis, Sequence, SequenceRecognizer, SequenceState, StopReason},
 };
 
 use super::Pipeline;
@@ -34,12 +34,46 @@ pub(crate) async fn finish_or_add_toks_to_seq(
         let rate_limit_allowed = is_done.is_some() || token_index % STREAMING_RATE_LIMIT == 0;
 
         if rate_limit_allowed {
-            if let Some(delta) = crate::handle_seq_error_ok!(seq.get_delta(), seq.responder()) {
+            let tool_call_deltas = if seq.get_mut_group().is_chat {
+                seq.get_tool_call_deltas(is_done)
+            } else {
+                None
+            };
+            let chunk_added = if let Some(tool_calls) = tool_call_deltas {
+                // Output which may still become a tool call is held back until the call's name is complete
+                if !tool_calls.is_empty() || is_done.is_some() {
+                    seq.add_streaming_chunk_choice_to_group(crate::ChunkChoice {
+                        delta: crate::Delta {
+                            content: String::new(),
+                            role: "assistant".to_string(),
+                            tool_calls: (!tool_calls.is_empty()).then_some(tool_calls),
+                        },
+                        index: seq.get_response_index(),
+                        finish_reason: is_done.map(|_| "tool_calls".to_string()),
+                        logprobs: if seq.return_logprobs() {
+                            Some(crate::ResponseLogprob {
+                                token: logprobs.bytes.clone(),
+                                bytes: logprobs.bytes.clone().into_bytes(),
+                                logprob: logprobs.logprob,
+                                top_logprobs: logprobs.top_logprobs.clone().unwrap(),
+                            })
+                        } else {
+                            None
+                        },
+                    });
+                    true
+                } else {
+                    false
+                }
+            } else if let Some(delta) =
+                crate::handle_seq_error_ok!(seq.get_delta(), seq.responder())
+            {
                 if seq.get_mut_group().is_chat {
                     seq.add_streaming_chunk_choice_to_group(crate::ChunkChoice {
                         delta: crate::Delta {
                             content: delta.clone(),
                             role: "assistant".to_string(),
+                            tool_calls: None,
                         },
                         index: seq.get_response_index(),
                         finish_reason: is_done.map(|x| x.to_string()),
@@ -55,31 +89,38 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                         },
                     });
                 } else {
+                    let completion_logprobs = if seq.return_logprobs() {
+                        let (delta_logprobs, text_offset) = seq.get_delta_logprobs(&delta);
+                        Some(crate::CompletionLogprobs::new(
+                            delta_logprobs,
+                            text_offset,
+                            text_offset == 0,
+                        ))
+                    } else {
+                        None
+                    };
                     seq.add_streaming_completion_chunk_choice_to_group(
                         crate::CompletionChunkChoice {
-                            text: delta.clone(),
+                            text: delta,
                             index: seq.get_response_index(),
                             finish_reason: is_done.map(|x| x.to_string()),
-                            logprobs: if seq.return_logprobs() {
-                                Some(crate::ResponseLogprob {
-                                    token: delta,
-                                    bytes: logprobs.bytes.clone().into_bytes(),
-                                    logprob: logprobs.logprob,
-                                    top_logprobs: logprobs.top_logprobs.unwrap().clone(),
-                                })
-                            } else {
-                                None
-                            },
+                            logprobs: completion_logprobs,
                         },
                     );
                 }
+                true
+            } else {
+                false
+            };
 
+            if chunk_added {
                 if let Some(reason) = is_done {
                     if use_prefix_cacher {
                         prefix_cacher.add_sequence(seq);
                         prefix_cacher.evict_to_cpu()?;
                     }
                     seq.set_state(crate::sequence::SequenceState::Done(reason));
+                    seq.add_streaming_usage_to_group();
                     this.reset_non_granular_state();
                 }
 
@@ -161,8 +202,13 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                     }
                     tool_calls = calls;
                 }
+                let finish_reason = if tool_calls.is_empty() {
+                    reason.to_string()
+                } else {
+                    "tool_calls".to_string()
+                };
                 let choice = crate::Choice {
-                    finish_reason: reason.to_string(),
+                    finish_reason,
                     index: seq.get_response_index(),
                     message: crate::ResponseMessage {
                         content: text_new,
@@ -170,14 +216,25 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                         tool_calls,
                     },
                     logprobs: logprobs.map(|l| crate::Logprobs { content: Some(l) }),
+                    beam_score: None,
                 };
                 seq.add_choice_to_group(choice);
             } else {
+                let completion_logprobs = if seq.return_logprobs() {
+                    Some(crate::CompletionLogprobs::new(
+                        seq.logprobs(),
+                        seq.prefix_len(),
+                        true,
+                    ))
+                } else {
+                    None
+                };
                 let choice = crate::CompletionChoice {
                     finish_reason: reason.to_string(),
                     index: seq.get_response_index(),
                     text,
-                    logprobs: None,
+                    logprobs: completion_logprobs,
+                    beam_score: None,
                 };
                 seq.add_completion_choice_to_group(choice);
             }
@@ -239,9 +296,32 @@ pub async fn sample_and_add_toks(
     let seqs_len = seqs.len();
     debug_assert_eq!(logits_seq.len(), seqs_len);
 
-    let use_async_pool = seqs_len > 1;
+    let metadata = this.get_metadata();
+    let eos_tok = if disable_eos_stop {
+        None
+    } else {
+        Some(&metadata.eos_tok[..])
+    };
 
-    let sampling_futures: Vec<_> = std::iter::zip(logits_seq, seqs.iter_mut())
+    // Beams are advanced together, per request, instead of being sampled independently.
+    let mut beam_groups: HashMap<usize, Vec<(Tensor, &mut Sequence)>> = HashMap::new();
+    let mut sampled_seqs = Vec::new();
+    let mut sampled_logits = Vec::new();
+    for (logits_per_seq, seq) in std::iter::zip(logits_seq, seqs.iter_mut()) {
+        if seq.beam_width().is_some() {
+            beam_groups
+                .entry(seq.request_id())
+                .or_default()
+                .push((logits_per_seq, &mut **seq));
+        } else {
+            sampled_logits.push(logits_per_seq);
+            sampled_seqs.push(&mut **seq);
+        }
+    }
+
+    let use_async_pool = sampled_seqs.len() > 1;
+
+    let sampling_futures: Vec<_> = std::iter::zip(sampled_logits, sampled_seqs.iter_mut())
         .map(|(logits_per_seq, seq)| {
             let return_logprobs = seq.return_logprobs();
             sample_sequence(
@@ -257,18 +337,241 @@ pub async fn sample_and_add_toks(
         .collect();
     let sampled_vec = futures::future::join_all(sampling_futures).await;
 
-    for (sampled, seq) in std::iter::zip(sampled_vec, seqs.iter_mut()) {
+    for (sampled, seq) in std::iter::zip(sampled_vec, sampled_seqs.iter_mut()) {
         let next_token = crate::handle_seq_error_stateaware_ok!(sampled, seq);
 
-        let metadata = this.get_metadata();
-        let eos_tok = if disable_eos_stop {
-            None
+        finish_or_add_toks_to_seq(this, prefix_cacher, seq, next_token, eos_tok, true).await?;
+    }
+
+    for beams in beam_groups.into_values() {
+        beam_search_step(this, beams, eos_tok).await?;
+    }
+
+    Ok(())
+}
+
+/// Advance all beams of a beam search group by one token.
+///
+/// The best `width` continuations over all beams are kept, each beam being replaced by a fork of
+/// its parent's tokens and KV cache. Continuations which stop become finished hypotheses, and once
+/// `width` of them are finished the best `n_choices` are sent as the response.
+async fn beam_search_step(
+    this: &dyn Pipeline,
+    mut beams: Vec<(Tensor, &mut Sequence)>,
+    eos_tok: Option<&[u32]>,
+) -> Result<()> {
+    beams.sort_by_key(|(_, seq)| seq.get_response_index());
+    let (width, n_choices) = {
+        let group = beams[0].1.get_mut_group();
+        let beam_search = group
+            .beam_search
+            .as_ref()
+            .expect("Beam search group has no beam search state.");
+        (beam_search.width, group.n_choices())
+    };
+    // The engine defers beams whose group was not scheduled in full.
+    debug_assert_eq!(beams.len(), width);
+
+    // Before the first generated token all beams are identical, so only expand the first one.
+    let is_first_step = beams[0].1.logprobs().is_empty();
+    let mut candidates = Vec::new();
+    for (parent, (logits, seq)) in beams.iter_mut().enumerate() {
+        if is_first_step && parent > 0 {
+            break;
+        }
+        let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
+        let ctxt = seq.get_toks().to_vec();
+        let cumulative_logprob = seq.cumulative_logprob();
+        // Taking 2 * width candidates per beam ensures width of them survive even if the rest stop.
+        for logprobs in seq.sampler().beam_search_candidates(
+            logits,
+            Some(&ctxt),
+            seq.prompt_tokens(),
+            2 * width,
+            seq.return_logprobs(),
+        )? {
+            candidates.push((cumulative_logprob + logprobs.0, parent, logprobs.1));
+        }
+    }
+    // Sort by descending cumulative logprob
+    candidates.sort_by(|a, b| b.0.partial_cmp(&a.0).expect("No ordering."));
+
+    let parents = beams
+        .iter()
+        .map(|(_, seq)| seq.beam_state())
+        .collect::<Vec<_>>();
+    let max_seq_len = this.get_metadata().max_seq_len;
+    let mut n_live = 0;
+    for (cumulative_logprob, parent, logprobs) in candidates {
+        if n_live == width {
+            break;
+        }
+        let seq = &mut *beams[n_live].1;
+        seq.restore_beam_state(parents[parent].clone());
+        let is_done = seq.is_done(logprobs.token, eos_tok, max_seq_len);
+        seq.add_token(
+            logprobs.clone(),
+            this.get_metadata().tok_trie.decode(&[logprobs.token]),
+            &is_done,
+        );
+        // Beams are scored by the logprobs they were ranked with, which may differ from the reported ones.
+        seq.set_cumulative_logprob(cumulative_logprob);
+
+        match is_done {
+            Some(reason) => {
+                let completion_bytes = match reason {
+                    StopReason::StopString {
+                        completion_bytes_pos,
+                        ..
+                    } => seq.completion_bytes()[..completion_bytes_pos].to_vec(),
+                    _ => seq.completion_bytes().to_vec(),
+                };
+                let mut group = seq.get_mut_group();
+                let beam_search = group
+                    .beam_search
+                    .as_mut()
+                    .expect("Beam search group has no beam search state.");
+                let score = beam_search.score(seq.cumulative_logprob(), seq.logprobs().len());
+                beam_search.finished.push(BeamHypothesis {
+                    score,
+                    logprobs: seq.logprobs().to_vec(),
+                    completion_bytes,
+                    stop_reason: reason,
+                });
+            }
+            // This slot now holds a live beam, the next candidate goes into the next slot.
+            None => n_live += 1,
+        }
+    }
+
+    let hypotheses = {
+        let group = beams[0].1.get_mut_group();
+        let beam_search = group
+            .beam_search
+            .as_ref()
+            .expect("Beam search group has no beam search state.");
+        if beam_search.finished.len() < width && n_live == width {
+            return Ok(());
+        }
+        beam_search.best_hypotheses(n_choices)
+    };
+
+    /*
+    ***********************
+    Finish the group now
+    ***********************
+    */
+    let (tokenizer, pipeline_name) = (this.tokenizer(), this.name());
+    for (_, seq) in &beams {
+        seq.set_state(SequenceState::Done(hypotheses[0].stop_reason));
+    }
+    let seq = &*beams[0].1;
+
+    let mut choices = Vec::new();
+    let mut completion_choices = Vec::new();
+    for (index, hypothesis) in hypotheses.into_iter().enumerate() {
+        let logprobs = if seq.return_logprobs() {
+            let mut logprobs = Vec::new();
+            for logprob in &hypothesis.logprobs {
+                logprobs.push(crate::ResponseLogprob {
+                    token: crate::handle_seq_error_ok!(
+                        tokenizer.decode(&[logprob.token], false),
+                        seq.responder()
+                    ),
+                    bytes: logprob.bytes.clone().into_bytes(),
+                    logprob: logprob.logprob,
+                    top_logprobs: logprob.top_logprobs.clone().unwrap(),
+                });
+            }
+            Some(logprobs)
         } else {
-            Some(&metadata.eos_tok[..])
+            None
         };
+        let text = String::from_utf8_lossy(&hypothesis.completion_bytes)
+            .trim_start()
+            .to_string();
 
-        finish_or_add_toks_to_seq(this, prefix_cacher, seq, next_token, eos_tok, true).await?;
+        if seq.get_mut_group().is_chat {
+            let mut tool_calls = Vec::new();
+            let mut text_new = Some(text.clone());
+            if let Some(ref matcher) = seq.tools {
+                let calls = matcher
+                    .get_call(&text)
+                    .map_err(|e| candle_core::Error::Msg(e.to_string()))?;
+                if !calls.is_empty() {
+                    text_new = None;
+                }
+                tool_calls = calls;
+            }
+            let finish_reason = if tool_calls.is_empty() {
+                hypothesis.stop_reason.to_string()
+            } else {
+                "tool_calls".to_string()
+            };
+            choices.push(crate::Choice {
+                finish_reason,
+                index,
+                message: crate::ResponseMessage {
+                    content: text_new,
+                    role: "assistant".to_string(),
+                    tool_calls,
+                },
+                logprobs: logprobs.map(|l| crate::Logprobs { content: Some(l) }),
+                beam_score: Some(hypothesis.score),
+            });
+        } else {
+            completion_choices.push((
+                hypothesis.score,
+                crate::CompletionChoice {
+                    finish_reason: hypothesis.stop_reason.to_string(),
+                    index,
+                    text,
+                    logprobs: seq.return_logprobs().then(|| {
+                        crate::CompletionLogprobs::new(&hypothesis.logprobs, seq.prefix_len(), true)
+                    }),
+                    beam_score: Some(hypothesis.score),
+                },
+            ));
+        }
+    }
+    seq.add_beam_choices_to_group(choices);
+    seq.add_beam_completion_choices_to_group(completion_choices);
+
+    let group = seq.get_mut_group();
+    if group.is_chat {
+        group
+            .maybe_send_chat_done_response(
+                crate::ChatCompletionResponse {
+                    id: seq.id().to_string(),
+                    choices: group.get_choices().to_vec(),
+                    created: seq.creation_time(),
+                    model: pipeline_name,
+                    system_fingerprint: crate::SYSTEM_FINGERPRINT.to_string(),
+                    object: "chat.completion".to_string(),
+                    usage: group.get_usage(),
+                },
+                seq.responder(),
+            )
+            .await
+            .map_err(candle_core::Error::msg)?;
+    } else {
+        group
+            .maybe_send_completion_done_response(
+                crate::CompletionResponse {
+                    id: seq.id().to_string(),
+                    choices: group.get_completion_choices().to_vec(),
+                    created: seq.creation_time(),
+                    model: pipeline_name,
+                    system_fingerprint: crate::SYSTEM_FINGERPRINT.to_string(),
+                    object: "text_completion".to_string(),
+                    usage: group.get_usage(),
+                },
+                seq.responder(),
+            )
+            .await
+            .map_err(candle_core::Error::msg)?;
     }
+    this.reset_non_granular_state();
 
     Ok(())
 }
@@ -287,7 +590,16 @@ pub async fn sample_sequence(
     let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
 
     let sampler = seq.sampler();
-    let ctx_clone = seq.get_toks()[seq.prompt_tokens()..].to_vec();
+    let ctx_clone = seq.get_toks().to_vec();
+    let prompt_len = seq.prompt_tokens();
+    let mirostat_mu = seq.mirostat_mu();
+    // The Mirostat state is restored if the first sample is rejected by the grammar.
+    let mirostat_mu_before = mirostat_mu
+        .as_ref()
+        .map(|mu| *mu.lock().expect("could not lock mirostat mutex"));
+    let mirostat_mu_clone = mirostat_mu.clone();
+    // Seeded requests sample from their own stream, independent of the rest of the batch.
+    let rng = seq.rng().unwrap_or(rng);
     let rng_clone = rng.clone();
     let logits_clone = logits.clone();
     let first_lobprobs_response = if use_async_pool {
@@ -295,8 +607,10 @@ pub async fn sample_sequence(
             sampler.sample(
                 logits_clone,
                 Some(&ctx_clone),
+                prompt_len,
                 return_logprobs,
                 rng_clone,
+                mirostat_mu_clone,
                 sample_speculative,
             )
         })
@@ -305,8 +619,10 @@ pub async fn sample_sequence(
         sampler.sample(
             logits_clone,
             Some(&ctx_clone),
+            prompt_len,
             return_logprobs,
             rng_clone,
+            mirostat_mu_clone,
             sample_speculative,
         )?
     };
@@ -324,18 +640,24 @@ pub async fn sample_sequence(
         Some(token_set) => {
             let mut acc = vec![-f32::INFINITY; seq.tok_trie.vocab_size()];
             token_set.apply_to(&mut acc);
-            let new_logits = (logits + Tensor::from_slice(&acc, acc.len(), &Device::Cpu)?)?;
+            let new_logits = (&logits + Tensor::from_slice(&acc, acc.len(), &Device::Cpu)?)?;
 
-            let ctx_clone = seq.get_toks()[seq.prompt_tokens()..].to_vec();
+            if let (Some(mu), Some(before)) = (&mirostat_mu, mirostat_mu_before) {
+                *mu.lock().expect("could not lock mirostat mutex") = before;
+            }
+            let ctx_clone = seq.get_toks().to_vec();
             let rng_clone = rng.clone();
             let sampler = seq.sampler();
-            if use_async_pool {
+            let processed_logprobs = sampler.processed_logprobs();
+            let constrained = if use_async_pool {
                 tokio_rayon::spawn(move || {
                     sampler.sample(
                         new_logits,
                         Some(&ctx_clone),
+                        prompt_len,
                         return_logprobs,
                         rng_clone,
+                        mirostat_mu,
                         sample_speculative,
                     )
                 })
@@ -344,10 +666,19 @@ pub async fn sample_sequence(
                 sampler.sample(
                     new_logits,
                     Some(&ctx_clone),
+                    prompt_len,
                     return_logprobs,
                     rng_clone,
+                    mirostat_mu,
                     sample_speculative,
                 )?
+            };
+            if processed_logprobs {
+                constrained
+            } else {
+                // Report the logprobs of the unconstrained logits.
+                seq.sampler()
+                    .raw_logprobs(&logits, constrained.token, return_logprobs)?
             }
         }
         None => first_lobprobs_response,
@@ -281,6 +281,7 @@ impl Loader for VisionLoader {
                 kind: self.kind.clone(),
                 has_no_kv_cache: false,
                 activation_dtype: dtype,
+                isq: in_situ_quant,
                 sliding_window,
                 cache_config,
                 cache_engine,
@@ -1,9 +1,23 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
 
-use candle_core::{Device, Result, Tensor};
+use candle_core::{quantized::GgmlDType, safetensors, Device, Result, Tensor};
+use indexmap::IndexMap;
 use radix_trie::{Trie, TrieCommon, TrieKey};
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
 
-use crate::{get_mut_arcmutex, pipeline::LayerCaches, sequence::Sequence};
+use crate::{
+    get_mut_arcmutex,
+    pipeline::{GeneralMetadata, LayerCaches},
+    sequence::Sequence,
+};
 
 #[derive(PartialEq, Eq)]
 struct Tokens(Vec<u32>);
@@ -23,7 +37,239 @@ impl From<Vec<u32>> for Tokens {
     }
 }
 
-type EvictionCacheGroup = (Arc<Mutex<LayerCaches>>, Option<Arc<Mutex<LayerCaches>>>);
+/// Byte budgets of the prefix cache tiers. Caches start on the device and are moved to host RAM and then to disk
+/// as each tier fills, least recently used first.
+#[derive(Clone, Debug, Default)]
+pub struct PrefixCacheTiers {
+    /// Maximum bytes of caches to keep on the device, in addition to the limit on the number of caches.
+    pub device_bytes: Option<usize>,
+    /// Maximum bytes of caches to keep in host RAM. Unlimited if `None`.
+    pub host_bytes: Option<usize>,
+    /// Directory of the disk tier, with one safetensors file per cache keyed by the hash of the model and the
+    /// tokens. Caches are written in the background when they are moved to host RAM, so that they survive
+    /// restarts. Without it, caches evicted from host RAM are dropped.
+    pub disk_dir: Option<PathBuf>,
+    /// Maximum bytes of cache files to keep on disk. Unlimited if `None`.
+    pub disk_bytes: Option<usize>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Tier {
+    Device,
+    Host,
+}
+
+struct CacheEntry {
+    toks: Vec<u32>,
+    normal: Arc<Mutex<LayerCaches>>,
+    xlora: Option<Arc<Mutex<LayerCaches>>>,
+    bytes: usize,
+    tier: Tier,
+    /// Whether the cache was loaded from or queued for writing to the disk tier.
+    on_disk: bool,
+}
+
+/// Key of the cache file of these tokens: the SHA-256 hash, which is stable across builds, of `model_key` and
+/// the tokens.
+fn disk_key(model_key: &str, toks: &[u32]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update((model_key.len() as u64).to_le_bytes());
+    hasher.update(model_key.as_bytes());
+    for tok in toks {
+        hasher.update(tok.to_le_bytes());
+    }
+    hasher
+        .finalize()
+        .iter()
+        .fold(String::new(), |mut key, byte| {
+            write!(key, "{byte:02x}").unwrap();
+            key
+        })
+}
+
+/// A cache to write to the disk tier, with its tensors in host RAM.
+struct DiskWrite {
+    key: String,
+    toks: Vec<u32>,
+    normal: LayerCaches,
+    xlora: Option<LayerCaches>,
+}
+
+/// Size in bytes of each cache file by key, least recently used first.
+type DiskFiles = Arc<Mutex<IndexMap<String, u64>>>;
+
+struct DiskTier {
+    dir: PathBuf,
+    files: DiskFiles,
+    /// Caches are written by a background thread, so that the engine never waits on the disk.
+    writer: mpsc::Sender<DiskWrite>,
+}
+
+impl DiskTier {
+    fn new(dir: PathBuf, max_bytes: Option<usize>) -> std::io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let key = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".safetensors"))
+                .filter(|key| key.len() == 64 && key.bytes().all(|b| b.is_ascii_hexdigit()));
+            if let Some(key) = key {
+                let metadata = entry.metadata()?;
+                files.push((metadata.modified()?, key.to_string(), metadata.len()));
+            }
+        }
+        files.sort_by_key(|(modified, _, _)| *modified);
+        let files: DiskFiles = Arc::new(Mutex::new(
+            files
+                .into_iter()
+                .map(|(_, key, bytes)| (key, bytes))
+                .collect(),
+        ));
+
+        let (writer, rx) = mpsc::channel();
+        let writer_dir = dir.clone();
+        let writer_files = files.clone();
+        // The thread stops once the prefix cache manager, holding the sender, is dropped.
+        thread::Builder::new()
+            .name("prefix-cache-writer".to_string())
+            .spawn(move || {
+                for write in rx {
+                    if let Err(e) = Self::write(&writer_dir, max_bytes, &writer_files, write) {
+                        warn!("Failed to write prefix cache to disk: {e}");
+                    }
+                }
+            })?;
+        Ok(Self { dir, files, writer })
+    }
+
+    fn path(dir: &Path, key: &str) -> PathBuf {
+        dir.join(format!("{key}.safetensors"))
+    }
+
+    /// Queue a cache for writing. Its tensors must be in host RAM, and only complete caches are written.
+    fn save(&self, key: String, toks: Vec<u32>, normal: LayerCaches, xlora: Option<LayerCaches>) {
+        let complete = |cache: &LayerCaches| cache.iter().all(Option::is_some);
+        if !complete(&normal) || !xlora.as_ref().map_or(true, complete) {
+            return;
+        }
+        // The send only fails if the writer thread has stopped, which it then has already warned about.
+        let _ = self.writer.send(DiskWrite {
+            key,
+            toks,
+            normal,
+            xlora,
+        });
+    }
+
+    fn write(
+        dir: &Path,
+        max_bytes: Option<usize>,
+        files: &DiskFiles,
+        write: DiskWrite,
+    ) -> Result<()> {
+        let DiskWrite {
+            key,
+            toks,
+            normal,
+            xlora,
+        } = write;
+        let mut tensors = HashMap::new();
+        tensors.insert(
+            "tokens".to_string(),
+            Tensor::new(toks.as_slice(), &Device::Cpu)?,
+        );
+        for (name, cache) in [("layer", Some(normal)), ("xlora", xlora)] {
+            for (i, (k, v)) in cache.into_iter().flatten().flatten().enumerate() {
+                tensors.insert(format!("{name}.{i}.k"), k);
+                tensors.insert(format!("{name}.{i}.v"), v);
+            }
+        }
+        let path = Self::path(dir, &key);
+        safetensors::save(&tensors, &path)?;
+        let bytes = fs::metadata(&path)?.len();
+
+        let mut files = files.lock().expect("Prefix cache file index was poisoned");
+        // Mark as recently used.
+        files.shift_remove(&key);
+        files.insert(key, bytes);
+        if let Some(max_bytes) = max_bytes {
+            while files.values().sum::<u64>() > max_bytes as u64 {
+                let Some((key, _)) = files.shift_remove_index(0) else {
+                    break;
+                };
+                if let Err(e) = fs::remove_file(Self::path(dir, &key)) {
+                    warn!("Failed to remove prefix cache file: {e}");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Load the caches saved for these tokens, if any.
+    fn load(
+        &self,
+        key: &str,
+        toks: &[u32],
+        device: &Device,
+    ) -> Result<Option<(LayerCaches, Option<LayerCaches>)>> {
+        if !self
+            .files
+            .lock()
+            .expect("Prefix cache file index was poisoned")
+            .contains_key(key)
+        {
+            return Ok(None);
+        }
+        let mut tensors = safetensors::load(Self::path(&self.dir, key), device)?;
+        // Guard against hash collisions.
+        let saved_toks = tensors
+            .remove("tokens")
+            .map(|t| t.to_vec1::<u32>())
+            .transpose()?;
+        if saved_toks.as_deref() != Some(toks) {
+            return Ok(None);
+        }
+        // Mark as recently used.
+        {
+            let mut files = self
+                .files
+                .lock()
+                .expect("Prefix cache file index was poisoned");
+            if let Some(bytes) = files.shift_remove(key) {
+                files.insert(key.to_string(), bytes);
+            }
+        }
+
+        let mut take_layers = |name: &str| {
+            let mut cache = Vec::new();
+            while let (Some(k), Some(v)) = (
+                tensors.remove(&format!("{name}.{}.k", cache.len())),
+                tensors.remove(&format!("{name}.{}.v", cache.len())),
+            ) {
+                cache.push(Some((k, v)));
+            }
+            cache
+        };
+        let normal = take_layers("layer");
+        let xlora = take_layers("xlora");
+        Ok(Some((normal, (!xlora.is_empty()).then_some(xlora))))
+    }
+
+    /// Forget a cache file which cannot be loaded, and remove it.
+    fn remove(&self, key: &str) {
+        self.files
+            .lock()
+            .expect("Prefix cache file index was poisoned")
+            .shift_remove(key);
+        if let Err(e) = fs::remove_file(Self::path(&self.dir, key)) {
+            warn!("Failed to remove prefix cache file: {e}");
+        }
+    }
+}
 
 pub struct PrefixCacheManager {
     caches: Trie<Tokens, Arc<Mutex<LayerCaches>>>,
@@ -31,7 +277,13 @@ pub struct PrefixCacheManager {
     device: Device,
     pub n_on_device: usize,
     no_prefix_cache: bool,
-    eviction_cache_ptrs: Vec<EvictionCacheGroup>,
+    /// Least recently used first.
+    entries: Vec<CacheEntry>,
+    device_bytes: Option<usize>,
+    host_bytes: Option<usize>,
+    disk: Option<DiskTier>,
+    /// Cache files are keyed by the model, as identified by [`PrefixCacheManager::model_key`], as well as the tokens.
+    model_key: String,
 }
 
 #[derive(Clone)]
@@ -42,15 +294,105 @@ pub struct MatchingCache {
 }
 
 impl PrefixCacheManager {
-    pub fn new(device: Device, n_on_device: usize, is_xlora: bool, no_prefix_cache: bool) -> Self {
+    pub fn new(
+        device: Device,
+        n_on_device: usize,
+        is_xlora: bool,
+        no_prefix_cache: bool,
+        tiers: PrefixCacheTiers,
+        model_key: String,
+    ) -> Self {
+        let disk = match tiers.disk_dir {
+            Some(dir) if !no_prefix_cache => match DiskTier::new(dir.clone(), tiers.disk_bytes) {
+                Ok(disk) => {
+                    info!(
+                        "Using {} prefix cache files in `{}`.",
+                        disk.files
+                            .lock()
+                            .expect("Prefix cache file index was poisoned")
+                            .len(),
+                        dir.display()
+                    );
+                    Some(disk)
+                }
+                Err(e) => {
+                    warn!(
+                        "Cannot use `{}` for the prefix cache, it will not be written to disk: {e}",
+                        dir.display()
+                    );
+                    None
+                }
+            },
+            _ => None,
+        };
         PrefixCacheManager {
             caches: Trie::new(),
             xlora_caches: if is_xlora { Some(Trie::new()) } else { None },
             device,
             n_on_device,
             no_prefix_cache,
-            eviction_cache_ptrs: Vec::new(),
+            entries: Vec::new(),
+            device_bytes: tiers.device_bytes,
+            host_bytes: tiers.host_bytes,
+            disk,
+            model_key,
+        }
+    }
+
+    /// Identify a model by its name, kind, activation dtype and in-situ quantization, so that cache files are only
+    /// loaded for the model which computed them.
+    pub fn model_key(name: &str, metadata: &GeneralMetadata, isq: Option<GgmlDType>) -> String {
+        format!(
+            "{name}\n{}\n{:?}\n{isq:?}",
+            metadata.kind, metadata.activation_dtype
+        )
+    }
+
+    /// Change the model key after the model was quantized again. The caches in memory are dropped, as they were
+    /// computed with the previous weights.
+    pub fn set_model_key(&mut self, model_key: String) {
+        self.caches = Trie::new();
+        if let Some(xlora_caches) = &mut self.xlora_caches {
+            *xlora_caches = Trie::new();
         }
+        self.entries.clear();
+        self.model_key = model_key;
+    }
+
+    fn cache_bytes(cache: &LayerCaches) -> usize {
+        cache
+            .iter()
+            .flatten()
+            .map(|(k, v)| (k.elem_count() + v.elem_count()) * k.dtype().size_in_bytes())
+            .sum()
+    }
+
+    fn insert(
+        &mut self,
+        toks: Vec<u32>,
+        normal: LayerCaches,
+        xlora: Option<LayerCaches>,
+        on_disk: bool,
+    ) {
+        let bytes = Self::cache_bytes(&normal) + xlora.as_ref().map_or(0, Self::cache_bytes);
+        let normal = Arc::new(Mutex::new(normal));
+        self.caches.insert(toks.clone().into(), normal.clone());
+        let xlora = xlora.map(|xlora| {
+            let xlora = Arc::new(Mutex::new(xlora));
+            self.xlora_caches
+                .as_mut()
+                .unwrap()
+                .insert(toks.clone().into(), xlora.clone());
+            xlora
+        });
+        self.entries.push(CacheEntry {
+            toks,
+            normal,
+            xlora,
+            bytes,
+            tier: Tier::Device,
+            on_disk,
+        });
     }
 
     /// This always keeps the cache on the device. If later on, a new seq cannot be allocated due to memory shortage,
@@ -59,19 +401,15 @@ impl PrefixCacheManager {
         if self.no_prefix_cache {
             return;
         }
-        let cache = Arc::new(Mutex::new(seq.cache().clone()));
-        self.caches
-            .insert(seq.get_toks().to_vec().into(), cache.clone());
-        if seq.is_xlora() {
-            let xlora_cache = Arc::new(Mutex::new(seq.xlora_cache().clone()));
-            self.xlora_caches
-                .as_mut()
-                .unwrap()
-                .insert(seq.get_toks().to_vec().into(), xlora_cache.clone());
-            self.eviction_cache_ptrs.push((cache, Some(xlora_cache)));
-        } else {
-            self.eviction_cache_ptrs.push((cache, None));
+        let toks = seq.get_toks().to_vec();
+        let cache = seq.cache().clone();
+        let xlora_cache = seq.is_xlora().then(|| seq.xlora_cache().clone());
+
+        // Replace the old cache of the same tokens.
+        if let Some(idx) = self.entries.iter().position(|entry| entry.toks == toks) {
+            self.entries.remove(idx);
         }
+        self.insert(toks, cache, xlora_cache, false);
     }
 
     fn cache_to<'a>(
@@ -86,50 +424,105 @@ impl PrefixCacheManager {
         Ok(())
     }
 
-    /// Evict the caches to CPU. This will evict the first k seqs such that the number of sequences on device after the copy is
-    /// the maximum allowed. Returns the number of evicted sequences.
+    fn entry_to(entry: &mut CacheEntry, tier: Tier, device: &Device) -> Result<()> {
+        let device = match tier {
+            Tier::Device => device,
+            Tier::Host => &Device::Cpu,
+        };
+        Self::cache_to(get_mut_arcmutex!(entry.normal).iter_mut(), device)?;
+        if let Some(xlora) = &entry.xlora {
+            Self::cache_to(get_mut_arcmutex!(xlora).iter_mut(), device)?;
+        }
+        entry.tier = tier;
+        Ok(())
+    }
+
+    /// Queue a cache which was just moved to host RAM for writing to the disk tier, unless it is already there.
+    fn persist(disk: Option<&DiskTier>, model_key: &str, entry: &mut CacheEntry) {
+        let Some(disk) = disk else {
+            return;
+        };
+        if entry.on_disk {
+            return;
+        }
+        let normal = get_mut_arcmutex!(entry.normal).clone();
+        let xlora = entry
+            .xlora
+            .as_ref()
+            .map(|xlora| get_mut_arcmutex!(xlora).clone());
+        disk.save(
+            disk_key(model_key, &entry.toks),
+            entry.toks.clone(),
+            normal,
+            xlora,
+        );
+        entry.on_disk = true;
+    }
+
+    /// Drop the least recently used caches in host RAM which exceed its budget. They remain on disk if there is
+    /// a disk tier. Returns the number of dropped caches.
+    fn evict_from_host(&mut self) -> usize {
+        let Some(host_bytes) = self.host_bytes else {
+            return 0;
+        };
+        let mut in_host = self
+            .entries
+            .iter()
+            .filter(|entry| entry.tier == Tier::Host)
+            .map(|entry| entry.bytes)
+            .sum::<usize>();
+        let mut n_dropped = 0;
+        while in_host > host_bytes {
+            let Some(idx) = self
+                .entries
+                .iter()
+                .position(|entry| entry.tier == Tier::Host)
+            else {
+                break;
+            };
+            let entry = self.entries.remove(idx);
+            in_host -= entry.bytes;
+            let toks = Tokens(entry.toks);
+            self.caches.remove(&toks);
+            if let Some(xlora_caches) = &mut self.xlora_caches {
+                xlora_caches.remove(&toks);
+            }
+            n_dropped += 1;
+        }
+        n_dropped
+    }
+
+    /// Evict the caches to CPU. This will evict the least recently used caches such that the number and size of
+    /// the caches on device after the copy are within the maximum allowed, and then drop caches from host RAM
+    /// which exceed its budget. Returns the number of evicted sequences.
     pub fn evict_to_cpu(&mut self) -> Result<usize> {
         if self.no_prefix_cache {
             return Ok(0);
         }
-        let mut n_on_device = 0;
-        for (cache, _) in &self.eviction_cache_ptrs {
-            if !matches!(
-                get_mut_arcmutex!(cache.as_ref())[0]
-                    .as_ref()
-                    .unwrap()
-                    .0
-                    .device(),
-                Device::Cpu
-            ) {
-                n_on_device += 1;
-            }
-        }
+        let on_device = self
+            .entries
+            .iter()
+            .filter(|entry| entry.tier == Tier::Device);
+        let mut n_on_device = on_device.clone().count();
+        let mut device_bytes = on_device.map(|entry| entry.bytes).sum::<usize>();
+
         let mut n_evicted = 0;
-        // Intentionally evict the first ones first, as they are the oldest
-        for (cache, xlora_cache) in &self.eviction_cache_ptrs {
-            if n_on_device - n_evicted == self.n_on_device {
+        // Intentionally evict the first ones first, as they are the least recently used
+        for entry in &mut self.entries {
+            if n_on_device <= self.n_on_device
+                && self.device_bytes.map_or(true, |max| device_bytes <= max)
+            {
                 break;
             }
-            if !matches!(
-                get_mut_arcmutex!(cache.as_ref())[0]
-                    .as_ref()
-                    .unwrap()
-                    .0
-                    .device(),
-                Device::Cpu
-            ) {
-                let mut cache = get_mut_arcmutex!(cache);
-                let mut xlora_cache = xlora_cache.as_ref().map(|c| get_mut_arcmutex!(c));
-
-                Self::cache_to(cache.iter_mut(), &Device::Cpu)?;
-                if let Some(ref mut xlora_cache) = xlora_cache {
-                    Self::cache_to(xlora_cache.iter_mut(), &Device::Cpu)?;
-                }
+            if entry.tier == Tier::Device {
+                Self::entry_to(entry, Tier::Host, &self.device)?;
+                Self::persist(self.disk.as_ref(), &self.model_key, entry);
+                n_on_device -= 1;
+                device_bytes -= entry.bytes;
                 n_evicted += 1;
             }
         }
-        Ok(self.caches.len().saturating_sub(self.n_on_device))
+        Ok(n_evicted + self.evict_from_host())
     }
 
     /// Evict all the caches to CPU.
@@ -137,60 +530,178 @@ impl PrefixCacheManager {
         if self.no_prefix_cache {
             return Ok(0);
         }
-        // Intentionally evict the first ones first, as they are the oldest
-        for (cache, xlora_cache) in &self.eviction_cache_ptrs {
-            if !matches!(
-                get_mut_arcmutex!(cache.as_ref())[0]
-                    .as_ref()
-                    .unwrap()
-                    .0
-                    .device(),
-                Device::Cpu
-            ) {
-                let mut cache = get_mut_arcmutex!(cache);
-                let mut xlora_cache = xlora_cache.as_ref().map(|c| get_mut_arcmutex!(c));
-
-                Self::cache_to(cache.iter_mut(), &Device::Cpu)?;
-                if let Some(ref mut xlora_cache) = xlora_cache {
-                    Self::cache_to(xlora_cache.iter_mut(), &Device::Cpu)?;
-                }
+        for entry in &mut self.entries {
+            if entry.tier == Tier::Device {
+                Self::entry_to(entry, Tier::Host, &self.device)?;
+                Self::persist(self.disk.as_ref(), &self.model_key, entry);
             }
         }
+        self.evict_from_host();
         Ok(self.caches.len())
     }
 
-    /// Search for a matching cache given some toks
+    /// Search for a matching cache given some toks, loading it from disk if it is not in memory.
     pub fn search_for_matching_cache(&mut self, toks: &[u32]) -> Result<Option<MatchingCache>> {
         if self.no_prefix_cache {
             return Ok(None);
         }
 
         let toks = Tokens(toks.to_vec());
-        if let Some(cache) = self.caches.get(&toks) {
-            Self::cache_to(get_mut_arcmutex!(cache.as_ref()).iter_mut(), &self.device)?;
-            let cache = get_mut_arcmutex!(cache.as_ref()).clone();
-            let xlora_cache = if let Some(ref xlora_caches) = self.xlora_caches {
-                let mut xlora_cache = get_mut_arcmutex!(xlora_caches.get(&toks).unwrap().as_ref());
-                Self::cache_to(xlora_cache.iter_mut(), &self.device)?;
-                Some(xlora_cache.clone())
-            } else {
-                None
+        if self.caches.get(&toks).is_none() {
+            let Some(disk) = &self.disk else {
+                return Ok(None);
+            };
+            let key = disk_key(&self.model_key, &toks.0);
+            // A cache file which cannot be loaded is only a cache miss.
+            let loaded = match disk.load(&key, &toks.0, &self.device) {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    warn!("Ignoring prefix cache file which cannot be loaded: {e}");
+                    disk.remove(&key);
+                    None
+                }
             };
-            let ancestor = &self
-                .caches
-                .get_ancestor(&toks)
-                .expect("No ancestor.")
-                .key()
-                .expect("Cannot get the key.")
-                .0;
-            // Know ancestor.len() < toks.len(), and toks[0..ancestor.len()] == toks
-            Ok(Some(MatchingCache {
-                normal: cache,
-                xlora: xlora_cache,
-                toks: toks.0[ancestor.len()..].to_vec(),
-            }))
-        } else {
-            Ok(None)
+            match loaded {
+                Some((normal, xlora)) if xlora.is_some() == self.xlora_caches.is_some() => {
+                    self.insert(toks.0.clone(), normal, xlora, true);
+                    self.evict_to_cpu()?;
+                }
+                _ => return Ok(None),
+            }
+        }
+
+        // Mark as most recently used and bring it back to the device.
+        if let Some(idx) = self.entries.iter().position(|entry| entry.toks == toks.0) {
+            let mut entry = self.entries.remove(idx);
+            Self::entry_to(&mut entry, Tier::Device, &self.device)?;
+            self.entries.push(entry);
         }
+
+        let cache = get_mut_arcmutex!(self.caches.get(&toks).unwrap().as_ref()).clone();
+        let xlora_cache = self.xlora_caches.as_ref().map(|xlora_caches| {
+            get_mut_arcmutex!(xlora_caches.get(&toks).unwrap().as_ref()).clone()
+        });
+        let ancestor = &self
+            .caches
+            .get_ancestor(&toks)
+            .expect("No ancestor.")
+            .key()
+            .expect("Cannot get the key.")
+            .0;
+        // Know ancestor.len() < toks.len(), and toks[0..ancestor.len()] == toks
+        let matching = MatchingCache {
+            normal: cache,
+            xlora: xlora_cache,
+            toks: toks.0[ancestor.len()..].to_vec(),
+        };
+        self.evict_to_cpu()?;
+        Ok(Some(matching))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+        thread,
+        time::Duration,
+    };
+
+    use candle_core::{Device, Tensor};
+
+    use super::{disk_key, PrefixCacheManager, PrefixCacheTiers};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "mistralrs-prefix-cache-{name}-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn manager(dir: &Path, model_key: &str) -> PrefixCacheManager {
+        PrefixCacheManager::new(
+            Device::Cpu,
+            16,
+            false,
+            false,
+            PrefixCacheTiers {
+                disk_dir: Some(dir.to_path_buf()),
+                ..Default::default()
+            },
+            model_key.to_string(),
+        )
+    }
+
+    #[test]
+    fn test_disk_key_is_stable() {
+        assert_eq!(
+            disk_key("model", &[1, 2, 3]),
+            "aa6b62e3031e743b538d3bda0856aef28900f83677699fa08c6bc81d003dfed5"
+        );
+        assert_ne!(
+            disk_key("model", &[1, 2, 3]),
+            disk_key("model-isq", &[1, 2, 3])
+        );
+    }
+
+    #[test]
+    fn test_disk_tier_survives_restart() -> candle_core::Result<()> {
+        let dir = temp_dir("restart");
+        let toks = vec![1, 2, 3, 4];
+        let k = Tensor::arange(0f32, 8f32, &Device::Cpu)?.reshape((1, 2, 4))?;
+        let v = (&k + 8.)?;
+        {
+            let mut manager = manager(&dir, "model");
+            manager.insert(
+                toks.clone(),
+                vec![Some((k.clone(), v.clone()))],
+                None,
+                false,
+            );
+            // Moving the cache to host RAM writes it in the background.
+            manager.n_on_device = 0;
+            assert_eq!(manager.evict_to_cpu()?, 1);
+            let key = disk_key("model", &toks);
+            let files = manager.disk.as_ref().unwrap().files.clone();
+            for _ in 0..500 {
+                if files.lock().unwrap().contains_key(&key) {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            assert!(files.lock().unwrap().contains_key(&key));
+        }
+
+        let mut restarted = manager(&dir, "model");
+        let matching = restarted.search_for_matching_cache(&toks)?.unwrap();
+        let (loaded_k, loaded_v) = matching.normal[0].clone().unwrap();
+        assert_eq!(loaded_k.to_vec3::<f32>()?, k.to_vec3::<f32>()?);
+        assert_eq!(loaded_v.to_vec3::<f32>()?, v.to_vec3::<f32>()?);
+        // The caches of another model, or quantization, are not used.
+        assert!(manager(&dir, "model-isq")
+            .search_for_matching_cache(&toks)?
+            .is_none());
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_unreadable_cache_file_is_a_miss() -> candle_core::Result<()> {
+        let dir = temp_dir("unreadable");
+        fs::create_dir_all(&dir)?;
+        let toks = vec![5, 6, 7];
+        let path = dir.join(format!("{}.safetensors", disk_key("model", &toks)));
+        fs::write(&path, b"not a safetensors file")?;
+
+        let mut manager = manager(&dir, "model");
+        assert!(manager.search_for_matching_cache(&toks)?.is_none());
+        assert!(!path.exists());
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
     }
 }
@@ -4,17 +4,19 @@ use indexmap::IndexMap;
 
 use crate::{
     response::Response,
-    sampler::SamplingParams,
+    sampler::{LogitsProcessor, SamplingParams},
     tools::{Tool, ToolChoice},
 };
-use std::fmt::Debug;
+use std::{fmt::Debug, sync::Arc};
 use tokio::sync::mpsc::Sender;
 
 #[derive(Clone)]
-/// Control the constraint with Regex or Yacc.
+/// Control the constraint with Regex, Yacc, or a JSON schema.
 pub enum Constraint {
     Regex(String),
     Yacc(String),
+    /// Only generate JSON which is valid against this schema.
+    JsonSchema(serde_json::Value),
     None,
 }
 
@@ -50,6 +52,14 @@ pub struct NormalRequest {
     pub adapters: Option<Vec<String>>,
     pub tools: Option<Vec<Tool>>,
     pub tool_choice: Option<ToolChoice>,
+    /// Scheduling priority, only used by [`crate::DefaultSchedulerMethod::Priority`]. Higher values are scheduled first.
+    pub priority: i32,
+    /// Key used to share capacity fairly between tenants, only used by [`crate::DefaultSchedulerMethod::Priority`].
+    pub tenant: Option<String>,
+    /// Custom logits processors applied to each sequence of the request before sampling.
+    pub logits_processors: Option<Vec<Arc<dyn LogitsProcessor>>>,
+    /// When streaming, send a final chunk with the usage of the request once all choices are finished.
+    pub include_usage: bool,
 }
 
 impl NormalRequest {
@@ -73,17 +83,69 @@ impl NormalRequest {
             constraint: Constraint::None,
             suffix: None,
             adapters: None,
+            priority: 0,
+            tenant: None,
+            logits_processors: None,
+            include_usage: false,
         }
     }
 }
 
+#[derive(Clone, Debug)]
+/// One input to embed for an [`EmbeddingRequest`].
+pub enum EmbeddingInput {
+    Text(String),
+    Tokens(Vec<u32>),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+/// How the per-token hidden states are reduced to a single embedding.
+pub enum EmbeddingPooling {
+    /// Average over all tokens.
+    #[default]
+    Mean,
+    /// Take the hidden state of the last token.
+    Last,
+}
+
+#[derive(Clone)]
+/// A request for the final hidden states of one or more inputs, pooled to one vector each.
+pub struct EmbeddingRequest {
+    pub input: Vec<EmbeddingInput>,
+    pub pooling: EmbeddingPooling,
+    /// L2-normalize each embedding.
+    pub normalize: bool,
+    pub response: Sender<Response>,
+    pub id: usize,
+}
+
+#[derive(Clone)]
+/// A request for the log-probabilities of given tokens, computed with a single prefill and without sampling.
+pub struct ScoreRequest {
+    /// The prompt, as text (tokenized with special tokens) or tokens.
+    pub prompt: Either<String, Vec<u32>>,
+    /// If set, only these tokens, which follow the prompt, are scored. Otherwise every prompt token after the
+    /// first is scored. Text is tokenized without special tokens.
+    pub continuation: Option<Either<String, Vec<u32>>>,
+    /// The number of most likely alternatives to return for each scored token.
+    pub top_n_logprobs: usize,
+    pub response: Sender<Response>,
+    pub id: usize,
+}
+
 #[derive(Clone)]
 /// A request to the Engine, encapsulating the various parameters as well as
 /// the `mspc` response `Sender` used to return the [`Response`].
 pub enum Request {
     Normal(NormalRequest),
+    Embedding(EmbeddingRequest),
+    Score(ScoreRequest),
     ReIsq(GgmlDType),
     ActivateAdapters(Vec<String>),
+    /// Cancel all sequences of the [`NormalRequest`] with this id.
+    Cancel(usize),
+    /// Stop the engine once the requests it has received are finished.
+    Terminate,
 }
 
 impl Debug for Request {
@@ -101,10 +163,38 @@ impl Debug for Request {
                 adapters,
                 tool_choice: _,
                 tools: _,
+                priority,
+                tenant,
+                logits_processors: _,
+                include_usage: _,
             }) => {
                 write!(
                     f,
-                    "Request {id} {{ messages: `{messages:?}`, sampling_params: {sampling_params:?}, is_streaming: {is_streaming}, adapters: {adapters:?}}}",
+                    "Request {id} {{ messages: `{messages:?}`, sampling_params: {sampling_params:?}, is_streaming: {is_streaming}, adapters: {adapters:?}, priority: {priority}, tenant: {tenant:?}}}",
+                )
+            }
+            Request::Embedding(EmbeddingRequest {
+                input,
+                pooling,
+                normalize,
+                response: _,
+                id,
+            }) => {
+                write!(
+                    f,
+                    "Embedding Request {id} {{ input: `{input:?}`, pooling: {pooling:?}, normalize: {normalize}}}",
+                )
+            }
+            Request::Score(ScoreRequest {
+                prompt,
+                continuation,
+                top_n_logprobs,
+                response: _,
+                id,
+            }) => {
+                write!(
+                    f,
+                    "Score Request {id} {{ prompt: `{prompt:?}`, continuation: `{continuation:?}`, top_n_logprobs: {top_n_logprobs}}}",
                 )
             }
             Request::ActivateAdapters(adapters) => {
@@ -113,6 +203,12 @@ impl Debug for Request {
             Request::ReIsq(tp) => {
                 write!(f, "Re ISQ Request {tp:?}",)
             }
+            Request::Cancel(id) => {
+                write!(f, "Cancel Request {id}",)
+            }
+            Request::Terminate => {
+                write!(f, "Terminate Request")
+            }
         }
     }
 }
@@ -1,10 +1,14 @@
 use std::error::Error;
 
+use indexmap::IndexMap;
 #[cfg(feature = "pyo3_macros")]
 use pyo3::{pyclass, pymethods};
 use serde::Serialize;
 
-use crate::{sampler::TopLogprob, tools::ToolCallResponse};
+use crate::{
+    sampler::{self, TopLogprob},
+    tools::{ToolCallDelta, ToolCallResponse},
+};
 
 pub const SYSTEM_FINGERPRINT: &str = "local";
 
@@ -39,6 +43,7 @@ generate_repr!(ResponseMessage);
 pub struct Delta {
     pub content: String,
     pub role: String,
+    pub tool_calls: Option<Vec<ToolCallDelta>>,
 }
 
 generate_repr!(Delta);
@@ -75,6 +80,9 @@ pub struct Choice {
     pub index: usize,
     pub message: ResponseMessage,
     pub logprobs: Option<Logprobs>,
+    /// Length-normalized score of this choice, when beam search is used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub beam_score: Option<f32>,
 }
 
 generate_repr!(Choice);
@@ -92,6 +100,118 @@ pub struct ChunkChoice {
 
 generate_repr!(ChunkChoice);
 
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+/// OpenAI compatible logprobs of a completion, one entry per token. Only the first token of an echoed
+/// prompt has no logprobs.
+pub struct CompletionLogprobs {
+    pub tokens: Vec<String>,
+    pub token_logprobs: Vec<Option<f32>>,
+    pub top_logprobs: Vec<Option<IndexMap<String, f32>>>,
+    /// Character offset of each token in the returned text.
+    pub text_offset: Vec<usize>,
+}
+
+generate_repr!(CompletionLogprobs);
+
+impl CompletionLogprobs {
+    /// Build the logprobs of `logprobs`, the first of which starts at character `text_offset`.
+    /// If `trim_start`, leading whitespace of the tokens is not counted, as it is trimmed from the text.
+    pub(crate) fn new(
+        logprobs: &[sampler::Logprobs],
+        text_offset: usize,
+        trim_start: bool,
+    ) -> Self {
+        let mut this = Self {
+            tokens: Vec::with_capacity(logprobs.len()),
+            token_logprobs: Vec::with_capacity(logprobs.len()),
+            top_logprobs: Vec::with_capacity(logprobs.len()),
+            text_offset: Vec::with_capacity(logprobs.len()),
+        };
+        // Position in the untrimmed text, and the number of characters trimmed before it
+        let mut pos = 0;
+        let mut trimmed = 0;
+        let mut in_leading_whitespace = trim_start;
+        for logprob in logprobs {
+            this.text_offset.push(text_offset + pos - trimmed);
+            let len = logprob.bytes.chars().count();
+            if in_leading_whitespace {
+                let whitespace = len - logprob.bytes.trim_start().chars().count();
+                trimmed += whitespace;
+                in_leading_whitespace = whitespace == len;
+            }
+            pos += len;
+            this.tokens.push(logprob.bytes.clone());
+            this.token_logprobs.push(Some(logprob.logprob));
+            this.top_logprobs.push(Some(
+                logprob
+                    .top_logprobs
+                    .iter()
+                    .flatten()
+                    .map(|top| (top.bytes.clone(), top.logprob))
+                    .collect(),
+            ));
+        }
+        this
+    }
+
+    /// The logprobs of a scored prompt, as returned when the prompt is echoed. Special tokens are left
+    /// out, as they are not part of the echoed text.
+    pub fn from_scored_tokens(scored: &[ScoredToken]) -> Self {
+        let mut this = Self {
+            tokens: Vec::with_capacity(scored.len()),
+            token_logprobs: Vec::with_capacity(scored.len()),
+            top_logprobs: Vec::with_capacity(scored.len()),
+            text_offset: Vec::with_capacity(scored.len()),
+        };
+        let mut pos = 0;
+        for token in scored.iter().filter(|token| !token.special) {
+            this.text_offset.push(pos);
+            pos += token.text.chars().count();
+            this.tokens.push(token.text.clone());
+            this.token_logprobs.push(token.logprob);
+            this.top_logprobs.push(token.logprob.map(|_| {
+                token
+                    .top_logprobs
+                    .iter()
+                    .map(|top| (top.bytes.clone(), top.logprob))
+                    .collect()
+            }));
+        }
+        this
+    }
+
+    /// Put the logprobs of `prefix`, such as an echoed prompt, before these. The text offsets of these are
+    /// moved to start at the end of the text of `prefix`.
+    pub fn prepend(&mut self, prefix: Self) {
+        let Self {
+            mut tokens,
+            mut token_logprobs,
+            mut top_logprobs,
+            mut text_offset,
+        } = prefix;
+        let prefix_len = text_offset
+            .last()
+            .zip(tokens.last())
+            .map_or(0, |(offset, token)| offset + token.chars().count());
+        let start = self.text_offset.first().copied().unwrap_or_default();
+        for offset in self.text_offset.iter_mut() {
+            *offset = *offset - start + prefix_len;
+        }
+        tokens.append(&mut self.tokens);
+        token_logprobs.append(&mut self.token_logprobs);
+        top_logprobs.append(&mut self.top_logprobs);
+        text_offset.append(&mut self.text_offset);
+        *self = Self {
+            tokens,
+            token_logprobs,
+            top_logprobs,
+            text_offset,
+        };
+    }
+}
+
 #[cfg_attr(feature = "pyo3_macros", pyclass)]
 #[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
 #[derive(Debug, Clone, Serialize)]
@@ -99,7 +219,7 @@ generate_repr!(ChunkChoice);
 pub struct CompletionChunkChoice {
     pub text: String,
     pub index: usize,
-    pub logprobs: Option<ResponseLogprob>,
+    pub logprobs: Option<CompletionLogprobs>,
     pub finish_reason: Option<String>,
 }
 
@@ -150,6 +270,9 @@ pub struct ChatCompletionChunkResponse {
     pub model: String,
     pub system_fingerprint: String,
     pub object: String,
+    /// Only set in the final chunk, which has no choices, of a request with `include_usage`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
 }
 
 generate_repr!(ChatCompletionChunkResponse);
@@ -162,7 +285,10 @@ pub struct CompletionChoice {
     pub finish_reason: String,
     pub index: usize,
     pub text: String,
-    pub logprobs: Option<()>,
+    pub logprobs: Option<CompletionLogprobs>,
+    /// Length-normalized score of this choice, when beam search is used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub beam_score: Option<f32>,
 }
 
 generate_repr!(CompletionChoice);
@@ -194,14 +320,92 @@ pub struct CompletionChunkResponse {
     pub model: String,
     pub system_fingerprint: String,
     pub object: String,
+    /// Only set in the final chunk, which has no choices, of a request with `include_usage`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
 }
 
 generate_repr!(CompletionChunkResponse);
 
-/// The response enum contains 3 types of variants:
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+/// One pooled embedding.
+pub struct Embedding {
+    pub object: String,
+    pub embedding: Vec<f32>,
+    pub index: usize,
+}
+
+generate_repr!(Embedding);
+
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+/// Token usage of an embedding request.
+pub struct EmbeddingUsage {
+    pub prompt_tokens: usize,
+    pub total_tokens: usize,
+}
+
+generate_repr!(EmbeddingUsage);
+
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+/// An OpenAI compatible embeddings response.
+pub struct EmbeddingResponse {
+    pub object: String,
+    pub data: Vec<Embedding>,
+    pub model: String,
+    pub usage: EmbeddingUsage,
+}
+
+generate_repr!(EmbeddingResponse);
+
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+/// The log-probability of one token of a [`crate::ScoreRequest`].
+pub struct ScoredToken {
+    pub token: u32,
+    /// The text which this token adds to the decoded text, or the token itself if it is special.
+    pub text: String,
+    /// Whether this is a special token, such as BOS, which is not part of the decoded text.
+    pub special: bool,
+    /// Natural log-probability of this token given all the previous ones. The first token of the prompt
+    /// has none.
+    pub logprob: Option<f32>,
+    /// Whether this token is the most likely one at its position.
+    pub is_greedy: bool,
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+generate_repr!(ScoredToken);
+
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+/// The result of a [`crate::ScoreRequest`].
+pub struct ScoreResponse {
+    pub model: String,
+    /// All prompt and continuation tokens, in order. Only the scored tokens have a logprob.
+    pub tokens: Vec<ScoredToken>,
+    /// Sum of the logprobs of the scored tokens.
+    pub total_logprob: f32,
+    /// Whether every scored token is the most likely one, i.e. greedy decoding would produce them.
+    pub is_greedy: bool,
+    pub prompt_tokens: usize,
+}
+
+generate_repr!(ScoreResponse);
+
+/// The response enum contains 5 types of variants:
 /// - Error (-Error suffix)
 /// - Chat (no prefix)
 /// - Completion (Completion- prefix)
+/// - Embeddings
+/// - Score
 pub enum Response {
     InternalError(Box<dyn Error + Send + Sync>),
     ValidationError(Box<dyn Error + Send + Sync>),
@@ -213,4 +417,8 @@ pub enum Response {
     CompletionModelError(String, CompletionResponse),
     CompletionDone(CompletionResponse),
     CompletionChunk(CompletionChunkResponse),
+    // Embeddings
+    Embeddings(EmbeddingResponse),
+    // Scoring
+    Score(ScoreResponse),
 }
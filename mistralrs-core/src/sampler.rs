@@ -1,8 +1,7 @@
 #![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 
 use std::{
-    collections::HashMap,
-    iter::zip,
+    collections::{HashMap, HashSet},
     sync::{Arc, Mutex},
 };
 
@@ -22,6 +21,74 @@ pub enum StopTokens {
     Ids(Vec<u32>),
 }
 
+#[derive(Clone, Debug)]
+/// Parameters of the DRY ("Don't Repeat Yourself") sampler, which penalizes tokens that would extend a repetition
+/// of an earlier part of the sequence. The penalty is `multiplier * base^(n - allowed_length)` for a repetition of
+/// `n >= allowed_length` tokens.
+pub struct DrySamplingParams {
+    pub multiplier: f32,
+    pub base: f32,
+    pub allowed_length: usize,
+    /// Repetitions are not matched across these strings. Each is matched by the last token it encodes to.
+    pub sequence_breakers: Vec<String>,
+}
+
+impl DrySamplingParams {
+    /// DRY parameters with the default base of 1.75, allowed length of 2 and sequence breakers of newline, colon,
+    /// quote and asterisk, for those not specified.
+    pub fn new_with_defaults(
+        multiplier: f32,
+        sequence_breakers: Option<Vec<String>>,
+        base: Option<f32>,
+        allowed_length: Option<usize>,
+    ) -> Self {
+        Self {
+            multiplier,
+            base: base.unwrap_or(1.75),
+            allowed_length: allowed_length.unwrap_or(2),
+            sequence_breakers: sequence_breakers
+                .unwrap_or_else(|| ["\n", ":", "\"", "*"].map(ToString::to_string).to_vec()),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+/// Parameters of the Mirostat v2 sampler, which truncates the tokens more surprising than a per-sequence
+/// threshold `mu` and adjusts `mu` after each token so that the observed surprise approaches `tau`.
+pub struct MirostatParams {
+    /// Target surprise, in bits.
+    pub tau: f32,
+    /// Learning rate of `mu`.
+    pub eta: f32,
+}
+
+/// Custom logic applied to the logits of each sequence of a request before sampling, after the logits bias and
+/// before the penalties. Grammar constraints are applied after the processors.
+///
+/// Any `Fn(&mut [f32], &[u32]) -> candle_core::Result<()>` closure which is `Send + Sync` is a logits processor.
+pub trait LogitsProcessor: Send + Sync {
+    /// Modify the logits of the next token. `context` holds the prompt and completion tokens of the sequence.
+    fn apply(&self, logits: &mut [f32], context: &[u32]) -> Result<()>;
+}
+
+impl<F> LogitsProcessor for F
+where
+    F: Fn(&mut [f32], &[u32]) -> Result<()> + Send + Sync,
+{
+    fn apply(&self, logits: &mut [f32], context: &[u32]) -> Result<()> {
+        self(logits, context)
+    }
+}
+
+#[derive(Clone, Debug)]
+/// DRY parameters, with the sequence breakers tokenized.
+struct DryParams {
+    multiplier: f32,
+    base: f32,
+    allowed_length: usize,
+    sequence_breakers: HashSet<u32>,
+}
+
 #[derive(Clone, Debug)]
 /// Sampling params are used to control sampling.
 pub struct SamplingParams {
@@ -36,6 +103,27 @@ pub struct SamplingParams {
     pub max_len: Option<usize>,
     pub logits_bias: Option<HashMap<u32, f32>>,
     pub n_choices: usize,
+    /// If set, use beam search with this many beams instead of sampling. `n_choices` must not exceed it.
+    pub beam_width: Option<usize>,
+    /// Exponent applied to the generated length when scoring finished beams. Defaults to 1.0.
+    pub length_penalty: Option<f32>,
+    /// Seed for sampling. Each sequence of the request then samples from its own RNG stream, so the output
+    /// does not depend on the other sequences in the batch.
+    pub seed: Option<u64>,
+    /// Report the logprobs of the distribution sampled from, after penalties, temperature and top-k/top-p/min-p,
+    /// instead of the natural log-softmax of the raw logits.
+    pub processed_logprobs: bool,
+    /// HF-style multiplicative penalty for tokens of the prompt or completion. Values above 1.0 discourage repetition.
+    pub repetition_penalty: Option<f32>,
+    /// Ban tokens which would repeat an n-gram of this size from the prompt or completion.
+    pub no_repeat_ngram_size: Option<usize>,
+    pub dry_params: Option<DrySamplingParams>,
+    /// Locally typical sampling: keep the tokens closest to the expected surprise, up to this cumulative probability.
+    pub typical_p: Option<f64>,
+    /// Top-a sampling: drop the tokens with a probability below `top_a * max_prob^2`.
+    pub top_a: Option<f64>,
+    /// Use Mirostat v2 instead of the top-k/top-p/min-p settings.
+    pub mirostat: Option<MirostatParams>,
 }
 
 impl Default for SamplingParams {
@@ -52,6 +140,16 @@ impl Default for SamplingParams {
             max_len: None,
             logits_bias: None,
             n_choices: 1,
+            beam_width: None,
+            length_penalty: None,
+            seed: None,
+            processed_logprobs: false,
+            repetition_penalty: None,
+            no_repeat_ngram_size: None,
+            dry_params: None,
+            typical_p: None,
+            top_a: None,
+            mirostat: None,
         }
     }
 }
@@ -64,9 +162,18 @@ pub struct Sampler {
     tokenizer: Arc<Tokenizer>,
     frequency_penalty: Option<f32>,
     presence_penalty: Option<f32>,
+    repetition_penalty: Option<f32>,
+    no_repeat_ngram_size: Option<usize>,
+    dry_params: Option<DryParams>,
+    logits_bias: Option<HashMap<u32, f32>>,
+    logits_processors: Vec<Arc<dyn LogitsProcessor>>,
     top_k: i64,
     top_p: f64,
     min_p: f64,
+    typical_p: f64,
+    top_a: f64,
+    mirostat: Option<MirostatParams>,
+    processed_logprobs: bool,
 }
 
 #[cfg_attr(feature = "pyo3_macros", pyclass)]
@@ -99,83 +206,114 @@ impl Sampler {
         tokenizer: Arc<Tokenizer>,
         frequency_penalty: Option<f32>,
         presence_penalty: Option<f32>,
+        repetition_penalty: Option<f32>,
+        no_repeat_ngram_size: Option<usize>,
+        dry_params: Option<DrySamplingParams>,
+        logits_bias: Option<HashMap<u32, f32>>,
+        logits_processors: Vec<Arc<dyn LogitsProcessor>>,
         top_k: i64,
         top_p: f64,
         min_p: f64,
-    ) -> Self {
+        typical_p: f64,
+        top_a: f64,
+        mirostat: Option<MirostatParams>,
+        processed_logprobs: bool,
+    ) -> Result<Self> {
         let temperature = if temperature.map_or(true, |v| v < 1e-7) {
             None
         } else {
             temperature
         };
-        Self {
+        if let Some(penalty) = repetition_penalty.filter(|p| *p <= 0.) {
+            bail!("Repetition penalty must be greater than 0, got {penalty}.");
+        }
+        let dry_params = match dry_params {
+            Some(params) => {
+                let mut sequence_breakers = HashSet::new();
+                for breaker in &params.sequence_breakers {
+                    let encoded = tokenizer
+                        .encode(breaker.as_str(), false)
+                        .map_err(|x| Error::Msg(x.to_string()))?;
+                    // Earlier tokens may be a prefix like a leading space, which must not break repetitions.
+                    sequence_breakers.extend(encoded.get_ids().last());
+                }
+                Some(DryParams {
+                    multiplier: params.multiplier,
+                    base: params.base,
+                    allowed_length: params.allowed_length,
+                    sequence_breakers,
+                })
+            }
+            None => None,
+        };
+        Ok(Self {
             temperature,
             top_n_logprobs,
             tokenizer,
             frequency_penalty,
             presence_penalty,
+            repetition_penalty,
+            no_repeat_ngram_size,
+            dry_params,
+            logits_bias,
+            logits_processors,
             top_k,
             top_p,
             min_p,
-        }
+            typical_p,
+            top_a,
+            mirostat,
+            processed_logprobs,
+        })
     }
 
-    fn get_top_logprobs(
-        &self,
-        probs: &[f32],
-        argsort_indices: &[usize],
-    ) -> Result<Vec<TopLogprob>> {
-        let mut argsort_indices_sorted = argsort_indices.to_vec();
-        // Sort by descending prob
-        argsort_indices_sorted
-            .sort_by(|a, b| probs[*b].partial_cmp(&probs[*a]).expect("No ordering."));
-        // These are where the top n are
-        let top_n_toks_range = 0..self.top_n_logprobs;
-        // The top n's values
-        let top_n_logprobs = argsort_indices_sorted[top_n_toks_range.clone()]
-            .iter()
-            .map(|x| probs[*x].log(10.0))
-            .collect::<Vec<_>>();
-        // Find where they actually are in the logits
-        let mut top_n_toks = Vec::new();
-        for val in top_n_toks_range {
-            top_n_toks.push(argsort_indices[val]);
-        }
-
-        let mut bytes = Vec::new();
-        for tok in &top_n_toks {
-            bytes.push(
-                self.tokenizer
-                    .decode(&[*tok as u32], false)
-                    .map_err(|x| Error::Msg(x.to_string()))?,
-            );
-        }
-        Ok(zip(bytes, zip(top_n_toks, top_n_logprobs))
-            .map(|(bytes, (token, logprob))| TopLogprob {
-                token: token as u32,
-                logprob,
-                bytes,
+    /// The `top_n_logprobs` most likely tokens, from natural log probabilities over the vocabulary.
+    fn get_top_logprobs(&self, logprobs: &[f32]) -> Result<Vec<TopLogprob>> {
+        let mut top_n_toks = (0..logprobs.len()).collect::<Vec<_>>();
+        let top_n = self.top_n_logprobs.min(top_n_toks.len());
+        // Sort by descending logprob
+        let by_logprob = |a: &usize, b: &usize| {
+            logprobs[*b]
+                .partial_cmp(&logprobs[*a])
+                .expect("No ordering.")
+        };
+        if top_n < top_n_toks.len() {
+            top_n_toks.select_nth_unstable_by(top_n, by_logprob);
+        }
+        top_n_toks.truncate(top_n);
+        top_n_toks.sort_by(by_logprob);
+
+        top_n_toks
+            .into_iter()
+            .map(|token| {
+                Ok(TopLogprob {
+                    token: token as u32,
+                    logprob: logprobs[token],
+                    bytes: self
+                        .tokenizer
+                        .decode(&[token as u32], false)
+                        .map_err(|x| Error::Msg(x.to_string()))?,
+                })
             })
-            .collect::<Vec<_>>())
+            .collect()
     }
 
-    fn sample_argmax(&self, logits: Tensor, return_logprobs: bool) -> Result<Logprobs> {
-        let next_token = logits.argmax(D::Minus1)?.to_scalar::<u32>()?;
-
-        let probs: Vec<f32> = logits.to_vec1()?;
-
-        let argsort_indices = (0..probs.len()).collect::<Vec<_>>();
-        let logprob = probs[next_token as usize].log(10.0);
-
+    /// Build the logprobs of the sampled token from natural log probabilities over the vocabulary.
+    fn get_logprobs(
+        &self,
+        next_token: u32,
+        logprobs: &[f32],
+        return_logprobs: bool,
+    ) -> Result<Logprobs> {
         let top_logprobs = if return_logprobs {
-            Some(self.get_top_logprobs(&probs, &argsort_indices)?)
+            Some(self.get_top_logprobs(logprobs)?)
         } else {
             None
         };
 
         Ok(Logprobs {
             token: next_token,
-            logprob,
+            logprob: logprobs[next_token as usize],
             top_logprobs,
             bytes: self
                 .tokenizer
@@ -186,13 +324,11 @@ impl Sampler {
 
     fn sample_speculative_top_kp_min_p(
         &self,
-        logits: Tensor,
-        return_logprobs: bool,
+        probs: &mut [f32],
         top_k: i64,
         top_p: f32,
         min_p: f32,
-    ) -> Result<Logprobs> {
-        let mut probs: Vec<f32> = logits.to_vec1()?;
+    ) -> Result<u32> {
         let mut argsort_indices = (0..probs.len()).collect::<Vec<_>>();
 
         // Sort by descending probability.
@@ -238,68 +374,27 @@ impl Sampler {
             }
         }
 
-        let logits = Tensor::from_slice(&probs, logits.shape(), &Device::Cpu)?;
+        let probs = Tensor::from_slice(probs, probs.len(), &Device::Cpu)?;
 
-        let next_token = argmax_sample_last_dim(&logits)?.to_scalar::<u32>()?;
-
-        let logprob = probs[next_token as usize].log(10.0);
-
-        let top_logprobs = if return_logprobs {
-            Some(self.get_top_logprobs(&probs, &argsort_indices)?)
-        } else {
-            None
-        };
-
-        Ok(Logprobs {
-            token: next_token,
-            logprob,
-            top_logprobs,
-            bytes: self
-                .tokenizer
-                .decode(&[next_token], false)
-                .map_err(|x| Error::Msg(x.to_string()))?,
-        })
+        argmax_sample_last_dim(&probs)?.to_scalar::<u32>()
     }
 
-    fn sample_multinomial(
-        &self,
-        probs: &mut Vec<f32>,
-        argsort_indices: Vec<usize>,
-        return_logprobs: bool,
-        rng: Arc<Mutex<Isaac64Rng>>,
-    ) -> Result<Logprobs> {
-        let distr = WeightedIndex::new(&*probs).map_err(Error::wrap)?;
+    fn sample_multinomial(&self, probs: &[f32], rng: Arc<Mutex<Isaac64Rng>>) -> Result<u32> {
+        let distr = WeightedIndex::new(probs).map_err(Error::wrap)?;
 
         let mut mut_ref_rng = &mut *rng.lock().expect("could not lock rng mutex");
         let next_token = distr.sample(&mut mut_ref_rng); // "Find the first item which has a weight *higher* than the chosen weight."
-        let logprob = probs[next_token].log(10.0);
-
-        let top_logprobs = if return_logprobs {
-            Some(self.get_top_logprobs(probs, &argsort_indices)?)
-        } else {
-            None
-        };
-
-        Ok(Logprobs {
-            token: next_token as u32,
-            logprob,
-            top_logprobs,
-            bytes: self
-                .tokenizer
-                .decode(&[next_token.try_into().unwrap()], false)
-                .map_err(|x| Error::Msg(x.to_string()))?,
-        })
+        Ok(next_token as u32)
     }
 
     fn sample_top_kp_min_p(
         &self,
-        probs: &mut Vec<f32>,
+        probs: &mut [f32],
         top_k: i64,
         top_p: f32,
         min_p: f32,
-        return_logprobs: bool,
         rng: Arc<Mutex<Isaac64Rng>>,
-    ) -> Result<Logprobs> {
+    ) -> Result<u32> {
         let mut argsort_indices = (0..probs.len()).collect::<Vec<_>>();
         // Sort by descending probability.
         argsort_indices
@@ -315,7 +410,7 @@ impl Sampler {
         }
 
         if top_p <= 0.0 || top_p >= 1.0 {
-            return self.sample_multinomial(probs, argsort_indices, return_logprobs, rng);
+            return self.sample_multinomial(probs, rng);
         }
 
         // TOP P
@@ -335,7 +430,7 @@ impl Sampler {
         }
 
         if min_p <= 0.0 || min_p >= 1.0 {
-            return self.sample_multinomial(probs, argsort_indices, return_logprobs, rng);
+            return self.sample_multinomial(probs, rng);
         }
 
         let max_p = probs[argsort_indices[0]];
@@ -353,100 +448,390 @@ impl Sampler {
         }
 
         // Sample with clamped probabilities.
-        self.sample_multinomial(probs, argsort_indices, return_logprobs, rng)
+        self.sample_multinomial(probs, rng)
     }
 
-    fn apply_penalties(&self, mut logits: Vec<f32>, context: Option<&[u32]>) -> Result<Tensor> {
-        if self.frequency_penalty.is_some() || self.presence_penalty.is_some() {
-            if context.is_none() {
-                bail!("Must specify penalty context.");
-            }
-            let context = context.as_ref().unwrap();
-            let frequency_penalty = self.frequency_penalty.unwrap_or(0.);
-            let presence_penalty = self.presence_penalty.unwrap_or(0.);
+    /// Locally typical sampling: keep the smallest set of tokens whose surprise is closest to the entropy of the
+    /// distribution and which exceeds probability typical_p.
+    fn apply_typical_p(&self, probs: &mut [f32]) {
+        if self.typical_p <= 0.0 || self.typical_p >= 1.0 {
+            return;
+        }
+        let entropy = -probs
+            .iter()
+            .filter(|p| **p > 0.)
+            .map(|p| p * p.ln())
+            .sum::<f32>();
+        let distance = |p: f32| (-p.ln() - entropy).abs();
 
-            //mu[j] -> mu[j] - c[j] * alpha_frequency - float(c[j] > 0) * alpha_presence
+        let mut argsort_indices = (0..probs.len()).collect::<Vec<_>>();
+        // Sort by ascending distance to the entropy.
+        argsort_indices.sort_unstable_by(|&i, &j| {
+            distance(probs[i])
+                .partial_cmp(&distance(probs[j]))
+                .expect("No ordering.")
+        });
 
-            let mut counts = vec![0.0f32; logits.len()];
-            for ctx in context.iter() {
-                counts[*ctx as usize] += 1.0;
+        // Clamp the probabilities of the less typical tokens to zero.
+        let mut cumsum = 0.;
+        for index in argsort_indices {
+            if cumsum >= self.typical_p as f32 {
+                probs[index] = 0.0;
+            } else {
+                cumsum += probs[index];
             }
+        }
+    }
 
-            for (token_id, logit) in logits.iter_mut().enumerate() {
-                let count = counts[token_id];
-                *logit = *logit
-                    - count * frequency_penalty
-                    - if count > 0.0 { 1. } else { 0. } * presence_penalty;
+    /// Top-a sampling: clamp the probabilities below `top_a * max_prob^2` to zero.
+    fn apply_top_a(&self, probs: &mut [f32]) {
+        if self.top_a <= 0.0 {
+            return;
+        }
+        let max_p = probs.iter().copied().fold(0., f32::max);
+        let threshold = self.top_a as f32 * max_p * max_p;
+        for p in probs.iter_mut().filter(|p| **p < threshold) {
+            *p = 0.0;
+        }
+    }
+
+    /// Mirostat v2: sample from the tokens no more surprising than `mu`, then move `mu` so that the surprise
+    /// of the sampled token approaches the target.
+    fn sample_mirostat(
+        &self,
+        probs: &mut [f32],
+        params: &MirostatParams,
+        mu: &Mutex<f32>,
+        rng: Arc<Mutex<Isaac64Rng>>,
+    ) -> Result<u32> {
+        let mut mu = mu.lock().expect("could not lock mirostat mutex");
+
+        // Clamp the more surprising tokens to zero, always keeping the most likely one.
+        let max_p = probs.iter().copied().fold(0., f32::max);
+        for p in probs.iter_mut().filter(|p| **p < max_p && -p.log2() > *mu) {
+            *p = 0.0;
+        }
+
+        let next_token = self.sample_multinomial(probs, rng)?;
+        let surprise = -(probs[next_token as usize] / probs.iter().sum::<f32>()).log2();
+        *mu -= params.eta * (surprise - params.tau);
+        Ok(next_token)
+    }
+
+    /// The initial Mirostat state of a sequence, if Mirostat is used.
+    pub fn new_mirostat_state(&self) -> Option<Arc<Mutex<f32>>> {
+        self.mirostat
+            .as_ref()
+            .map(|params| Arc::new(Mutex::new(2. * params.tau)))
+    }
+
+    /// Apply the logits bias, then the custom logits processors, then the penalties to the logits. `context` holds
+    /// the prompt and completion tokens, of which the first `prompt_len` are the prompt. The frequency and presence
+    /// penalties only count completion tokens.
+    fn apply_penalties(
+        &self,
+        mut logits: Vec<f32>,
+        context: Option<&[u32]>,
+        prompt_len: usize,
+    ) -> Result<Tensor> {
+        if let Some(logits_bias) = &self.logits_bias {
+            for (tok, bias) in logits_bias {
+                if let Some(logit) = logits.get_mut(*tok as usize) {
+                    *logit += bias;
+                }
             }
         }
+        let has_penalties = self.frequency_penalty.is_some()
+            || self.presence_penalty.is_some()
+            || self.repetition_penalty.is_some()
+            || self.no_repeat_ngram_size.is_some()
+            || self.dry_params.is_some();
+        if has_penalties || !self.logits_processors.is_empty() {
+            let Some(context) = context else {
+                bail!("Must specify penalty context.");
+            };
+            for processor in &self.logits_processors {
+                processor.apply(&mut logits, context)?;
+            }
+            self.apply_freq_presence_penalties(&mut logits, &context[prompt_len..]);
+            self.apply_repetition_penalty(&mut logits, context);
+            self.apply_no_repeat_ngram(&mut logits, context);
+            self.apply_dry_penalty(&mut logits, context);
+        }
         let vocab_size = logits.len();
         Tensor::from_vec(logits, vocab_size, &Device::Cpu)
     }
 
+    fn apply_freq_presence_penalties(&self, logits: &mut [f32], context: &[u32]) {
+        if self.frequency_penalty.is_none() && self.presence_penalty.is_none() {
+            return;
+        }
+        let frequency_penalty = self.frequency_penalty.unwrap_or(0.);
+        let presence_penalty = self.presence_penalty.unwrap_or(0.);
+
+        //mu[j] -> mu[j] - c[j] * alpha_frequency - float(c[j] > 0) * alpha_presence
+
+        let mut counts = vec![0.0f32; logits.len()];
+        for ctx in context.iter() {
+            counts[*ctx as usize] += 1.0;
+        }
+
+        for (token_id, logit) in logits.iter_mut().enumerate() {
+            let count = counts[token_id];
+            *logit = *logit
+                - count * frequency_penalty
+                - if count > 0.0 { 1. } else { 0. } * presence_penalty;
+        }
+    }
+
+    fn apply_repetition_penalty(&self, logits: &mut [f32], context: &[u32]) {
+        let Some(penalty) = self.repetition_penalty else {
+            return;
+        };
+        let mut seen = vec![false; logits.len()];
+        for tok in context {
+            seen[*tok as usize] = true;
+        }
+        // Both positive and negative logits are moved towards being less likely.
+        for (logit, _) in logits.iter_mut().zip(seen).filter(|(_, seen)| *seen) {
+            if *logit < 0. {
+                *logit *= penalty;
+            } else {
+                *logit /= penalty;
+            }
+        }
+    }
+
+    fn apply_no_repeat_ngram(&self, logits: &mut [f32], context: &[u32]) {
+        let Some(n) = self.no_repeat_ngram_size.filter(|n| *n > 0) else {
+            return;
+        };
+        if context.len() + 1 < n {
+            return;
+        }
+        // Ban the tokens which follow an earlier occurrence of the last `n - 1` tokens.
+        let prefix = &context[context.len() + 1 - n..];
+        for ngram in context.windows(n) {
+            if &ngram[..n - 1] == prefix {
+                logits[ngram[n - 1] as usize] = f32::NEG_INFINITY;
+            }
+        }
+    }
+
+    fn apply_dry_penalty(&self, logits: &mut [f32], context: &[u32]) {
+        let Some(params) = &self.dry_params else {
+            return;
+        };
+        let Some(&last) = context.last() else {
+            return;
+        };
+        if params.multiplier == 0. || params.sequence_breakers.contains(&last) {
+            return;
+        }
+
+        // For each earlier occurrence of the last token, the token after it would extend the repetition of the
+        // tokens up to it. Find the longest such repetition for each token.
+        let mut match_lengths = HashMap::new();
+        for (i, _) in context[..context.len() - 1]
+            .iter()
+            .enumerate()
+            .filter(|(_, tok)| **tok == last)
+        {
+            let next = context[i + 1];
+            if params.sequence_breakers.contains(&next) {
+                continue;
+            }
+            let mut match_length = 1;
+            while match_length <= i {
+                let previous = context[context.len() - 1 - match_length];
+                if context[i - match_length] != previous
+                    || params.sequence_breakers.contains(&previous)
+                {
+                    break;
+                }
+                match_length += 1;
+            }
+            let longest = match_lengths.entry(next).or_insert(0);
+            *longest = match_length.max(*longest);
+        }
+
+        for (tok, match_length) in match_lengths {
+            if match_length >= params.allowed_length {
+                let exponent = (match_length - params.allowed_length) as f32;
+                logits[tok as usize] -= params.multiplier * params.base.powf(exponent);
+            }
+        }
+    }
+
+    /// Get the `n` most likely next tokens for beam search, each with the natural log probability used to rank it.
+    ///
+    /// Penalties are applied, but the temperature and top-k/top-p/min-p settings are not, as beam search is deterministic.
+    /// The reported logprobs are those of the penalized distribution if `processed_logprobs` is set, and of the raw logits otherwise.
+    pub fn beam_search_candidates(
+        &self,
+        logits: Tensor,
+        penalty_ctxt: Option<&[u32]>,
+        prompt_len: usize,
+        n: usize,
+        return_logprobs: bool,
+    ) -> Result<Vec<(f32, Logprobs)>> {
+        let raw_logits: Vec<f32> = logits.to_vec1()?;
+        let logits = self.apply_penalties(raw_logits.clone(), penalty_ctxt, prompt_len)?;
+        let search_logprobs: Vec<f32> =
+            candle_nn::ops::log_softmax(&logits, D::Minus1)?.to_vec1()?;
+        let logprobs = if self.processed_logprobs {
+            search_logprobs.clone()
+        } else {
+            log_softmax(&raw_logits)
+        };
+
+        let mut argsort_indices = (0..search_logprobs.len()).collect::<Vec<_>>();
+        // Sort by descending probability.
+        argsort_indices.sort_unstable_by(|&i, &j| {
+            search_logprobs[j]
+                .partial_cmp(&search_logprobs[i])
+                .expect("No ordering.")
+        });
+
+        let top_logprobs = if return_logprobs {
+            Some(self.get_top_logprobs(&logprobs)?)
+        } else {
+            None
+        };
+
+        argsort_indices
+            .into_iter()
+            .take(n)
+            .map(|token| {
+                Ok((
+                    search_logprobs[token],
+                    Logprobs {
+                        token: token as u32,
+                        logprob: logprobs[token],
+                        top_logprobs: top_logprobs.clone(),
+                        bytes: self
+                            .tokenizer
+                            .decode(&[token as u32], false)
+                            .map_err(|x| Error::Msg(x.to_string()))?,
+                    },
+                ))
+            })
+            .collect()
+    }
+
     /// Sample the provided tokens.
     ///
     /// If the temperature is `None`, argmax sampling is used. Otherwise, the selected sampling is used.
     /// With `top-p` sampling, if the `top-p` value is `<= 0.0` or `>= 1.0`, multinomial sampling is used.
-    /// If `frequency_penalty.is_some()` or `presence_penalty.is_some()`, then `penalty_ctxt` must be provided.
+    /// If any penalty or logits processor is set, then `penalty_ctxt` must be provided. It holds the prompt and completion tokens, of
+    /// which the first `prompt_len` are the prompt.
+    ///
+    /// Typical-p and top-a are applied before the other settings. Mirostat, which needs the sequence's `mirostat_mu`,
+    /// replaces the top-k/top-p/min-p settings when not sampling speculatively.
+    ///
+    /// The returned logprobs are natural log-softmax values of the provided logits. If `processed_logprobs` is set,
+    /// they are instead those of the distribution actually sampled from, after penalties, temperature and
+    /// top-k/top-p/min-p filtering.
     pub fn sample(
         &self,
         logits: Tensor,
         penalty_ctxt: Option<&[u32]>,
+        prompt_len: usize,
         return_logprobs: bool,
         rng: Arc<Mutex<Isaac64Rng>>,
+        mirostat_mu: Option<Arc<Mutex<f32>>>,
         sample_speculative: bool,
     ) -> Result<Logprobs> {
-        let logits = self.apply_penalties(logits.to_vec1()?, penalty_ctxt)?;
-        let next_token = if sample_speculative {
-            match self.temperature {
-                None => self.sample_speculative_top_kp_min_p(
-                    logits,
-                    return_logprobs,
-                    self.top_k,
-                    self.top_p as f32,
-                    self.min_p as f32,
-                )?,
-                Some(temperature) => {
-                    let logits = (&logits / temperature)?;
-                    let probs = candle_nn::ops::softmax_last_dim(&logits)?;
+        let raw_logits: Vec<f32> = logits.to_vec1()?;
+        let logits = self.apply_penalties(raw_logits.clone(), penalty_ctxt, prompt_len)?;
+        let (next_token, probs) = match self.temperature {
+            None if !sample_speculative => {
+                let next_token = argmax_sample_last_dim(&logits)?.to_scalar::<u32>()?;
+                let probs = if self.processed_logprobs {
+                    candle_nn::ops::softmax_last_dim(&logits)?.to_vec1()?
+                } else {
+                    Vec::new()
+                };
+                (next_token, probs)
+            }
+            temperature => {
+                let logits = (&logits / temperature.unwrap_or(1.))?;
+                let mut probs: Vec<f32> = candle_nn::ops::softmax_last_dim(&logits)?.to_vec1()?;
+                self.apply_typical_p(&mut probs);
+                self.apply_top_a(&mut probs);
 
+                let next_token = if let (Some(params), Some(mu), false) =
+                    (&self.mirostat, mirostat_mu, sample_speculative)
+                {
+                    self.sample_mirostat(&mut probs, params, &mu, rng)?
+                } else if sample_speculative {
                     self.sample_speculative_top_kp_min_p(
-                        probs,
-                        return_logprobs,
+                        &mut probs,
                         self.top_k,
                         self.top_p as f32,
                         self.min_p as f32,
                     )?
-                }
-            }
-        } else {
-            match self.temperature {
-                None => self.sample_argmax(logits, return_logprobs)?,
-                Some(temperature) => {
-                    let logits = (&logits / temperature)?;
-                    let probs = candle_nn::ops::softmax_last_dim(&logits)?;
-                    let mut probs: Vec<f32> = probs.to_vec1()?;
-
+                } else {
                     self.sample_top_kp_min_p(
                         &mut probs,
                         self.top_k,
                         self.top_p as f32,
                         self.min_p as f32,
-                        return_logprobs,
                         rng,
                     )?
-                }
+                };
+                (next_token, probs)
             }
         };
-        Ok(next_token)
+
+        let logprobs = if self.processed_logprobs {
+            // Renormalize, as the filtered out tokens were clamped to zero.
+            let total = probs.iter().sum::<f32>();
+            probs.iter().map(|p| (p / total).ln()).collect::<Vec<_>>()
+        } else {
+            log_softmax(&raw_logits)
+        };
+        self.get_logprobs(next_token, &logprobs, return_logprobs)
+    }
+
+    /// Natural log probabilities of the tokens under the distribution given by `logits`, before penalties or
+    /// sampling settings are applied.
+    pub fn raw_logprobs(
+        &self,
+        logits: &Tensor,
+        next_token: u32,
+        return_logprobs: bool,
+    ) -> Result<Logprobs> {
+        self.get_logprobs(
+            next_token,
+            &log_softmax(&logits.to_vec1()?),
+            return_logprobs,
+        )
+    }
+
+    /// Whether the reported logprobs are those of the processed distribution instead of the raw logits.
+    pub fn processed_logprobs(&self) -> bool {
+        self.processed_logprobs
     }
 }
 
+/// Numerically stable natural log-softmax.
+fn log_softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let log_sum_exp = logits.iter().map(|x| (x - max).exp()).sum::<f32>().ln() + max;
+    logits.iter().map(|x| x - log_sum_exp).collect()
+}
+
+#[cfg(test)]
 mod tests {
+    use super::{DrySamplingParams, MirostatParams, Sampler};
+    use candle_core::{Device, Result, Tensor};
     use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
+    use rand::SeedableRng;
+    use rand_isaac::Isaac64Rng;
+    use std::sync::{Arc, Mutex};
     use tokenizers::Tokenizer;
 
-    #[allow(dead_code)]
     fn get_tokenizer() -> Tokenizer {
         let api = ApiBuilder::new().with_progress(true).build().unwrap();
         let api = api.repo(Repo::with_revision(
@@ -459,39 +844,258 @@ mod tests {
         Tokenizer::from_file(tokenizer_filename).unwrap()
     }
 
+    /// The parameters of a [`Sampler`] which the tests change, the others are the same for all tests.
+    struct TestSampler {
+        temperature: Option<f64>,
+        repetition_penalty: Option<f32>,
+        no_repeat_ngram_size: Option<usize>,
+        dry_params: Option<DrySamplingParams>,
+        typical_p: f64,
+        top_a: f64,
+        mirostat: Option<MirostatParams>,
+        processed_logprobs: bool,
+    }
+
+    impl Default for TestSampler {
+        fn default() -> Self {
+            Self {
+                temperature: None,
+                repetition_penalty: None,
+                no_repeat_ngram_size: None,
+                dry_params: None,
+                typical_p: 0.,
+                top_a: 0.,
+                mirostat: None,
+                processed_logprobs: false,
+            }
+        }
+    }
+
+    impl TestSampler {
+        fn build(self) -> Result<Sampler> {
+            Sampler::new(
+                self.temperature,
+                10,
+                get_tokenizer().into(),
+                None,
+                None,
+                self.repetition_penalty,
+                self.no_repeat_ngram_size,
+                self.dry_params,
+                None,
+                Vec::new(),
+                32,
+                0.1,
+                0.05,
+                self.typical_p,
+                self.top_a,
+                self.mirostat,
+                self.processed_logprobs,
+            )
+        }
+    }
+
+    /// Natural logprob of the last token when the logits are `0..1024`.
+    fn expected_logprob() -> f32 {
+        -(0..1024)
+            .map(|x| f64::from(x - 1023).exp())
+            .sum::<f64>()
+            .ln() as f32
+    }
+
     #[test]
     fn test_argmax() {
-        use super::Sampler;
-        use candle_core::{Device, Tensor};
-        use rand::SeedableRng;
-        use rand_isaac::Isaac64Rng;
-        use std::sync::Arc;
-        use std::sync::Mutex;
-
-        let sampler = Sampler::new(None, 10, get_tokenizer().into(), None, None, 32, 0.1, 0.05);
+        let sampler = TestSampler::default().build().unwrap();
         let logits = Tensor::arange(0f32, 1024f32, &Device::Cpu).unwrap();
         let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
-        let res = sampler.sample(logits, None, false, rng, false).unwrap();
+        let res = sampler
+            .sample(logits, None, 0, false, rng, None, false)
+            .unwrap();
         assert_eq!(res.token, 1023);
         assert_eq!(res.top_logprobs, None);
-        assert_eq!(res.logprob, 1023f64.log(10.) as f32)
+        assert!((res.logprob - expected_logprob()).abs() < 1e-5)
     }
 
     #[test]
     fn test_gumbel_speculative() {
-        use super::Sampler;
-        use candle_core::{Device, Tensor};
-        use rand::SeedableRng;
-        use rand_isaac::Isaac64Rng;
-        use std::sync::Arc;
-        use std::sync::Mutex;
-
-        let sampler = Sampler::new(None, 10, get_tokenizer().into(), None, None, 32, 0.1, 0.05);
+        let sampler = TestSampler::default().build().unwrap();
         let logits = Tensor::arange(0f32, 1024f32, &Device::Cpu).unwrap();
         let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
-        let res = sampler.sample(logits, None, false, rng, true).unwrap();
+        let res = sampler
+            .sample(logits, None, 0, false, rng, None, true)
+            .unwrap();
         assert_eq!(res.token, 1023);
         assert_eq!(res.top_logprobs, None);
-        assert_eq!(res.logprob, 1023f64.log(10.) as f32)
+        assert!((res.logprob - expected_logprob()).abs() < 1e-5)
+    }
+
+    #[test]
+    fn test_processed_logprobs() {
+        let sampler = TestSampler {
+            temperature: Some(1.0),
+            processed_logprobs: true,
+            ..Default::default()
+        }
+        .build()
+        .unwrap();
+        let logits = Tensor::arange(0f32, 1024f32, &Device::Cpu).unwrap();
+        let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+        let res = sampler
+            .sample(logits, None, 0, true, rng, None, true)
+            .unwrap();
+        assert_eq!(res.token, 1023);
+        // Top-p leaves only the most likely token.
+        assert_eq!(res.logprob, 0.);
+        assert_eq!(res.top_logprobs.unwrap()[0].token, 1023);
+    }
+
+    #[test]
+    fn test_no_repeat_ngram() {
+        let sampler = TestSampler {
+            no_repeat_ngram_size: Some(2),
+            ..Default::default()
+        }
+        .build()
+        .unwrap();
+        let logits = Tensor::arange(0f32, 1024f32, &Device::Cpu).unwrap();
+        let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+        // `7 1023` was already generated, so it may not follow `7` again.
+        let res = sampler
+            .sample(logits, Some(&[7, 1023, 7]), 1, false, rng, None, false)
+            .unwrap();
+        assert_eq!(res.token, 1022);
+    }
+
+    #[test]
+    fn test_repetition_penalty() {
+        let sampler = TestSampler {
+            repetition_penalty: Some(2.),
+            ..Default::default()
+        }
+        .build()
+        .unwrap();
+        // Positive logits are divided and negative ones multiplied by the penalty.
+        let mut logits = vec![-1., 2., 3.];
+        sampler.apply_repetition_penalty(&mut logits, &[0, 1, 1]);
+        assert_eq!(logits, vec![-2., 1., 3.]);
+
+        let logits = Tensor::arange(0f32, 1024f32, &Device::Cpu).unwrap();
+        let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+        // The prompt counts as well as the completion.
+        let res = sampler
+            .sample(logits, Some(&[1023]), 1, false, rng, None, false)
+            .unwrap();
+        assert_eq!(res.token, 1022);
+
+        for penalty in [0., -1.] {
+            assert!(TestSampler {
+                repetition_penalty: Some(penalty),
+                ..Default::default()
+            }
+            .build()
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn test_dry() {
+        let tokenizer = get_tokenizer();
+        let newline = *tokenizer
+            .encode("\n", false)
+            .unwrap()
+            .get_ids()
+            .last()
+            .unwrap();
+        let sampler = TestSampler {
+            dry_params: Some(DrySamplingParams {
+                multiplier: 1.,
+                base: 2.,
+                allowed_length: 2,
+                sequence_breakers: vec!["\n".to_string()],
+            }),
+            ..Default::default()
+        }
+        .build()
+        .unwrap();
+        // Only the last token of a breaker is used.
+        assert_eq!(
+            sampler.dry_params.as_ref().unwrap().sequence_breakers,
+            [newline].into()
+        );
+
+        // `5 6 7` repeats, so `8` would extend a repetition of 3 tokens.
+        let mut logits = vec![0.; 16];
+        sampler.apply_dry_penalty(&mut logits, &[5, 6, 7, 8, 5, 6, 7]);
+        let mut expected = vec![0.; 16];
+        expected[8] = -2.;
+        assert_eq!(logits, expected);
+
+        // The repetition is cut by the breaker, so it is shorter than the allowed length.
+        let mut logits = vec![0.; 16];
+        sampler.apply_dry_penalty(&mut logits, &[5, newline, 7, 8, 5, newline, 7]);
+        assert_eq!(logits, vec![0.; 16]);
+    }
+
+    #[test]
+    fn test_typical_p() {
+        let sampler = TestSampler {
+            temperature: Some(1.0),
+            typical_p: 0.2,
+            ..Default::default()
+        }
+        .build()
+        .unwrap();
+        // The entropy is about 1.14 nats, which is closest to the surprise of the second token.
+        let mut probs = vec![0.5, 0.3, 0.15, 0.05];
+        sampler.apply_typical_p(&mut probs);
+        assert_eq!(probs, vec![0., 0.3, 0., 0.]);
+    }
+
+    #[test]
+    fn test_top_a() {
+        let sampler = TestSampler {
+            temperature: Some(1.0),
+            top_a: 1.,
+            ..Default::default()
+        }
+        .build()
+        .unwrap();
+        // The threshold is 1 * 0.5^2.
+        let mut probs = vec![0.5, 0.3, 0.15, 0.05];
+        sampler.apply_top_a(&mut probs);
+        assert_eq!(probs, vec![0.5, 0.3, 0., 0.]);
+    }
+
+    #[test]
+    fn test_mirostat() {
+        let params = MirostatParams { tau: 1., eta: 0.5 };
+        let sampler = TestSampler {
+            temperature: Some(1.0),
+            mirostat: Some(params.clone()),
+            ..Default::default()
+        }
+        .build()
+        .unwrap();
+        let mu = sampler.new_mirostat_state().unwrap();
+        let other_mu = sampler.new_mirostat_state().unwrap();
+        assert_eq!(*mu.lock().unwrap(), 2.);
+
+        // With mu = 2 bits, the last two tokens are too surprising.
+        let mut probs = vec![0.5, 0.3, 0.15, 0.05];
+        let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+        let token = sampler
+            .sample_mirostat(&mut probs, &params, &mu, rng)
+            .unwrap();
+        assert_eq!(&probs[2..], &[0., 0.]);
+
+        // mu moves by eta times the difference between the surprise of the token and tau.
+        let surprise = match token {
+            0 => -(0.5f32 / 0.8).log2(),
+            1 => -(0.3f32 / 0.8).log2(),
+            _ => panic!("Sampled a truncated token."),
+        };
+        assert!((*mu.lock().unwrap() - (2. - 0.5 * (surprise - 1.))).abs() < 1e-5);
+        // Each sequence has its own mu.
+        assert_eq!(*other_mu.lock().unwrap(), 2.);
     }
 }
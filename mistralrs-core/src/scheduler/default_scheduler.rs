@@ -1,4 +1,5 @@
 use std::{
+    cmp::Reverse,
     collections::{HashMap, VecDeque},
     num::NonZeroUsize,
     sync::atomic::Ordering,
@@ -51,6 +52,15 @@ pub struct DefaultSchedulerOutput<'a> {
 #[derive(Clone)]
 pub enum DefaultSchedulerMethod {
     Fixed(NonZeroUsize),
+    /// Run at most `max_seqs` sequences, scheduling requests with a higher [`crate::NormalRequest::priority`]
+    /// first. Running sequences of a lower priority are preempted (moved back to the waiting list, keeping their
+    /// cache) to make room for them. Within a priority, the running sequences are shared between tenants in
+    /// proportion to their weight. Tenants which are not in `tenant_weights` (and requests without a tenant)
+    /// have a weight of 1.
+    Priority {
+        max_seqs: NonZeroUsize,
+        tenant_weights: HashMap<String, f64>,
+    },
 }
 
 pub struct BucketedSeqs<Backer: FcfsBacker> {
@@ -68,9 +78,10 @@ pub trait BucketingManager<Backer: FcfsBacker> {
     ) -> BucketedSeqs<Backer>;
 }
 
-// (adapters, cache length, (has_imgs && is_prompt))
+// (adapters, cache length, (has_imgs && is_prompt), prompt chunk offset)
 // Buckey by that metric for images because if we are not a prompt, then this doesn't apply
-type BucketKey = (Option<Vec<String>>, usize, bool);
+// Bucket by the prompt chunk offset so sequences in the middle of a chunked prefill only run with each other
+type BucketKey = (Option<Vec<String>>, usize, bool, usize);
 
 struct FixedBucketingManager;
 
@@ -93,6 +104,7 @@ impl<Backer: FcfsBacker> BucketingManager<Backer> for FixedBucketingManager {
                 seq.get_adapters(),
                 len,
                 seq.images().is_some() && seq.is_prompt(),
+                seq.prompt_chunk_offset(),
             )) {
                 Some(bucket) => {
                     if !discrete {
@@ -101,6 +113,7 @@ impl<Backer: FcfsBacker> BucketingManager<Backer> for FixedBucketingManager {
                                 seq.get_adapters(),
                                 len,
                                 seq.images().is_some() && seq.is_prompt(),
+                                seq.prompt_chunk_offset(),
                             ))
                             .unwrap() += seq.compute_priority();
                     }
@@ -113,6 +126,7 @@ impl<Backer: FcfsBacker> BucketingManager<Backer> for FixedBucketingManager {
                                 seq.get_adapters(),
                                 len,
                                 seq.images().is_some() && seq.is_prompt(),
+                                seq.prompt_chunk_offset(),
                             ),
                             seq.compute_priority(),
                         );
@@ -122,6 +136,7 @@ impl<Backer: FcfsBacker> BucketingManager<Backer> for FixedBucketingManager {
                             seq.get_adapters(),
                             len,
                             seq.images().is_some() && seq.is_prompt(),
+                            seq.prompt_chunk_offset(),
                         ),
                         vec![seq],
                     );
@@ -140,7 +155,7 @@ impl<Backer: FcfsBacker> BucketingManager<Backer> for FixedBucketingManager {
             // Allow the min seqs to catch up.
             let min = seq_buckets
                 .keys()
-                .min_by_key(|(_, x, _)| *x)
+                .min_by_key(|(_, x, _, _)| *x)
                 .expect("No sequence buckets.")
                 .clone();
             let len = if !discrete {
@@ -171,6 +186,155 @@ impl<Backer: FcfsBacker> BucketingManager<Backer> for FixedBucketingManager {
     }
 }
 
+struct PriorityBucketingManager;
+
+impl<Backer: FcfsBacker> BucketingManager<Backer> for PriorityBucketingManager {
+    /// Move the sequences into buckets, and run the bucket holding the highest priority request. Ties are
+    /// broken like [`FixedBucketingManager`]. The others are moved to the waiting list without a state modification.
+    fn bucket_and_waitlist_seqs_waiting(
+        &mut self,
+        running: Vec<Sequence>,
+        mut waiting: Backer,
+        discrete: bool,
+    ) -> BucketedSeqs<Backer> {
+        let mut seq_buckets: HashMap<BucketKey, Vec<Sequence>> = HashMap::new();
+        for seq in running {
+            seq_buckets
+                .entry((
+                    seq.get_adapters(),
+                    seq.len(),
+                    seq.images().is_some() && seq.is_prompt(),
+                    seq.prompt_chunk_offset(),
+                ))
+                .or_default()
+                .push(seq);
+        }
+        if seq_buckets.len() <= 1 {
+            let running = seq_buckets
+                .into_iter()
+                .flat_map(|(_, x)| x)
+                .map(|s| s.reset_urgency())
+                .collect();
+            return BucketedSeqs { running, waiting };
+        }
+
+        let request_priority =
+            |seqs: &[Sequence]| seqs.iter().map(|seq| seq.request_priority()).max();
+        let urgency =
+            |seqs: &[Sequence]| seqs.iter().map(|seq| seq.compute_priority()).sum::<f64>();
+        let key = seq_buckets
+            .iter()
+            .max_by(|(key_a, a), (key_b, b)| {
+                request_priority(a).cmp(&request_priority(b)).then_with(|| {
+                    if discrete {
+                        key_b.1.cmp(&key_a.1)
+                    } else {
+                        urgency(a).partial_cmp(&urgency(b)).unwrap()
+                    }
+                })
+            })
+            .map(|(key, _)| key.clone())
+            .expect("No sequence buckets.");
+        let running = seq_buckets
+            .remove(&key)
+            .unwrap()
+            .into_iter()
+            .map(|s| s.reset_urgency())
+            .collect();
+        for (_, seqs) in seq_buckets {
+            for seq in seqs {
+                waiting.add(seq.add_urgency());
+            }
+        }
+        BucketedSeqs { running, waiting }
+    }
+}
+
+/// Choose which sequences run with [`DefaultSchedulerMethod::Priority`], returning the running and waiting sequences.
+///
+/// Sequences are admitted from the highest priority down. Within a priority, the tenant with the fewest admitted
+/// sequences relative to its weight goes next, taking its sequences which have already started before the others,
+/// oldest first. Started sequences which are not admitted are preempted: they wait with their state and cache intact.
+fn select_by_priority<Backer: FcfsBacker>(
+    running: Vec<Sequence>,
+    waiting: Backer,
+    max_seqs: usize,
+    tenant_weights: &HashMap<String, f64>,
+) -> (Vec<Sequence>, Backer) {
+    // The beams of a beam search request are admitted or preempted together.
+    let mut units: Vec<Vec<Sequence>> = Vec::new();
+    let mut beam_units: HashMap<usize, usize> = HashMap::new();
+    for seq in running.into_iter().chain(waiting.into_iter()) {
+        if seq.beam_width().is_some() {
+            if let Some(i) = beam_units.get(&seq.request_id()) {
+                units[*i].push(seq);
+                continue;
+            }
+            beam_units.insert(seq.request_id(), units.len());
+        }
+        units.push(vec![seq]);
+    }
+    units.sort_by_key(|unit| {
+        (
+            Reverse(unit[0].request_priority()),
+            unit[0].is_waiting(),
+            unit.iter().map(|seq| *seq.id()).min(),
+        )
+    });
+
+    let mut selected = Vec::new();
+    let mut new_waiting = Backer::new();
+    let mut admitted: HashMap<Option<String>, usize> = HashMap::new();
+    let mut units = units.into_iter().peekable();
+    while let Some(first) = units.next() {
+        // Queue the units of this priority by tenant, in order of the tenants' first unit.
+        let priority = first[0].request_priority();
+        let mut tenants: Vec<(Option<String>, VecDeque<Vec<Sequence>>)> = Vec::new();
+        for unit in std::iter::once(first).chain(std::iter::from_fn(|| {
+            units.next_if(|unit| unit[0].request_priority() == priority)
+        })) {
+            let tenant = unit[0].tenant().map(ToString::to_string);
+            match tenants.iter_mut().find(|(t, _)| *t == tenant) {
+                Some((_, queue)) => queue.push_back(unit),
+                None => tenants.push((tenant, VecDeque::from([unit]))),
+            }
+        }
+
+        loop {
+            #[allow(clippy::cast_precision_loss)]
+            let share = |tenant: &Option<String>| {
+                let weight = tenant
+                    .as_ref()
+                    .and_then(|t| tenant_weights.get(t))
+                    .copied()
+                    .unwrap_or(1.);
+                admitted.get(tenant).copied().unwrap_or(0) as f64 / weight
+            };
+            let Some((tenant, queue)) = tenants
+                .iter_mut()
+                .filter(|(_, queue)| !queue.is_empty())
+                .min_by(|(a, _), (b, _)| share(a).partial_cmp(&share(b)).unwrap())
+            else {
+                break;
+            };
+            let unit = queue.pop_front().unwrap();
+            if selected.len() + unit.len() <= max_seqs {
+                *admitted.entry(tenant.clone()).or_default() += unit.len();
+                selected.extend(unit);
+            } else {
+                unit.into_iter().for_each(|seq| new_waiting.add(seq));
+            }
+        }
+    }
+
+    for seq in &selected {
+        if seq.is_waiting() {
+            seq.set_state(SequenceState::RunningPrompt);
+        }
+    }
+    (selected, new_waiting)
+}
+
 pub struct DefaultScheduler<Backer: FcfsBacker> {
     waiting: Backer,
     running: Vec<Sequence>,
@@ -182,6 +346,7 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
     pub fn new(method: DefaultSchedulerMethod) -> Self {
         let bucketing_manager: Box<dyn BucketingManager<_>> = match method {
             DefaultSchedulerMethod::Fixed(_) => Box::new(FixedBucketingManager),
+            DefaultSchedulerMethod::Priority { .. } => Box::new(PriorityBucketingManager),
         };
         Self {
             running: Vec::new(),
@@ -213,6 +378,22 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
             .filter(|seq| seq.is_running())
             .collect::<Vec<_>>();
 
+        if let DefaultSchedulerMethod::Priority {
+            max_seqs,
+            tenant_weights,
+        } = &self.method
+        {
+            let (running, new_waiting) =
+                select_by_priority(running, waiting, (*max_seqs).into(), tenant_weights);
+            if TERMINATE_ALL_NEXT_STEP.load(Ordering::SeqCst) {
+                running
+                    .iter()
+                    .for_each(|seq| seq.set_state(SequenceState::Done(StopReason::Canceled)));
+                TERMINATE_ALL_NEXT_STEP.store(false, Ordering::SeqCst);
+            }
+            return self.bucket_and_split(running, new_waiting);
+        }
+
         match (waiting.len(), running.len()) {
             (0, 0) => {
                 self.running = running;
@@ -266,6 +447,15 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
             }
         }
 
+        self.bucket_and_split(running, new_waiting)
+    }
+
+    /// Bucket the sequences which may run, and split the running ones into prompt and completion sequences.
+    fn bucket_and_split(
+        &mut self,
+        running: Vec<Sequence>,
+        new_waiting: Backer,
+    ) -> DefaultSchedulerOutput {
         let BucketedSeqs {
             running,
             waiting: new_waiting,
@@ -292,9 +482,23 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
         }
     }
 
-    fn sequence_fits(&self, running: &[Sequence], _seq: &Sequence) -> bool {
+    fn sequence_fits(&self, running: &[Sequence], seq: &Sequence) -> bool {
+        // The beams of a beam search request must run together: the first beam reserves space for
+        // all of them, and the others (which directly follow it by id) are admitted with it.
+        let n_required = match seq.beam_width() {
+            Some(_) if seq.get_response_index() > 0 => {
+                return running
+                    .last()
+                    .is_some_and(|last| last.request_id() == seq.request_id());
+            }
+            Some(width) => width,
+            None => 1,
+        };
         match &self.method {
-            DefaultSchedulerMethod::Fixed(n) => (running.len() + 1) <= (*n).into(),
+            DefaultSchedulerMethod::Fixed(n)
+            | DefaultSchedulerMethod::Priority { max_seqs: n, .. } => {
+                (running.len() + n_required) <= (*n).into()
+            }
         }
     }
 }
@@ -308,6 +512,9 @@ impl Scheduler for DefaultScheduler<VecDeque<Sequence>> {
     fn waiting_len(&self) -> usize {
         self.waiting.len()
     }
+    fn running_len(&self) -> usize {
+        self.running.len()
+    }
     fn add_seq(&mut self, seq: Sequence) {
         if seq.is_running() {
             // prefill case
@@ -316,6 +523,14 @@ impl Scheduler for DefaultScheduler<VecDeque<Sequence>> {
             self.waiting.add(seq);
         }
     }
+    fn cancel_request(&mut self, request_id: usize) {
+        // Canceled running sequences (and their KV caches) are dropped on the next scheduling step.
+        self.running
+            .iter()
+            .filter(|seq| seq.request_id() == request_id)
+            .for_each(|seq| seq.set_state(SequenceState::Done(StopReason::Canceled)));
+        self.waiting.retain(|seq| seq.request_id() != request_id);
+    }
     fn block_tables(&self) -> Option<&BlockTables> {
         None
     }
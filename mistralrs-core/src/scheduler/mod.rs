@@ -1,5 +1,7 @@
 mod default_scheduler;
 
+use std::num::NonZeroUsize;
+
 pub use default_scheduler::{DefaultScheduler, DefaultSchedulerMethod, DefaultSchedulerOutput};
 
 use crate::{
@@ -22,14 +24,24 @@ pub enum SchedulerConfig {
 }
 
 impl SchedulerConfig {
-    pub fn into_scheduler(self) -> Box<dyn Scheduler> {
+    /// Build the scheduler. `prefill_chunk_size` is the maximum number of prompt tokens to run in one step.
+    /// `prefix_caching` enables sharing of prompt prefix blocks with PagedAttention.
+    pub fn into_scheduler(
+        self,
+        prefill_chunk_size: Option<NonZeroUsize>,
+        prefix_caching: bool,
+    ) -> Box<dyn Scheduler> {
         match self {
             Self::DefaultScheduler { method } => Box::new(DefaultScheduler::new(method)),
             Self::PagedAttentionMeta {
                 max_num_seqs,
                 config,
             } => Box::new(PagedAttentionScheduler::new(
-                PagedAttentionSchedulerConfig { max_num_seqs },
+                PagedAttentionSchedulerConfig {
+                    max_num_seqs,
+                    prefill_chunk_size,
+                    prefix_caching,
+                },
                 config,
             )),
         }
@@ -48,7 +60,10 @@ pub enum SchedulerOutput<'a> {
 pub trait Scheduler {
     fn schedule(&mut self) -> SchedulerOutput<'_>;
     fn waiting_len(&self) -> usize;
+    fn running_len(&self) -> usize;
     fn add_seq(&mut self, seq: Sequence);
+    /// Mark all sequences belonging to the request with this id as canceled, and free their resources.
+    fn cancel_request(&mut self, request_id: usize);
     /// This may do nothing. It depends on the implementation
     fn free_finished_sequence_groups(&mut self);
 
@@ -1,7 +1,7 @@
 use std::{
     fmt::Display,
     sync::{Arc, RwLock},
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 use tokio::sync::{
     mpsc::{error::SendError, Sender},
@@ -12,7 +12,7 @@ use crate::{
     aici::{cfg::CfgParser, recognizer::StackRecognizer, rx::RecRx, toktree::TokTrie},
     paged_attention::{BlockEngineSequence, LogicalTokenBlock},
     response::CompletionChoice,
-    tools::ToolCallingMatcher,
+    tools::{ToolCallDelta, ToolCallingMatcher},
     CompletionChunkChoice, CompletionChunkResponse, CompletionResponse,
 };
 use crate::{
@@ -23,6 +23,8 @@ use crate::{
     ChatCompletionResponse, Usage,
 };
 use candle_core::Tensor;
+use rand::SeedableRng;
+use rand_isaac::Isaac64Rng;
 use regex_automata::util::primitives::StateID;
 
 #[derive(Clone, Copy, PartialEq, Debug)]
@@ -143,9 +145,22 @@ impl SequenceCustomMetadata {
     }
 }
 
+/// The decoding state of a single beam, used to fork beams during beam search.
+#[derive(Clone)]
+pub(crate) struct BeamState {
+    tokens: Vec<u32>,
+    logprobs: Vec<Logprobs>,
+    cumulative_logprob: f32,
+    completion_bytes: Vec<u8>,
+    cache: LayerCaches,
+    xlora_cache: Option<LayerCaches>,
+    scaling_cache: Option<Tensor>,
+}
+
 pub struct Sequence {
     // Metadata, const
     id: usize,
+    request_id: usize,
     prompt_len: usize,
     max_len: Option<usize>,
     timestamp: u128,
@@ -162,6 +177,10 @@ pub struct Sequence {
     is_tmp: bool,
     adapters: Option<Vec<String>>,
     pub(crate) tok_trie: TokTrie,
+    priority: i32,
+    tenant: Option<String>,
+    rng: Option<Arc<std::sync::Mutex<Isaac64Rng>>>,
+    mirostat_mu: Option<Arc<std::sync::Mutex<f32>>>,
 
     // Cache
     scaling_cache: Option<Tensor>,
@@ -178,21 +197,30 @@ pub struct Sequence {
     last_is_done: Option<StopReason>,
     completion_bytes: Vec<u8>,
     stream_idx: usize,
+    // The number of logprobs already streamed, and the length in characters of the streamed text
+    logprobs_stream_idx: usize,
+    stream_text_offset: usize,
     pub recognizer: SequenceRecognizer,
     scheduling_urgency: usize, // The number of passes since scheduling
     input_images: Option<Vec<image::DynamicImage>>,
+    // Chunked prefill: the number of prompt tokens already in the KV cache, and the number to run in this
+    // step along with whether that leaves some of the prompt for later steps
+    prompt_chunk_offset: usize,
+    prompt_chunk: Option<(usize, bool)>,
 
     // GPU things
     pub prompt_tok_per_sec: f32,
     pub prompt_timestamp: Option<u128>,
+    last_token_time: Option<Instant>,
     group: Arc<Mutex<SequenceGroup>>,
     state: RwLock<SequenceState>,
 
     // Custom backend metadata
     custom_metadata: SequenceCustomMetadata,
 
-    // Tool calls
+    // Tool calls, and the length of the arguments already streamed for each call
     pub tools: Option<Arc<ToolCallingMatcher>>,
+    streamed_tool_calls: Vec<usize>,
 }
 
 impl BlockEngineSequence for Sequence {
@@ -221,6 +249,16 @@ impl BlockEngineSequence for Sequence {
             SequenceCustomMetadata::None => unreachable!(),
         }
     }
+
+    fn logical_token_blocks(&self) -> &[LogicalTokenBlock] {
+        match &self.custom_metadata {
+            SequenceCustomMetadata::PagedAttention {
+                logical_token_blocks,
+                block_size: _,
+            } => logical_token_blocks,
+            SequenceCustomMetadata::None => unreachable!(),
+        }
+    }
 }
 
 impl Sequence {
@@ -228,6 +266,7 @@ impl Sequence {
     pub fn new_waiting(
         tokens: Vec<u32>,
         id: usize,
+        request_id: usize,
         timestamp: u128,
         layers: usize,
         responder: Sender<Response>,
@@ -250,8 +289,14 @@ impl Sequence {
         //
         tok_trie: TokTrie,
         tools: Option<Arc<ToolCallingMatcher>>,
+        // Scheduling
+        priority: i32,
+        tenant: Option<String>,
+        // Sampling
+        seed: Option<u64>,
     ) -> Self {
         let prompt_len = tokens.len();
+        let mirostat_mu = sampler.new_mirostat_state();
         let mut custom_metadata = if let Some(block_size) = block_size {
             SequenceCustomMetadata::PagedAttention {
                 logical_token_blocks: Vec::new(),
@@ -267,6 +312,7 @@ impl Sequence {
             logprobs: Vec::new(),
             prompt_len,
             id,
+            request_id,
             timestamp,
             state: RwLock::new(SequenceState::Waiting),
             cache: vec![None; layers],
@@ -284,6 +330,7 @@ impl Sequence {
             return_logprobs,
             prompt_tok_per_sec: 0.,
             prompt_timestamp: None,
+            last_token_time: None,
             group,
             scaling_cache: None,
             response_index,
@@ -295,16 +342,25 @@ impl Sequence {
             cumulative_logprob: 0.,
             completion_bytes: Vec::new(),
             stream_idx: 0,
+            logprobs_stream_idx: 0,
+            stream_text_offset: 0,
             last_completion_bytes_len: 0,
             last_logprob: 0.0,
             last_is_done: None,
             is_tmp: false,
             scheduling_urgency: 0,
+            prompt_chunk_offset: 0,
+            prompt_chunk: None,
             adapters,
             input_images,
             custom_metadata,
             tok_trie,
             tools,
+            streamed_tool_calls: Vec::new(),
+            priority,
+            tenant,
+            rng: seed.map(|seed| Arc::new(std::sync::Mutex::new(Isaac64Rng::seed_from_u64(seed)))),
+            mirostat_mu,
         }
     }
 
@@ -318,6 +374,26 @@ impl Sequence {
         self
     }
 
+    /// The priority of the request this sequence belongs to. Higher values are scheduled first.
+    pub fn request_priority(&self) -> i32 {
+        self.priority
+    }
+
+    /// The tenant key used for fair sharing, if any.
+    pub fn tenant(&self) -> Option<&str> {
+        self.tenant.as_deref()
+    }
+
+    /// The RNG of this sequence if its request was seeded, used instead of the engine's shared RNG.
+    pub fn rng(&self) -> Option<Arc<std::sync::Mutex<Isaac64Rng>>> {
+        self.rng.clone()
+    }
+
+    /// The current Mirostat surprise threshold of this sequence, if its request uses Mirostat.
+    pub fn mirostat_mu(&self) -> Option<Arc<std::sync::Mutex<f32>>> {
+        self.mirostat_mu.clone()
+    }
+
     /// Simple metric: (scheduling urgency) + log2(length)
     /// Takes into account: urgency (scales linear) and length (scales logarithmic)
     /// Scaling urgency is the number of scheduling passes where we have not been scheduled.
@@ -366,6 +442,11 @@ impl Sequence {
         &self.id
     }
 
+    /// The id of the [`NormalRequest`](crate::NormalRequest) this sequence belongs to.
+    pub fn request_id(&self) -> usize {
+        self.request_id
+    }
+
     pub fn is_running(&self) -> bool {
         matches!(
             *self.state.read().unwrap(),
@@ -399,6 +480,7 @@ impl Sequence {
             SequenceState::FinishedAborted
                 | SequenceState::FinishedIgnored
                 | SequenceState::Done(_)
+                | SequenceState::Error
         )
     }
 
@@ -455,6 +537,51 @@ impl Sequence {
         self.sampler.clone()
     }
 
+    /// The number of prompt tokens which earlier steps of a chunked prefill have added to the KV cache.
+    pub fn prompt_chunk_offset(&self) -> usize {
+        self.prompt_chunk_offset
+    }
+
+    /// Only run the next `len` prompt tokens in the next prompt step, or all of them if `None`.
+    pub(crate) fn set_prompt_chunk_len(&mut self, len: Option<usize>) {
+        self.prompt_chunk = len.map(|len| {
+            let len = len.min(self.get_toks().len() - self.prompt_chunk_offset);
+            (len, self.prompt_chunk_offset + len < self.get_toks().len())
+        });
+    }
+
+    /// The offset and length of the prompt tokens to run in this step, if this is a chunked prefill.
+    pub(crate) fn prompt_chunk(&self) -> Option<(usize, usize)> {
+        self.prompt_chunk
+            .map(|(len, _)| (self.prompt_chunk_offset, len))
+    }
+
+    /// Skip the first `n` prompt tokens in the next prompt step, as their KV cache is held by blocks shared
+    /// with other sequences.
+    pub(crate) fn set_cached_prompt_toks(&mut self, n: usize) {
+        self.prompt_chunk_offset = n;
+        self.set_prompt_chunk_len((n > 0).then(|| self.get_toks().len() - n));
+    }
+
+    /// If this step runs a prompt chunk which does not reach the end of the prompt, there is nothing to sample.
+    pub(crate) fn is_partial_prompt_chunk(&self) -> bool {
+        self.prompt_chunk.is_some_and(|(_, is_partial)| is_partial)
+    }
+
+    /// Finish the prompt chunk of this step, returning whether the whole prompt has been run.
+    pub(crate) fn finish_prompt_chunk(&mut self) -> bool {
+        match self.prompt_chunk.take() {
+            Some((len, true)) => {
+                self.prompt_chunk_offset += len;
+                false
+            }
+            Some((_, false)) | None => {
+                self.prompt_chunk_offset = 0;
+                true
+            }
+        }
+    }
+
     /// Add a some prefill tokens. Only meant for internal speculative decoding usage.
     pub fn set_prefill_toks(&mut self, toks: Vec<u32>) {
         self.prefill_prompt_toks = Some(toks)
@@ -575,6 +702,43 @@ impl Sequence {
         &self.logprobs
     }
 
+    pub fn cumulative_logprob(&self) -> f32 {
+        self.cumulative_logprob
+    }
+
+    pub(crate) fn set_cumulative_logprob(&mut self, cumulative_logprob: f32) {
+        self.cumulative_logprob = cumulative_logprob;
+    }
+
+    /// The beam width if this sequence is one of the beams of a beam search group.
+    pub fn beam_width(&self) -> Option<usize> {
+        get_mut_group!(self).beam_search.as_ref().map(|b| b.width)
+    }
+
+    /// Snapshot the tokens and KV cache of this beam so another beam can be forked from it.
+    pub(crate) fn beam_state(&self) -> BeamState {
+        BeamState {
+            tokens: self.tokens.clone(),
+            logprobs: self.logprobs.clone(),
+            cumulative_logprob: self.cumulative_logprob,
+            completion_bytes: self.completion_bytes.clone(),
+            cache: self.cache.clone(),
+            xlora_cache: self.xlora_cache.clone(),
+            scaling_cache: self.scaling_cache.clone(),
+        }
+    }
+
+    /// Replace the tokens and KV cache of this beam with those of another beam.
+    pub(crate) fn restore_beam_state(&mut self, state: BeamState) {
+        self.tokens = state.tokens;
+        self.logprobs = state.logprobs;
+        self.cumulative_logprob = state.cumulative_logprob;
+        self.completion_bytes = state.completion_bytes;
+        self.cache = state.cache;
+        self.xlora_cache = state.xlora_cache;
+        self.scaling_cache = state.scaling_cache;
+    }
+
     pub fn return_logprobs(&self) -> bool {
         self.return_logprobs
     }
@@ -608,6 +772,62 @@ impl Sequence {
         Ok(Some(new_decoded.to_string()))
     }
 
+    /// Returns the parts of the tool calls which have not been streamed yet, or `None` if the output is not a
+    /// tool call and should be streamed as content instead. Calls are only streamed once their name is
+    /// complete, after which their arguments are streamed as they are generated.
+    pub fn get_tool_call_deltas(
+        &mut self,
+        is_done: Option<StopReason>,
+    ) -> Option<Vec<ToolCallDelta>> {
+        let matcher = self.tools.clone()?;
+        let text = String::from_utf8_lossy(&self.completion_bytes).to_string();
+        let text = match is_done {
+            Some(StopReason::StopString {
+                completion_bytes_pos,
+                ..
+            }) => &text[..completion_bytes_pos],
+            Some(_) => text.as_str(),
+            // The end may be part of a character which has not been generated completely
+            None => text.trim_end_matches('\u{FFFD}'),
+        };
+        let Some(calls) = matcher.get_partial_calls(text.trim_start()) else {
+            return (!self.streamed_tool_calls.is_empty()).then(Vec::new);
+        };
+        let mut deltas = Vec::new();
+        for (i, call) in calls.into_iter().enumerate() {
+            let Some(name) = call.name else {
+                break;
+            };
+            match self.streamed_tool_calls.get_mut(i) {
+                Some(sent) => {
+                    if let Some(arguments) = call.arguments.get(*sent..).filter(|a| !a.is_empty()) {
+                        deltas.push(ToolCallDelta::arguments(i, arguments.to_string()));
+                        *sent = call.arguments.len();
+                    }
+                }
+                None => {
+                    deltas.push(ToolCallDelta::start(i, name, call.arguments.to_string()));
+                    self.streamed_tool_calls.push(call.arguments.len());
+                }
+            }
+        }
+        // Output which turned out not to be a complete call is streamed as content
+        if is_done.is_some() && self.streamed_tool_calls.is_empty() {
+            return None;
+        }
+        Some(deltas)
+    }
+
+    /// Get the logprobs of the tokens which have not been streamed yet, along with the character offset
+    /// of the first one in the streamed text. `delta` is the text which is streamed with them.
+    pub fn get_delta_logprobs(&mut self, delta: &str) -> (&[Logprobs], usize) {
+        let start = self.logprobs_stream_idx;
+        let text_offset = self.stream_text_offset;
+        self.logprobs_stream_idx = self.logprobs.len();
+        self.stream_text_offset += delta.chars().count();
+        (&self.logprobs[start..], text_offset)
+    }
+
     pub fn timestamp(&self) -> u128 {
         self.timestamp
     }
@@ -616,6 +836,11 @@ impl Sequence {
         self.prompt_timestamp
     }
 
+    /// Set the time the last token was generated, returning the previous one.
+    pub(crate) fn replace_last_token_time(&mut self, time: Instant) -> Option<Instant> {
+        self.last_token_time.replace(time)
+    }
+
     fn update_time_info(&self) {
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
@@ -638,6 +863,13 @@ impl Sequence {
         self.update_time_info();
     }
 
+    /// The length in characters of the echoed prompt which precedes the completion text.
+    pub fn prefix_len(&self) -> usize {
+        self.prefix
+            .as_deref()
+            .map_or(0, |prefix| prefix.chars().count())
+    }
+
     pub fn add_completion_choice_to_group(&self, mut choice: CompletionChoice) {
         choice.text = format!(
             "{}{}{}",
@@ -651,6 +883,29 @@ impl Sequence {
         self.update_time_info();
     }
 
+    /// Add the best finished hypotheses of a beam search group as its choices.
+    pub fn add_beam_choices_to_group(&self, choices: Vec<Choice>) {
+        for choice in choices {
+            self.add_choice_to_group(choice);
+        }
+    }
+
+    /// Add the best finished hypotheses of a beam search group as its completion choices.
+    pub fn add_beam_completion_choices_to_group(&self, choices: Vec<(f32, CompletionChoice)>) {
+        for (score, mut choice) in choices {
+            choice.text = format!(
+                "{}{}{}",
+                self.prefix.as_deref().unwrap_or(""),
+                choice.text,
+                self.suffix.as_deref().unwrap_or("")
+            );
+            get_mut_group!(self)
+                .completion_choices
+                .push((score, choice));
+            self.update_time_info();
+        }
+    }
+
     pub fn get_response_index(&self) -> usize {
         self.response_index
     }
@@ -659,6 +914,12 @@ impl Sequence {
         get_mut_group!(self)
     }
 
+    /// Add the token counts and timing of a finished streamed sequence to its group.
+    pub fn add_streaming_usage_to_group(&self) {
+        self.update_time_info();
+        get_mut_group!(self).finished_streams += 1;
+    }
+
     pub fn add_streaming_chunk_choice_to_group(&self, chunk: ChunkChoice) {
         get_mut_group!(self).chat_streaming_chunks.push(chunk);
     }
@@ -680,6 +941,47 @@ impl Sequence {
     }
 }
 
+/// A beam which has finished, either by a stop condition or by reaching the length limit.
+#[derive(Clone)]
+pub struct BeamHypothesis {
+    pub score: f32,
+    pub logprobs: Vec<Logprobs>,
+    pub completion_bytes: Vec<u8>,
+    pub stop_reason: StopReason,
+}
+
+/// Beam search state shared by all beams of a group.
+pub struct BeamSearch {
+    pub width: usize,
+    pub length_penalty: f32,
+    pub finished: Vec<BeamHypothesis>,
+}
+
+impl BeamSearch {
+    pub fn new(width: usize, length_penalty: f32) -> Self {
+        Self {
+            width,
+            length_penalty,
+            finished: Vec::new(),
+        }
+    }
+
+    /// Length-normalized score: cumulative logprob / (generated length ^ length penalty).
+    pub fn score(&self, cumulative_logprob: f32, generated_len: usize) -> f32 {
+        #[allow(clippy::cast_precision_loss)]
+        let len = generated_len.max(1) as f32;
+        cumulative_logprob / len.powf(self.length_penalty)
+    }
+
+    /// The finished hypotheses, sorted by descending score.
+    pub fn best_hypotheses(&self, n: usize) -> Vec<BeamHypothesis> {
+        let mut finished = self.finished.clone();
+        finished.sort_by(|a, b| b.score.partial_cmp(&a.score).expect("No ordering."));
+        finished.truncate(n);
+        finished
+    }
+}
+
 pub struct SequenceGroup {
     n_choices: usize, // The target number of choices to return. Can be decreased if an error is thrown.
     best_of: usize,   // Top n seqs based on cumulative logprobs.
@@ -694,6 +996,10 @@ pub struct SequenceGroup {
     pub completion_streaming_chunks: Vec<CompletionChunkChoice>,
     pub is_streaming: bool,
     pub is_chat: bool,
+    pub beam_search: Option<BeamSearch>,
+    /// Send a final chunk with the usage once all streamed sequences are finished.
+    pub include_usage: bool,
+    finished_streams: usize,
 }
 
 impl SequenceGroup {
@@ -712,9 +1018,16 @@ impl SequenceGroup {
             is_streaming,
             is_chat,
             best_of,
+            beam_search: None,
+            include_usage: false,
+            finished_streams: 0,
         }
     }
 
+    pub fn n_choices(&self) -> usize {
+        self.n_choices
+    }
+
     /// This does not apply best_of.
     pub fn get_choices(&self) -> &[Choice] {
         &self.choices
@@ -780,6 +1093,7 @@ impl SequenceGroup {
                     model: model.clone(),
                     system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
                     object: "chat.completion.chunk".to_string(),
+                    usage: None,
                 }))
                 .await?;
         } else if self.completion_streaming_chunks.len() == self.n_choices && self.is_streaming {
@@ -798,9 +1112,38 @@ impl SequenceGroup {
                     model: model.clone(),
                     system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
                     object: "text_completion".to_string(),
+                    usage: None,
                 }))
                 .await?;
         }
+
+        if self.include_usage && self.is_streaming && self.finished_streams == self.n_choices {
+            // Only send the usage once.
+            self.include_usage = false;
+            let usage = Some(self.get_usage());
+            let response = if self.is_chat {
+                Response::Chunk(ChatCompletionChunkResponse {
+                    id: seq.id.to_string(),
+                    choices: vec![],
+                    created: seq.timestamp,
+                    model,
+                    system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
+                    object: "chat.completion.chunk".to_string(),
+                    usage,
+                })
+            } else {
+                Response::CompletionChunk(CompletionChunkResponse {
+                    id: seq.id.to_string(),
+                    choices: vec![],
+                    created: seq.timestamp,
+                    model,
+                    system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
+                    object: "text_completion".to_string(),
+                    usage,
+                })
+            };
+            seq.responder().send(response).await?;
+        }
         Ok(())
     }
 
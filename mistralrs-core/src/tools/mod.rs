@@ -7,6 +7,8 @@ use serde_json::Value;
 use std::collections::HashMap;
 use uuid::Uuid;
 
+use crate::{json_schema::json_object_to_regex, Constraint};
+
 pub struct ToolCallingMatcher {
     tool_choice: ToolChoice,
 }
@@ -25,11 +27,65 @@ pub struct CalledFunctionArguments {
     pub arguments: HashMap<String, Value>,
 }
 
+/// A tool call found in the output generated so far.
+pub(crate) struct PartialToolCall<'a> {
+    /// The name of the function, once it has been generated completely.
+    pub name: Option<String>,
+    /// The raw JSON of the arguments generated so far. It only grows as more output is generated.
+    pub arguments: &'a str,
+}
+
 impl ToolCallingMatcher {
     pub fn new(tool_choice: ToolChoice) -> anyhow::Result<Self> {
         Ok(Self { tool_choice })
     }
 
+    /// Find the tool calls in `message`, the output generated so far, so that they can be streamed before
+    /// they are complete. Returns `None` if the output is not a tool call.
+    pub(crate) fn get_partial_calls<'a>(
+        &self,
+        message: &'a str,
+    ) -> Option<Vec<PartialToolCall<'a>>> {
+        if matches!(self.tool_choice, ToolChoice::None) {
+            return None;
+        }
+        let mut scanner = JsonScanner {
+            text: message,
+            pos: 0,
+        };
+        let mut calls = Vec::new();
+        scanner.skip_whitespace();
+        match scanner.peek() {
+            Some(b'{') => {
+                scanner.call(&mut calls)?;
+            }
+            Some(b'[') => {
+                scanner.pos += 1;
+                loop {
+                    scanner.skip_whitespace();
+                    match scanner.peek() {
+                        Some(b'{') => {
+                            if !scanner.call(&mut calls)? {
+                                break;
+                            }
+                        }
+                        Some(b']') if calls.is_empty() => break,
+                        None => break,
+                        _ => return None,
+                    }
+                    scanner.skip_whitespace();
+                    match scanner.peek() {
+                        Some(b',') => scanner.pos += 1,
+                        Some(b']') | None => break,
+                        _ => return None,
+                    }
+                }
+            }
+            _ => return None,
+        }
+        Some(calls)
+    }
+
     pub fn get_call(&self, message: &str) -> anyhow::Result<Vec<ToolCallResponse>> {
         if matches!(self.tool_choice, ToolChoice::None) {
             return Ok(Vec::new());
@@ -86,10 +142,215 @@ impl ToolCallingMatcher {
                 })
                 .collect::<anyhow::Result<Vec<_>>>()?)
         } else {
-            if matches!(self.tool_choice, ToolChoice::Tool(_)) {
+            if matches!(self.tool_choice, ToolChoice::Tool(_) | ToolChoice::Required) {
                 anyhow::bail!("Tool choice was required but no tools were called.")
             }
             Ok(Vec::new())
         }
     }
 }
+
+/// Scans the JSON of tool calls which may not be complete yet.
+struct JsonScanner<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> JsonScanner<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.text.as_bytes().get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(|c| c.is_ascii_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    /// Skip a value, returning whether it is complete. Numbers and literals are only complete once
+    /// something follows them.
+    fn value(&mut self) -> bool {
+        let mut depth = 0usize;
+        let mut in_string = false;
+        let mut escaped = false;
+        while let Some(c) = self.peek() {
+            if in_string {
+                match c {
+                    _ if escaped => escaped = false,
+                    b'\\' => escaped = true,
+                    b'"' => {
+                        in_string = false;
+                        if depth == 0 {
+                            self.pos += 1;
+                            return true;
+                        }
+                    }
+                    _ => {}
+                }
+            } else {
+                match c {
+                    b'"' => in_string = true,
+                    b'{' | b'[' => depth += 1,
+                    b'}' | b']' | b',' if depth == 0 => return true,
+                    b'}' | b']' => {
+                        depth -= 1;
+                        if depth == 0 {
+                            self.pos += 1;
+                            return true;
+                        }
+                    }
+                    c if depth == 0 && c.is_ascii_whitespace() => return true,
+                    _ => {}
+                }
+            }
+            self.pos += 1;
+        }
+        false
+    }
+
+    /// Scan a call object and add it to `calls`, returning whether it is complete. Returns `None` if the
+    /// object is not a tool call.
+    fn call(&mut self, calls: &mut Vec<PartialToolCall<'a>>) -> Option<bool> {
+        self.pos += 1;
+        calls.push(PartialToolCall {
+            name: None,
+            arguments: "",
+        });
+        let call = calls.last_mut().expect("The call was just added.");
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b'"') => {}
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Some(true);
+                }
+                None => return Some(false),
+                _ => return None,
+            }
+            let start = self.pos;
+            if !self.value() {
+                return Some(false);
+            }
+            let key = serde_json::from_str::<String>(&self.text[start..self.pos]).ok()?;
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b':') => self.pos += 1,
+                None => return Some(false),
+                _ => return None,
+            }
+            self.skip_whitespace();
+            if self.peek().is_none() {
+                return Some(false);
+            }
+            let start = self.pos;
+            let complete = self.value();
+            let value = &self.text[start..self.pos];
+            match key.as_str() {
+                "name" if complete => call.name = Some(serde_json::from_str(value).ok()?),
+                "name" => {}
+                "parameters" | "arguments" => call.arguments = value,
+                _ => return None,
+            }
+            if !complete {
+                return Some(false);
+            }
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Some(true);
+                }
+                None => return Some(false),
+                _ => return None,
+            }
+        }
+    }
+}
+
+/// Build a constraint so that the model can only emit a valid call to an allowed tool, if the tool
+/// choice requires a call. The call has the form `{"name": ..., "parameters": ...}`, where the
+/// parameters are constrained by the function's `parameters` schema.
+pub(crate) fn tool_call_constraint(
+    tools: &[Tool],
+    tool_choice: &ToolChoice,
+) -> anyhow::Result<Option<Constraint>> {
+    let allowed = match tool_choice {
+        ToolChoice::None | ToolChoice::Auto => return Ok(None),
+        ToolChoice::Required => tools.iter().collect::<Vec<_>>(),
+        ToolChoice::Tool(choice) => {
+            let Some(tool) = tools
+                .iter()
+                .find(|tool| tool.function.name == choice.function.name)
+            else {
+                anyhow::bail!(
+                    "Tool choice `{}` is not one of the provided tools.",
+                    choice.function.name
+                );
+            };
+            vec![tool]
+        }
+    };
+    if allowed.is_empty() {
+        anyhow::bail!("Tool choice requires a tool call, but no tools were provided.");
+    }
+
+    let calls = allowed
+        .into_iter()
+        .map(|tool| {
+            let name = serde_json::json!({ "const": tool.function.name });
+            let parameters = match &tool.function.parameters {
+                Some(parameters) => serde_json::to_value(parameters)?,
+                None => serde_json::json!({ "type": "object" }),
+            };
+            json_object_to_regex(&[("name", &name), ("parameters", &parameters)])
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(Some(Constraint::Regex(format!("({})", calls.join("|")))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ToolCallingMatcher, ToolChoice};
+
+    #[test]
+    fn partial_calls() {
+        let matcher = ToolCallingMatcher::new(ToolChoice::Auto).unwrap();
+
+        let calls = matcher
+            .get_partial_calls(r#"{"name": "get_weather", "parameters": {"city": "Par"#)
+            .unwrap();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].name.as_deref(), Some("get_weather"));
+        assert_eq!(calls[0].arguments, r#"{"city": "Par"#);
+
+        let calls = matcher.get_partial_calls(r#"{"name": "get_wea"#).unwrap();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].name, None);
+
+        let calls = matcher.get_partial_calls(
+            r#"[{"name": "a", "parameters": {}}, {"arguments": {"x": 1}, "name": "b#,
+            )
+            .unwrap();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].name.as_deref(), Some("a"));
+        assert_eq!(calls[0].arguments, "{}");
+        assert_eq!(calls[1].name, None);
+        assert_eq!(calls[1].arguments, r#"{"x": 1}"#,
+        );
+    }
+
+    #[test]
+    fn partial_calls_not_a_call() {
+        let matcher = ToolCallingMatcher::new(ToolChoice::Auto).unwrap();
+        assert!(matcher.get_partial_calls("Hello").is_none());
+        assert!(matcher.get_partial_calls(r#"{"city": "Paris"}"#).is_none());
+        assert!(matcher.get_partial_calls("[1, 2]").is_none());
+
+        let matcher = ToolCallingMatcher::new(ToolChoice::None).unwrap();
+        assert!(matcher
+            .get_partial_calls(r#"{"name": "get_weather""#)
+            .is_none());
+    }
+}
@@ -14,6 +14,9 @@ pub enum ToolChoice {
     None,
     #[serde(rename = "auto")]
     Auto,
+    /// The model must call one of the tools.
+    #[serde(rename = "required")]
+    Required,
     #[serde(untagged)]
     Tool(Tool),
 }
@@ -24,3 +24,52 @@ pub struct ToolCallResponse {
     pub tp: ToolCallType,
     pub function: CalledFunction,
 }
+
+#[cfg_attr(feature = "pyo3_macros", pyo3::pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct CalledFunctionDelta {
+    pub name: Option<String>,
+    pub arguments: String,
+}
+
+#[cfg_attr(feature = "pyo3_macros", pyo3::pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Clone, Debug, serde::Serialize)]
+/// Part of a tool call in a streaming response. The id, type and name are only sent with the first
+/// part of each call, and the argument fragments should be concatenated.
+pub struct ToolCallDelta {
+    pub index: usize,
+    pub id: Option<String>,
+    #[serde(rename = "type")]
+    pub tp: Option<ToolCallType>,
+    pub function: CalledFunctionDelta,
+}
+
+impl ToolCallDelta {
+    /// The first part of the call with this index, with the arguments generated so far.
+    pub(crate) fn start(index: usize, name: String, arguments: String) -> Self {
+        Self {
+            index,
+            id: Some(format!("call-{}", uuid::Uuid::new_v4())),
+            tp: Some(ToolCallType::Function),
+            function: CalledFunctionDelta {
+                name: Some(name),
+                arguments,
+            },
+        }
+    }
+
+    /// A fragment of the arguments of the call with this index.
+    pub(crate) fn arguments(index: usize, arguments: String) -> Self {
+        Self {
+            index,
+            id: None,
+            tp: None,
+            function: CalledFunctionDelta {
+                name: None,
+                arguments,
+            },
+        }
+    }
+}
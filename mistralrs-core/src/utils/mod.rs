@@ -113,6 +113,7 @@ macro_rules! handle_pipeline_forward_error {
                                 tool_calls: Vec::new(),
                             },
                             logprobs: None,
+                            beam_score: None,
                         };
                         seq.add_choice_to_group(choice);
                     } else {
@@ -121,6 +122,7 @@ macro_rules! handle_pipeline_forward_error {
                             index: seq.get_response_index(),
                             text: res,
                             logprobs: None,
+                            beam_score: None,
                         };
                         seq.add_completion_choice_to_group(choice);
                     }
@@ -5,8 +5,7 @@ use std::sync::Arc;
 
 use candle_core::Result;
 use candle_core::{DType, Device, Tensor};
-use image::GenericImageView;
-use itertools::Itertools;
+use image::{DynamicImage, GenericImageView};
 use regex_automata::meta::Regex;
 use tokenizers::Tokenizer;
 use tracing::warn;
@@ -26,8 +25,8 @@ use crate::vision_models::{preprocessor_config, ModelInputs};
 
 use super::llava_next::LLaVANextVisionSpecificArgs;
 use super::utils::{
-    calculate_unpad, divide_to_samples, get_anyres_image_grid_shape, get_num_samples,
-    resize_and_pad_image, select_best_resolution, LLaVAImageProcessor,
+    calculate_unpad, divide_to_samples, get_anyres_image_grid_shape, get_input_ids,
+    get_num_samples, resize_and_pad_image, select_best_resolution, LLaVAImageProcessor,
 };
 
 pub struct LLaVANextProcessor {
@@ -81,6 +80,32 @@ impl InputsProcessor for LLaVANextInputProcessor {
     fn get_type(&self) -> InputsProcessorType {
         InputsProcessorType::Vision
     }
+
+    fn prompt_tokens_with_images(
+        &self,
+        tokenizer: &Tokenizer,
+        toks: Vec<u32>,
+        images: Vec<DynamicImage>,
+        other_config: Option<Arc<dyn Any>>,
+    ) -> anyhow::Result<Vec<u32>> {
+        let config = other_config.expect("Need a PreProcessorConfig config.");
+        let config: &PreProcessorConfig = config.downcast_ref().expect("Downcast failed.");
+        let num_img_tokens = self
+            .preprocess(images, config, &Device::Cpu)?
+            .num_img_tokens
+            .expect("LLaVA Next preprocessor must return the number of image tokens.");
+        let detokenized = tokenizer.decode(&toks, false).map_err(anyhow::Error::msg)?;
+        let input_ids = get_input_ids(
+            &self.image_tag_splitter,
+            tokenizer,
+            &detokenized,
+            &num_img_tokens,
+        )?;
+        Ok(input_ids
+            .into_iter()
+            .map(|x| if x < 0 { 0u32 } else { x as u32 })
+            .collect())
+    }
     fn process_inputs(
         &self,
         tokenizer: Arc<Tokenizer>,
@@ -246,39 +271,15 @@ impl InputsProcessor for LLaVANextInputProcessor {
                 .iter_mut()
                 .zip(num_img_tokens.unwrap().into_iter()),
         ) {
-            let splits = self
-                .image_tag_splitter
-                .split(&detokenized)
-                .map(|span| &detokenized[span.range()])
-                .collect::<Vec<_>>();
-            let prompt_chunks = splits
-                .iter()
-                .map(|s| {
-                    // we don't use encode_batch here, because encode_batch will pad 0 to the end of the shor sequences, which will cause the image_ids_pad to be wrong.
-                    tokenizer
-                        .encode(*s, true)
-                        .unwrap()
-                        .get_ids()
-                        .to_vec()
-                        .iter()
-                        .map(|x| *x as i64)
-                        .collect()
-                })
-                .collect::<Vec<Vec<_>>>();
-            let mut image_ids_pad = Vec::new();
-            for (i, num_img_token) in num_img_tokens.iter().enumerate() {
-                let mut image_id_pad = vec![0; *num_img_token];
-                image_id_pad[0] = -(i as i64 + 1);
-                image_ids_pad.push(image_id_pad);
-            }
-            let mut input_ids: Vec<i64> = Vec::new();
-            for item in prompt_chunks
-                .iter()
-                .map(|x| x.to_vec())
-                .interleave(image_ids_pad)
-            {
-                input_ids.extend(item);
-            }
+            let input_ids = match get_input_ids(
+                &self.image_tag_splitter,
+                &tokenizer,
+                &detokenized,
+                &num_img_tokens,
+            ) {
+                Ok(input_ids) => input_ids,
+                Err(e) => return Box::new(std::iter::once(Err(e))),
+            };
             // NOTE(EricLBuehler): Casting to u32 is fine, we don't care about the other toks
             seq.set_toks(
                 input_ids
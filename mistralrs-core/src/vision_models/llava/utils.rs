@@ -9,7 +9,52 @@ use image::{
     imageops::{overlay, FilterType},
     DynamicImage, GenericImageView, Rgb, RgbImage,
 };
+use itertools::Itertools;
+use regex_automata::meta::Regex;
 use std::cmp::min;
+use tokenizers::Tokenizer;
+
+/// The input ids of a prompt, with the tokens of each image after the text before its image tag. The first token
+/// of an image is its negated index plus one, the others are 0.
+pub(crate) fn get_input_ids(
+    image_tag_splitter: &Regex,
+    tokenizer: &Tokenizer,
+    detokenized: &str,
+    num_img_tokens: &[usize],
+) -> anyhow::Result<Vec<i64>> {
+    let splits = image_tag_splitter
+        .split(detokenized)
+        .map(|span| &detokenized[span.range()])
+        .collect::<Vec<_>>();
+    let prompt_chunks = splits
+        .iter()
+        .map(|s| {
+            // we don't use encode_batch here, because encode_batch will pad 0 to the end of the shor sequences, which will cause the image_ids_pad to be wrong.
+            Ok(tokenizer
+                .encode(*s, true)
+                .map_err(anyhow::Error::msg)?
+                .get_ids()
+                .iter()
+                .map(|x| *x as i64)
+                .collect())
+        })
+        .collect::<anyhow::Result<Vec<Vec<_>>>>()?;
+    let mut image_ids_pad = Vec::new();
+    for (i, num_img_token) in num_img_tokens.iter().enumerate() {
+        let mut image_id_pad = vec![0; *num_img_token];
+        image_id_pad[0] = -(i as i64 + 1);
+        image_ids_pad.push(image_id_pad);
+    }
+    let mut input_ids: Vec<i64> = Vec::new();
+    for item in prompt_chunks
+        .iter()
+        .map(|x| x.to_vec())
+        .interleave(image_ids_pad)
+    {
+        input_ids.extend(item);
+    }
+    Ok(input_ids)
+}
 
 pub(crate) fn get_anyres_image_grid_shape(
     image_size: (u32, u32),
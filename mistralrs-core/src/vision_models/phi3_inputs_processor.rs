@@ -64,10 +64,109 @@ impl Processor for Phi3Processor {
     }
 }
 
+impl Phi3InputsProcessor {
+    /// The input ids of a prompt, with the image tokens of each image tag as the negated id of the image.
+    fn input_ids(
+        &self,
+        tokenizer: &Tokenizer,
+        detokenized: &str,
+        num_img_tokens: &[usize],
+        n_images: usize,
+    ) -> anyhow::Result<Vec<i64>> {
+        let splits = self
+            .image_tag_splitter
+            .split(detokenized)
+            .map(|span| &detokenized[span.range()])
+            .collect::<Vec<_>>();
+        let prompt_chunks = tokenizer
+            .encode_batch(splits, true)
+            .map_err(anyhow::Error::msg)?
+            .into_iter()
+            .map(|enc| enc.get_ids().to_vec())
+            .collect::<Vec<_>>();
+
+        let image_tags = self.image_tag_splitter.find_iter(detokenized);
+        let image_ids = image_tags
+            .into_iter()
+            .map(|s| {
+                let s = &detokenized[s.range()];
+                s.split('|')
+                    .nth(1)
+                    .unwrap()
+                    .split('_')
+                    .nth(1)
+                    .unwrap()
+                    .parse::<u32>()
+                    .expect("Failed to parse image id to u32")
+            })
+            .collect::<Vec<_>>();
+        let unique_image_ids = image_ids
+            .iter()
+            .copied()
+            .unique()
+            .sorted()
+            .collect::<Vec<_>>();
+        // `image_ids` must start from 1, and must be continuous int, e.g. [1, 2, 3], cannot be [1, 4, 5]
+        if unique_image_ids != (1u32..unique_image_ids.len() as u32 + 1).collect::<Vec<_>>() {
+            anyhow::bail!(
+                "`image_ids` must start from 1, and must be continuous, e.g. [1, 2, 3], cannot be [1, 4, 5]."
+            );
+        }
+        // Total images must be the same as the number of image tags
+        if unique_image_ids.len() != n_images {
+            anyhow::bail!("Total images must be the same as the number of image tags.");
+        }
+
+        // Use the TryInto + unwrap_or to handle case when id==0
+        let image_ids_pad = image_ids
+            .iter()
+            .map(|id| {
+                [-(*id as i64)].repeat(
+                    num_img_tokens[TryInto::<usize>::try_into(*id as isize - 1)
+                        .unwrap_or(num_img_tokens.len() - 1)],
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let mut input_ids: Vec<i64> = Vec::new();
+        for item in prompt_chunks
+            .iter()
+            .map(|x| x.iter().map(|x| *x as i64).collect::<Vec<_>>())
+            .interleave(image_ids_pad)
+        {
+            input_ids.extend(item);
+        }
+        Ok(input_ids)
+    }
+}
+
 impl InputsProcessor for Phi3InputsProcessor {
     fn get_type(&self) -> InputsProcessorType {
         InputsProcessorType::Vision
     }
+
+    fn prompt_tokens_with_images(
+        &self,
+        tokenizer: &Tokenizer,
+        toks: Vec<u32>,
+        images: Vec<DynamicImage>,
+        other_config: Option<Arc<dyn Any>>,
+    ) -> anyhow::Result<Vec<u32>> {
+        let config = other_config.expect("Need a PreProcessorConfig config.");
+        let config: &PreProcessorConfig = config.downcast_ref().expect("Downcast failed.");
+        let n_images = images.len();
+        let num_img_tokens = self
+            .preprocess(images, config, &Device::Cpu)?
+            .num_img_tokens
+            .expect("Phi 3 preprocessor must return the number of image tokens.");
+        let detokenized = tokenizer.decode(&toks, false).map_err(anyhow::Error::msg)?;
+        let input_ids = self.input_ids(tokenizer, &detokenized, &num_img_tokens, n_images)?;
+        Ok(input_ids
+            .into_iter()
+            .map(|x| if x < 0 { 0u32 } else { x as u32 })
+            .collect())
+    }
+
     fn process_inputs(
         &self,
         tokenizer: Arc<Tokenizer>,
@@ -206,71 +305,11 @@ impl InputsProcessor for Phi3InputsProcessor {
                 .iter_mut()
                 .zip(num_img_tokens.unwrap().into_iter().zip(n_images)),
         ) {
-            let splits = self
-                .image_tag_splitter
-                .split(&detokenized)
-                .map(|span| &detokenized[span.range()])
-                .collect::<Vec<_>>();
-            let prompt_chunks = tokenizer
-                .encode_batch(splits, true)
-                .expect("Encode failed")
-                .into_iter()
-                .map(|enc| enc.get_ids().to_vec())
-                .collect::<Vec<_>>();
-
-            let image_tags = self.image_tag_splitter.find_iter(&detokenized);
-            let image_ids = image_tags
-                .into_iter()
-                .map(|s| {
-                    let s = &detokenized[s.range()];
-                    s.split('|')
-                        .nth(1)
-                        .unwrap()
-                        .split('_')
-                        .nth(1)
-                        .unwrap()
-                        .parse::<u32>()
-                        .expect("Failed to parse image id to u32")
-                })
-                .collect::<Vec<_>>();
-            let unique_image_ids = image_ids
-                .iter()
-                .copied()
-                .unique()
-                .sorted()
-                .collect::<Vec<_>>();
-            // `image_ids` must start from 1, and must be continuous int, e.g. [1, 2, 3], cannot be [1, 4, 5]
-            if unique_image_ids != (1u32..unique_image_ids.len() as u32 + 1).collect::<Vec<_>>() {
-                return Box::new(std::iter::once(Err(anyhow::Error::msg(
-                    "`image_ids` must start from 1, and must be continuous, e.g. [1, 2, 3], cannot be [1, 4, 5].",
-                ))));
-            }
-            // Total images must be the same as the number of image tags
-            if unique_image_ids.len() != n_images {
-                return Box::new(std::iter::once(Err(anyhow::Error::msg(
-                    "Total images must be the same as the number of image tags.",
-                ))));
-            }
-
-            // Use the TryInto + unwrap_or to handle case when id==0
-            let image_ids_pad = image_ids
-                .iter()
-                .map(|id| {
-                    [-(*id as i64)].repeat(
-                        num_img_tokens[TryInto::<usize>::try_into(*id as isize - 1)
-                            .unwrap_or(num_img_tokens.len() - 1)],
-                    )
-                })
-                .collect::<Vec<_>>();
-
-            let mut input_ids: Vec<i64> = Vec::new();
-            for item in prompt_chunks
-                .iter()
-                .map(|x| x.iter().map(|x| *x as i64).collect::<Vec<_>>())
-                .interleave(image_ids_pad)
-            {
-                input_ids.extend(item);
-            }
+            let input_ids =
+                match self.input_ids(&tokenizer, &detokenized, &num_img_tokens, n_images) {
+                    Ok(input_ids) => input_ids,
+                    Err(e) => return Box::new(std::iter::once(Err(e))),
+                };
 
             // NOTE(EricLBuehler): Casting to u32 is fine, we don't care about the other toks
             seq.set_toks(
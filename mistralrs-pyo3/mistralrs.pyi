@@ -6,6 +6,7 @@ from typing import Iterator
 class ToolChoice(Enum):
     NoTools = "None"
     Auto = "Auto"
+    Required = "Required"
 
 @dataclass
 class ChatCompletionRequest:
@@ -39,6 +40,23 @@ class ChatCompletionRequest:
     min_p: float | None = None
     tool_schemas: list[str] | None = None
     tool_choice: ToolChoice | None = None
+    beam_width: int | None = None
+    length_penalty: float | None = None
+    seed: int | None = None
+    priority: int | None = None
+    tenant: str | None = None
+    processed_logprobs: bool = False
+    repetition_penalty: float | None = None
+    no_repeat_ngram_size: int | None = None
+    dry_multiplier: float | None = None
+    dry_base: float | None = None
+    dry_allowed_length: int | None = None
+    dry_sequence_breakers: list[str] | None = None
+    typical_p: float | None = None
+    top_a: float | None = None
+    mirostat_tau: float | None = None
+    mirostat_eta: float | None = None
+    include_usage: bool = False
 
 @dataclass
 class CompletionRequest:
@@ -67,6 +85,48 @@ class CompletionRequest:
     min_p: float | None = None
     tool_schemas: list[str] | None = None
     tool_choice: ToolChoice | None = None
+    beam_width: int | None = None
+    length_penalty: float | None = None
+    seed: int | None = None
+    logprobs: int | None = None
+    priority: int | None = None
+    tenant: str | None = None
+    processed_logprobs: bool = False
+    repetition_penalty: float | None = None
+    no_repeat_ngram_size: int | None = None
+    dry_multiplier: float | None = None
+    dry_base: float | None = None
+    dry_allowed_length: int | None = None
+    dry_sequence_breakers: list[str] | None = None
+    typical_p: float | None = None
+    top_a: float | None = None
+    mirostat_tau: float | None = None
+    mirostat_eta: float | None = None
+
+@dataclass
+class EmbeddingRequest:
+    """
+    An EmbeddingRequest asks the mistral.rs engine for the final hidden states of the inputs, pooled to
+    one vector per input. `input` may be a string, a list of strings, a list of tokens or a list of lists of tokens.
+    `pooling` is either `mean` or `last` (the last token's hidden state).
+    """
+
+    input: str | list[str] | list[int] | list[list[int]]
+    model: str
+    pooling: str = "mean"
+    normalize: bool = True
+
+@dataclass
+class ScoreRequest:
+    """
+    A ScoreRequest computes the natural log-probabilities of given tokens with a single prefill, without sampling.
+    If `continuation` is specified, only its tokens are scored. Otherwise, every prompt token after the first is.
+    Text prompts are tokenized with special tokens, text continuations without.
+    """
+
+    prompt: str | list[int]
+    continuation: str | list[int] | None = None
+    top_logprobs: int = 0
 
 @dataclass
 class Architecture(Enum):
@@ -181,6 +241,10 @@ class Runner:
         max_seqs: int = 16,
         no_kv_cache: bool = False,
         prefix_cache_n: int = 16,
+        prefix_cache_device_mb: int | None = None,
+        prefix_cache_host_mb: int | None = None,
+        prefix_cache_dir: str | None = None,
+        prefix_cache_disk_mb: int | None = None,
         token_source: str = "cache",
         speculative_gamma: int = 32,
         which_draft: Which | None = None,
@@ -191,6 +255,9 @@ class Runner:
         pa_gpu_mem: int | float | None = None,
         pa_blk_size: int | None = None,
         no_paged_attn: bool = False,
+        priority_scheduling: bool = False,
+        tenant_weights: dict[str, float] | None = None,
+        prefill_chunk_size: int | None = None,
     ) -> None:
         """
         Load a model.
@@ -199,6 +266,11 @@ class Runner:
         - `max_seqs` specifies how many sequences may be running at any time.
         - `no_kv_cache` disables the KV cache.
         - `prefix_cache_n` sets the number of sequences to hold in the device prefix cache, others will be evicted to CPU.
+        - `prefix_cache_device_mb` sets the maximum size in MB of the device prefix cache, others will be evicted to CPU.
+        - `prefix_cache_host_mb` sets the maximum size in MB of the prefix caches held in host RAM. Others are only kept on disk,
+            if `prefix_cache_dir` is set.
+        - `prefix_cache_dir` sets a directory to write prefix caches to as safetensors files, so that they survive restarts.
+        - `prefix_cache_disk_mb` sets the maximum size in MB of the prefix cache files in `prefix_cache_dir`.
         - `token_source` specifies where to load the HF token from.
             The token source follows the following format: "literal:<value>", "env:<value>", "path:<value>", "cache" to use a cached token or "none" to use no token.
         - `speculative_gamma` specifies the `gamma` parameter for specuative decoding, the ratio of draft tokens to generate before calling
@@ -226,6 +298,12 @@ class Runner:
         - `pa_blk_size` sets the block size (number of tokens per block) for PagedAttention. If this is not set and the device is CUDA,
             it will default to 32. PagedAttention is only supported on CUDA and is always automatically activated.
         - `no_paged_attn` disables PagedAttention on CUDA
+        - `priority_scheduling` schedules requests with a higher `priority` first, preempting running sequences of a lower priority.
+            This is not supported with PagedAttention.
+        - `tenant_weights` sets the weights used by the priority scheduler to share the running sequences between the `tenant`s of requests.
+            Tenants which are not specified have a weight of 1.
+        - `prefill_chunk_size` sets the maximum number of prompt tokens to run in one step. Longer prompts are split over
+            multiple steps so that running sequences keep generating. With PagedAttention, the chunks are rounded down to whole blocks.
         """
         ...
 
@@ -242,6 +320,32 @@ class Runner:
         Send a chat completion request to the mistral.rs engine, returning the response object.
         """
 
+    def send_embedding_request(self, request: EmbeddingRequest) -> EmbeddingResponse:
+        """
+        Send an embedding request to the mistral.rs engine, returning the response object.
+        """
+
+    def send_score_request(self, request: ScoreRequest) -> ScoreResponse:
+        """
+        Send a scoring request to the mistral.rs engine, returning the log-probabilities of the scored tokens.
+        """
+
+    def tokenize(
+        self,
+        text: str | list[dict[str, str]],
+        add_generation_prompt: bool = True,
+        add_special_tokens: bool = True,
+    ) -> list[int]:
+        """
+        Tokenize a prompt, or chat messages after applying the chat template of the model.
+        `add_special_tokens` only applies to a prompt, and `add_generation_prompt` only to messages.
+        """
+
+    def detokenize(self, tokens: list[int], skip_special_tokens: bool = False) -> str:
+        """
+        Decode tokens into text with the tokenizer of the model.
+        """
+
     def send_re_isq(self, dtype: str) -> CompletionResponse:
         """
         Send a request to re-ISQ the model. If the model was loaded as GGUF or GGML then nothing will happen.
@@ -330,6 +434,18 @@ class ToolCallResponse:
     type: ToolCallType
     function: CalledFunction
 
+@dataclass
+class CalledFunctionDelta:
+    name: str | None
+    arguments: str
+
+@dataclass
+class ToolCallDelta:
+    index: int
+    id: str | None
+    type: ToolCallType | None
+    function: CalledFunctionDelta
+
 @dataclass
 class ResponseMessage:
     content: str
@@ -359,6 +475,7 @@ class Choice:
     index: int
     message: ResponseMessage
     logprobs: Logprobs
+    beam_score: float | None
 
 @dataclass
 class ChatCompletionResponse:
@@ -374,6 +491,7 @@ class ChatCompletionResponse:
 class Delta:
     content: str
     role: str
+    tool_calls: list[ToolCallDelta] | None
 
 @dataclass
 class ChunkChoice:
@@ -390,13 +508,22 @@ class ChatCompletionChunkResponse:
     model: str
     system_fingerprint: str
     object: str
+    usage: Usage | None
+
+@dataclass
+class CompletionLogprobs:
+    tokens: list[str]
+    token_logprobs: list[float | None]
+    top_logprobs: list[dict[str, float] | None]
+    text_offset: list[int]
 
 @dataclass
 class CompletionChoice:
     finish_reason: str
     index: int
     text: str
-    # NOTE(EricLBuehler): `logprobs` in undocumented
+    logprobs: CompletionLogprobs | None
+    beam_score: float | None
 
 @dataclass
 class CompletionResponse:
@@ -407,3 +534,38 @@ class CompletionResponse:
     system_fingerprint: str
     object: str
     usage: Usage
+
+@dataclass
+class Embedding:
+    object: str
+    embedding: list[float]
+    index: int
+
+@dataclass
+class EmbeddingUsage:
+    prompt_tokens: int
+    total_tokens: int
+
+@dataclass
+class EmbeddingResponse:
+    object: str
+    data: list[Embedding]
+    model: str
+    usage: EmbeddingUsage
+
+@dataclass
+class ScoredToken:
+    token: int
+    text: str
+    special: bool
+    logprob: float | None
+    is_greedy: bool
+    top_logprobs: list[TopLogprob]
+
+@dataclass
+class ScoreResponse:
+    model: str
+    tokens: list[ScoredToken]
+    total_logprob: float
+    is_greedy: bool
+    prompt_tokens: int
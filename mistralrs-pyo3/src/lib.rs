@@ -4,13 +4,16 @@ use anymoe::{AnyMoeConfig, AnyMoeExpertType};
 use base64::{engine::general_purpose, Engine};
 use either::Either;
 use indexmap::IndexMap;
-use requests::{ChatCompletionRequest, CompletionRequest, ToolChoice};
+use requests::{
+    ChatCompletionRequest, CompletionRequest, EmbeddingRequest, ScoreRequest, ToolChoice,
+};
 use std::{
     cell::RefCell,
     collections::HashMap,
     fs,
     io::Read,
     num::NonZeroUsize,
+    path::PathBuf,
     str::FromStr,
     sync::{Arc, Mutex, OnceLock},
 };
@@ -21,10 +24,12 @@ use candle_core::Device;
 use mistralrs_core::{
     initialize_logging, paged_attn_supported, parse_isq_value, AnyMoeLoader,
     ChatCompletionResponse, CompletionResponse, Constraint, DefaultSchedulerMethod,
-    DeviceLayerMapMetadata, DeviceMapMetadata, GGMLLoaderBuilder, GGMLSpecificConfig,
-    GGUFLoaderBuilder, Loader, MemoryGpuConfig, MistralRs, MistralRsBuilder, ModelDType,
-    NormalLoaderBuilder, NormalRequest, NormalSpecificConfig, PagedAttentionConfig,
-    Request as _Request, RequestMessage, Response, SamplingParams, SchedulerConfig,
+    DeviceLayerMapMetadata, DeviceMapMetadata, DrySamplingParams,
+    EmbeddingRequest as _EmbeddingRequest, EmbeddingResponse, GGMLLoaderBuilder,
+    GGMLSpecificConfig, GGUFLoaderBuilder, Loader, MemoryGpuConfig, MirostatParams, MistralRs,
+    MistralRsBuilder, ModelDType, NormalLoaderBuilder, NormalRequest, NormalSpecificConfig,
+    PagedAttentionConfig, PrefixCacheTiers, Request as _Request, RequestMessage, Response,
+    SamplingParams, SchedulerConfig, ScoreRequest as _ScoreRequest, ScoreResponse,
     SpeculativeConfig, SpeculativeLoader, StopTokens, TokenSource, Tool, VisionLoaderBuilder,
     VisionSpecificConfig,
 };
@@ -297,6 +302,10 @@ impl Runner {
         max_seqs = 16,
         no_kv_cache = false,
         prefix_cache_n = 16,
+        prefix_cache_device_mb = None,
+        prefix_cache_host_mb = None,
+        prefix_cache_dir = None,
+        prefix_cache_disk_mb = None,
         token_source = "cache",
         speculative_gamma = 32,
         which_draft = None,
@@ -310,12 +319,19 @@ impl Runner {
         pa_blk_size = None,
         no_paged_attn = false,
         prompt_batchsize = None,
+        priority_scheduling = false,
+        tenant_weights = None,
+        prefill_chunk_size = None,
     ))]
     fn new(
         which: Which,
         max_seqs: usize,
         no_kv_cache: bool,
         prefix_cache_n: usize,
+        prefix_cache_device_mb: Option<usize>,
+        prefix_cache_host_mb: Option<usize>,
+        prefix_cache_dir: Option<PathBuf>,
+        prefix_cache_disk_mb: Option<usize>,
         token_source: &str,
         speculative_gamma: usize,
         which_draft: Option<Which>,
@@ -329,6 +345,9 @@ impl Runner {
         pa_blk_size: Option<usize>,
         no_paged_attn: bool,
         prompt_batchsize: Option<usize>,
+        priority_scheduling: bool,
+        tenant_weights: Option<HashMap<String, f64>>,
+        prefill_chunk_size: Option<usize>,
     ) -> PyResult<Self> {
         let tgt_non_granular_index = match which {
             Which::Plain { .. }
@@ -367,6 +386,16 @@ impl Runner {
             None => None,
         };
 
+        let prefill_chunk_size = match prefill_chunk_size {
+            Some(0) => {
+                return Err(PyValueError::new_err(
+                    "`prefill_chunk_size` must be a strictly positive integer, got 0.",
+                ))
+            }
+            Some(x) => Some(NonZeroUsize::new(x).unwrap()),
+            None => None,
+        };
+
         let loader = parse_which(which, no_kv_cache, chat_template.clone(), prompt_batchsize)?;
         let loader = if let Some(draft_which) = which_draft {
             let draft = parse_which(draft_which, no_kv_cache, chat_template, prompt_batchsize)?;
@@ -502,34 +531,50 @@ impl Runner {
             )
             .map_err(|e| PyValueError::new_err(e.to_string()))?;
 
+        let max_seqs: NonZeroUsize = max_seqs
+            .try_into()
+            .map_err(|e| PyValueError::new_err(format!("{e:?}")))?;
+        let default_scheduler_method = if priority_scheduling {
+            let tenant_weights = tenant_weights.unwrap_or_default();
+            if let Some((tenant, weight)) = tenant_weights.iter().find(|(_, w)| **w <= 0.) {
+                return Err(PyValueError::new_err(format!(
+                    "Tenant weight for `{tenant}` must be positive, got {weight}."
+                )));
+            }
+            DefaultSchedulerMethod::Priority {
+                max_seqs,
+                tenant_weights,
+            }
+        } else {
+            DefaultSchedulerMethod::Fixed(max_seqs)
+        };
         let scheduler_config = if cache_config.is_some() {
             // Handle case where we may have device mapping
             if let Some(ref cache_config) = pipeline.blocking_lock().get_metadata().cache_config {
                 SchedulerConfig::PagedAttentionMeta {
-                    max_num_seqs: max_seqs,
+                    max_num_seqs: max_seqs.get(),
                     config: cache_config.clone(),
                 }
             } else {
                 SchedulerConfig::DefaultScheduler {
-                    method: DefaultSchedulerMethod::Fixed(
-                        max_seqs
-                            .try_into()
-                            .map_err(|e| PyValueError::new_err(format!("{e:?}")))?,
-                    ),
+                    method: default_scheduler_method,
                 }
             }
         } else {
             SchedulerConfig::DefaultScheduler {
-                method: DefaultSchedulerMethod::Fixed(
-                    max_seqs
-                        .try_into()
-                        .map_err(|e| PyValueError::new_err(format!("{e:?}")))?,
-                ),
+                method: default_scheduler_method,
             }
         };
         let mistralrs = MistralRsBuilder::new(pipeline, scheduler_config)
             .with_no_kv_cache(no_kv_cache)
             .with_prefix_cache_n(prefix_cache_n)
+            .with_prefix_cache_tiers(PrefixCacheTiers {
+                device_bytes: prefix_cache_device_mb.map(|mb| mb * 1024 * 1024),
+                host_bytes: prefix_cache_host_mb.map(|mb| mb * 1024 * 1024),
+                disk_dir: prefix_cache_dir,
+                disk_bytes: prefix_cache_disk_mb.map(|mb| mb * 1024 * 1024),
+            })
+            .with_prefill_chunk_size(prefill_chunk_size)
             .build();
 
         Ok(Self { runner: mistralrs })
@@ -561,9 +606,18 @@ impl Runner {
                     ));
                 }
                 Constraint::Yacc(request.grammar.as_ref().unwrap().clone())
+            } else if request.grammar_type == Some("json_schema".to_string()) {
+                if request.grammar.is_none() {
+                    return Err(PyValueError::new_err(
+                        "Grammar type is specified but not grammar text",
+                    ));
+                }
+                let schema = serde_json::from_str(request.grammar.as_ref().unwrap())
+                    .map_err(|e| PyValueError::new_err(e.to_string()))?;
+                Constraint::JsonSchema(schema)
             } else if request.grammar_type.is_some() {
                 return Err(PyValueError::new_err(
-                    "Grammar type is specified but is not `regex` or `yacc`",
+                    "Grammar type is specified but is not `regex`, `yacc` or `json_schema`",
                 ));
             } else {
                 Constraint::None
@@ -737,6 +791,7 @@ impl Runner {
             let tool_choice = request.tool_choice.as_ref().map(|x| match x {
                 ToolChoice::Auto => mistralrs_core::ToolChoice::Auto,
                 ToolChoice::NoTools => mistralrs_core::ToolChoice::None,
+                ToolChoice::Required => mistralrs_core::ToolChoice::Required,
             });
 
             let tools = if let Some(tools) = &request.tool_schemas {
@@ -752,6 +807,14 @@ impl Runner {
                 None
             };
 
+            let dry_params = request.dry_multiplier.map(|multiplier| {
+                DrySamplingParams::new_with_defaults(
+                    multiplier,
+                    request.dry_sequence_breakers.clone(),
+                    request.dry_base,
+                    request.dry_allowed_length,
+                )
+            });
             let model_request = _Request::Normal(NormalRequest {
                 id: {
                     let l = NEXT_REQUEST_ID.lock().unwrap();
@@ -773,6 +836,19 @@ impl Runner {
                     logits_bias: request.logit_bias.clone(),
                     n_choices: request.n_choices,
                     min_p: request.min_p,
+                    beam_width: request.beam_width,
+                    length_penalty: request.length_penalty,
+                    seed: request.seed,
+                    processed_logprobs: request.processed_logprobs,
+                    repetition_penalty: request.repetition_penalty,
+                    no_repeat_ngram_size: request.no_repeat_ngram_size,
+                    dry_params,
+                    typical_p: request.typical_p,
+                    top_a: request.top_a,
+                    mirostat: request.mirostat_tau.map(|tau| MirostatParams {
+                        tau,
+                        eta: request.mirostat_eta.unwrap_or(0.1),
+                    }),
                 },
                 response: tx,
                 return_logprobs: request.logprobs,
@@ -782,6 +858,10 @@ impl Runner {
                 adapters: request.adapters.clone(),
                 tool_choice,
                 tools,
+                priority: request.priority.unwrap_or_default(),
+                tenant: request.tenant.clone(),
+                logits_processors: None,
+                include_usage: request.include_usage,
             });
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
@@ -789,7 +869,10 @@ impl Runner {
             sender.blocking_send(model_request).unwrap();
 
             if request.stream {
-                Ok(Either::Right(ChatCompletionStreamer::from_rx(rx)))
+                Ok(Either::Right(ChatCompletionStreamer::from_rx(
+                    rx,
+                    request.include_usage,
+                )))
             } else {
                 let response = rx.blocking_recv().unwrap();
 
@@ -803,6 +886,8 @@ impl Runner {
                     Response::CompletionDone(_) => unreachable!(),
                     Response::CompletionModelError(_, _) => unreachable!(),
                     Response::CompletionChunk(_) => unreachable!(),
+                    Response::Embeddings(_) => unreachable!(),
+                    Response::Score(_) => unreachable!(),
                 }
             }
         })
@@ -834,9 +919,18 @@ impl Runner {
                     ));
                 }
                 Constraint::Yacc(request.grammar.as_ref().unwrap().clone())
+            } else if request.grammar_type == Some("json_schema".to_string()) {
+                if request.grammar.is_none() {
+                    return Err(PyValueError::new_err(
+                        "Grammar type is specified but not grammar text",
+                    ));
+                }
+                let schema = serde_json::from_str(request.grammar.as_ref().unwrap())
+                    .map_err(|e| PyValueError::new_err(e.to_string()))?;
+                Constraint::JsonSchema(schema)
             } else if request.grammar_type.is_some() {
                 return Err(PyValueError::new_err(
-                    "Grammar type is specified but is not `regex` or `yacc`",
+                    "Grammar type is specified but is not `regex`, `yacc` or `json_schema`",
                 ));
             } else {
                 Constraint::None
@@ -845,6 +939,7 @@ impl Runner {
             let tool_choice = request.tool_choice.as_ref().map(|x| match x {
                 ToolChoice::Auto => mistralrs_core::ToolChoice::Auto,
                 ToolChoice::NoTools => mistralrs_core::ToolChoice::None,
+                ToolChoice::Required => mistralrs_core::ToolChoice::Required,
             });
 
             let tools = if let Some(tools) = &request.tool_schemas {
@@ -860,6 +955,14 @@ impl Runner {
                 None
             };
 
+            let dry_params = request.dry_multiplier.map(|multiplier| {
+                DrySamplingParams::new_with_defaults(
+                    multiplier,
+                    request.dry_sequence_breakers.clone(),
+                    request.dry_base,
+                    request.dry_allowed_length,
+                )
+            });
             let model_request = _Request::Normal(NormalRequest {
                 id: {
                     let l = NEXT_REQUEST_ID.lock().unwrap();
@@ -877,7 +980,7 @@ impl Runner {
                     temperature: request.temperature,
                     top_k: request.top_k,
                     top_p: request.top_p,
-                    top_n_logprobs: 1,
+                    top_n_logprobs: request.logprobs.unwrap_or(1),
                     frequency_penalty: request.frequency_penalty,
                     presence_penalty: request.presence_penalty,
                     max_len: request.max_tokens,
@@ -885,15 +988,32 @@ impl Runner {
                     logits_bias: request.logit_bias.clone(),
                     n_choices: request.n_choices,
                     min_p: request.min_p,
+                    beam_width: request.beam_width,
+                    length_penalty: request.length_penalty,
+                    seed: request.seed,
+                    processed_logprobs: request.processed_logprobs,
+                    repetition_penalty: request.repetition_penalty,
+                    no_repeat_ngram_size: request.no_repeat_ngram_size,
+                    dry_params,
+                    typical_p: request.typical_p,
+                    top_a: request.top_a,
+                    mirostat: request.mirostat_tau.map(|tau| MirostatParams {
+                        tau,
+                        eta: request.mirostat_eta.unwrap_or(0.1),
+                    }),
                 },
                 response: tx,
-                return_logprobs: false,
+                return_logprobs: request.logprobs.is_some(),
                 is_streaming: false,
                 constraint,
                 suffix: request.suffix.clone(),
                 adapters: request.adapters.clone(),
                 tool_choice,
                 tools,
+                priority: request.priority.unwrap_or_default(),
+                tenant: request.tenant.clone(),
+                logits_processors: None,
+                include_usage: false,
             });
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
@@ -913,10 +1033,133 @@ impl Runner {
                 Response::Done(_) => unreachable!(),
                 Response::ModelError(_, _) => unreachable!(),
                 Response::CompletionChunk(_) => unreachable!(),
+                Response::Embeddings(_) => unreachable!(),
+                Response::Score(_) => unreachable!(),
+            }
+        })
+    }
+
+    /// Send an OpenAI API compatible embedding request, returning the result.
+    fn send_embedding_request(
+        &mut self,
+        request: Py<EmbeddingRequest>,
+    ) -> PyResult<EmbeddingResponse> {
+        let (tx, mut rx) = channel(1);
+        Python::with_gil(|py| {
+            let request = request.bind(py).borrow();
+            let model_request = _Request::Embedding(_EmbeddingRequest {
+                input: request.input.clone(),
+                pooling: request.pooling,
+                normalize: request.normalize,
+                response: tx,
+                id: {
+                    let l = NEXT_REQUEST_ID.lock().unwrap();
+                    let last = &mut *l.borrow_mut();
+                    let last_v = *last;
+                    *last += 1;
+                    last_v
+                },
+            });
+
+            MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
+            let sender = self.runner.get_sender()?;
+            sender.blocking_send(model_request).unwrap();
+            let response = rx.blocking_recv().unwrap();
+
+            match response {
+                Response::ValidationError(e) | Response::InternalError(e) => {
+                    Err(PyValueError::new_err(e.to_string()))
+                }
+                Response::Embeddings(response) => Ok(response),
+                Response::ModelError(_, _) => unreachable!(),
+                Response::Done(_) => unreachable!(),
+                Response::Chunk(_) => unreachable!(),
+                Response::CompletionModelError(_, _) => unreachable!(),
+                Response::CompletionDone(_) => unreachable!(),
+                Response::CompletionChunk(_) => unreachable!(),
+                Response::Score(_) => unreachable!(),
+            }
+        })
+    }
+
+    /// Score the tokens of a prompt, or of a continuation of it, returning their log-probabilities.
+    fn send_score_request(&mut self, request: Py<ScoreRequest>) -> PyResult<ScoreResponse> {
+        let (tx, mut rx) = channel(1);
+        Python::with_gil(|py| {
+            let request = request.bind(py).borrow();
+            let model_request = _Request::Score(_ScoreRequest {
+                prompt: request.prompt.clone(),
+                continuation: request.continuation.clone(),
+                top_n_logprobs: request.top_logprobs,
+                response: tx,
+                id: {
+                    let l = NEXT_REQUEST_ID.lock().unwrap();
+                    let last = &mut *l.borrow_mut();
+                    let last_v = *last;
+                    *last += 1;
+                    last_v
+                },
+            });
+
+            MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
+            let sender = self.runner.get_sender()?;
+            sender.blocking_send(model_request).unwrap();
+            let response = rx.blocking_recv().unwrap();
+
+            match response {
+                Response::ValidationError(e) | Response::InternalError(e) => {
+                    Err(PyValueError::new_err(e.to_string()))
+                }
+                Response::Score(response) => Ok(response),
+                Response::ModelError(_, _) => unreachable!(),
+                Response::Done(_) => unreachable!(),
+                Response::Chunk(_) => unreachable!(),
+                Response::CompletionModelError(_, _) => unreachable!(),
+                Response::CompletionDone(_) => unreachable!(),
+                Response::CompletionChunk(_) => unreachable!(),
+                Response::Embeddings(_) => unreachable!(),
             }
         })
     }
 
+    /// Tokenize a prompt, or chat messages after applying the chat template.
+    #[pyo3(signature = (text, add_generation_prompt = true, add_special_tokens = true))]
+    fn tokenize(
+        &self,
+        text: Either<Vec<IndexMap<String, String>>, String>,
+        add_generation_prompt: bool,
+        add_special_tokens: bool,
+    ) -> PyResult<Vec<u32>> {
+        let text = text.map_left(|messages| {
+            messages
+                .into_iter()
+                .map(|message| {
+                    message
+                        .into_iter()
+                        .map(|(k, v)| (k, Either::Left(v)))
+                        .collect()
+                })
+                .collect()
+        });
+        self.runner
+            .tokenize(
+                text,
+                None,
+                add_generation_prompt,
+                add_special_tokens,
+                Vec::new(),
+            )
+            .map_err(|e| PyValueError::new_err(e.to_string()))
+    }
+
+    /// Decode tokens into text with the tokenizer of the model.
+    #[pyo3(signature = (tokens, skip_special_tokens = false))]
+    fn detokenize(&self, tokens: Vec<u32>, skip_special_tokens: bool) -> PyResult<String> {
+        self.runner
+            .detokenize(&tokens, skip_special_tokens)
+            .map_err(|e| PyValueError::new_err(e.to_string()))
+    }
+
     /// Send a request to re-ISQ the model. If the model was loaded as GGUF or GGML
     /// then nothing will happen.
     fn send_re_isq(&self, dtype: String) -> PyResult<()> {
@@ -946,6 +1189,8 @@ fn mistralrs(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<Which>()?;
     m.add_class::<ChatCompletionRequest>()?;
     m.add_class::<CompletionRequest>()?;
+    m.add_class::<EmbeddingRequest>()?;
+    m.add_class::<ScoreRequest>()?;
     m.add_class::<Architecture>()?;
     m.add_class::<VisionArchitecture>()?;
     m.add_class::<AnyMoeConfig>()?;
@@ -954,6 +1199,8 @@ fn mistralrs(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
 
     m.add_class::<mistralrs_core::ResponseMessage>()?;
     m.add_class::<mistralrs_core::Delta>()?;
+    m.add_class::<mistralrs_core::ToolCallDelta>()?;
+    m.add_class::<mistralrs_core::CalledFunctionDelta>()?;
     m.add_class::<mistralrs_core::ResponseLogprob>()?;
     m.add_class::<mistralrs_core::Logprobs>()?;
     m.add_class::<mistralrs_core::Choice>()?;
@@ -961,8 +1208,14 @@ fn mistralrs(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<mistralrs_core::Usage>()?;
     m.add_class::<mistralrs_core::ChatCompletionResponse>()?;
     m.add_class::<mistralrs_core::ChatCompletionChunkResponse>()?;
+    m.add_class::<mistralrs_core::CompletionLogprobs>()?;
     m.add_class::<mistralrs_core::CompletionChoice>()?;
     m.add_class::<mistralrs_core::CompletionResponse>()?;
     m.add_class::<mistralrs_core::TopLogprob>()?;
+    m.add_class::<mistralrs_core::Embedding>()?;
+    m.add_class::<mistralrs_core::EmbeddingUsage>()?;
+    m.add_class::<mistralrs_core::EmbeddingResponse>()?;
+    m.add_class::<mistralrs_core::ScoredToken>()?;
+    m.add_class::<mistralrs_core::ScoreResponse>()?;
     Ok(())
 }
@@ -1,8 +1,9 @@
 use std::collections::HashMap;
 
 use either::Either;
+use mistralrs_core::{EmbeddingInput, EmbeddingPooling};
 use pyo3::{
-    exceptions::PyTypeError,
+    exceptions::{PyTypeError, PyValueError},
     pyclass, pymethods,
     types::{PyAnyMethods, PyList, PyString},
     Py, PyAny, PyErr, PyResult, Python,
@@ -13,6 +14,7 @@ use pyo3::{
 pub enum ToolChoice {
     NoTools,
     Auto,
+    Required,
 }
 
 #[pyclass]
@@ -39,6 +41,23 @@ pub struct CompletionRequest {
     pub(crate) min_p: Option<f64>,
     pub(crate) tool_schemas: Option<Vec<String>>,
     pub(crate) tool_choice: Option<ToolChoice>,
+    pub(crate) beam_width: Option<usize>,
+    pub(crate) length_penalty: Option<f32>,
+    pub(crate) seed: Option<u64>,
+    pub(crate) logprobs: Option<usize>,
+    pub(crate) priority: Option<i32>,
+    pub(crate) tenant: Option<String>,
+    pub(crate) processed_logprobs: bool,
+    pub(crate) repetition_penalty: Option<f32>,
+    pub(crate) no_repeat_ngram_size: Option<usize>,
+    pub(crate) dry_multiplier: Option<f32>,
+    pub(crate) dry_base: Option<f32>,
+    pub(crate) dry_allowed_length: Option<usize>,
+    pub(crate) dry_sequence_breakers: Option<Vec<String>>,
+    pub(crate) typical_p: Option<f64>,
+    pub(crate) top_a: Option<f64>,
+    pub(crate) mirostat_tau: Option<f32>,
+    pub(crate) mirostat_eta: Option<f32>,
 }
 
 #[pymethods]
@@ -65,6 +84,23 @@ impl CompletionRequest {
         min_p=None,
         tool_schemas=None,
         tool_choice=None,
+        beam_width=None,
+        length_penalty=None,
+        seed=None,
+        logprobs=None,
+        priority=None,
+        tenant=None,
+        processed_logprobs=false,
+        repetition_penalty=None,
+        no_repeat_ngram_size=None,
+        dry_multiplier=None,
+        dry_base=None,
+        dry_allowed_length=None,
+        dry_sequence_breakers=None,
+        typical_p=None,
+        top_a=None,
+        mirostat_tau=None,
+        mirostat_eta=None,
     ))]
     fn new(
         prompt: String,
@@ -87,6 +123,23 @@ impl CompletionRequest {
         min_p: Option<f64>,
         tool_schemas: Option<Vec<String>>,
         tool_choice: Option<ToolChoice>,
+        beam_width: Option<usize>,
+        length_penalty: Option<f32>,
+        seed: Option<u64>,
+        logprobs: Option<usize>,
+        priority: Option<i32>,
+        tenant: Option<String>,
+        processed_logprobs: bool,
+        repetition_penalty: Option<f32>,
+        no_repeat_ngram_size: Option<usize>,
+        dry_multiplier: Option<f32>,
+        dry_base: Option<f32>,
+        dry_allowed_length: Option<usize>,
+        dry_sequence_breakers: Option<Vec<String>>,
+        typical_p: Option<f64>,
+        top_a: Option<f64>,
+        mirostat_tau: Option<f32>,
+        mirostat_eta: Option<f32>,
     ) -> PyResult<Self> {
         Ok(Self {
             prompt,
@@ -109,6 +162,23 @@ impl CompletionRequest {
             min_p,
             tool_schemas,
             tool_choice,
+            beam_width,
+            length_penalty,
+            seed,
+            logprobs,
+            priority,
+            tenant,
+            processed_logprobs,
+            repetition_penalty,
+            no_repeat_ngram_size,
+            dry_multiplier,
+            dry_base,
+            dry_allowed_length,
+            dry_sequence_breakers,
+            typical_p,
+            top_a,
+            mirostat_tau,
+            mirostat_eta,
         })
     }
 }
@@ -146,6 +216,23 @@ pub struct ChatCompletionRequest {
     pub(crate) min_p: Option<f64>,
     pub(crate) tool_schemas: Option<Vec<String>>,
     pub(crate) tool_choice: Option<ToolChoice>,
+    pub(crate) beam_width: Option<usize>,
+    pub(crate) length_penalty: Option<f32>,
+    pub(crate) seed: Option<u64>,
+    pub(crate) priority: Option<i32>,
+    pub(crate) tenant: Option<String>,
+    pub(crate) processed_logprobs: bool,
+    pub(crate) repetition_penalty: Option<f32>,
+    pub(crate) no_repeat_ngram_size: Option<usize>,
+    pub(crate) dry_multiplier: Option<f32>,
+    pub(crate) dry_base: Option<f32>,
+    pub(crate) dry_allowed_length: Option<usize>,
+    pub(crate) dry_sequence_breakers: Option<Vec<String>>,
+    pub(crate) typical_p: Option<f64>,
+    pub(crate) top_a: Option<f64>,
+    pub(crate) mirostat_tau: Option<f32>,
+    pub(crate) mirostat_eta: Option<f32>,
+    pub(crate) include_usage: bool,
 }
 
 #[pymethods]
@@ -172,6 +259,23 @@ impl ChatCompletionRequest {
         min_p=None,
         tool_schemas=None,
         tool_choice=None,
+        beam_width=None,
+        length_penalty=None,
+        seed=None,
+        priority=None,
+        tenant=None,
+        processed_logprobs=false,
+        repetition_penalty=None,
+        no_repeat_ngram_size=None,
+        dry_multiplier=None,
+        dry_base=None,
+        dry_allowed_length=None,
+        dry_sequence_breakers=None,
+        typical_p=None,
+        top_a=None,
+        mirostat_tau=None,
+        mirostat_eta=None,
+        include_usage=false,
     ))]
     fn new(
         messages: Py<PyAny>,
@@ -194,6 +298,23 @@ impl ChatCompletionRequest {
         min_p: Option<f64>,
         tool_schemas: Option<Vec<String>>,
         tool_choice: Option<ToolChoice>,
+        beam_width: Option<usize>,
+        length_penalty: Option<f32>,
+        seed: Option<u64>,
+        priority: Option<i32>,
+        tenant: Option<String>,
+        processed_logprobs: bool,
+        repetition_penalty: Option<f32>,
+        no_repeat_ngram_size: Option<usize>,
+        dry_multiplier: Option<f32>,
+        dry_base: Option<f32>,
+        dry_allowed_length: Option<usize>,
+        dry_sequence_breakers: Option<Vec<String>>,
+        typical_p: Option<f64>,
+        top_a: Option<f64>,
+        mirostat_tau: Option<f32>,
+        mirostat_eta: Option<f32>,
+        include_usage: bool,
     ) -> PyResult<Self> {
         let messages = Python::with_gil(|py| {
             if let Ok(messages) = messages.bind(py).downcast_exact::<PyList>() {
@@ -264,6 +385,107 @@ impl ChatCompletionRequest {
             min_p,
             tool_choice,
             tool_schemas,
+            beam_width,
+            length_penalty,
+            seed,
+            priority,
+            tenant,
+            processed_logprobs,
+            repetition_penalty,
+            no_repeat_ngram_size,
+            dry_multiplier,
+            dry_base,
+            dry_allowed_length,
+            dry_sequence_breakers,
+            typical_p,
+            top_a,
+            mirostat_tau,
+            mirostat_eta,
+            include_usage,
+        })
+    }
+}
+
+#[pyclass]
+#[derive(Debug)]
+/// An OpenAI API compatible embedding request.
+pub struct EmbeddingRequest {
+    pub(crate) input: Vec<EmbeddingInput>,
+    pub(crate) _model: String,
+    pub(crate) pooling: EmbeddingPooling,
+    pub(crate) normalize: bool,
+}
+
+#[pymethods]
+impl EmbeddingRequest {
+    #[new]
+    #[pyo3(signature = (
+        input,
+        model,
+        pooling = "mean".to_string(),
+        normalize = true,
+    ))]
+    fn new(input: Py<PyAny>, model: String, pooling: String, normalize: bool) -> PyResult<Self> {
+        let input = Python::with_gil(|py| {
+            let input = input.bind(py);
+            if let Ok(text) = input.extract::<String>() {
+                Ok(vec![EmbeddingInput::Text(text)])
+            } else if let Ok(texts) = input.extract::<Vec<String>>() {
+                Ok(texts.into_iter().map(EmbeddingInput::Text).collect())
+            } else if let Ok(toks) = input.extract::<Vec<u32>>() {
+                Ok(vec![EmbeddingInput::Tokens(toks)])
+            } else if let Ok(toks) = input.extract::<Vec<Vec<u32>>>() {
+                Ok(toks.into_iter().map(EmbeddingInput::Tokens).collect())
+            } else {
+                Err(PyTypeError::new_err(
+                    "Expected a string, a list of strings, a list of tokens or a list of lists of tokens.",
+                ))
+            }
+        })?;
+        let pooling = match pooling.as_str() {
+            "mean" => EmbeddingPooling::Mean,
+            "last" => EmbeddingPooling::Last,
+            other => {
+                return Err(PyValueError::new_err(format!(
+                    "Expected pooling to be `mean` or `last`, got `{other}`."
+                )))
+            }
+        };
+        Ok(Self {
+            input,
+            _model: model,
+            pooling,
+            normalize,
         })
     }
 }
+
+#[pyclass]
+#[derive(Debug)]
+/// A request for the log-probabilities of the tokens of a prompt or of a continuation of it.
+pub struct ScoreRequest {
+    pub(crate) prompt: Either<String, Vec<u32>>,
+    pub(crate) continuation: Option<Either<String, Vec<u32>>>,
+    pub(crate) top_logprobs: usize,
+}
+
+#[pymethods]
+impl ScoreRequest {
+    #[new]
+    #[pyo3(signature = (
+        prompt,
+        continuation = None,
+        top_logprobs = 0,
+    ))]
+    fn new(
+        prompt: Either<String, Vec<u32>>,
+        continuation: Option<Either<String, Vec<u32>>>,
+        top_logprobs: usize,
+    ) -> Self {
+        Self {
+            prompt,
+            continuation,
+            top_logprobs,
+        }
+    }
+}
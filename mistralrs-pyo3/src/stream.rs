@@ -7,11 +7,16 @@ use pyo3::{exceptions::PyValueError, pyclass, pymethods, PyRef, PyRefMut, PyResu
 pub struct ChatCompletionStreamer {
     rx: Receiver<Response>,
     is_done: bool,
+    include_usage: bool,
 }
 
 impl ChatCompletionStreamer {
-    pub fn from_rx(rx: Receiver<Response>) -> Self {
-        Self { rx, is_done: false }
+    pub fn from_rx(rx: Receiver<Response>, include_usage: bool) -> Self {
+        Self {
+            rx,
+            is_done: false,
+            include_usage,
+        }
     }
 }
 
@@ -30,7 +35,10 @@ impl ChatCompletionStreamer {
                 Response::ValidationError(e) => Some(Err(PyValueError::new_err(e.to_string()))),
                 Response::InternalError(e) => Some(Err(PyValueError::new_err(e.to_string()))),
                 Response::Chunk(response) => {
-                    if response.choices.iter().all(|x| x.finish_reason.is_some()) {
+                    // With `include_usage`, the usage is sent in a final chunk after the one finishing the choices.
+                    if response.choices.iter().all(|x| x.finish_reason.is_some())
+                        && (!this.include_usage || response.usage.is_some())
+                    {
                         this.is_done = true;
                     }
                     Some(Ok(response))
@@ -39,6 +47,8 @@ impl ChatCompletionStreamer {
                 Response::CompletionDone(_) => unreachable!(),
                 Response::CompletionModelError(_, _) => unreachable!(),
                 Response::CompletionChunk(_) => unreachable!(),
+                Response::Embeddings(_) => unreachable!(),
+                Response::Score(_) => unreachable!(),
             },
             None => Some(Err(PyValueError::new_err(
                 "Received none in ChatCompletionStreamer".to_string(),
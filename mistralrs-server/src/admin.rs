@@ -0,0 +1,136 @@
+use std::{
+    fs,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use axum::{
+    extract::{Json, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use mistralrs_core::ModelSelected;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use utoipa::ToSchema;
+
+use crate::{load_model, registry::ModelRegistry, Args};
+
+/// Counter making the names of the temporary TOML selector files unique.
+static NEXT_SELECTOR_FILE: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct LoadModelRequest {
+    /// Name requests select the model with. A model already served under this name is replaced.
+    #[schema(example = "mistral")]
+    name: String,
+    /// TOML selector of the model, as in the files passed to `--additional-models`.
+    #[schema(
+        example = "[model]\nmodel_id = \"mistralai/Mistral-7B-Instruct-v0.1\"\narch = \"mistral\""
+    )]
+    toml: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct UnloadModelRequest {
+    #[schema(example = "mistral")]
+    name: String,
+}
+
+#[derive(Serialize)]
+struct JsonError {
+    message: String,
+}
+
+fn error_response(status: StatusCode, message: String) -> Response {
+    let mut r = Json(JsonError { message }).into_response();
+    *r.status_mut() = status;
+    r
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/admin/models/load",
+    request_body = LoadModelRequest,
+    responses((status = 200, description = "Load a model, replacing the model of the same name once its requests have finished"))
+)]
+pub async fn load_served_model(
+    State(models): State<Arc<ModelRegistry>>,
+    Extension(args): Extension<Arc<Args>>,
+    Json(request): Json<LoadModelRequest>,
+) -> Result<String, Response> {
+    // The loaders read TOML selectors from files.
+    let file = std::env::temp_dir().join(format!(
+        "mistralrs-selector-{}-{}.toml",
+        std::process::id(),
+        NEXT_SELECTOR_FILE.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::write(&file, &request.toml)
+        .map_err(|e| error_response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    info!("Loading model `{}`.", request.name);
+    let selector = ModelSelected::Toml {
+        file: file.display().to_string(),
+    };
+    let load_args = args.clone();
+    // Loading downloads and reads the weights synchronously, so keep it off the async workers.
+    let builder = tokio::task::spawn_blocking(move || {
+        tokio::runtime::Handle::current().block_on(load_model(selector, None, &load_args))
+    })
+    .await;
+    let _ = fs::remove_file(&file);
+    let builder = builder
+        .map_err(|e| error_response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| error_response(StatusCode::BAD_REQUEST, e.to_string()))?;
+    let builder = if args.throughput_log {
+        builder.with_throughput_logging()
+    } else {
+        builder
+    };
+
+    match models.get_exact(&request.name) {
+        Some(model) => {
+            // Swapping waits for the requests in flight on the old model to finish.
+            tokio::task::spawn_blocking(move || model.swap_model(builder))
+                .await
+                .map_err(|e| error_response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+                .map_err(|e| error_response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+            Ok(format!("Replaced model `{}`.", request.name))
+        }
+        None => {
+            models.insert(request.name.clone(), builder.build());
+            Ok(format!("Loaded model `{}`.", request.name))
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/admin/models/unload",
+    request_body = UnloadModelRequest,
+    responses((status = 200, description = "Stop serving a model and free it once its requests have finished"))
+)]
+pub async fn unload_served_model(
+    State(models): State<Arc<ModelRegistry>>,
+    Json(request): Json<UnloadModelRequest>,
+) -> Result<String, Response> {
+    let Some(model) = models.remove(&request.name) else {
+        return Err(error_response(
+            StatusCode::NOT_FOUND,
+            format!("The model `{}` does not exist.", request.name),
+        ));
+    };
+
+    info!("Unloading model `{}`.", request.name);
+    // New requests are no longer routed to the model, wait for the ones in flight to finish.
+    tokio::task::spawn_blocking(move || model.unload())
+        .await
+        .map_err(|e| error_response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| error_response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(format!("Unloaded model `{}`.", request.name))
+}
@@ -0,0 +1,428 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use axum::{
+    body::Body,
+    extract::{Json, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::openai::StreamOptions;
+
+/// Length of the window the per-minute limits are counted over.
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+/// An API key and its limits, as listed in the API keys file.
+#[derive(Deserialize)]
+struct ApiKeyConfig {
+    key: String,
+    /// Name of the key in logs and metrics, so that the key itself is never exposed.
+    name: String,
+    requests_per_minute: Option<usize>,
+    tokens_per_minute: Option<usize>,
+    max_concurrent_requests: Option<usize>,
+    /// Whether the key may use the routes which change the served models.
+    #[serde(default)]
+    admin: bool,
+}
+
+/// The API key a request was authenticated with, added to the extensions of the request.
+#[derive(Clone)]
+pub struct AuthenticatedKey {
+    pub name: String,
+    pub admin: bool,
+}
+
+/// Usage of an API key since the server started.
+#[derive(Debug, Clone, Default)]
+pub struct KeyUsage {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+}
+
+#[derive(Default)]
+struct RateWindow {
+    requests: VecDeque<Instant>,
+    tokens: VecDeque<(Instant, usize)>,
+}
+
+impl RateWindow {
+    fn prune(&mut self, now: Instant) {
+        while self
+            .requests
+            .front()
+            .is_some_and(|t| now.duration_since(*t) >= RATE_LIMIT_WINDOW)
+        {
+            self.requests.pop_front();
+        }
+        while self
+            .tokens
+            .front()
+            .is_some_and(|(t, _)| now.duration_since(*t) >= RATE_LIMIT_WINDOW)
+        {
+            self.tokens.pop_front();
+        }
+    }
+}
+
+struct KeyState {
+    config: ApiKeyConfig,
+    window: Mutex<RateWindow>,
+    in_flight: AtomicUsize,
+    usage: Mutex<KeyUsage>,
+}
+
+impl KeyState {
+    fn record_usage(&self, prompt_tokens: usize, completion_tokens: usize, total_tokens: usize) {
+        self.window
+            .lock()
+            .expect("API key lock was poisoned")
+            .tokens
+            .push_back((Instant::now(), total_tokens));
+        let mut usage = self.usage.lock().expect("API key lock was poisoned");
+        usage.prompt_tokens += prompt_tokens as u64;
+        usage.completion_tokens += completion_tokens as u64;
+        usage.total_tokens += total_tokens as u64;
+    }
+
+    /// Record the `usage` object of an OpenAI response, or the token counts of the last object of an Ollama
+    /// response, returning whether there were any.
+    fn record_usage_value(&self, response: &Value) -> bool {
+        let tokens = |object: &Value, name| {
+            object
+                .get(name)
+                .and_then(Value::as_u64)
+                .and_then(|n| usize::try_from(n).ok())
+                .unwrap_or(0)
+        };
+        if let Some(usage) = response.get("usage").filter(|usage| usage.is_object()) {
+            self.record_usage(
+                tokens(usage, "prompt_tokens"),
+                tokens(usage, "completion_tokens"),
+                tokens(usage, "total_tokens"),
+            );
+            true
+        } else if response.get("eval_count").is_some() {
+            let prompt_tokens = tokens(response, "prompt_eval_count");
+            let completion_tokens = tokens(response, "eval_count");
+            self.record_usage(
+                prompt_tokens,
+                completion_tokens,
+                prompt_tokens + completion_tokens,
+            );
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// The API keys accepted by the server, with their limits and usage.
+pub struct ApiKeys {
+    keys: HashMap<String, Arc<KeyState>>,
+}
+
+impl ApiKeys {
+    /// Load the API keys from a JSON file holding an array of objects with the keys `key`, `name` and optionally
+    /// `requests_per_minute`, `tokens_per_minute` and `max_concurrent_requests`.
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read API keys file `{}`", path.display()))?;
+        let configs: Vec<ApiKeyConfig> = serde_json::from_str(&contents)
+            .with_context(|| format!("Invalid API keys file `{}`", path.display()))?;
+        let mut keys = HashMap::new();
+        for config in configs {
+            let key = config.key.clone();
+            let state = KeyState {
+                config,
+                window: Mutex::new(RateWindow::default()),
+                in_flight: AtomicUsize::new(0),
+                usage: Mutex::new(KeyUsage::default()),
+            };
+            if keys.insert(key, Arc::new(state)).is_some() {
+                anyhow::bail!(
+                    "An API key is listed more than once in `{}`.",
+                    path.display()
+                );
+            }
+        }
+        Ok(Self { keys })
+    }
+
+    /// Usage of each key since the server started, by key name.
+    pub fn usage(&self) -> Vec<(String, KeyUsage)> {
+        let mut usage = self
+            .keys
+            .values()
+            .map(|key| {
+                (
+                    key.config.name.clone(),
+                    key.usage.lock().expect("API key lock was poisoned").clone(),
+                )
+            })
+            .collect::<Vec<_>>();
+        usage.sort_by(|(a, _), (b, _)| a.cmp(b));
+        usage
+    }
+}
+
+#[derive(Serialize)]
+struct OpenAIErrorBody {
+    message: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    param: Option<String>,
+    code: &'static str,
+}
+
+#[derive(Serialize)]
+struct OpenAIError {
+    error: OpenAIErrorBody,
+}
+
+fn error_response(
+    status: StatusCode,
+    kind: &'static str,
+    code: &'static str,
+    message: String,
+) -> Response {
+    let mut r = Json(OpenAIError {
+        error: OpenAIErrorBody {
+            message,
+            kind,
+            param: None,
+            code,
+        },
+    })
+    .into_response();
+    *r.status_mut() = status;
+    r
+}
+
+/// Added to the extensions of a streamed response whose usage chunk the client did not ask for, so it is not sent.
+#[derive(Clone)]
+pub struct HideUsage;
+
+/// Make an authenticated streaming request send its usage, which the tokens of its key are tallied from.
+/// Returns whether the client did not ask for the usage, in which case the response must be marked with
+/// [`HideUsage`].
+pub fn force_stream_usage(
+    stream: Option<bool>,
+    stream_options: &mut Option<StreamOptions>,
+) -> bool {
+    if !stream.unwrap_or(false) || stream_options.as_ref().is_some_and(|o| o.include_usage) {
+        return false;
+    }
+    *stream_options = Some(StreamOptions {
+        include_usage: true,
+    });
+    true
+}
+
+/// Holds a concurrent request slot of a key until the response body is dropped. Streamed responses, as server-sent
+/// events or newline-delimited JSON, are tallied from their `usage`, or by their chunks when they have none.
+struct RequestGuard {
+    key: Arc<KeyState>,
+    streamed_tokens: usize,
+    saw_usage: bool,
+}
+
+impl RequestGuard {
+    /// Tally a chunk of the response, returning whether it held the usage.
+    fn observe_chunk(&mut self, bytes: &[u8]) -> bool {
+        let Ok(text) = std::str::from_utf8(bytes) else {
+            return false;
+        };
+        let mut has_usage = false;
+        for line in text.lines() {
+            let data = line.strip_prefix("data:").unwrap_or(line);
+            let Ok(event) = serde_json::from_str::<Value>(data.trim()) else {
+                continue;
+            };
+            if self.key.record_usage_value(&event) {
+                self.saw_usage = true;
+                has_usage = true;
+            } else if let Some(choices) = event.get("choices").and_then(Value::as_array) {
+                // Each chunk holds one token of each of its choices.
+                self.streamed_tokens += choices.len();
+            } else if event.get("done").and_then(Value::as_bool) == Some(false) {
+                self.streamed_tokens += 1;
+            }
+        }
+        has_usage
+    }
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        if !self.saw_usage && self.streamed_tokens > 0 {
+            self.key
+                .record_usage(0, self.streamed_tokens, self.streamed_tokens);
+        }
+        self.key.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Check that the request has a valid API key and that the key is within its limits, then tally the usage
+/// of the response.
+pub async fn authenticate(
+    State(keys): State<Arc<ApiKeys>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let Some(provided) = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return error_response(
+            StatusCode::UNAUTHORIZED,
+            "invalid_request_error",
+            "invalid_api_key",
+            "You didn't provide an API key. You need to provide your API key in an Authorization header using Bearer auth (i.e. Authorization: Bearer YOUR_KEY).".to_string(),
+        );
+    };
+    let Some(key) = keys.keys.get(provided.trim()).cloned() else {
+        return error_response(
+            StatusCode::UNAUTHORIZED,
+            "invalid_request_error",
+            "invalid_api_key",
+            "Incorrect API key provided.".to_string(),
+        );
+    };
+
+    {
+        let now = Instant::now();
+        let mut window = key.window.lock().expect("API key lock was poisoned");
+        window.prune(now);
+        if let Some(limit) = key.config.requests_per_minute {
+            if window.requests.len() >= limit {
+                return error_response(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "requests",
+                    "rate_limit_exceeded",
+                    format!(
+                        "Rate limit reached for key `{}` on requests per minute: Limit {limit}, Used {}.",
+                        key.config.name,
+                        window.requests.len()
+                    ),
+                );
+            }
+        }
+        if let Some(limit) = key.config.tokens_per_minute {
+            let used = window.tokens.iter().map(|(_, n)| n).sum::<usize>();
+            if used >= limit {
+                return error_response(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "tokens",
+                    "rate_limit_exceeded",
+                    format!(
+                        "Rate limit reached for key `{}` on tokens per minute: Limit {limit}, Used {used}.",
+                        key.config.name
+                    ),
+                );
+            }
+        }
+        let max_in_flight = key.config.max_concurrent_requests.unwrap_or(usize::MAX);
+        if key
+            .in_flight
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < max_in_flight).then_some(n + 1)
+            })
+            .is_err()
+        {
+            return error_response(
+                StatusCode::TOO_MANY_REQUESTS,
+                "requests",
+                "rate_limit_exceeded",
+                format!(
+                    "Concurrent request limit reached for key `{}`: Limit {max_in_flight}.",
+                    key.config.name
+                ),
+            );
+        }
+        window.requests.push_back(now);
+    }
+    key.usage
+        .lock()
+        .expect("API key lock was poisoned")
+        .requests += 1;
+    let mut guard = RequestGuard {
+        key: key.clone(),
+        streamed_tokens: 0,
+        saw_usage: false,
+    };
+    request.extensions_mut().insert(AuthenticatedKey {
+        name: key.config.name.clone(),
+        admin: key.config.admin,
+    });
+
+    let response = next.run(request).await;
+    let (parts, body) = response.into_parts();
+    let content_type = parts
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    if content_type.starts_with("text/event-stream")
+        || content_type.starts_with("application/x-ndjson")
+    {
+        let hide_usage = parts.extensions.get::<HideUsage>().is_some();
+        let body = body.into_data_stream().filter_map(move |chunk| {
+            let is_usage = matches!(&chunk, Ok(bytes) if guard.observe_chunk(bytes));
+            std::future::ready((!(is_usage && hide_usage)).then_some(chunk))
+        });
+        Response::from_parts(parts, Body::from_stream(body))
+    } else if content_type.starts_with("application/json") {
+        let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                return error_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "server_error",
+                    "internal_error",
+                    e.to_string(),
+                )
+            }
+        };
+        if let Ok(value) = serde_json::from_slice::<Value>(&bytes) {
+            key.record_usage_value(&value);
+        }
+        Response::from_parts(parts, Body::from(bytes))
+    } else {
+        Response::from_parts(parts, body)
+    }
+}
+
+/// Reject requests authenticated with a key which is not an admin key. Requests are let through when the server
+/// has no API keys.
+pub async fn require_admin(request: Request, next: Next) -> Response {
+    if let Some(key) = request.extensions().get::<AuthenticatedKey>() {
+        if !key.admin {
+            return error_response(
+                StatusCode::FORBIDDEN,
+                "invalid_request_error",
+                "insufficient_permissions",
+                format!("The API key `{}` is not an admin key.", key.name),
+            );
+        }
+    }
+    next.run(request).await
+}
@@ -0,0 +1,333 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use anyhow::Context;
+use axum::{
+    extract::{Json, State},
+    response::IntoResponse,
+};
+use futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::{
+    chat_completion::chatcompletions,
+    completions::completions,
+    embeddings::embeddings,
+    openai::{ChatCompletionRequest, CompletionRequest, EmbeddingRequest},
+    registry::ModelRegistry,
+};
+
+/// Maximum number of requests sent to the engine at once. The scheduler batches the running requests.
+const MAX_IN_FLIGHT: usize = 1024;
+
+/// A line of an OpenAI batch input file.
+#[derive(Deserialize)]
+struct BatchInputLine {
+    custom_id: String,
+    method: String,
+    url: String,
+    body: Value,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BatchResponse {
+    status_code: u16,
+    body: Value,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BatchError {
+    code: String,
+    message: String,
+}
+
+/// A line of an OpenAI batch output file.
+#[derive(Serialize, Deserialize)]
+struct BatchOutputLine {
+    id: String,
+    custom_id: String,
+    response: Option<BatchResponse>,
+    error: Option<BatchError>,
+}
+
+impl BatchOutputLine {
+    fn error(id: String, custom_id: String, code: &str, message: String) -> Self {
+        Self {
+            id,
+            custom_id,
+            response: None,
+            error: Some(BatchError {
+                code: code.to_string(),
+                message,
+            }),
+        }
+    }
+}
+
+/// Read the `custom_id`s of the requests already in the output file. A partially written last line, left by an
+/// interrupted run, is removed so that its request runs again.
+fn completed_requests(output: &Path) -> anyhow::Result<HashSet<String>> {
+    let mut completed = HashSet::new();
+    if !output.exists() {
+        return Ok(completed);
+    }
+    let contents = fs::read_to_string(output)
+        .with_context(|| format!("Failed to read batch output file `{}`", output.display()))?;
+    let complete_len = contents.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    if complete_len < contents.len() {
+        warn!("Removing a partially written line from the end of the batch output file.");
+        OpenOptions::new()
+            .write(true)
+            .open(output)?
+            .set_len(complete_len as u64)?;
+    }
+    for line in contents[..complete_len].lines() {
+        if let Ok(line) = serde_json::from_str::<BatchOutputLine>(line) {
+            completed.insert(line.custom_id);
+        }
+    }
+    Ok(completed)
+}
+
+/// Run a request through the handler of its endpoint, exactly as if it had been sent to the server.
+async fn run_request(
+    models: Arc<ModelRegistry>,
+    id: String,
+    line: BatchInputLine,
+) -> BatchOutputLine {
+    if line.method != "POST" {
+        return BatchOutputLine::error(
+            id,
+            line.custom_id,
+            "invalid_method",
+            format!(
+                "Only the `POST` method is supported, got `{}`.",
+                line.method
+            ),
+        );
+    }
+
+    macro_rules! parse_body {
+        ($request:ty) => {
+            match serde_json::from_value::<$request>(line.body) {
+                Ok(request) => request,
+                Err(e) => {
+                    return BatchOutputLine::error(id, line.custom_id, "invalid_body", e.to_string())
+                }
+            }
+        };
+    }
+    let stream_error = |id, custom_id| {
+        BatchOutputLine::error(
+            id,
+            custom_id,
+            "invalid_body",
+            "Streaming is not supported in batches.".to_string(),
+        )
+    };
+
+    let response = match line.url.as_str() {
+        "/v1/chat/completions" => {
+            let request = parse_body!(ChatCompletionRequest);
+            if request.stream.is_some_and(|stream| stream) {
+                return stream_error(id, line.custom_id);
+            }
+            chatcompletions(State(models), None, Json(request))
+                .await
+                .into_response()
+        }
+        "/v1/completions" => {
+            let request = parse_body!(CompletionRequest);
+            if request.stream.is_some_and(|stream| stream) {
+                return stream_error(id, line.custom_id);
+            }
+            completions(State(models), None, Json(request))
+                .await
+                .into_response()
+        }
+        "/v1/embeddings" => {
+            let request = parse_body!(EmbeddingRequest);
+            embeddings(State(models), Json(request))
+                .await
+                .into_response()
+        }
+        url => {
+            return BatchOutputLine::error(
+                id,
+                line.custom_id,
+                "invalid_url",
+                format!("Unsupported batch endpoint `{url}`."),
+            )
+        }
+    };
+
+    let status_code = response.status().as_u16();
+    let body = match axum::body::to_bytes(response.into_body(), usize::MAX).await {
+        Ok(body) => body,
+        Err(e) => {
+            return BatchOutputLine::error(id, line.custom_id, "internal_error", e.to_string())
+        }
+    };
+    let body = serde_json::from_slice(&body)
+        .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&body).to_string()));
+    BatchOutputLine {
+        id,
+        custom_id: line.custom_id,
+        response: Some(BatchResponse { status_code, body }),
+        error: None,
+    }
+}
+
+/// Run the requests of an OpenAI batch input file, appending the results to the output file as they complete.
+/// Requests whose `custom_id` is already in the output file are skipped, so an interrupted batch may be resumed.
+pub async fn batch_mode(
+    models: Arc<ModelRegistry>,
+    input: &Path,
+    output: &Path,
+) -> anyhow::Result<()> {
+    let completed = completed_requests(output)?;
+
+    let reader = BufReader::new(
+        File::open(input)
+            .with_context(|| format!("Failed to open batch input file `{}`", input.display()))?,
+    );
+    let mut lines = Vec::new();
+    let mut invalid = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let id = format!("batch_req_{i}");
+        match serde_json::from_str::<BatchInputLine>(&line) {
+            Ok(line) => {
+                if !completed.contains(&line.custom_id) {
+                    lines.push((id, line));
+                }
+            }
+            // The request fails, but the rest of the batch still runs.
+            Err(e) => {
+                let custom_id = serde_json::from_str::<Value>(&line)
+                    .ok()
+                    .and_then(|line| line.get("custom_id")?.as_str().map(ToString::to_string))
+                    .unwrap_or_else(|| format!("line-{}", i + 1));
+                if !completed.contains(&custom_id) {
+                    invalid.push(BatchOutputLine::error(
+                        id,
+                        custom_id,
+                        "invalid_request",
+                        format!("Invalid batch request on line {}: {e}", i + 1),
+                    ));
+                }
+            }
+        }
+    }
+    if !completed.is_empty() {
+        info!(
+            "Resuming batch: {} requests already completed, {} remaining.",
+            completed.len(),
+            lines.len() + invalid.len()
+        );
+    }
+
+    let mut writer = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(output)
+        .with_context(|| format!("Failed to open batch output file `{}`", output.display()))?;
+    let n_requests = lines.len() + invalid.len();
+    let mut results = stream::iter(invalid).chain(
+        stream::iter(lines)
+            .map(|(id, line)| run_request(models.clone(), id, line))
+            .buffer_unordered(MAX_IN_FLIGHT),
+    );
+    let mut n_done = 0;
+    let mut n_failed = 0;
+    while let Some(result) = results.next().await {
+        if result.error.is_some()
+            || result
+                .response
+                .as_ref()
+                .is_some_and(|r| r.status_code != 200)
+        {
+            n_failed += 1;
+        }
+        // Write whole lines, so that an interrupted batch leaves at most one partial line.
+        let mut line = serde_json::to_string(&result)?;
+        line.push('\n');
+        writer.write_all(line.as_bytes())?;
+        writer.flush()?;
+        n_done += 1;
+        if n_done % 100 == 0 {
+            info!("Batch progress: {n_done}/{n_requests} requests completed.");
+        }
+    }
+    info!("Batch completed: {n_done} requests, of which {n_failed} failed.");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use super::completed_requests;
+
+    fn temp_file(name: &str) -> PathBuf {
+        let file = std::env::temp_dir().join(format!(
+            "mistralrs-batch-{name}-{}.jsonl",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&file);
+        file
+    }
+
+    #[test]
+    fn resume_skips_completed_requests() {
+        let output = temp_file("resume");
+        assert!(completed_requests(&output).unwrap().is_empty());
+
+        fs::write(
+            &output,
+            concat!(
+                r#"{"id":"batch_req_0","custom_id":"a","response":{"status_code":200,"body":{}},"error":null}"#,
+                "\n",
+                r#"{"id":"batch_req_1","custom_id":"b","response":null,"error":{"code":"invalid_body","message":""}}"#,
+                "\n",
+            ),
+        )
+        .unwrap();
+        let completed = completed_requests(&output).unwrap();
+        assert_eq!(completed.len(), 2);
+        assert!(completed.contains("a") && completed.contains("b"));
+
+        fs::remove_file(&output).unwrap();
+    }
+
+    #[test]
+    fn partial_last_line_is_removed() {
+        let output = temp_file("partial");
+        let complete = concat!(
+            r#"{"id":"batch_req_0","custom_id":"a","response":{"status_code":200,"body":{}},"error":null}"#,
+            "\n",
+        );
+        fs::write(
+            &output,
+            format!(r#"{complete}{{"id":"batch_req_1","custom_id":"b","resp"#),
+        )
+        .unwrap();
+
+        let completed = completed_requests(&output).unwrap();
+        assert_eq!(completed.len(), 1);
+        assert!(completed.contains("a"));
+        assert_eq!(fs::read_to_string(&output).unwrap(), complete);
+
+        fs::remove_file(&output).unwrap();
+    }
+}
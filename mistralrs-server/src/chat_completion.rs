@@ -13,7 +13,13 @@ use std::{
 };
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 
-use crate::openai::{ChatCompletionRequest, Grammar, MessageInnerContent, StopTokens};
+use crate::{
+    auth::{force_stream_usage, AuthenticatedKey, HideUsage},
+    openai::{
+        ChatCompletionRequest, Grammar, Message, MessageInnerContent, ResponseFormat, StopTokens,
+    },
+    registry::{ModelNotFound, ModelRegistry},
+};
 use anyhow::Result;
 use axum::{
     extract::{Json, State},
@@ -22,12 +28,14 @@ use axum::{
         sse::{Event, KeepAlive},
         IntoResponse, Sse,
     },
+    Extension,
 };
 use either::Either;
 use indexmap::IndexMap;
 use mistralrs_core::{
-    ChatCompletionResponse, Constraint, MistralRs, NormalRequest, Request, RequestMessage,
-    Response, SamplingParams, StopTokens as InternalStopTokens,
+    ChatCompletionResponse, Constraint, DrySamplingParams, MirostatParams, MistralRs,
+    NormalRequest, Request, RequestMessage, Response, SamplingParams,
+    StopTokens as InternalStopTokens,
 };
 use serde::Serialize;
 
@@ -44,6 +52,20 @@ pub struct Streamer {
     rx: Receiver<Response>,
     is_done: bool,
     state: Arc<MistralRs>,
+    request_id: usize,
+    /// The stream ends with a chunk holding the usage, after the chunk finishing the choices.
+    include_usage: bool,
+}
+
+impl Drop for Streamer {
+    fn drop(&mut self) {
+        // The client disconnected before the request finished, so stop generating for it.
+        if !self.is_done {
+            if let Err(e) = self.state.cancel_request(self.request_id) {
+                MistralRs::maybe_log_error(self.state.clone(), &e);
+            }
+        }
+    }
 }
 
 impl futures::Stream for Streamer {
@@ -53,7 +75,11 @@ impl futures::Stream for Streamer {
         if self.is_done {
             return Poll::Ready(None);
         }
-        match self.rx.try_recv() {
+        let resp = self.rx.try_recv();
+        if let Ok(resp) = &resp {
+            self.state.get_metrics().record_response(resp);
+        }
+        match resp {
             Ok(resp) => match resp {
                 Response::ModelError(msg, _) => {
                     MistralRs::maybe_log_error(
@@ -70,7 +96,9 @@ impl futures::Stream for Streamer {
                     Poll::Ready(Some(Ok(Event::default().data(e.to_string()))))
                 }
                 Response::Chunk(response) => {
-                    if response.choices.iter().all(|x| x.finish_reason.is_some()) {
+                    if response.choices.iter().all(|x| x.finish_reason.is_some())
+                        && (!self.include_usage || response.usage.is_some())
+                    {
                         self.is_done = true;
                     }
                     MistralRs::maybe_log_response(self.state.clone(), &response);
@@ -80,6 +108,8 @@ impl futures::Stream for Streamer {
                 Response::CompletionDone(_) => unreachable!(),
                 Response::CompletionModelError(_, _) => unreachable!(),
                 Response::CompletionChunk(_) => unreachable!(),
+                Response::Embeddings(_) => unreachable!(),
+                Response::Score(_) => unreachable!(),
             },
             Err(_) => Poll::Pending,
         }
@@ -150,20 +180,13 @@ impl IntoResponse for ChatCompletionResponder {
     }
 }
 
-async fn parse_request(
-    oairequest: ChatCompletionRequest,
-    state: Arc<MistralRs>,
-    tx: Sender<Response>,
-) -> Result<(Request, bool)> {
-    let repr = serde_json::to_string(&oairequest).expect("Serialization of request failed.");
-    MistralRs::maybe_log_request(state.clone(), repr);
-
-    let stop_toks = match oairequest.stop_seqs {
-        Some(StopTokens::Multi(m)) => Some(InternalStopTokens::Seqs(m)),
-        Some(StopTokens::Single(s)) => Some(InternalStopTokens::Seqs(vec![s])),
-        None => None,
-    };
-    let messages = match oairequest.messages {
+/// Convert the messages, or a single prompt, of a chat completion request. The images of the messages are fetched
+/// if `fetch_images` is set.
+pub(crate) async fn parse_messages(
+    messages: Either<Vec<Message>, String>,
+    fetch_images: bool,
+) -> Result<RequestMessage> {
+    Ok(match messages {
         Either::Left(req_messages) => {
             let mut messages = Vec::new();
             let mut image_urls = Vec::new();
@@ -257,7 +280,7 @@ async fn parse_request(
                     }
                 }
             }
-            if !image_urls.is_empty() {
+            if fetch_images && !image_urls.is_empty() {
                 let mut images = Vec::new();
                 for url in image_urls {
                     let bytes = if url.contains("http") {
@@ -292,8 +315,49 @@ async fn parse_request(
             messages.push(message_map);
             RequestMessage::Chat(messages)
         }
+    })
+}
+
+async fn parse_request(
+    oairequest: ChatCompletionRequest,
+    state: Arc<MistralRs>,
+    tx: Sender<Response>,
+) -> Result<(Request, bool)> {
+    let repr = serde_json::to_string(&oairequest).expect("Serialization of request failed.");
+    MistralRs::maybe_log_request(state.clone(), repr);
+
+    let stop_toks = match oairequest.stop_seqs {
+        Some(StopTokens::Multi(m)) => Some(InternalStopTokens::Seqs(m)),
+        Some(StopTokens::Single(s)) => Some(InternalStopTokens::Seqs(vec![s])),
+        None => None,
     };
 
+    let constraint = match (oairequest.grammar, oairequest.response_format) {
+        (Some(_), Some(ResponseFormat::JsonSchema { .. } | ResponseFormat::JsonObject)) => {
+            anyhow::bail!("`grammar` cannot be used together with a JSON response format.")
+        }
+        (None, Some(ResponseFormat::JsonSchema { json_schema })) => {
+            Constraint::JsonSchema(json_schema.schema)
+        }
+        (None, Some(ResponseFormat::JsonObject)) => {
+            Constraint::JsonSchema(serde_json::json!({"type": "object"}))
+        }
+        (Some(Grammar::Yacc(yacc)), _) => Constraint::Yacc(yacc),
+        (Some(Grammar::Regex(regex)), _) => Constraint::Regex(regex),
+        (Some(Grammar::JsonSchema(schema)), _) => Constraint::JsonSchema(schema),
+        (None, _) => Constraint::None,
+    };
+    let messages = parse_messages(oairequest.messages, true).await?;
+
+    let dry_params = oairequest.dry_multiplier.map(|multiplier| {
+        DrySamplingParams::new_with_defaults(
+            multiplier,
+            oairequest.dry_sequence_breakers,
+            oairequest.dry_base,
+            oairequest.dry_allowed_length,
+        )
+    });
+
     let is_streaming = oairequest.stream.unwrap_or(false);
     Ok((
         Request::Normal(NormalRequest {
@@ -311,19 +375,35 @@ async fn parse_request(
                 stop_toks,
                 logits_bias: oairequest.logit_bias,
                 n_choices: oairequest.n_choices,
+                beam_width: oairequest.beam_width,
+                length_penalty: oairequest.length_penalty,
+                seed: oairequest.seed,
+                processed_logprobs: oairequest.processed_logprobs,
+                repetition_penalty: oairequest.repetition_penalty,
+                no_repeat_ngram_size: oairequest.no_repeat_ngram_size,
+                dry_params,
+                typical_p: oairequest.typical_p,
+                top_a: oairequest.top_a,
+                mirostat: oairequest.mirostat_tau.map(|tau| MirostatParams {
+                    tau,
+                    eta: oairequest.mirostat_eta.unwrap_or(0.1),
+                }),
             },
             response: tx,
             return_logprobs: oairequest.logprobs,
             is_streaming,
             suffix: None,
-            constraint: match oairequest.grammar {
-                Some(Grammar::Yacc(yacc)) => Constraint::Yacc(yacc),
-                Some(Grammar::Regex(regex)) => Constraint::Regex(regex),
-                None => Constraint::None,
-            },
+            constraint,
             adapters: oairequest.adapters,
             tool_choice: oairequest.tool_choice,
             tools: oairequest.tools,
+            priority: oairequest.priority.unwrap_or_default(),
+            tenant: oairequest.tenant,
+            logits_processors: None,
+            include_usage: oairequest
+                .stream_options
+                .as_ref()
+                .is_some_and(|options| options.include_usage),
         }),
         is_streaming,
     ))
@@ -337,8 +417,27 @@ async fn parse_request(
     responses((status = 200, description = "Chat completions"))
 )]
 pub async fn chatcompletions(
-    State(state): State<Arc<MistralRs>>,
-    Json(oairequest): Json<ChatCompletionRequest>,
+    State(models): State<Arc<ModelRegistry>>,
+    key: Option<Extension<AuthenticatedKey>>,
+    Json(mut oairequest): Json<ChatCompletionRequest>,
+) -> Result<axum::response::Response, ModelNotFound> {
+    let state = models.get(&oairequest.model)?;
+    let mut hide_usage = false;
+    // The tenant of an authenticated request cannot be chosen by the client.
+    if let Some(Extension(key)) = key {
+        oairequest.tenant = Some(key.name);
+        hide_usage = force_stream_usage(oairequest.stream, &mut oairequest.stream_options);
+    }
+    let mut response = chat_completion(state, oairequest).await.into_response();
+    if hide_usage {
+        response.extensions_mut().insert(HideUsage);
+    }
+    Ok(response)
+}
+
+async fn chat_completion(
+    state: Arc<MistralRs>,
+    oairequest: ChatCompletionRequest,
 ) -> ChatCompletionResponder {
     let (tx, mut rx) = channel(10_000);
     let (request, is_streaming) = match parse_request(oairequest, state.clone(), tx).await {
@@ -349,6 +448,12 @@ pub async fn chatcompletions(
             return ChatCompletionResponder::InternalError(e.into());
         }
     };
+    let (request_id, include_usage) = match request {
+        Request::Normal(NormalRequest {
+            id, include_usage, ..
+        }) => (id, include_usage),
+        _ => unreachable!(),
+    };
     let sender = state.get_sender().unwrap();
 
     if let Err(e) = sender.send(request).await {
@@ -362,6 +467,8 @@ pub async fn chatcompletions(
             rx,
             is_done: false,
             state,
+            request_id,
+            include_usage,
         };
 
         ChatCompletionResponder::Sse(
@@ -384,6 +491,7 @@ pub async fn chatcompletions(
                 return ChatCompletionResponder::InternalError(e.into());
             }
         };
+        state.get_metrics().record_response(&response);
 
         match response {
             Response::InternalError(e) => {
@@ -404,6 +512,8 @@ pub async fn chatcompletions(
             Response::CompletionDone(_) => unreachable!(),
             Response::CompletionModelError(_, _) => unreachable!(),
             Response::CompletionChunk(_) => unreachable!(),
+            Response::Embeddings(_) => unreachable!(),
+            Response::Score(_) => unreachable!(),
         }
     }
 }
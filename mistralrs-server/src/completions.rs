@@ -4,11 +4,15 @@ use std::{
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
-    time::Duration,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 
-use crate::openai::{CompletionRequest, Grammar, StopTokens};
+use crate::{
+    auth::{force_stream_usage, AuthenticatedKey, HideUsage},
+    openai::{CompletionRequest, Grammar, StopTokens},
+    registry::{ModelNotFound, ModelRegistry},
+};
 use axum::{
     extract::{Json, State},
     http::{self, StatusCode},
@@ -16,13 +20,15 @@ use axum::{
         sse::{Event, KeepAlive},
         IntoResponse, Sse,
     },
+    Extension,
 };
+use either::Either;
 use mistralrs_core::{
-    CompletionResponse, Constraint, MistralRs, NormalRequest, Request, RequestMessage, Response,
-    SamplingParams, StopTokens as InternalStopTokens,
+    CompletionChoice, CompletionLogprobs, CompletionResponse, Constraint, DrySamplingParams,
+    MirostatParams, MistralRs, NormalRequest, Request, RequestMessage, Response, SamplingParams,
+    ScoreRequest, ScoreResponse, StopTokens as InternalStopTokens, Usage, SYSTEM_FINGERPRINT,
 };
 use serde::Serialize;
-use tracing::warn;
 
 #[derive(Debug)]
 struct ModelErrorMessage(String);
@@ -37,6 +43,20 @@ pub struct Streamer {
     rx: Receiver<Response>,
     is_done: bool,
     state: Arc<MistralRs>,
+    request_id: usize,
+    /// The stream ends with a chunk holding the usage, after the chunk finishing the choices.
+    include_usage: bool,
+}
+
+impl Drop for Streamer {
+    fn drop(&mut self) {
+        // The client disconnected before the request finished, so stop generating for it.
+        if !self.is_done {
+            if let Err(e) = self.state.cancel_request(self.request_id) {
+                MistralRs::maybe_log_error(self.state.clone(), &e);
+            }
+        }
+    }
 }
 
 impl futures::Stream for Streamer {
@@ -46,7 +66,11 @@ impl futures::Stream for Streamer {
         if self.is_done {
             return Poll::Ready(None);
         }
-        match self.rx.try_recv() {
+        let resp = self.rx.try_recv();
+        if let Ok(resp) = &resp {
+            self.state.get_metrics().record_response(resp);
+        }
+        match resp {
             Ok(resp) => match resp {
                 Response::ModelError(msg, _) => {
                     MistralRs::maybe_log_error(
@@ -63,7 +87,9 @@ impl futures::Stream for Streamer {
                     Poll::Ready(Some(Ok(Event::default().data(e.to_string()))))
                 }
                 Response::CompletionChunk(response) => {
-                    if response.choices.iter().all(|x| x.finish_reason.is_some()) {
+                    if response.choices.iter().all(|x| x.finish_reason.is_some())
+                        && (!self.include_usage || response.usage.is_some())
+                    {
                         self.is_done = true;
                     }
                     MistralRs::maybe_log_response(self.state.clone(), &response);
@@ -73,6 +99,8 @@ impl futures::Stream for Streamer {
                 Response::CompletionDone(_) => unreachable!(),
                 Response::CompletionModelError(_, _) => unreachable!(),
                 Response::Chunk(_) => unreachable!(),
+                Response::Embeddings(_) => unreachable!(),
+                Response::Score(_) => unreachable!(),
             },
             Err(_) => Poll::Pending,
         }
@@ -155,9 +183,14 @@ fn parse_request(
         None => None,
     };
 
-    if oairequest.logprobs.is_some() {
-        warn!("Completion requests do not support logprobs.");
-    }
+    let dry_params = oairequest.dry_multiplier.map(|multiplier| {
+        DrySamplingParams::new_with_defaults(
+            multiplier,
+            oairequest.dry_sequence_breakers,
+            oairequest.dry_base,
+            oairequest.dry_allowed_length,
+        )
+    });
 
     let is_streaming = oairequest.stream.unwrap_or(false);
     (
@@ -173,31 +206,142 @@ fn parse_request(
                 top_k: oairequest.top_k,
                 top_p: oairequest.top_p,
                 min_p: oairequest.min_p,
-                top_n_logprobs: 1,
+                top_n_logprobs: oairequest.logprobs.unwrap_or(1),
                 frequency_penalty: oairequest.frequency_penalty,
                 presence_penalty: oairequest.presence_penalty,
                 max_len: oairequest.max_tokens,
                 stop_toks,
                 logits_bias: oairequest.logit_bias,
                 n_choices: oairequest.n_choices,
+                beam_width: oairequest.beam_width,
+                length_penalty: oairequest.length_penalty,
+                seed: oairequest.seed,
+                processed_logprobs: oairequest.processed_logprobs,
+                repetition_penalty: oairequest.repetition_penalty,
+                no_repeat_ngram_size: oairequest.no_repeat_ngram_size,
+                dry_params,
+                typical_p: oairequest.typical_p,
+                top_a: oairequest.top_a,
+                mirostat: oairequest.mirostat_tau.map(|tau| MirostatParams {
+                    tau,
+                    eta: oairequest.mirostat_eta.unwrap_or(0.1),
+                }),
             },
             response: tx,
-            return_logprobs: false,
+            return_logprobs: oairequest.logprobs.is_some(),
             is_streaming,
             suffix: oairequest.suffix,
             constraint: match oairequest.grammar {
                 Some(Grammar::Yacc(yacc)) => Constraint::Yacc(yacc),
                 Some(Grammar::Regex(regex)) => Constraint::Regex(regex),
+                Some(Grammar::JsonSchema(schema)) => Constraint::JsonSchema(schema),
                 None => Constraint::None,
             },
             adapters: oairequest.adapters,
             tool_choice: oairequest.tool_choice,
             tools: oairequest.tools,
+            priority: oairequest.priority.unwrap_or_default(),
+            tenant: oairequest.tenant,
+            logits_processors: None,
+            include_usage: oairequest
+                .stream_options
+                .as_ref()
+                .is_some_and(|options| options.include_usage),
         }),
         is_streaming,
     )
 }
 
+/// Score the tokens of the prompt.
+async fn score_prompt(
+    state: Arc<MistralRs>,
+    prompt: String,
+    top_n_logprobs: usize,
+) -> Result<ScoreResponse, CompletionResponder> {
+    let (tx, mut rx) = channel(1);
+    let request = Request::Score(ScoreRequest {
+        prompt: Either::Left(prompt),
+        continuation: None,
+        top_n_logprobs,
+        response: tx,
+        id: state.next_request_id(),
+    });
+    let sender = state.get_sender().unwrap();
+
+    if let Err(e) = sender.send(request).await {
+        let e = anyhow::Error::msg(e.to_string());
+        MistralRs::maybe_log_error(state, &*e);
+        return Err(CompletionResponder::InternalError(e.into()));
+    }
+
+    let response = rx.recv().await;
+    if let Some(response) = &response {
+        state.get_metrics().record_response(response);
+    }
+    match response {
+        Some(Response::Score(scores)) => Ok(scores),
+        Some(Response::ValidationError(e)) => Err(CompletionResponder::ValidationError(e)),
+        Some(Response::InternalError(e)) => {
+            MistralRs::maybe_log_error(state, &*e);
+            Err(CompletionResponder::InternalError(e))
+        }
+        Some(_) => unreachable!(),
+        None => {
+            let e = anyhow::Error::msg("No response received from the model.");
+            MistralRs::maybe_log_error(state, &*e);
+            Err(CompletionResponder::InternalError(e.into()))
+        }
+    }
+}
+
+/// The response to a request which only echoes the prompt, with its logprobs, and generates nothing.
+/// The echoed text is the decoded prompt, like when generating, so that it matches the logprobs.
+fn echoed_prompt_response(
+    state: &Arc<MistralRs>,
+    scores: &ScoreResponse,
+    n_choices: usize,
+) -> CompletionResponse {
+    let prompt = scores
+        .tokens
+        .iter()
+        .filter(|token| !token.special)
+        .map(|token| token.text.as_str())
+        .collect::<String>();
+    let created = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time travel has occurred!")
+        .as_secs();
+    let response = CompletionResponse {
+        id: state.next_request_id().to_string(),
+        choices: (0..n_choices)
+            .map(|index| CompletionChoice {
+                finish_reason: "length".to_string(),
+                index,
+                text: prompt.clone(),
+                logprobs: Some(CompletionLogprobs::from_scored_tokens(&scores.tokens)),
+                beam_score: None,
+            })
+            .collect(),
+        created,
+        model: scores.model.clone(),
+        system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
+        object: "text_completion".to_string(),
+        usage: Usage {
+            completion_tokens: 0,
+            prompt_tokens: scores.prompt_tokens,
+            total_tokens: scores.prompt_tokens,
+            avg_tok_per_sec: 0.,
+            avg_prompt_tok_per_sec: 0.,
+            avg_compl_tok_per_sec: 0.,
+            total_time_sec: 0.,
+            total_prompt_time_sec: 0.,
+            total_completion_time_sec: 0.,
+        },
+    };
+    MistralRs::maybe_log_response(state.clone(), &response);
+    response
+}
+
 #[utoipa::path(
     post,
     tag = "Mistral.rs",
@@ -206,17 +350,57 @@ fn parse_request(
     responses((status = 200, description = "Completions"))
 )]
 pub async fn completions(
-    State(state): State<Arc<MistralRs>>,
-    Json(oairequest): Json<CompletionRequest>,
-) -> CompletionResponder {
+    State(models): State<Arc<ModelRegistry>>,
+    key: Option<Extension<AuthenticatedKey>>,
+    Json(mut oairequest): Json<CompletionRequest>,
+) -> Result<axum::response::Response, ModelNotFound> {
+    let state = models.get(&oairequest.model)?;
+    let mut hide_usage = false;
+    // The tenant of an authenticated request cannot be chosen by the client.
+    if let Some(Extension(key)) = key {
+        oairequest.tenant = Some(key.name);
+        hide_usage = force_stream_usage(oairequest.stream, &mut oairequest.stream_options);
+    }
+    let mut response = completion(state, oairequest).await.into_response();
+    if hide_usage {
+        response.extensions_mut().insert(HideUsage);
+    }
+    Ok(response)
+}
+
+async fn completion(state: Arc<MistralRs>, oairequest: CompletionRequest) -> CompletionResponder {
     let (tx, mut rx) = channel(10_000);
-    if oairequest.logprobs.is_some() {
-        return CompletionResponder::ValidationError(
-            "Completion requests do not support logprobs.".into(),
-        );
+
+    // When echoing the prompt, its logprobs are returned too. They are computed by scoring the prompt.
+    let prompt_scores = match oairequest.logprobs {
+        Some(top_n_logprobs) if oairequest.echo_prompt => {
+            if oairequest.stream.unwrap_or(false) {
+                return CompletionResponder::ValidationError(
+                    "Prompt logprobs cannot be echoed when streaming.".into(),
+                );
+            }
+            match score_prompt(state.clone(), oairequest.prompt.clone(), top_n_logprobs).await {
+                Ok(scores) => Some(scores),
+                Err(responder) => return responder,
+            }
+        }
+        _ => None,
+    };
+    if let (Some(scores), Some(0)) = (&prompt_scores, oairequest.max_tokens) {
+        return CompletionResponder::Json(echoed_prompt_response(
+            &state,
+            scores,
+            oairequest.n_choices,
+        ));
     }
 
     let (request, is_streaming) = parse_request(oairequest, state.clone(), tx);
+    let (request_id, include_usage) = match request {
+        Request::Normal(NormalRequest {
+            id, include_usage, ..
+        }) => (id, include_usage),
+        _ => unreachable!(),
+    };
     let sender = state.get_sender().unwrap();
 
     if let Err(e) = sender.send(request).await {
@@ -230,6 +414,8 @@ pub async fn completions(
             rx,
             is_done: false,
             state,
+            request_id,
+            include_usage,
         };
 
         CompletionResponder::Sse(
@@ -252,6 +438,7 @@ pub async fn completions(
                 return CompletionResponder::InternalError(e.into());
             }
         };
+        state.get_metrics().record_response(&response);
 
         match response {
             Response::InternalError(e) => {
@@ -264,11 +451,21 @@ pub async fn completions(
                 CompletionResponder::ModelError(msg, response)
             }
             Response::ValidationError(e) => CompletionResponder::ValidationError(e),
-            Response::CompletionDone(response) => {
+            Response::CompletionDone(mut response) => {
+                if let Some(scores) = prompt_scores {
+                    for choice in &mut response.choices {
+                        if let Some(logprobs) = &mut choice.logprobs {
+                            logprobs
+                                .prepend(CompletionLogprobs::from_scored_tokens(&scores.tokens));
+                        }
+                    }
+                }
                 MistralRs::maybe_log_response(state, &response);
                 CompletionResponder::Json(response)
             }
             Response::CompletionChunk(_) => unreachable!(),
+            Response::Embeddings(_) => unreachable!(),
+            Response::Score(_) => unreachable!(),
             Response::Chunk(_) => unreachable!(),
             Response::Done(_) => unreachable!(),
             Response::ModelError(_, _) => unreachable!(),
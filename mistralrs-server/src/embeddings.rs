@@ -0,0 +1,149 @@
+use std::{error::Error, sync::Arc};
+use tokio::sync::mpsc::channel;
+
+use crate::{
+    openai::{EmbeddingInput, EmbeddingPooling as OpenAIEmbeddingPooling, EmbeddingRequest},
+    registry::{ModelNotFound, ModelRegistry},
+};
+use axum::{
+    extract::{Json, State},
+    http::{self, StatusCode},
+    response::IntoResponse,
+};
+use mistralrs_core::{
+    EmbeddingInput as InternalEmbeddingInput, EmbeddingPooling,
+    EmbeddingRequest as InternalEmbeddingRequest, EmbeddingResponse, MistralRs, Request, Response,
+};
+use serde::Serialize;
+
+pub enum EmbeddingResponder {
+    Json(EmbeddingResponse),
+    InternalError(Box<dyn Error>),
+    ValidationError(Box<dyn Error>),
+}
+
+trait ErrorToResponse: Serialize {
+    fn to_response(&self, code: StatusCode) -> axum::response::Response {
+        let mut r = Json(self).into_response();
+        *r.status_mut() = code;
+        r
+    }
+}
+
+#[derive(Serialize)]
+struct JsonError {
+    message: String,
+}
+
+impl JsonError {
+    fn new(message: String) -> Self {
+        Self { message }
+    }
+}
+impl ErrorToResponse for JsonError {}
+
+impl IntoResponse for EmbeddingResponder {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            EmbeddingResponder::Json(s) => Json(s).into_response(),
+            EmbeddingResponder::InternalError(e) => {
+                JsonError::new(e.to_string()).to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
+            }
+            EmbeddingResponder::ValidationError(e) => {
+                JsonError::new(e.to_string()).to_response(http::StatusCode::UNPROCESSABLE_ENTITY)
+            }
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/v1/embeddings",
+    request_body = EmbeddingRequest,
+    responses((status = 200, description = "Embeddings"))
+)]
+pub async fn embeddings(
+    State(models): State<Arc<ModelRegistry>>,
+    Json(oairequest): Json<EmbeddingRequest>,
+) -> Result<EmbeddingResponder, ModelNotFound> {
+    let state = models.get(&oairequest.model)?;
+    Ok(embedding(state, oairequest).await)
+}
+
+async fn embedding(state: Arc<MistralRs>, oairequest: EmbeddingRequest) -> EmbeddingResponder {
+    let repr = serde_json::to_string(&oairequest).expect("Serialization of request failed.");
+    MistralRs::maybe_log_request(state.clone(), repr);
+
+    if oairequest
+        .encoding_format
+        .as_ref()
+        .is_some_and(|format| format != "float")
+    {
+        return EmbeddingResponder::ValidationError(
+            "Only the `float` encoding format is supported.".into(),
+        );
+    }
+
+    let input = match oairequest.input {
+        EmbeddingInput::Single(text) => vec![InternalEmbeddingInput::Text(text)],
+        EmbeddingInput::Multi(texts) => texts
+            .into_iter()
+            .map(InternalEmbeddingInput::Text)
+            .collect(),
+        EmbeddingInput::Tokens(toks) => vec![InternalEmbeddingInput::Tokens(toks)],
+        EmbeddingInput::MultiTokens(toks) => toks
+            .into_iter()
+            .map(InternalEmbeddingInput::Tokens)
+            .collect(),
+    };
+    let pooling = match oairequest.pooling {
+        Some(OpenAIEmbeddingPooling::Mean) | None => EmbeddingPooling::Mean,
+        Some(OpenAIEmbeddingPooling::Last) => EmbeddingPooling::Last,
+    };
+
+    let (tx, mut rx) = channel(1);
+    let request = Request::Embedding(InternalEmbeddingRequest {
+        input,
+        pooling,
+        normalize: oairequest.normalize,
+        response: tx,
+        id: state.next_request_id(),
+    });
+    let sender = state.get_sender().unwrap();
+
+    if let Err(e) = sender.send(request).await {
+        let e = anyhow::Error::msg(e.to_string());
+        MistralRs::maybe_log_error(state, &*e);
+        return EmbeddingResponder::InternalError(e.into());
+    }
+
+    let response = match rx.recv().await {
+        Some(response) => response,
+        None => {
+            let e = anyhow::Error::msg("No response received from the model.");
+            MistralRs::maybe_log_error(state, &*e);
+            return EmbeddingResponder::InternalError(e.into());
+        }
+    };
+    state.get_metrics().record_response(&response);
+
+    match response {
+        Response::InternalError(e) => {
+            MistralRs::maybe_log_error(state, &*e);
+            EmbeddingResponder::InternalError(e)
+        }
+        Response::ValidationError(e) => EmbeddingResponder::ValidationError(e),
+        Response::Embeddings(response) => {
+            MistralRs::maybe_log_response(state, &response);
+            EmbeddingResponder::Json(response)
+        }
+        Response::ModelError(_, _) => unreachable!(),
+        Response::Done(_) => unreachable!(),
+        Response::Chunk(_) => unreachable!(),
+        Response::CompletionModelError(_, _) => unreachable!(),
+        Response::CompletionDone(_) => unreachable!(),
+        Response::CompletionChunk(_) => unreachable!(),
+        Response::Score(_) => unreachable!(),
+    }
+}
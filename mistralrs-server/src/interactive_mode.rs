@@ -43,6 +43,16 @@ pub async fn interactive_mode(mistralrs: Arc<MistralRs>, vision_chat: bool, thro
         stop_toks: None,
         logits_bias: None,
         n_choices: 1,
+        beam_width: None,
+        length_penalty: None,
+        seed: None,
+        processed_logprobs: false,
+        repetition_penalty: None,
+        no_repeat_ngram_size: None,
+        dry_params: None,
+        typical_p: None,
+        top_a: None,
+        mirostat: None,
     };
     info!("Starting interactive loop with sampling params: {sampling_params:?}");
 
@@ -143,6 +153,10 @@ pub async fn interactive_mode(mistralrs: Arc<MistralRs>, vision_chat: bool, thro
             suffix: None,
             adapters: None,
             tool_choice: None,
+            priority: 0,
+            tenant: None,
+            logits_processors: None,
+            include_usage: false,
             tools: None,
         });
         sender.send(req).await.unwrap();
@@ -182,6 +196,8 @@ pub async fn interactive_mode(mistralrs: Arc<MistralRs>, vision_chat: bool, thro
                 Response::CompletionDone(_) => unreachable!(),
                 Response::CompletionModelError(_, _) => unreachable!(),
                 Response::CompletionChunk(_) => unreachable!(),
+                Response::Embeddings(_) => unreachable!(),
+                Response::Score(_) => unreachable!(),
             }
         }
         if throughput {
@@ -2,8 +2,10 @@ use anyhow::Result;
 use axum::{
     extract::{DefaultBodyLimit, Json, State},
     http::{self, Method},
+    middleware,
+    response::IntoResponse,
     routing::{get, post},
-    Router,
+    Extension, Router,
 };
 use candle_core::{quantized::GgmlDType, Device};
 use clap::Parser;
@@ -11,20 +13,54 @@ use mistralrs_core::{
     get_model_dtype, get_tgt_non_granular_index, initialize_logging, paged_attn_supported,
     parse_isq_value, DefaultSchedulerMethod, DeviceLayerMapMetadata, DeviceMapMetadata, Loader,
     LoaderBuilder, MemoryGpuConfig, MistralRs, MistralRsBuilder, ModelSelected,
-    PagedAttentionConfig, Request, SchedulerConfig, TokenSource,
+    PagedAttentionConfig, PrefixCacheTiers, Request, SchedulerConfig, TokenSource,
+};
+use openai::{
+    ChatCompletionRequest, DetokenizeRequest, EmbeddingInput, EmbeddingPooling, EmbeddingRequest,
+    Message, ModelObjects, StopTokens, StreamOptions, TokenizeRequest,
 };
-use openai::{ChatCompletionRequest, Message, ModelObjects, StopTokens};
 use serde::{Deserialize, Serialize};
-use std::{num::NonZeroUsize, sync::Arc};
+use std::{collections::HashMap, num::NonZeroUsize, path::PathBuf, sync::Arc};
+mod admin;
+mod auth;
+mod batch;
 mod chat_completion;
 mod completions;
-use crate::{chat_completion::__path_chatcompletions, completions::completions};
+mod embeddings;
+mod metrics;
+mod ollama;
+mod tokenize;
+use crate::{
+    admin::{
+        __path_load_served_model, __path_unload_served_model, load_served_model,
+        unload_served_model, LoadModelRequest, UnloadModelRequest,
+    },
+    chat_completion::__path_chatcompletions,
+    completions::completions,
+    embeddings::__path_embeddings,
+    embeddings::embeddings,
+    metrics::__path_metrics,
+    metrics::metrics,
+    ollama::{
+        __path_chat, __path_generate, __path_show, __path_tags, chat, generate, show, tags,
+        GenerateRequest, OllamaChatRequest, OllamaMessage, OllamaOptions, ShowRequest,
+    },
+    tokenize::__path_detokenize,
+    tokenize::__path_tokenize,
+    tokenize::detokenize,
+    tokenize::tokenize,
+};
 
 use crate::{chat_completion::chatcompletions, openai::ModelObject};
 mod interactive_mode;
 mod openai;
+mod registry;
 
+use auth::{authenticate, require_admin, ApiKeys};
+use batch::batch_mode;
+use indexmap::IndexMap;
 use interactive_mode::interactive_mode;
+use registry::{ModelNotFound, ModelRegistry};
 use tower_http::cors::{AllowOrigin, CorsLayer};
 use tracing::{info, warn};
 use utoipa::{OpenApi, ToSchema};
@@ -67,6 +103,16 @@ struct Args {
     #[arg(long, default_value_t = 16)]
     max_seqs: usize,
 
+    /// Use the priority scheduler: requests with a higher `priority` are scheduled first and may preempt running
+    /// sequences of a lower priority. This is not supported with PagedAttention.
+    #[arg(long, default_value_t = false)]
+    priority_scheduling: bool,
+
+    /// Weights used by the priority scheduler to share the running sequences between tenants, following the pattern
+    /// TENANT:WEIGHT;... Tenants which are not specified have a weight of 1.
+    #[arg(long, value_parser, value_delimiter = ';')]
+    tenant_weights: Option<Vec<String>>,
+
     /// Use no KV cache.
     #[arg(long, default_value_t = false)]
     no_kv_cache: bool,
@@ -90,10 +136,53 @@ struct Args {
     #[clap(long = "vi", action)]
     vision_interactive_mode: bool,
 
+    /// Additional models to serve, following the pattern NAME:TOML_FILE;... where TOML_FILE is a TOML selector.
+    /// Requests are routed to a model by their `model` field, and the model selected by the subcommand is named
+    /// by its model ID.
+    #[arg(long, value_parser, value_delimiter = ';')]
+    additional_models: Option<Vec<String>>,
+
+    /// JSON file listing the API keys accepted by the server, with their optional rate limits. If set, requests
+    /// must pass one of the keys as a bearer token.
+    #[arg(long)]
+    api_keys_file: Option<PathBuf>,
+
+    /// Serve the `/admin/models/load` and `/admin/models/unload` routes, which load and unload models at runtime.
+    /// They may load any model selector, including local paths, so only enable them on a trusted network or with
+    /// `--api-keys-file`.
+    #[arg(long, default_value_t = false)]
+    enable_admin_routes: bool,
+
+    /// Run the requests of an OpenAI batch input file (JSONL) instead of serving, and write the results to
+    /// `--batch-output`. If the output file already has results, only the remaining requests are run.
+    #[arg(long, requires = "batch_output")]
+    batch_input: Option<PathBuf>,
+
+    /// Output file (JSONL) for the results of `--batch-input`.
+    #[arg(long, requires = "batch_input")]
+    batch_output: Option<PathBuf>,
+
     /// Number of prefix caches to hold on the device. Other caches are evicted to the CPU based on a LRU strategy.
     #[arg(long, default_value_t = 16)]
     prefix_cache_n: usize,
 
+    /// Maximum size in MB of the prefix caches held on the device. Other caches are evicted to the CPU.
+    #[arg(long)]
+    prefix_cache_device_mb: Option<usize>,
+
+    /// Maximum size in MB of the prefix caches held in host RAM. Other caches are only kept on disk, if
+    /// `--prefix-cache-dir` is set.
+    #[arg(long)]
+    prefix_cache_host_mb: Option<usize>,
+
+    /// Directory to write prefix caches to as safetensors files, so that they survive restarts.
+    #[arg(long)]
+    prefix_cache_dir: Option<PathBuf>,
+
+    /// Maximum size in MB of the prefix cache files in `--prefix-cache-dir`.
+    #[arg(long)]
+    prefix_cache_disk_mb: Option<usize>,
+
     /// Number of device layers to load and run on GPU(s). All others will be on the CPU.
     /// If one GPU is used, then this value should be an integer. Otherwise, it follows the following pattern:
     /// ORD:NUM;... Where ORD is a unique device ordinal and NUM is the number of layers for that device.
@@ -139,6 +228,11 @@ struct Args {
     /// Number of tokens to batch the prompt step into. This can help with OOM errors when in the prompt step, but reduces performance.
     #[arg(long = "prompt-batchsize")]
     prompt_batchsize: Option<usize>,
+
+    /// Maximum number of prompt tokens to run in one engine step. Longer prompts are split over multiple steps so that
+    /// running sequences keep generating. With PagedAttention, the chunks are rounded down to whole blocks.
+    #[arg(long = "prefill-chunk-size")]
+    prefill_chunk_size: Option<usize>,
 }
 
 #[utoipa::path(
@@ -147,15 +241,19 @@ struct Args {
     path = "/v1/models",
     responses((status = 200, description = "Served model info", body = ModelObjects))
 )]
-async fn models(State(state): State<Arc<MistralRs>>) -> Json<ModelObjects> {
+async fn models(State(models): State<Arc<ModelRegistry>>) -> Json<ModelObjects> {
     Json(ModelObjects {
         object: "list",
-        data: vec![ModelObject {
-            id: state.get_id(),
-            object: "model",
-            created: state.get_creation_time(),
-            owned_by: "local",
-        }],
+        data: models
+            .list()
+            .into_iter()
+            .map(|(id, state)| ModelObject {
+                id,
+                object: "model",
+                created: state.get_creation_time(),
+                owned_by: "local",
+            })
+            .collect(),
     })
 }
 
@@ -173,6 +271,8 @@ async fn health() -> &'static str {
 struct AdapterActivationRequest {
     #[schema(example = json!(vec!["adapter_1","adapter_2"]))]
     adapter_names: Vec<String>,
+    #[schema(example = json!(Option::None::<String>))]
+    model: Option<String>,
 }
 
 #[utoipa::path(
@@ -183,20 +283,23 @@ struct AdapterActivationRequest {
     responses((status = 200, description = "Activate a set of pre-loaded LoRA adapters"))
 )]
 async fn activate_adapters(
-    State(state): State<Arc<MistralRs>>,
+    State(models): State<Arc<ModelRegistry>>,
     Json(request): Json<AdapterActivationRequest>,
-) -> String {
+) -> Result<String, ModelNotFound> {
+    let state = models.get(request.model.as_deref().unwrap_or("default"))?;
     let repr = format!("Adapter activation: {:?}", request.adapter_names);
     MistralRs::maybe_log_request(state.clone(), repr.clone());
     let request = Request::ActivateAdapters(request.adapter_names);
     state.get_sender().unwrap().send(request).await.unwrap();
-    repr
+    Ok(repr)
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 struct ReIsqRequest {
     #[schema(example = "Q4K")]
     ggml_type: String,
+    #[schema(example = json!(Option::None::<String>))]
+    model: Option<String>,
 }
 
 #[utoipa::path(
@@ -207,22 +310,30 @@ struct ReIsqRequest {
     responses((status = 200, description = "Reapply ISQ to a non GGUF or GGML model."))
 )]
 async fn re_isq(
-    State(state): State<Arc<MistralRs>>,
+    State(models): State<Arc<ModelRegistry>>,
     Json(request): Json<ReIsqRequest>,
-) -> Result<String, String> {
+) -> Result<String, axum::response::Response> {
+    let state = models
+        .get(request.model.as_deref().unwrap_or("default"))
+        .map_err(IntoResponse::into_response)?;
     let repr = format!("Re ISQ: {:?}", request.ggml_type);
     MistralRs::maybe_log_request(state.clone(), repr.clone());
-    let request = Request::ReIsq(parse_isq_value(&request.ggml_type)?);
+    let request =
+        Request::ReIsq(parse_isq_value(&request.ggml_type).map_err(IntoResponse::into_response)?);
     state.get_sender().unwrap().send(request).await.unwrap();
     Ok(repr)
 }
 
-fn get_router(state: Arc<MistralRs>) -> Router {
+fn get_router(
+    models: Arc<ModelRegistry>,
+    args: Arc<Args>,
+    api_keys: Option<Arc<ApiKeys>>,
+) -> Router {
     #[derive(OpenApi)]
     #[openapi(
-        paths(models, health, chatcompletions),
+        paths(models, health, chatcompletions, embeddings, tokenize, detokenize, metrics, load_served_model, unload_served_model, generate, chat, tags, show),
         components(
-            schemas(ModelObjects, ModelObject, ChatCompletionRequest, StopTokens, Message)),
+            schemas(ModelObjects, ModelObject, ChatCompletionRequest, StopTokens, StreamOptions, Message, EmbeddingRequest, EmbeddingInput, EmbeddingPooling, TokenizeRequest, DetokenizeRequest, LoadModelRequest, UnloadModelRequest, GenerateRequest, OllamaChatRequest, OllamaMessage, OllamaOptions, ShowRequest)),
         tags(
             (name = "Mistral.rs", description = "Mistral.rs API")
         ),
@@ -243,36 +354,70 @@ fn get_router(state: Arc<MistralRs>) -> Router {
         .allow_headers([http::header::CONTENT_TYPE, http::header::AUTHORIZATION])
         .allow_origin(allow_origin);
 
-    Router::new()
-        .merge(SwaggerUi::new("/docs").url("/api-doc/openapi.json", doc))
+    let router = Router::new()
         .route("/v1/chat/completions", post(chatcompletions))
         .route("/v1/completions", post(completions))
+        .route("/v1/embeddings", post(embeddings))
+        .route("/tokenize", post(tokenize))
+        .route("/detokenize", post(detokenize))
         .route("/v1/models", get(models))
+        .route("/metrics", get(metrics))
+        .route("/api/generate", post(generate))
+        .route("/api/chat", post(chat))
+        .route("/api/tags", get(tags))
+        .route("/api/show", post(show));
+    // Routes which change the served models, only allowed for admin keys.
+    let admin_router = Router::new()
+        .route("/activate_adapters", post(activate_adapters))
+        .route("/re_isq", post(re_isq));
+    let admin_router = if args.enable_admin_routes {
+        admin_router
+            .route("/admin/models/load", post(load_served_model))
+            .route("/admin/models/unload", post(unload_served_model))
+    } else {
+        admin_router
+    };
+    let router = router.merge(admin_router.route_layer(middleware::from_fn(require_admin)));
+    // The health check and the docs are served without an API key.
+    let router = match api_keys {
+        Some(api_keys) => router
+            .route_layer(middleware::from_fn_with_state(
+                api_keys.clone(),
+                authenticate,
+            ))
+            .layer(Extension(api_keys)),
+        None => router,
+    };
+
+    router
+        .merge(SwaggerUi::new("/docs").url("/api-doc/openapi.json", doc))
         .route("/health", get(health))
         .route("/", get(health))
-        .route("/activate_adapters", post(activate_adapters))
-        .route("/re_isq", post(re_isq))
+        .layer(Extension(args))
         .layer(cors_layer)
         .layer(DefaultBodyLimit::max(N_INPUT_SIZE * MB_TO_B))
-        .with_state(state)
+        .with_state(models)
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let mut args = Args::parse();
-    initialize_logging();
-
+/// Load a model and prepare its engine with the options of the server. The chat template only applies to this model.
+async fn load_model(
+    model: ModelSelected,
+    chat_template: Option<String>,
+    args: &Args,
+) -> Result<MistralRsBuilder> {
     #[cfg(not(feature = "flash-attn"))]
     let use_flash_attn = false;
     #[cfg(feature = "flash-attn")]
     let use_flash_attn = true;
 
-    let tgt_non_granular_index = get_tgt_non_granular_index(&args.model);
-    let dtype = get_model_dtype(&args.model)?;
+    let tgt_non_granular_index = get_tgt_non_granular_index(&model);
+    let dtype = get_model_dtype(&model)?;
 
-    if tgt_non_granular_index.is_some() {
-        args.max_seqs = 1;
-    }
+    let max_seqs = if tgt_non_granular_index.is_some() {
+        1
+    } else {
+        args.max_seqs
+    };
 
     let prompt_batchsize = match args.prompt_batchsize {
         Some(0) => {
@@ -282,9 +427,17 @@ async fn main() -> Result<()> {
         None => None,
     };
 
-    let loader: Box<dyn Loader> = LoaderBuilder::new(args.model)
+    let prefill_chunk_size = match args.prefill_chunk_size {
+        Some(0) => {
+            anyhow::bail!("`prefill_chunk_size` must be a strictly positive integer, got 0.",)
+        }
+        Some(x) => Some(NonZeroUsize::new(x).unwrap()),
+        None => None,
+    };
+
+    let loader: Box<dyn Loader> = LoaderBuilder::new(model)
         .with_no_kv_cache(args.no_kv_cache)
-        .with_chat_template(args.chat_template)
+        .with_chat_template(chat_template)
         .with_use_flash_attn(use_flash_attn)
         .with_prompt_batchsize(prompt_batchsize)
         .build()?;
@@ -311,7 +464,7 @@ async fn main() -> Result<()> {
     info!("Model kind is: {}", loader.get_kind().to_string());
 
     // Parse device mapper
-    let mapper = if let Some(device_layers) = args.num_device_layers {
+    let mapper = if let Some(device_layers) = &args.num_device_layers {
         if device_layers.len() == 1 && device_layers[0].parse::<usize>().is_ok() {
             let layers = device_layers[0].parse::<usize>().unwrap();
             DeviceMapMetadata::from_num_device_layers(vec![DeviceLayerMapMetadata {
@@ -406,7 +559,7 @@ async fn main() -> Result<()> {
 
     let pipeline = loader.load_model_from_hf(
         None,
-        args.token_source,
+        args.token_source.clone(),
         &dtype,
         &device,
         false,
@@ -416,29 +569,72 @@ async fn main() -> Result<()> {
     )?;
     info!("Model loaded.");
 
+    let default_scheduler_method = if args.priority_scheduling {
+        let mut tenant_weights = HashMap::new();
+        for tenant_weight in args.tenant_weights.clone().unwrap_or_default() {
+            let Some((tenant, weight)) = tenant_weight.rsplit_once(':') else {
+                anyhow::bail!("Expected tenant weight to be formatted like TENANT:WEIGHT, got `{tenant_weight}`.");
+            };
+            let weight = weight.parse::<f64>()?;
+            if weight <= 0. {
+                anyhow::bail!("Tenant weight for `{tenant}` must be positive, got {weight}.");
+            }
+            tenant_weights.insert(tenant.to_string(), weight);
+        }
+        DefaultSchedulerMethod::Priority {
+            max_seqs: max_seqs.try_into().unwrap(),
+            tenant_weights,
+        }
+    } else {
+        DefaultSchedulerMethod::Fixed(max_seqs.try_into().unwrap())
+    };
     let scheduler_config = if cache_config.is_some() {
         // Handle case where we may have device mapping
         if let Some(ref cache_config) = pipeline.lock().await.get_metadata().cache_config {
+            if args.priority_scheduling {
+                warn!("Priority scheduling is not supported with PagedAttention, using the PagedAttention scheduler.");
+            }
             SchedulerConfig::PagedAttentionMeta {
-                max_num_seqs: args.max_seqs,
+                max_num_seqs: max_seqs,
                 config: cache_config.clone(),
             }
         } else {
             SchedulerConfig::DefaultScheduler {
-                method: DefaultSchedulerMethod::Fixed(args.max_seqs.try_into().unwrap()),
+                method: default_scheduler_method,
             }
         }
     } else {
         SchedulerConfig::DefaultScheduler {
-            method: DefaultSchedulerMethod::Fixed(args.max_seqs.try_into().unwrap()),
+            method: default_scheduler_method,
         }
     };
     // Throughput logging in the server
     let builder = MistralRsBuilder::new(pipeline, scheduler_config)
-        .with_opt_log(args.log)
+        .with_opt_log(args.log.clone())
         .with_truncate_sequence(args.truncate_sequence)
         .with_no_kv_cache(args.no_kv_cache)
-        .with_prefix_cache_n(args.prefix_cache_n);
+        .with_prefix_cache_n(args.prefix_cache_n)
+        .with_prefix_cache_tiers(PrefixCacheTiers {
+            device_bytes: args.prefix_cache_device_mb.map(|mb| mb * 1024 * 1024),
+            host_bytes: args.prefix_cache_host_mb.map(|mb| mb * 1024 * 1024),
+            disk_dir: args.prefix_cache_dir.clone(),
+            disk_bytes: args.prefix_cache_disk_mb.map(|mb| mb * 1024 * 1024),
+        })
+        .with_prefill_chunk_size(prefill_chunk_size);
+    Ok(builder)
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    initialize_logging();
+
+    let api_keys = match &args.api_keys_file {
+        Some(file) => Some(Arc::new(ApiKeys::from_file(file)?)),
+        None => None,
+    };
+
+    let builder = load_model(args.model.clone(), args.chat_template.clone(), &args).await?;
 
     if args.interactive_mode && args.vision_interactive_mode {
         anyhow::bail!("Interactive mode and vision interactive mode are exclusive.");
@@ -450,22 +646,50 @@ async fn main() -> Result<()> {
         return Ok(());
     }
 
-    let builder = if args.throughput_log {
-        builder.with_throughput_logging()
-    } else {
-        builder
+    let with_throughput_logging = |builder: MistralRsBuilder| {
+        if args.throughput_log {
+            builder.with_throughput_logging()
+        } else {
+            builder
+        }
     };
-    let mistralrs = builder.build();
+    let mistralrs = with_throughput_logging(builder).build();
+
+    let mut models = IndexMap::new();
+    models.insert(mistralrs.get_id(), mistralrs);
+    for additional_model in args.additional_models.iter().flatten() {
+        let Some((name, file)) = additional_model.split_once(':') else {
+            anyhow::bail!("Expected additional model to be formatted like NAME:TOML_FILE, got `{additional_model}`.");
+        };
+        if models.contains_key(name) {
+            anyhow::bail!("Model name `{name}` is used by more than one model.");
+        }
+        info!("Loading model `{name}` from `{file}`.");
+        let builder = load_model(
+            ModelSelected::Toml {
+                file: file.to_string(),
+            },
+            None,
+            &args,
+        )
+        .await?;
+        models.insert(name.to_string(), with_throughput_logging(builder).build());
+    }
+    let models = Arc::new(ModelRegistry::new(models));
 
-    let port = args.port.expect("Expected port to be specified.");
+    if let (Some(input), Some(output)) = (&args.batch_input, &args.batch_output) {
+        return batch_mode(models, input, output).await;
+    }
 
-    let app = get_router(mistralrs);
+    let port = args.port.clone().expect("Expected port to be specified.");
 
     let ip = if let Some(ref ip) = args.serve_ip {
         ip.to_string()
     } else {
         "0.0.0.0".to_string()
     };
+
+    let app = get_router(models, Arc::new(args), api_keys);
     let listener = tokio::net::TcpListener::bind(format!("{ip}:{}", port)).await?;
     info!("Serving on http://{ip}:{}.", port);
     axum::serve(listener, app).await?;
@@ -0,0 +1,236 @@
+use std::{fmt::Write, sync::Arc};
+
+use axum::{extract::State, http::header, response::IntoResponse, Extension};
+use mistralrs_core::{EngineMetrics, HistogramSnapshot};
+
+use crate::{
+    auth::{ApiKeys, AuthenticatedKey, KeyUsage},
+    registry::ModelRegistry,
+};
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {name} {help}").unwrap();
+    writeln!(out, "# TYPE {name} {kind}").unwrap();
+}
+
+/// Escape a label value of the text format, in which `\`, `"` and newlines must be escaped.
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Write a metric with one sample per model, skipping models for which `value` is `None`.
+fn write_metric<T: std::fmt::Display>(
+    out: &mut String,
+    models: &[(String, &EngineMetrics)],
+    name: &str,
+    kind: &str,
+    help: &str,
+    value: impl Fn(&EngineMetrics) -> Option<T>,
+) {
+    write_header(out, name, kind, help);
+    for (model, metrics) in models {
+        let model = label(model);
+        if let Some(value) = value(metrics) {
+            writeln!(out, "{name}{{model=\"{model}\"}} {value}").unwrap();
+        }
+    }
+}
+
+fn write_histogram(
+    out: &mut String,
+    models: &[(String, &EngineMetrics)],
+    name: &str,
+    help: &str,
+    histogram: impl Fn(&EngineMetrics) -> HistogramSnapshot,
+) {
+    write_header(out, name, "histogram", help);
+    for (model, metrics) in models {
+        let model = label(model);
+        let histogram = histogram(metrics);
+        for (bound, count) in &histogram.buckets {
+            writeln!(
+                out,
+                "{name}_bucket{{model=\"{model}\",le=\"{bound}\"}} {count}"
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "{name}_bucket{{model=\"{model}\",le=\"+Inf\"}} {}",
+            histogram.count
+        )
+        .unwrap();
+        writeln!(out, "{name}_sum{{model=\"{model}\"}} {}", histogram.sum).unwrap();
+        writeln!(out, "{name}_count{{model=\"{model}\"}} {}", histogram.count).unwrap();
+    }
+}
+
+#[utoipa::path(
+    get,
+    tag = "Mistral.rs",
+    path = "/metrics",
+    responses((status = 200, description = "Metrics in the Prometheus text format"))
+)]
+pub async fn metrics(
+    State(models): State<Arc<ModelRegistry>>,
+    api_keys: Option<Extension<Arc<ApiKeys>>>,
+    key: Option<Extension<AuthenticatedKey>>,
+) -> impl IntoResponse {
+    let served = models.list();
+    let models = served
+        .iter()
+        .map(|(name, model)| (name.clone(), model.get_metrics()))
+        .collect::<Vec<_>>();
+    let mut out = String::new();
+
+    write_metric(
+        &mut out,
+        &models,
+        "mistralrs_waiting_sequences",
+        "gauge",
+        "Sequences waiting to be scheduled.",
+        |m| Some(m.waiting_seqs()),
+    );
+    write_metric(
+        &mut out,
+        &models,
+        "mistralrs_running_sequences",
+        "gauge",
+        "Sequences being run.",
+        |m| Some(m.running_seqs()),
+    );
+    write_metric(
+        &mut out,
+        &models,
+        "mistralrs_kv_cache_blocks_used",
+        "gauge",
+        "PagedAttention KV cache blocks in use.",
+        |m| m.kv_cache_blocks().map(|(used, _)| used),
+    );
+    write_metric(
+        &mut out,
+        &models,
+        "mistralrs_kv_cache_blocks_total",
+        "gauge",
+        "PagedAttention KV cache blocks.",
+        |m| m.kv_cache_blocks().map(|(_, total)| total),
+    );
+    write_metric(
+        &mut out,
+        &models,
+        "mistralrs_kv_cache_utilization",
+        "gauge",
+        "Fraction of the PagedAttention KV cache blocks in use.",
+        |m| {
+            m.kv_cache_blocks()
+                .map(|(used, total)| used as f64 / total as f64)
+        },
+    );
+    write_metric(
+        &mut out,
+        &models,
+        "mistralrs_prefix_cache_hits_total",
+        "counter",
+        "Prompts which reused a cached prefix.",
+        |m| Some(m.prefix_cache_hits()),
+    );
+    write_metric(
+        &mut out,
+        &models,
+        "mistralrs_prompt_tokens_total",
+        "counter",
+        "Prompt tokens processed.",
+        |m| Some(m.prompt_tokens()),
+    );
+    write_metric(
+        &mut out,
+        &models,
+        "mistralrs_completion_tokens_total",
+        "counter",
+        "Completion tokens generated.",
+        |m| Some(m.completion_tokens()),
+    );
+    write_histogram(
+        &mut out,
+        &models,
+        "mistralrs_time_to_first_token_seconds",
+        "Time from the arrival of a request to its first completion token.",
+        EngineMetrics::time_to_first_token,
+    );
+    write_histogram(
+        &mut out,
+        &models,
+        "mistralrs_inter_token_latency_seconds",
+        "Time between consecutive completion tokens of a sequence.",
+        EngineMetrics::inter_token_latency,
+    );
+    write_header(
+        &mut out,
+        "mistralrs_errors_total",
+        "counter",
+        "Error responses by response variant.",
+    );
+    for (model, metrics) in &models {
+        let model = label(model);
+        for (variant, count) in metrics.errors() {
+            writeln!(
+                out,
+                "mistralrs_errors_total{{model=\"{model}\",variant=\"{}\"}} {count}",
+                label(variant)
+            )
+            .unwrap();
+        }
+    }
+
+    // The usage of the other keys is only shown to admin keys.
+    let is_admin = key.is_some_and(|Extension(key)| key.admin);
+    if let Some(Extension(api_keys)) = api_keys.filter(|_| is_admin) {
+        let usage = api_keys.usage();
+        let metrics: [(&str, &str, fn(&KeyUsage) -> u64); 4] = [
+            (
+                "mistralrs_api_key_requests_total",
+                "Requests accepted by API key.",
+                |u| u.requests,
+            ),
+            (
+                "mistralrs_api_key_prompt_tokens_total",
+                "Prompt tokens used by API key.",
+                |u| u.prompt_tokens,
+            ),
+            (
+                "mistralrs_api_key_completion_tokens_total",
+                "Completion tokens used by API key.",
+                |u| u.completion_tokens,
+            ),
+            (
+                "mistralrs_api_key_tokens_total",
+                "Total tokens used by API key.",
+                |u| u.total_tokens,
+            ),
+        ];
+        for (name, help, value) in metrics {
+            write_header(&mut out, name, "counter", help);
+            for (key, usage) in &usage {
+                writeln!(out, "{name}{{key=\"{}\"}} {}", label(key), value(usage)).unwrap();
+            }
+        }
+    }
+
+    ([(header::CONTENT_TYPE, CONTENT_TYPE)], out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::label;
+
+    #[test]
+    fn label_values_are_escaped() {
+        assert_eq!(label("mistral"), "mistral");
+        assert_eq!(label("a \"b\"\\c\nd"), "a \\\"b\\\"\\\\c\\nd");
+    }
+}
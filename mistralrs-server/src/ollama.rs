@@ -0,0 +1,656 @@
+use std::{
+    convert::Infallible,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::{
+    body::Body,
+    extract::{Json, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response as AxumResponse},
+};
+use chrono::{DateTime, Utc};
+use either::Either;
+use indexmap::IndexMap;
+use mistralrs_core::{
+    Constraint, MistralRs, NormalRequest, Request, RequestMessage, Response, SamplingParams,
+    StopTokens as InternalStopTokens, Usage,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{channel, Receiver};
+use tracing::debug;
+use utoipa::ToSchema;
+
+use crate::registry::{ModelNotFound, ModelRegistry};
+
+/// The Ollama `options` object. Options which have no equivalent here, such as `num_ctx`, are ignored.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, ToSchema)]
+pub struct OllamaOptions {
+    /// Maximum number of tokens to generate. Negative values do not limit the generation.
+    num_predict: Option<i64>,
+    top_k: Option<usize>,
+    top_p: Option<f64>,
+    min_p: Option<f64>,
+    typical_p: Option<f64>,
+    temperature: Option<f64>,
+    repeat_penalty: Option<f32>,
+    presence_penalty: Option<f32>,
+    frequency_penalty: Option<f32>,
+    seed: Option<u64>,
+    stop: Option<Vec<String>>,
+}
+
+impl OllamaOptions {
+    fn into_sampling_params(self) -> SamplingParams {
+        SamplingParams {
+            temperature: self.temperature,
+            top_k: self.top_k,
+            top_p: self.top_p,
+            min_p: self.min_p,
+            top_n_logprobs: 1,
+            frequency_penalty: self.frequency_penalty,
+            presence_penalty: self.presence_penalty,
+            max_len: self.num_predict.and_then(|n| usize::try_from(n).ok()),
+            stop_toks: self.stop.map(InternalStopTokens::Seqs),
+            logits_bias: None,
+            n_choices: 1,
+            beam_width: None,
+            length_penalty: None,
+            seed: self.seed,
+            processed_logprobs: false,
+            repetition_penalty: self.repeat_penalty,
+            no_repeat_ngram_size: None,
+            dry_params: None,
+            typical_p: self.typical_p,
+            top_a: None,
+            mirostat: None,
+        }
+    }
+}
+
+fn default_stream() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct GenerateRequest {
+    #[schema(example = "mistral")]
+    model: String,
+    #[schema(example = "Why is the sky blue?")]
+    prompt: String,
+    /// System message used with the chat template of the model.
+    system: Option<String>,
+    /// Do not apply the chat template of the model to the prompt.
+    #[serde(default)]
+    raw: bool,
+    #[serde(default = "default_stream")]
+    stream: bool,
+    /// `"json"` or a JSON schema the response must follow.
+    format: Option<serde_json::Value>,
+    /// Ignored, models stay loaded until they are unloaded.
+    keep_alive: Option<serde_json::Value>,
+    #[serde(default)]
+    options: OllamaOptions,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct OllamaMessage {
+    #[schema(example = "user")]
+    role: String,
+    #[schema(example = "Why is the sky blue?")]
+    content: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct OllamaChatRequest {
+    #[schema(example = "mistral")]
+    model: String,
+    messages: Vec<OllamaMessage>,
+    #[serde(default = "default_stream")]
+    stream: bool,
+    /// `"json"` or a JSON schema the response must follow.
+    format: Option<serde_json::Value>,
+    /// Ignored, models stay loaded until they are unloaded.
+    keep_alive: Option<serde_json::Value>,
+    #[serde(default)]
+    options: OllamaOptions,
+}
+
+/// The name a model is served under. Ollama clients add the `latest` tag to names without a tag.
+fn model_name(model: &str) -> &str {
+    model.strip_suffix(":latest").unwrap_or(model)
+}
+
+/// The constraint for the `format` of a request.
+fn format_constraint(format: Option<serde_json::Value>) -> Result<Constraint, OllamaError> {
+    match format {
+        None => Ok(Constraint::None),
+        Some(serde_json::Value::String(format)) if format == "json" => Ok(Constraint::JsonSchema(
+            serde_json::json!({"type": "object"}),
+        )),
+        Some(schema @ serde_json::Value::Object(_)) => Ok(Constraint::JsonSchema(schema)),
+        Some(format) => Err(OllamaError(
+            StatusCode::BAD_REQUEST,
+            format!("invalid format {format}, expected \"json\" or a JSON schema"),
+        )),
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct ShowRequest {
+    #[serde(alias = "name")]
+    #[schema(example = "mistral")]
+    model: String,
+}
+
+/// Statistics of the last object of a response, in nanoseconds for the durations.
+#[derive(Serialize)]
+struct DoneStats {
+    done_reason: String,
+    total_duration: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prompt_eval_count: Option<usize>,
+    prompt_eval_duration: u128,
+    eval_count: usize,
+    eval_duration: u128,
+}
+
+impl DoneStats {
+    fn from_usage(done_reason: String, usage: &Usage) -> Self {
+        let nanos = |secs: f32| Duration::from_secs_f32(secs.max(0.)).as_nanos();
+        Self {
+            done_reason,
+            total_duration: nanos(usage.total_time_sec),
+            prompt_eval_count: Some(usage.prompt_tokens),
+            prompt_eval_duration: nanos(usage.total_prompt_time_sec),
+            eval_count: usage.completion_tokens,
+            eval_duration: nanos(usage.total_completion_time_sec),
+        }
+    }
+}
+
+/// Whether a request came from `/api/generate` or `/api/chat`, which return the text in different keys.
+#[derive(Clone, Copy)]
+enum Endpoint {
+    Generate,
+    Chat,
+}
+
+#[derive(Serialize)]
+struct OllamaResponse {
+    model: String,
+    created_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<OllamaMessage>,
+    done: bool,
+    #[serde(flatten)]
+    stats: Option<DoneStats>,
+}
+
+impl OllamaResponse {
+    fn new(endpoint: Endpoint, model: &str, text: String, stats: Option<DoneStats>) -> Self {
+        let (response, message) = match endpoint {
+            Endpoint::Generate => (Some(text), None),
+            Endpoint::Chat => (
+                None,
+                Some(OllamaMessage {
+                    role: "assistant".to_string(),
+                    content: text,
+                }),
+            ),
+        };
+        Self {
+            model: model.to_string(),
+            created_at: Utc::now().to_rfc3339(),
+            response,
+            message,
+            done: stats.is_some(),
+            stats,
+        }
+    }
+
+    /// Serialize as one line of a newline-delimited JSON stream.
+    fn to_line(&self) -> String {
+        let mut line = serde_json::to_string(self).expect("Serialization of response failed.");
+        line.push('\n');
+        line
+    }
+}
+
+/// An error in Ollama's format, `{"error": message}`.
+pub struct OllamaError(StatusCode, String);
+
+#[derive(Serialize)]
+struct OllamaErrorBody {
+    error: String,
+}
+
+impl IntoResponse for OllamaError {
+    fn into_response(self) -> AxumResponse {
+        let mut r = Json(OllamaErrorBody { error: self.1 }).into_response();
+        *r.status_mut() = self.0;
+        r
+    }
+}
+
+impl From<ModelNotFound> for OllamaError {
+    fn from(e: ModelNotFound) -> Self {
+        Self(StatusCode::NOT_FOUND, format!("model '{}' not found", e.0))
+    }
+}
+
+fn error_line(message: String) -> String {
+    let mut line = serde_json::to_string(&OllamaErrorBody { error: message })
+        .expect("Serialization of response failed.");
+    line.push('\n');
+    line
+}
+
+/// Streams a response as newline-delimited JSON objects, one per token, ending with an object with `done` set.
+struct OllamaStreamer {
+    rx: Receiver<Response>,
+    state: Arc<MistralRs>,
+    request_id: usize,
+    endpoint: Endpoint,
+    model: String,
+    is_done: bool,
+    /// Set by the chunk finishing the response. The last object is sent with the usage chunk after it.
+    done_reason: Option<String>,
+}
+
+impl OllamaStreamer {
+    /// The line for a chunk of text, or the last line once the usage of the request arrives.
+    fn chunk_lines(
+        &mut self,
+        text: String,
+        finish_reason: Option<String>,
+        usage: Option<Usage>,
+    ) -> String {
+        let mut lines = String::new();
+        if !text.is_empty() {
+            lines.push_str(&OllamaResponse::new(self.endpoint, &self.model, text, None).to_line());
+        }
+        if finish_reason.is_some() {
+            self.done_reason = finish_reason;
+        }
+        if let Some(usage) = usage {
+            self.is_done = true;
+            let done_reason = self.done_reason.take().unwrap_or_default();
+            let stats = DoneStats::from_usage(done_reason, &usage);
+            lines.push_str(
+                &OllamaResponse::new(self.endpoint, &self.model, String::new(), Some(stats))
+                    .to_line(),
+            );
+        }
+        lines
+    }
+}
+
+impl Drop for OllamaStreamer {
+    fn drop(&mut self) {
+        // The client disconnected before the request finished, so stop generating for it.
+        if !self.is_done {
+            if let Err(e) = self.state.cancel_request(self.request_id) {
+                MistralRs::maybe_log_error(self.state.clone(), &e);
+            }
+        }
+    }
+}
+
+impl futures::Stream for OllamaStreamer {
+    type Item = Result<String, Infallible>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.is_done {
+            return Poll::Ready(None);
+        }
+        let resp = match self.rx.poll_recv(cx) {
+            Poll::Ready(Some(resp)) => resp,
+            Poll::Ready(None) => {
+                self.is_done = true;
+                return Poll::Ready(None);
+            }
+            Poll::Pending => return Poll::Pending,
+        };
+        self.state.get_metrics().record_response(&resp);
+        let line = match resp {
+            Response::Chunk(response) => {
+                MistralRs::maybe_log_response(self.state.clone(), &response);
+                let choice = response.choices.into_iter().next();
+                let (text, finish_reason) = choice
+                    .map(|c| (c.delta.content, c.finish_reason))
+                    .unwrap_or_default();
+                self.chunk_lines(text, finish_reason, response.usage)
+            }
+            Response::CompletionChunk(response) => {
+                MistralRs::maybe_log_response(self.state.clone(), &response);
+                let choice = response.choices.into_iter().next();
+                let (text, finish_reason) = choice
+                    .map(|c| (c.text, c.finish_reason))
+                    .unwrap_or_default();
+                self.chunk_lines(text, finish_reason, response.usage)
+            }
+            Response::InternalError(e) => {
+                MistralRs::maybe_log_error(self.state.clone(), &*e);
+                self.is_done = true;
+                error_line(e.to_string())
+            }
+            Response::ValidationError(e) => {
+                self.is_done = true;
+                error_line(e.to_string())
+            }
+            Response::ModelError(msg, _) | Response::CompletionModelError(msg, _) => {
+                MistralRs::maybe_log_error(self.state.clone(), &*anyhow::Error::msg(msg.clone()));
+                self.is_done = true;
+                error_line(msg)
+            }
+            Response::Done(_) => unreachable!(),
+            Response::CompletionDone(_) => unreachable!(),
+            Response::Embeddings(_) => unreachable!(),
+            Response::Score(_) => unreachable!(),
+        };
+        Poll::Ready(Some(Ok(line)))
+    }
+}
+
+fn message_map(
+    role: String,
+    content: String,
+) -> IndexMap<String, Either<String, Vec<IndexMap<String, String>>>> {
+    let mut message = IndexMap::new();
+    message.insert("role".to_string(), Either::Left(role));
+    message.insert("content".to_string(), Either::Left(content));
+    message
+}
+
+/// Send a request for `messages` to the model and return its response, streamed or as a single object.
+async fn run_request(
+    state: Arc<MistralRs>,
+    endpoint: Endpoint,
+    model: String,
+    messages: RequestMessage,
+    options: OllamaOptions,
+    constraint: Constraint,
+    is_streaming: bool,
+) -> Result<AxumResponse, OllamaError> {
+    let (tx, mut rx) = channel(10_000);
+    let request_id = state.next_request_id();
+    let request = Request::Normal(NormalRequest {
+        id: request_id,
+        messages,
+        sampling_params: options.into_sampling_params(),
+        response: tx,
+        return_logprobs: false,
+        is_streaming,
+        suffix: None,
+        constraint,
+        adapters: None,
+        tool_choice: None,
+        tools: None,
+        priority: 0,
+        tenant: None,
+        logits_processors: None,
+        // The last streamed object reports the usage.
+        include_usage: is_streaming,
+    });
+
+    let internal_error = |state: Arc<MistralRs>, e: anyhow::Error| {
+        MistralRs::maybe_log_error(state, &*e);
+        OllamaError(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    };
+    let sender = state
+        .get_sender()
+        .map_err(|e| internal_error(state.clone(), anyhow::Error::msg(e.to_string())))?;
+    if let Err(e) = sender.send(request).await {
+        return Err(internal_error(state, anyhow::Error::msg(e.to_string())));
+    }
+
+    if is_streaming {
+        let streamer = OllamaStreamer {
+            rx,
+            state,
+            request_id,
+            endpoint,
+            model,
+            is_done: false,
+            done_reason: None,
+        };
+        return Ok((
+            [(header::CONTENT_TYPE, "application/x-ndjson")],
+            Body::from_stream(streamer),
+        )
+            .into_response());
+    }
+
+    let Some(response) = rx.recv().await else {
+        return Err(internal_error(
+            state,
+            anyhow::Error::msg("No response received from the model."),
+        ));
+    };
+    state.get_metrics().record_response(&response);
+    let (text, done_reason, usage) = match response {
+        Response::Done(response) => {
+            MistralRs::maybe_log_response(state, &response);
+            let choice = response.choices.into_iter().next();
+            let (text, done_reason) = choice
+                .map(|c| (c.message.content.unwrap_or_default(), c.finish_reason))
+                .unwrap_or_default();
+            (text, done_reason, response.usage)
+        }
+        Response::CompletionDone(response) => {
+            MistralRs::maybe_log_response(state, &response);
+            let choice = response.choices.into_iter().next();
+            let (text, done_reason) = choice
+                .map(|c| (c.text, c.finish_reason))
+                .unwrap_or_default();
+            (text, done_reason, response.usage)
+        }
+        Response::InternalError(e) => {
+            MistralRs::maybe_log_error(state, &*e);
+            return Err(OllamaError(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                e.to_string(),
+            ));
+        }
+        Response::ValidationError(e) => {
+            return Err(OllamaError(StatusCode::BAD_REQUEST, e.to_string()));
+        }
+        Response::ModelError(msg, _) | Response::CompletionModelError(msg, _) => {
+            return Err(internal_error(state, anyhow::Error::msg(msg)));
+        }
+        Response::Chunk(_) => unreachable!(),
+        Response::CompletionChunk(_) => unreachable!(),
+        Response::Embeddings(_) => unreachable!(),
+        Response::Score(_) => unreachable!(),
+    };
+    let stats = DoneStats::from_usage(done_reason, &usage);
+    Ok(Json(OllamaResponse::new(endpoint, &model, text, Some(stats))).into_response())
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/api/generate",
+    request_body = GenerateRequest,
+    responses((status = 200, description = "Ollama compatible completion"))
+)]
+pub async fn generate(
+    State(models): State<Arc<ModelRegistry>>,
+    Json(request): Json<GenerateRequest>,
+) -> Result<AxumResponse, OllamaError> {
+    let state = models.get(model_name(&request.model))?;
+    let repr = serde_json::to_string(&request).expect("Serialization of request failed.");
+    MistralRs::maybe_log_request(state.clone(), repr);
+    if request.keep_alive.is_some() {
+        debug!("Ignoring keep_alive, models stay loaded until they are unloaded.");
+    }
+    let constraint = format_constraint(request.format)?;
+
+    let messages = if request.raw {
+        RequestMessage::Completion {
+            text: request.prompt,
+            echo_prompt: false,
+            best_of: 1,
+        }
+    } else {
+        let mut messages = Vec::new();
+        if let Some(system) = request.system {
+            messages.push(message_map("system".to_string(), system));
+        }
+        messages.push(message_map("user".to_string(), request.prompt));
+        RequestMessage::Chat(messages)
+    };
+    run_request(
+        state,
+        Endpoint::Generate,
+        request.model,
+        messages,
+        request.options,
+        constraint,
+        request.stream,
+    )
+    .await
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/api/chat",
+    request_body = OllamaChatRequest,
+    responses((status = 200, description = "Ollama compatible chat completion"))
+)]
+pub async fn chat(
+    State(models): State<Arc<ModelRegistry>>,
+    Json(request): Json<OllamaChatRequest>,
+) -> Result<AxumResponse, OllamaError> {
+    let state = models.get(model_name(&request.model))?;
+    let repr = serde_json::to_string(&request).expect("Serialization of request failed.");
+    MistralRs::maybe_log_request(state.clone(), repr);
+    if request.keep_alive.is_some() {
+        debug!("Ignoring keep_alive, models stay loaded until they are unloaded.");
+    }
+    let constraint = format_constraint(request.format)?;
+
+    let messages = request
+        .messages
+        .into_iter()
+        .map(|message| message_map(message.role, message.content))
+        .collect();
+    run_request(
+        state,
+        Endpoint::Chat,
+        request.model,
+        RequestMessage::Chat(messages),
+        request.options,
+        constraint,
+        request.stream,
+    )
+    .await
+}
+
+#[derive(Serialize)]
+struct ModelDetails {
+    format: String,
+    family: String,
+    parameter_size: String,
+    quantization_level: String,
+}
+
+impl ModelDetails {
+    /// The details are not known for the models served here, so they are left empty.
+    fn unknown() -> Self {
+        Self {
+            format: String::new(),
+            family: String::new(),
+            parameter_size: String::new(),
+            quantization_level: String::new(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TagsModel {
+    name: String,
+    model: String,
+    modified_at: String,
+    size: u64,
+    digest: String,
+    details: ModelDetails,
+}
+
+#[derive(Serialize)]
+struct TagsResponse {
+    models: Vec<TagsModel>,
+}
+
+fn modified_at(state: &MistralRs) -> String {
+    i64::try_from(state.get_creation_time())
+        .ok()
+        .and_then(|secs| DateTime::from_timestamp(secs, 0))
+        .unwrap_or_default()
+        .to_rfc3339()
+}
+
+#[utoipa::path(
+    get,
+    tag = "Mistral.rs",
+    path = "/api/tags",
+    responses((status = 200, description = "Ollama compatible list of the served models"))
+)]
+pub async fn tags(State(models): State<Arc<ModelRegistry>>) -> impl IntoResponse {
+    Json(TagsResponse {
+        models: models
+            .list()
+            .into_iter()
+            .map(|(name, state)| {
+                let name = format!("{name}:latest");
+                TagsModel {
+                    model: name.clone(),
+                    name,
+                    modified_at: modified_at(&state),
+                    size: 0,
+                    digest: String::new(),
+                    details: ModelDetails::unknown(),
+                }
+            })
+            .collect(),
+    })
+}
+
+#[derive(Serialize)]
+struct ShowResponse {
+    modelfile: String,
+    parameters: String,
+    template: String,
+    details: ModelDetails,
+    model_info: IndexMap<String, String>,
+    modified_at: String,
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/api/show",
+    request_body = ShowRequest,
+    responses((status = 200, description = "Ollama compatible model information"))
+)]
+pub async fn show(
+    State(models): State<Arc<ModelRegistry>>,
+    Json(request): Json<ShowRequest>,
+) -> Result<impl IntoResponse, OllamaError> {
+    let state = models.get(model_name(&request.model))?;
+    Ok(Json(ShowResponse {
+        modelfile: format!("FROM {}\n", state.get_id()),
+        parameters: String::new(),
+        template: String::new(),
+        details: ModelDetails::unknown(),
+        model_info: IndexMap::from([("general.name".to_string(), state.get_id())]),
+        modified_at: modified_at(&state),
+    }))
+}
@@ -47,6 +47,10 @@ fn default_false() -> bool {
     false
 }
 
+fn default_true() -> bool {
+    true
+}
+
 fn default_1usize() -> usize {
     1
 }
@@ -62,6 +66,35 @@ pub enum Grammar {
     Regex(String),
     #[serde(rename = "yacc")]
     Yacc(String),
+    #[serde(rename = "json_schema")]
+    JsonSchema(serde_json::Value),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct JsonSchemaResponseFormat {
+    pub name: String,
+    pub schema: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct StreamOptions {
+    /// Send a final chunk with the usage of the request, with no choices.
+    #[serde(default)]
+    pub include_usage: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(tag = "type")]
+pub enum ResponseFormat {
+    #[serde(rename = "text")]
+    Text,
+    /// Any JSON object.
+    #[serde(rename = "json_object")]
+    JsonObject,
+    #[serde(rename = "json_schema")]
+    JsonSchema {
+        json_schema: JsonSchemaResponseFormat,
+    },
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
@@ -98,10 +131,16 @@ pub struct ChatCompletionRequest {
     pub top_p: Option<f64>,
     #[schema(example = true)]
     pub stream: Option<bool>,
+    #[schema(example = json!(Option::None::<StreamOptions>))]
+    pub stream_options: Option<StreamOptions>,
     #[schema(example = json!(Option::None::<Vec<Tool>>))]
     pub tools: Option<Vec<Tool>>,
     #[schema(example = json!(Option::None::<ToolChoice>))]
     pub tool_choice: Option<ToolChoice>,
+    #[schema(example = json!(Option::None::<ResponseFormat>))]
+    pub response_format: Option<ResponseFormat>,
+    #[schema(example = json!(Option::None::<u64>))]
+    pub seed: Option<u64>,
 
     // mistral.rs additional
     #[schema(example = json!(Option::None::<usize>))]
@@ -112,6 +151,38 @@ pub struct ChatCompletionRequest {
     pub adapters: Option<Vec<String>>,
     #[schema(example = json!(Option::None::<f64>))]
     pub min_p: Option<f64>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub beam_width: Option<usize>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub length_penalty: Option<f32>,
+    #[schema(example = json!(Option::None::<i32>))]
+    pub priority: Option<i32>,
+    /// Only used when the server has no API keys. Requests authenticated with a key use its name.
+    #[schema(example = json!(Option::None::<String>))]
+    pub tenant: Option<String>,
+    #[serde(default = "default_false")]
+    #[schema(example = false)]
+    pub processed_logprobs: bool,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub repetition_penalty: Option<f32>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub no_repeat_ngram_size: Option<usize>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub dry_multiplier: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub dry_base: Option<f32>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub dry_allowed_length: Option<usize>,
+    #[schema(example = json!(Option::None::<Vec<String>>))]
+    pub dry_sequence_breakers: Option<Vec<String>>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub typical_p: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub top_a: Option<f64>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_tau: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_eta: Option<f32>,
 }
 
 #[derive(Debug, Serialize, ToSchema)]
@@ -160,6 +231,8 @@ pub struct CompletionRequest {
     #[schema(example = json!(Option::None::<StopTokens>))]
     pub stop_seqs: Option<StopTokens>,
     pub stream: Option<bool>,
+    #[schema(example = json!(Option::None::<StreamOptions>))]
+    pub stream_options: Option<StreamOptions>,
     #[schema(example = 0.7)]
     pub temperature: Option<f64>,
     #[schema(example = json!(Option::None::<f64>))]
@@ -172,6 +245,8 @@ pub struct CompletionRequest {
     pub tools: Option<Vec<Tool>>,
     #[schema(example = json!(Option::None::<ToolChoice>))]
     pub tool_choice: Option<ToolChoice>,
+    #[schema(example = json!(Option::None::<u64>))]
+    pub seed: Option<u64>,
 
     // mistral.rs additional
     #[schema(example = json!(Option::None::<usize>))]
@@ -182,4 +257,122 @@ pub struct CompletionRequest {
     pub adapters: Option<Vec<String>>,
     #[schema(example = json!(Option::None::<f64>))]
     pub min_p: Option<f64>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub beam_width: Option<usize>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub length_penalty: Option<f32>,
+    #[schema(example = json!(Option::None::<i32>))]
+    pub priority: Option<i32>,
+    /// Only used when the server has no API keys. Requests authenticated with a key use its name.
+    #[schema(example = json!(Option::None::<String>))]
+    pub tenant: Option<String>,
+    #[serde(default = "default_false")]
+    #[schema(example = false)]
+    pub processed_logprobs: bool,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub repetition_penalty: Option<f32>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub no_repeat_ngram_size: Option<usize>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub dry_multiplier: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub dry_base: Option<f32>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub dry_allowed_length: Option<usize>,
+    #[schema(example = json!(Option::None::<Vec<String>>))]
+    pub dry_sequence_breakers: Option<Vec<String>>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub typical_p: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub top_a: Option<f64>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_tau: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_eta: Option<f32>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum EmbeddingInput {
+    Single(String),
+    Multi(Vec<String>),
+    Tokens(Vec<u32>),
+    MultiTokens(Vec<Vec<u32>>),
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, ToSchema)]
+pub enum EmbeddingPooling {
+    #[serde(rename = "mean")]
+    Mean,
+    #[serde(rename = "last")]
+    Last,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct EmbeddingRequest {
+    #[schema(example = "mistral")]
+    #[serde(default = "default_model")]
+    pub model: String,
+    #[schema(example = "The food was delicious.")]
+    pub input: EmbeddingInput,
+    #[schema(example = "float")]
+    pub encoding_format: Option<String>,
+    #[serde(rename = "user")]
+    pub _user: Option<String>,
+
+    // mistral.rs additional
+    #[schema(example = json!(Option::None::<EmbeddingPooling>))]
+    pub pooling: Option<EmbeddingPooling>,
+    #[serde(default = "default_true")]
+    #[schema(example = true)]
+    pub normalize: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum TokenizeRequest {
+    Messages {
+        #[schema(example = "mistral")]
+        #[serde(default = "default_model")]
+        model: String,
+        messages: Vec<Message>,
+        #[serde(default = "default_true")]
+        #[schema(example = true)]
+        add_generation_prompt: bool,
+        #[schema(example = json!(Option::None::<Vec<Tool>>))]
+        tools: Option<Vec<Tool>>,
+    },
+    Prompt {
+        #[schema(example = "mistral")]
+        #[serde(default = "default_model")]
+        model: String,
+        #[schema(example = "Hello, world!")]
+        prompt: String,
+        #[serde(default = "default_true")]
+        #[schema(example = true)]
+        add_special_tokens: bool,
+    },
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TokenizeResponse {
+    pub tokens: Vec<u32>,
+    pub count: usize,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct DetokenizeRequest {
+    #[schema(example = "mistral")]
+    #[serde(default = "default_model")]
+    pub model: String,
+    #[schema(example = json!(vec![1, 22557]))]
+    pub tokens: Vec<u32>,
+    #[serde(default = "default_false")]
+    #[schema(example = false)]
+    pub skip_special_tokens: bool,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DetokenizeResponse {
+    pub prompt: String,
 }
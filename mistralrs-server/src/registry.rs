@@ -0,0 +1,93 @@
+use std::sync::{Arc, RwLock};
+
+use axum::{
+    extract::Json,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use indexmap::IndexMap;
+use mistralrs_core::MistralRs;
+use serde::Serialize;
+
+/// The models served by the server, by the name requests select them with.
+pub struct ModelRegistry {
+    models: RwLock<IndexMap<String, Arc<MistralRs>>>,
+}
+
+/// The requested model is not served.
+#[derive(Debug)]
+pub struct ModelNotFound(pub(crate) String);
+
+#[derive(Serialize)]
+struct JsonError {
+    message: String,
+}
+
+impl IntoResponse for ModelNotFound {
+    fn into_response(self) -> Response {
+        let mut r = Json(JsonError {
+            message: format!("The model `{}` does not exist.", self.0),
+        })
+        .into_response();
+        *r.status_mut() = StatusCode::NOT_FOUND;
+        r
+    }
+}
+
+impl ModelRegistry {
+    pub fn new(models: IndexMap<String, Arc<MistralRs>>) -> Self {
+        Self {
+            models: RwLock::new(models),
+        }
+    }
+
+    /// Get the model a request for `name` is routed to. `default` or an empty name selects the first model.
+    pub fn get(&self, name: &str) -> Result<Arc<MistralRs>, ModelNotFound> {
+        let models = self
+            .models
+            .read()
+            .expect("Model registry lock was poisoned");
+        if let Some(model) = models.get(name) {
+            return Ok(model.clone());
+        }
+        match models.first() {
+            Some((_, model)) if name == "default" || name.is_empty() => Ok(model.clone()),
+            _ => Err(ModelNotFound(name.to_string())),
+        }
+    }
+
+    /// The model served under exactly this name, without the routing of [`Self::get`].
+    pub fn get_exact(&self, name: &str) -> Option<Arc<MistralRs>> {
+        self.models
+            .read()
+            .expect("Model registry lock was poisoned")
+            .get(name)
+            .cloned()
+    }
+
+    /// Serve `model` under `name`, after the models already served.
+    pub fn insert(&self, name: String, model: Arc<MistralRs>) {
+        self.models
+            .write()
+            .expect("Model registry lock was poisoned")
+            .insert(name, model);
+    }
+
+    /// Stop routing requests to the model named `name`, returning it.
+    pub fn remove(&self, name: &str) -> Option<Arc<MistralRs>> {
+        self.models
+            .write()
+            .expect("Model registry lock was poisoned")
+            .shift_remove(name)
+    }
+
+    /// All served models with their names.
+    pub fn list(&self) -> Vec<(String, Arc<MistralRs>)> {
+        self.models
+            .read()
+            .expect("Model registry lock was poisoned")
+            .iter()
+            .map(|(name, model)| (name.clone(), model.clone()))
+            .collect()
+    }
+}
@@ -0,0 +1,192 @@
+use std::{error::Error, sync::Arc};
+
+use crate::{
+    chat_completion::parse_messages,
+    openai::{DetokenizeRequest, DetokenizeResponse, TokenizeRequest, TokenizeResponse},
+    registry::{ModelNotFound, ModelRegistry},
+};
+use axum::{
+    extract::{Json, State},
+    http::{self, StatusCode},
+    response::IntoResponse,
+};
+use either::Either;
+use mistralrs_core::{MistralRs, RequestMessage};
+use serde::Serialize;
+
+pub enum TokenizationResponder<T> {
+    Json(T),
+    InternalError(Box<dyn Error>),
+    ValidationError(Box<dyn Error>),
+}
+
+trait ErrorToResponse: Serialize {
+    fn to_response(&self, code: StatusCode) -> axum::response::Response {
+        let mut r = Json(self).into_response();
+        *r.status_mut() = code;
+        r
+    }
+}
+
+#[derive(Serialize)]
+struct JsonError {
+    message: String,
+}
+
+impl JsonError {
+    fn new(message: String) -> Self {
+        Self { message }
+    }
+}
+impl ErrorToResponse for JsonError {}
+
+impl<T: Serialize> IntoResponse for TokenizationResponder<T> {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            TokenizationResponder::Json(s) => Json(s).into_response(),
+            TokenizationResponder::InternalError(e) => {
+                JsonError::new(e.to_string()).to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
+            }
+            TokenizationResponder::ValidationError(e) => {
+                JsonError::new(e.to_string()).to_response(http::StatusCode::UNPROCESSABLE_ENTITY)
+            }
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/tokenize",
+    request_body = TokenizeRequest,
+    responses((status = 200, description = "Tokens of the prompt or chat messages"))
+)]
+pub async fn tokenize(
+    State(models): State<Arc<ModelRegistry>>,
+    Json(request): Json<TokenizeRequest>,
+) -> Result<TokenizationResponder<TokenizeResponse>, ModelNotFound> {
+    let model = match &request {
+        TokenizeRequest::Messages { model, .. } | TokenizeRequest::Prompt { model, .. } => model,
+    };
+    let state = models.get(model)?;
+    Ok(tokenize_request(state, request).await)
+}
+
+async fn tokenize_request(
+    state: Arc<MistralRs>,
+    request: TokenizeRequest,
+) -> TokenizationResponder<TokenizeResponse> {
+    let repr = serde_json::to_string(&request).expect("Serialization of request failed.");
+    MistralRs::maybe_log_request(state.clone(), repr);
+
+    let (text, tools, add_generation_prompt, add_special_tokens, images) = match request {
+        TokenizeRequest::Messages {
+            model: _,
+            messages,
+            add_generation_prompt,
+            tools,
+        } => {
+            // Only vision models add tokens for the images, the others ignore them.
+            let fetch_images = match state.is_vision_model() {
+                Ok(is_vision_model) => is_vision_model,
+                Err(e) => {
+                    MistralRs::maybe_log_error(state, &*e);
+                    return TokenizationResponder::InternalError(e.into());
+                }
+            };
+            match parse_messages(Either::Left(messages), fetch_images).await {
+                Ok(RequestMessage::Chat(messages)) => (
+                    Either::Left(messages),
+                    tools,
+                    add_generation_prompt,
+                    true,
+                    Vec::new(),
+                ),
+                Ok(RequestMessage::VisionChat { images, messages }) => (
+                    Either::Left(messages),
+                    tools,
+                    add_generation_prompt,
+                    true,
+                    images,
+                ),
+                Ok(_) => unreachable!(),
+                Err(e) => return TokenizationResponder::ValidationError(e.into()),
+            }
+        }
+        TokenizeRequest::Prompt {
+            model: _,
+            prompt,
+            add_special_tokens,
+        } => (
+            Either::Right(prompt),
+            None,
+            false,
+            add_special_tokens,
+            Vec::new(),
+        ),
+    };
+
+    let runner = state.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        runner.tokenize(
+            text,
+            tools,
+            add_generation_prompt,
+            add_special_tokens,
+            images,
+        )
+    })
+    .await;
+
+    match result {
+        Ok(Ok(tokens)) => TokenizationResponder::Json(TokenizeResponse {
+            count: tokens.len(),
+            tokens,
+        }),
+        Ok(Err(e)) => TokenizationResponder::ValidationError(e.into()),
+        Err(e) => {
+            let e = anyhow::Error::msg(e.to_string());
+            MistralRs::maybe_log_error(state, &*e);
+            TokenizationResponder::InternalError(e.into())
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/detokenize",
+    request_body = DetokenizeRequest,
+    responses((status = 200, description = "Decoded text of the tokens"))
+)]
+pub async fn detokenize(
+    State(models): State<Arc<ModelRegistry>>,
+    Json(request): Json<DetokenizeRequest>,
+) -> Result<TokenizationResponder<DetokenizeResponse>, ModelNotFound> {
+    let state = models.get(&request.model)?;
+    Ok(detokenize_request(state, request).await)
+}
+
+async fn detokenize_request(
+    state: Arc<MistralRs>,
+    request: DetokenizeRequest,
+) -> TokenizationResponder<DetokenizeResponse> {
+    let repr = serde_json::to_string(&request).expect("Serialization of request failed.");
+    MistralRs::maybe_log_request(state.clone(), repr);
+
+    let runner = state.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        runner.detokenize(&request.tokens, request.skip_special_tokens)
+    })
+    .await;
+
+    match result {
+        Ok(Ok(prompt)) => TokenizationResponder::Json(DetokenizeResponse { prompt }),
+        Ok(Err(e)) => TokenizationResponder::ValidationError(e.into()),
+        Err(e) => {
+            let e = anyhow::Error::msg(e.to_string());
+            MistralRs::maybe_log_error(state, &*e);
+            TokenizationResponder::InternalError(e.into())
+        }
+    }
+}
@@ -96,6 +96,10 @@ fn main() -> anyhow::Result<()> {
         adapters: None,
         tools: None,
         tool_choice: None,
+        priority: 0,
+        tenant: None,
+        logits_processors: None,
+        include_usage: false,
     });
     mistralrs.get_sender()?.blocking_send(request)?;
 
@@ -72,6 +72,10 @@ fn main() -> anyhow::Result<()> {
         suffix: None,
         adapters: None,
         tool_choice: None,
+        priority: 0,
+        tenant: None,
+        logits_processors: None,
+        include_usage: false,
         tools: None,
     });
     mistralrs.get_sender()?.blocking_send(request)?;
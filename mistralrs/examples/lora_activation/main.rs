@@ -79,6 +79,10 @@ fn main() -> anyhow::Result<()> {
         suffix: None,
         adapters: Some(vec!["adapter_2".to_string()]),
         tool_choice: None,
+        priority: 0,
+        tenant: None,
+        logits_processors: None,
+        include_usage: false,
         tools: None,
     });
 
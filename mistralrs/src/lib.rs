@@ -35,6 +35,10 @@
 //!         suffix: None,
 //!         adapters: None,
 //!         tool_choice: None,
+//!         priority: 0,
+//!         tenant: None,
+//!         logits_processors: None,
+//!         include_usage: false,
 //!         tools: None,
 //!     });
 //!     mistralrs.get_sender()?.blocking_send(request)?;